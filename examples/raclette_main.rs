@@ -1,3 +1,5 @@
+#![allow(clippy::eq_op)]
+
 use raclette::*;
 use std::time::Duration;
 
@@ -63,6 +65,4 @@ fn tests() -> TestTree {
     )
 }
 
-fn main() {
-    default_main(Config::default().format(config::Format::Json), tests());
-}
+raclette::main!(tests(), Config::default().format(config::Format::Json));
@@ -0,0 +1,136 @@
+//! Tests written as async functions, see [async_test_case].  Requires
+//! the `tokio` or the `async-std` feature.
+
+use crate::{test_case_ctx, TestContext, TestTree};
+use std::cell::Cell;
+use std::future::Future;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Once;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+thread_local! {
+    /// The number of panics on this thread, counted by the panic hook,
+    /// so that panics in tasks spawned by a test fail it even if the
+    /// runtime swallows them.
+    static PANICS: Cell<u64> = const { Cell::new(0) };
+}
+
+fn count_panics() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANICS.with(|panics| panics.set(panics.get() + 1));
+            hook(info)
+        }));
+    });
+}
+
+/// Resolves to the output of the inner future, or to the payload of
+/// its panic.
+struct CatchUnwind<'a>(Pin<Box<dyn Future<Output = ()> + 'a>>);
+
+impl Future for CatchUnwind<'_> {
+    type Output = std::thread::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match catch_unwind(AssertUnwindSafe(|| self.0.as_mut().poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+/// Runs the future on a new runtime, None if it takes longer than
+/// `timeout`.
+#[cfg(feature = "tokio")]
+fn block_on<T>(timeout: Duration, future: impl Future<Output = T>) -> Option<T> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build the tokio runtime")
+        .block_on(async { tokio::time::timeout(timeout, future).await.ok() })
+}
+
+#[cfg(not(feature = "tokio"))]
+fn block_on<T>(timeout: Duration, future: impl Future<Output = T>) -> Option<T> {
+    async_std::task::block_on(async_std::future::timeout(timeout, future)).ok()
+}
+
+/// Runs the body of an async test, failing if it panics, if a task it
+/// spawned on this thread panicked, or if it takes longer than
+/// `timeout`.
+fn run(timeout: Duration, future: impl Future<Output = ()>) {
+    count_panics();
+    let panics_before = PANICS.with(Cell::get);
+    match block_on(timeout, CatchUnwind(Box::pin(future))) {
+        None => panic!("the test timed out after {:?}", timeout),
+        Some(Err(payload)) => resume_unwind(payload),
+        Some(Ok(())) => (),
+    }
+    if PANICS.with(Cell::get) != panics_before {
+        panic!("a task spawned by the test panicked");
+    }
+}
+
+/// Creates a test from an async function.  The function runs on a
+/// runtime created in the test process, with timers and I/O enabled,
+/// and the test fails if any task it spawns on the thread of the test
+/// panics.  The function is dropped once the time left until the
+/// timeout of the test at its start elapses, extensions of the
+/// timeout (see [TestContext::extend_timeout]) don't apply.
+pub fn async_test_case<N, F, Fut>(name: N, test: F) -> TestTree
+where
+    N: ToString,
+    F: FnOnce(TestContext) -> Fut + 'static,
+    Fut: Future<Output = ()>,
+{
+    test_case_ctx(name, move |ctx| {
+        let timeout = ctx.remaining_time();
+        run(timeout, test(ctx))
+    })
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn panic_message(result: std::thread::Result<()>) -> String {
+        let payload = result.expect_err("the test passed");
+        crate::try_get_panic_msg(&payload)
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    #[test]
+    fn async_tests_fail_on_panics_and_timeouts() {
+        let second = Duration::from_secs(1);
+        run(second, async {});
+        assert_eq!(
+            panic_message(catch_unwind(|| run(second, async { panic!("boom") }))),
+            "boom"
+        );
+        assert_eq!(
+            panic_message(catch_unwind(|| run(
+                Duration::from_millis(10),
+                std::future::pending()
+            ))),
+            "the test timed out after 10ms"
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_tests_fail_on_panics_of_spawned_tasks() {
+        let spawn = async {
+            let _ = tokio::spawn(async { panic!("in a task") }).await;
+        };
+        assert_eq!(
+            panic_message(catch_unwind(|| run(Duration::from_secs(1), spawn))),
+            "a task spawned by the test panicked"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Comparison of the results of a run against the results of a
+//! previous run, see [crate::config::Config::baseline].
+
+use crate::execution::{CompletedTask, Status};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// A test is considered to have become slower if it takes this many
+/// times longer than in the baseline...
+const REGRESSION_FACTOR: f64 = 1.5;
+/// ... and at least this much longer in absolute terms, so that the
+/// noise in durations of fast tests doesn't count.
+const MIN_REGRESSION: Duration = Duration::from_millis(500);
+
+/// The differences between a run and its baseline.  All lists are in
+/// the order of the run, removed tests in the order of the baseline.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Comparison {
+    /// Tests that failed, but didn't in the baseline.
+    pub newly_failing: Vec<String>,
+    /// Tests that passed, but failed in the baseline.
+    pub newly_passing: Vec<String>,
+    /// Tests that were skipped, but ran in the baseline.
+    pub newly_skipped: Vec<String>,
+    /// Tests missing from the baseline.
+    pub added: Vec<String>,
+    /// Tests of the baseline missing from the run.
+    pub removed: Vec<String>,
+    /// Tests that passed both times and became significantly slower,
+    /// with their durations in the baseline and in the run.
+    pub slower: Vec<(String, Duration, Duration)>,
+}
+
+impl Comparison {
+    pub fn is_empty(&self) -> bool {
+        *self == Comparison::default()
+    }
+}
+
+fn skipped(status: &Status) -> bool {
+    matches!(status, Status::Skipped(_))
+}
+
+fn passed(status: &Status) -> bool {
+    status.is_ok() && !skipped(status)
+}
+
+pub fn compare(baseline: &[CompletedTask], current: &[CompletedTask]) -> Comparison {
+    let before: HashMap<String, &CompletedTask> =
+        baseline.iter().map(|task| (task.name(), task)).collect();
+    let mut comparison = Comparison::default();
+
+    for task in current {
+        let name = task.name();
+        let old = match before.get(&name) {
+            Some(old) => old,
+            None => {
+                comparison.added.push(name);
+                continue;
+            }
+        };
+        if skipped(&task.status) {
+            // Tests skipped both times have nothing to compare.
+            if !skipped(&old.status) {
+                comparison.newly_skipped.push(name);
+            }
+        } else if !task.status.is_ok() && old.status.is_ok() {
+            comparison.newly_failing.push(name);
+        } else if passed(&task.status) && !old.status.is_ok() {
+            comparison.newly_passing.push(name);
+        } else if passed(&task.status)
+            && passed(&old.status)
+            && task.duration.as_secs_f64() > old.duration.as_secs_f64() * REGRESSION_FACTOR
+            && task.duration > old.duration + MIN_REGRESSION
+        {
+            comparison.slower.push((name, old.duration, task.duration));
+        }
+    }
+
+    let names: HashSet<String> = current.iter().map(|task| task.name()).collect();
+    comparison.removed = baseline
+        .iter()
+        .map(|task| task.name())
+        .filter(|name| !names.contains(name))
+        .collect();
+    comparison
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn compare_finds_differences() {
+        let task = |name: &str, status: Status, secs: u64| CompletedTask {
+            duration: Duration::from_secs(secs),
+            ..CompletedTask::new(vec![name.to_string()], status, std::time::UNIX_EPOCH)
+        };
+        let baseline = vec![
+            task("broken", Status::Success, 1),
+            task("fixed", Status::Timeout, 1),
+            task("slow", Status::Success, 2),
+            task("steady", Status::Success, 2),
+            task("gone", Status::Success, 1),
+            task("disabled", Status::Success, 1),
+            task("dormant", Status::Skipped("later".to_string()), 0),
+        ];
+        let current = vec![
+            task("broken", Status::Failure(1), 1),
+            task("fixed", Status::Success, 1),
+            task("slow", Status::Success, 4),
+            task("steady", Status::Success, 2),
+            task("new", Status::Skipped("later".to_string()), 0),
+            task("disabled", Status::Skipped("later".to_string()), 0),
+            task("dormant", Status::Skipped("later".to_string()), 0),
+        ];
+        assert_eq!(
+            compare(&baseline, &current),
+            Comparison {
+                newly_failing: vec!["broken".to_string()],
+                newly_passing: vec!["fixed".to_string()],
+                newly_skipped: vec!["disabled".to_string()],
+                added: vec!["new".to_string()],
+                removed: vec!["gone".to_string()],
+                slower: vec![(
+                    "slow".to_string(),
+                    Duration::from_secs(2),
+                    Duration::from_secs(4)
+                )],
+            }
+        );
+    }
+}
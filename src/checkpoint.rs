@@ -0,0 +1,136 @@
+//! Checkpoints of a run, see [crate::Config::checkpoint], so that an
+//! interrupted run can be resumed without executing the tests that
+//! already passed again.
+//!
+//! A checkpoint is a completed task per line in JSON, written as soon
+//! as the task finished.  A run killed while writing a line leaves it
+//! incomplete, so loading stops at the first line that doesn't parse.
+
+use crate::execution::{CompletedTask, Report, ReportEvent, Status};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+/// A report writing the completed tasks to a checkpoint before passing
+/// the events on to another report.
+pub struct CheckpointReport {
+    inner: Box<dyn Report>,
+    // None once writing failed, the run goes on without a checkpoint.
+    file: Option<File>,
+}
+
+impl CheckpointReport {
+    /// Creates the checkpoint in `path`, replacing any existing file.
+    pub fn create(path: &Path, inner: Box<dyn Report>) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            file: Some(File::create(path)?),
+        })
+    }
+}
+
+impl Report for CheckpointReport {
+    fn on_event(&mut self, event: &ReportEvent) {
+        if let (Some(file), ReportEvent::TestFinished(task)) = (self.file.as_mut(), event) {
+            // A single write per line, nothing stays buffered when the
+            // driver forks a test process.
+            let mut line = serde_json::to_vec(task).expect("failed to serialize a task");
+            line.push(b'\n');
+            if let Err(err) = file.write_all(&line) {
+                eprintln!("Failed to write the checkpoint: {}", err);
+                self.file = None;
+            }
+        }
+        self.inner.on_event(event);
+    }
+}
+
+/// Returns the tasks that passed according to the checkpoint in
+/// `input`.  The skipped tasks didn't pass, they are checked again.
+pub fn passed(input: impl BufRead) -> Vec<CompletedTask> {
+    input
+        .lines()
+        .map_while(|line| serde_json::from_str::<CompletedTask>(&line.ok()?).ok())
+        .filter(|task| task.status.is_ok() && !matches!(task.status, Status::Skipped(_)))
+        .collect()
+}
+
+/// Returns the tasks that passed according to the checkpoint in
+/// `path`, or nothing if there is no such file.
+pub fn load(path: &Path) -> io::Result<Vec<CompletedTask>> {
+    match File::open(path) {
+        Ok(file) => Ok(passed(BufReader::new(file))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// A report showing the tasks of a checkpoint as if they had been
+/// executed again, along with those actually executed.
+pub struct ResumedReport {
+    inner: Box<dyn Report>,
+    resumed: Vec<CompletedTask>,
+}
+
+impl ResumedReport {
+    pub fn new(resumed: Vec<CompletedTask>, inner: Box<dyn Report>) -> Self {
+        Self { inner, resumed }
+    }
+}
+
+impl Report for ResumedReport {
+    fn on_event(&mut self, event: &ReportEvent) {
+        match *event {
+            ReportEvent::Plan(names) => {
+                let mut all: Vec<String> = self.resumed.iter().map(CompletedTask::name).collect();
+                all.extend_from_slice(names);
+                self.inner.on_event(&ReportEvent::Plan(&all));
+            }
+            ReportEvent::SuiteStarted { test_count } => {
+                self.inner.on_event(&ReportEvent::SuiteStarted {
+                    test_count: test_count + self.resumed.len(),
+                })
+            }
+            ReportEvent::Header(_) => {
+                self.inner.on_event(event);
+                for task in &self.resumed {
+                    self.inner.on_event(&ReportEvent::TestStarted {
+                        full_name: &task.full_name,
+                    });
+                    self.inner.on_event(&ReportEvent::TestFinished(task));
+                }
+            }
+            _ => self.inner.on_event(event),
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn only_passed_tasks_are_resumed() {
+        let task = |name: &str, status| CompletedTask {
+            duration: std::time::Duration::from_secs(1),
+            ..CompletedTask::new(vec![name.to_string()], status, std::time::UNIX_EPOCH)
+        };
+        let mut checkpoint = Vec::new();
+        for task in [
+            task("a", Status::Success),
+            task("b", Status::Failure(1)),
+            task("c", Status::Skipped("later".to_string())),
+            task("d", Status::Success),
+        ] {
+            serde_json::to_writer(&mut checkpoint, &task).unwrap();
+            checkpoint.push(b'\n');
+        }
+        // The run was killed while writing the last line.
+        checkpoint.truncate(checkpoint.len() - 10);
+        let names: Vec<_> = passed(&checkpoint[..])
+            .iter()
+            .map(CompletedTask::name)
+            .collect();
+        assert_eq!(names, vec!["a"]);
+    }
+}
@@ -0,0 +1,71 @@
+//! Tests running external programs, see [command_test].
+
+use crate::{test_case, TestTree};
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+
+/// Creates a test running `command`, e.g. a shell or Python script,
+/// which passes if the command exits with code 0.
+///
+/// The command runs in the test process group with its standard
+/// streams, so its output is captured like the output of any test,
+/// and it's killed along with the test process when the test times
+/// out.
+pub fn command_test(name: impl ToString, command: Command) -> TestTree {
+    test_case(name, move || {
+        let mut command = command;
+        let program = command.get_program().to_string_lossy().into_owned();
+        let status = command
+            .status()
+            .unwrap_or_else(|err| panic!("failed to run {}: {}", program, err));
+        if let Some(signal) = status.signal() {
+            panic!("{} was killed by signal {}", program, signal);
+        }
+        if !status.success() {
+            panic!(
+                "{} exited with code {}",
+                program,
+                status.code().unwrap_or_default()
+            );
+        }
+    })
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn commands_pass_on_success_and_have_their_output_captured() {
+        use crate::{
+            config::Config,
+            execution::{test::run, Status},
+            test_suite,
+        };
+        let sh = |script: &str| {
+            let mut command = Command::new("sh");
+            command.args(["-c", script]);
+            command
+        };
+        let tasks = run(
+            &Config::default(),
+            test_suite(
+                "s",
+                vec![
+                    command_test("passing", Command::new("true")),
+                    command_test("failing", sh("echo out; echo err >&2; exit 3")),
+                    command_test("killed", sh("kill -KILL $$")),
+                ],
+            ),
+        );
+        let task = |name: &str| tasks.iter().find(|t| t.full_name[1] == name).unwrap();
+        assert!(task("passing").status.is_ok());
+
+        let failing = task("failing");
+        assert_eq!(failing.status, Status::Failure(101));
+        assert_eq!(failing.stdout_as_string(), "out\n");
+        // The harness captures the panic messages of the thread.
+        assert_eq!(failing.stderr_as_string(), "err\n");
+        assert_eq!(task("killed").status, Status::Failure(101));
+    }
+}
@@ -0,0 +1,187 @@
+//! Compression of the output of the completed tests kept in memory
+//! until the end of the run, see [crate::Config::compress_output].
+//!
+//! The driver keeps every completed task to return the results, and
+//! the JUnit and TRX reports keep them to write the file at the end.
+//! With suites of verbose passing tests, their output is most of the
+//! memory of the driver, although nothing reads it before the end.
+
+use crate::config::KeepOutput;
+use crate::execution::CompletedTask;
+
+/// The output of a task compressed with LZ4.
+#[cfg_attr(not(feature = "compression"), allow(dead_code))]
+struct CompressedOutput {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+#[cfg(feature = "compression")]
+fn compress(task: &mut CompletedTask) -> Option<CompressedOutput> {
+    if task.stdout.is_empty() && task.stderr.is_empty() {
+        return None;
+    }
+    let output = CompressedOutput {
+        stdout: lz4_flex::compress_prepend_size(&task.stdout),
+        stderr: lz4_flex::compress_prepend_size(&task.stderr),
+    };
+    task.stdout = Vec::new();
+    task.stderr = Vec::new();
+    Some(output)
+}
+
+#[cfg(feature = "compression")]
+fn decompress(task: &mut CompletedTask, output: CompressedOutput) {
+    let decompress = |bytes: &[u8]| {
+        lz4_flex::decompress_size_prepended(bytes).expect("failed to decompress the output")
+    };
+    task.stdout = decompress(&output.stdout);
+    task.stderr = decompress(&output.stderr);
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_task: &mut CompletedTask) -> Option<CompressedOutput> {
+    eprintln!("Failed to compress the output: raclette was built without the compression feature");
+    std::process::exit(1)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_task: &mut CompletedTask, _output: CompressedOutput) {
+    unreachable!("no output is compressed without the compression feature")
+}
+
+/// Discards the output of `task`, and that of its previous attempts
+/// unless `attempts` is false.
+fn discard_output(task: &mut CompletedTask, attempts: bool) {
+    task.stdout = Vec::new();
+    task.stderr = Vec::new();
+    task.output_chunks = Vec::new();
+    if attempts {
+        for attempt in &mut task.previous_attempts {
+            discard_output(attempt, true);
+        }
+    }
+}
+
+/// Completed tasks kept until the end of the run, with their output
+/// compressed or discarded if enabled.
+pub(crate) struct RetainedTasks {
+    compress: bool,
+    keep: KeepOutput,
+    // The stdout and stderr of the tasks with compressed output are
+    // empty.
+    tasks: Vec<(CompletedTask, Option<CompressedOutput>)>,
+}
+
+impl RetainedTasks {
+    pub(crate) fn new(compress: bool) -> Self {
+        Self {
+            compress,
+            keep: KeepOutput::All,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Discards the output of the tasks that `keep` doesn't select,
+    /// see [crate::Config::keep_output].
+    pub(crate) fn keep_output(mut self, keep: KeepOutput) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    pub(crate) fn push(&mut self, mut task: CompletedTask) {
+        match self.keep {
+            KeepOutput::All => (),
+            // The failed attempts of a flaky test keep their output.
+            KeepOutput::Failed if task.status.is_ok() => discard_output(&mut task, false),
+            KeepOutput::Failed => (),
+            KeepOutput::None => discard_output(&mut task, true),
+        }
+        let output = if self.compress {
+            compress(&mut task)
+        } else {
+            None
+        };
+        self.tasks.push((task, output));
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// The tasks, whose output may be missing, e.g. to count them by
+    /// status.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &CompletedTask> {
+        self.tasks.iter().map(|(task, _)| task)
+    }
+
+    /// The tasks with their whole output.
+    pub(crate) fn into_vec(self) -> Vec<CompletedTask> {
+        self.tasks
+            .into_iter()
+            .map(|(mut task, output)| {
+                if let Some(output) = output {
+                    decompress(&mut task, output);
+                }
+                task
+            })
+            .collect()
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::execution::Status;
+    #[allow(unused_imports)]
+    use std::time::UNIX_EPOCH;
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_output_is_restored() {
+        let task = |stdout: &str| CompletedTask {
+            stdout: stdout.as_bytes().to_vec(),
+            ..CompletedTask::new(vec!["t".to_string()], Status::Success, UNIX_EPOCH)
+        };
+        let mut tasks = RetainedTasks::new(true);
+        tasks.push(task(&"passed\n".repeat(1000)));
+        tasks.push(task(""));
+        assert!(tasks.iter().all(|task| task.stdout.is_empty()));
+        let tasks = tasks.into_vec();
+        assert_eq!(tasks[0].stdout, "passed\n".repeat(1000).into_bytes());
+        assert!(tasks[1].stdout.is_empty());
+    }
+
+    #[test]
+    fn output_is_kept_as_requested() {
+        let task = |status: Status| CompletedTask {
+            stdout: b"out".to_vec(),
+            stderr: b"err".to_vec(),
+            ..CompletedTask::new(vec!["t".to_string()], status, UNIX_EPOCH)
+        };
+        // A flaky test, which passed after a failed attempt.
+        let flaky = CompletedTask {
+            previous_attempts: vec![task(Status::Failure(1))],
+            ..task(Status::Success)
+        };
+        let kept = |keep: KeepOutput| {
+            let mut tasks = RetainedTasks::new(false).keep_output(keep);
+            tasks.push(flaky.clone());
+            tasks.push(task(Status::Failure(1)));
+            tasks
+                .into_vec()
+                .iter()
+                .map(|task| {
+                    (
+                        task.stdout.len() + task.stderr.len(),
+                        task.previous_attempts.iter().map(|a| a.stdout.len()).sum(),
+                    )
+                })
+                .collect::<Vec<(usize, usize)>>()
+        };
+        assert_eq!(kept(KeepOutput::All), vec![(6, 3), (6, 0)]);
+        assert_eq!(kept(KeepOutput::Failed), vec![(0, 3), (6, 0)]);
+        assert_eq!(kept(KeepOutput::None), vec![(0, 0), (0, 0)]);
+    }
+}
@@ -1,9 +1,15 @@
+use crate::execution::{Clock, CompletedTask, VirtualClock};
+use crate::filter::Filter;
+use crate::remote::Worker;
+use nix::sys::signal::Signal;
 use pico_args::Error as ArgsError;
-use std::{ffi::OsString, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, ffi::OsString, path::PathBuf, sync::Arc, time::Duration};
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Default)]
 pub enum When {
     /// Automatically detect if color support is available on the terminal.
+    #[default]
     Auto,
     /// Always display colors.
     Always,
@@ -11,12 +17,6 @@ pub enum When {
     Never,
 }
 
-impl Default for When {
-    fn default() -> Self {
-        Self::Auto
-    }
-}
-
 impl When {
     fn merge(l: When, r: When) -> When {
         match l {
@@ -26,10 +26,19 @@ impl When {
     }
 }
 
+/// An output stream of the process.
+#[derive(PartialEq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub enum Stream {
+    #[default]
+    Stdout,
+    Stderr,
+}
+
 /// Enumerates all the formats that can be used to report test results.
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Default)]
 pub enum Format {
     /// Default formatter.
+    #[default]
     Auto,
     /// Use the same format that libtest uses.
     LibTest,
@@ -38,13 +47,44 @@ pub enum Format {
     Json,
     /// Use the format specified on http://testanything.org.
     Tap,
+    /// Use the Visual Studio test results (TRX) format, written when
+    /// the run is done, e.g. for the PublishTestResults task of Azure
+    /// DevOps.
+    Trx,
+    /// Write a line of comma-separated values per test, e.g. for
+    /// spreadsheets.
+    Csv,
 }
 
-impl Default for Format {
-    fn default() -> Self {
-        Self::Auto
-    }
-}
+/// The values accepted by --format, with their descriptions.
+pub const FORMATS: &[(&str, Format, &str)] = &[
+    ("auto", Format::Auto, "default"),
+    (
+        "libtest",
+        Format::LibTest,
+        "emulate the output produced by cargo test",
+    ),
+    (
+        "json",
+        Format::Json,
+        "libtest JSON format, one event per line",
+    ),
+    (
+        "tap",
+        Format::Tap,
+        "Test Anything Protocol, http://testanything.org",
+    ),
+    (
+        "trx",
+        Format::Trx,
+        "Visual Studio test results, e.g. for Azure DevOps",
+    ),
+    (
+        "csv",
+        Format::Csv,
+        "comma-separated values, one line per test (csv-report feature)",
+    ),
+];
 
 impl Format {
     fn merge(l: Format, r: Format) -> Format {
@@ -55,17 +95,288 @@ impl Format {
     }
 }
 
+/// Controls what happens when a test leaves file descriptors open.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum LeakPolicy {
+    /// Do not track open file descriptors.
+    #[default]
+    Ignore,
+    /// Report leaked descriptors but do not affect the test status.
+    Warn,
+    /// Mark tests that leak descriptors as failed.
+    Fail,
+}
+
+/// Which tests keep their output in the results once they have been
+/// reported, see [Config::keep_output].
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum KeepOutput {
+    /// Keep the output of all the tests.
+    #[default]
+    All,
+    /// Keep the output of the tests that failed.
+    Failed,
+    /// Discard the output of all the tests.
+    None,
+}
+
+/// Controls how many tests share a process, see
+/// [Config::execution_policy].  There is no policy executing the
+/// tests as threads of the driver: the driver relies on test
+/// processes to capture their output, to enforce their timeouts and
+/// to survive their crashes.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum ExecutionPolicy {
+    /// Execute each test in a process of its own.
+    #[default]
+    ProcessPerTest,
+    /// Execute the tests of each top-level suite one after the other
+    /// in a process of their own.
+    ProcessPerSuite,
+}
+
+/// The I/O scheduling class and priority of the test processes, see
+/// ioprio_set(2).  Priorities go from 0, the highest, to 7.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum IoPriority {
+    /// Served before the other classes, requires privileges.
+    RealTime(u8),
+    /// The class of processes by default.
+    BestEffort(u8),
+    /// Only served when no other process needs the disk.
+    Idle,
+}
+
+/// A CI system whose log viewer understands markers in the output of
+/// the jobs, see [Config::ci].
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Ci {
+    Buildkite,
+    GitLab,
+    GitHub,
+}
+
+/// Controls whether skipped tests are executed.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum RunIgnored {
+    /// Do not execute skipped tests.
+    #[default]
+    No,
+    /// Execute skipped tests along with the other tests.
+    Yes,
+    /// Execute only the skipped tests.
+    Only,
+}
+
+/// A service shared by the tests with a tag, see
+/// [Config::global_fixture].
+pub(crate) struct GlobalFixture {
+    pub(crate) setup: Arc<dyn Fn() + Send + Sync>,
+    pub(crate) teardown: Arc<dyn Fn() + Send + Sync>,
+}
+
+/// A check of the environment of the tests, see
+/// [Config::health_check].
+pub(crate) struct HealthCheck {
+    pub(crate) name: String,
+    pub(crate) check: Box<dyn Fn() -> Result<(), String>>,
+}
+
 #[derive(Default)]
 pub struct Config {
-    pub(crate) filter: Option<String>,
+    pub(crate) filters: Vec<String>,
     pub(crate) skip_filters: Vec<String>,
+    pub(crate) tag_filters: Vec<String>,
+    pub(crate) exact: bool,
+    pub(crate) run_ignored: Option<RunIgnored>,
+    pub(crate) forbid_only: bool,
     pub(crate) timeout: Option<Duration>,
     pub(crate) color: When,
     pub(crate) jobs: Option<usize>,
     pub(crate) format: Format,
+    pub(crate) base64_output: bool,
+    pub(crate) diagnostics: Option<Stream>,
+    pub(crate) ui: bool,
     pub(crate) nocapture: bool,
+    pub(crate) transcript: bool,
+    pub(crate) timestamps: bool,
+    pub(crate) report_time: bool,
+    pub(crate) aligned: bool,
+    pub(crate) stage_times: bool,
+    pub(crate) ci: Option<Ci>,
+    pub(crate) max_output_bytes: Option<usize>,
+    pub(crate) output_hard_cap: Option<usize>,
+    pub(crate) memory_limit: Option<u64>,
+    pub(crate) fd_leaks: Option<LeakPolicy>,
+    pub(crate) execution_policy: Option<ExecutionPolicy>,
+    pub(crate) deny_stderr: bool,
+    pub(crate) coverage_dir: Option<PathBuf>,
+    pub(crate) core_dir: Option<PathBuf>,
+    pub(crate) baseline: Option<PathBuf>,
+    pub(crate) snapshot_dir: Option<PathBuf>,
+    pub(crate) bless: bool,
+    pub(crate) history_db: Option<PathBuf>,
+    pub(crate) suggest_split: Option<usize>,
+    pub(crate) record: Option<PathBuf>,
+    pub(crate) logfile: Option<PathBuf>,
+    pub(crate) junit: Option<PathBuf>,
+    pub(crate) checkpoint: Option<PathBuf>,
+    pub(crate) resume: bool,
+    // The index of this shard, starting at 0, and the number of shards.
+    pub(crate) shard: Option<(usize, usize)>,
+    // The file `bazel test` asks a sharded binary to touch, see
+    // TEST_SHARD_STATUS_FILE.
+    pub(crate) shard_status_file: Option<PathBuf>,
+    pub(crate) tmp_dir: Option<PathBuf>,
+    pub(crate) log_level: Option<log::Level>,
+    pub(crate) record_env: Vec<String>,
+    pub(crate) workers: Vec<Worker>,
+    pub(crate) remotes: Vec<String>,
+    pub(crate) notify: bool,
+    pub(crate) prefork: bool,
+    pub(crate) zygote: bool,
+    pub(crate) child_init: Option<fn()>,
+    pub(crate) classifier: Option<fn(&CompletedTask) -> Option<String>>,
+    pub(crate) tag_limits: HashMap<String, usize>,
+    pub(crate) resource_pools: HashMap<String, Vec<String>>,
+    pub(crate) global_fixtures: HashMap<String, GlobalFixture>,
+    pub(crate) health_checks: Vec<HealthCheck>,
+    // None if not set, Some(None) if disabled.
+    pub(crate) timeout_signal: Option<Option<Signal>>,
+    pub(crate) heartbeat: Option<Duration>,
+    pub(crate) launch_delay: Option<Duration>,
+    pub(crate) nice: Option<i32>,
+    pub(crate) cpus: Option<Vec<usize>>,
+    pub(crate) pin_jobs: bool,
+    pub(crate) io_priority: Option<IoPriority>,
+    pub(crate) reap_daemons: bool,
+    pub(crate) poll_timeout: Option<Duration>,
+    pub(crate) poll_events: Option<usize>,
+    pub(crate) read_buffer: Option<usize>,
+    pub(crate) splice_output: bool,
+    pub(crate) shm_reports: bool,
+    pub(crate) compress_output: bool,
+    pub(crate) keep_output: Option<KeepOutput>,
+    pub(crate) timeout_extension: Option<Duration>,
+    pub(crate) duration_resolution: Option<Duration>,
+    pub(crate) clock: Option<Box<dyn Clock>>,
+    pub(crate) retries: Option<usize>,
+}
+
+/// The action requested on the command line.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Command {
+    /// Execute the tests.  This is the default.
+    Run,
+    /// Print the tests that would be executed.
+    List,
+    /// Print the results recorded with `--format json` in a file in
+    /// the configured format.
+    Report(PathBuf),
+    /// Print the results recorded with `--format json` in several
+    /// files, e.g. by shards of a test suite, as the results of a
+    /// single run in the configured format.
+    Merge(Vec<PathBuf>),
+    /// Listen on an address for drivers executing tests remotely, see
+    /// [Config::remote].  The tests are executed with the options of
+    /// the worker.  Drivers aren't authenticated, so the address must
+    /// not be reachable from untrusted hosts.
+    Worker(String),
 }
 
+/// The names of the commands.  A test name filter equal to one of
+/// these has to follow an explicit `run`.
+const COMMANDS: &[&str] = &["run", "list", "report", "merge", "worker"];
+
+/// Where an option of the command line applies when the tests execute
+/// on workers, see [Config::worker].
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub(crate) enum Scope {
+    /// The option changes how the tests execute, the workers get it
+    /// too.
+    Tests,
+    /// The option only changes what the driver does with the results,
+    /// e.g. how it reports them.  The workers always print JSON.
+    Driver,
+}
+
+/// The options of the command line: their names, whether they take a
+/// value and where they apply.  `--test-threads` and `-Z` are left out
+/// of the help, see [Config::from_args].
+pub(crate) const OPTIONS: &[(&[&str], bool, Scope)] = &[
+    (&["-t", "--timeout"], true, Scope::Tests),
+    (&["-c", "--color"], true, Scope::Driver),
+    (&["-f", "--format"], true, Scope::Driver),
+    (&["--base64-output"], false, Scope::Tests),
+    (&["--diagnostics"], true, Scope::Driver),
+    (&["--ui"], false, Scope::Driver),
+    (&["-j", "--jobs"], true, Scope::Tests),
+    (&["--test-threads"], true, Scope::Tests),
+    (&["-Z"], true, Scope::Tests),
+    (&["--skip"], true, Scope::Tests),
+    (&["--tag"], true, Scope::Tests),
+    (&["--exact"], false, Scope::Tests),
+    (&["--forbid-only"], false, Scope::Tests),
+    (&["--ignored"], false, Scope::Tests),
+    (&["--include-ignored"], false, Scope::Tests),
+    // The output of the tests would mix with the events the workers
+    // print.
+    (&["--nocapture"], false, Scope::Driver),
+    (&["--transcript"], false, Scope::Tests),
+    (&["--timestamps"], false, Scope::Tests),
+    (&["--report-time"], false, Scope::Driver),
+    (&["--aligned"], false, Scope::Driver),
+    (&["--stage-times"], false, Scope::Driver),
+    (&["--ci"], true, Scope::Driver),
+    (&["--max-output-bytes"], true, Scope::Tests),
+    (&["--output-hard-cap"], true, Scope::Tests),
+    (&["--memory-limit"], true, Scope::Tests),
+    (&["--prefork"], false, Scope::Tests),
+    (&["--zygote"], false, Scope::Tests),
+    (&["--timeout-signal"], true, Scope::Tests),
+    (&["--heartbeat"], true, Scope::Tests),
+    (&["--launch-delay"], true, Scope::Tests),
+    (&["--nice"], true, Scope::Tests),
+    (&["--cpus"], true, Scope::Tests),
+    (&["--pin-jobs"], false, Scope::Tests),
+    (&["--io-priority"], true, Scope::Tests),
+    (&["--reap-daemons"], false, Scope::Tests),
+    (&["--poll-timeout"], true, Scope::Tests),
+    (&["--poll-events"], true, Scope::Tests),
+    (&["--read-buffer"], true, Scope::Tests),
+    (&["--splice-output"], false, Scope::Tests),
+    (&["--shm-reports"], false, Scope::Tests),
+    (&["--compress-output"], false, Scope::Tests),
+    (&["--keep-output"], true, Scope::Tests),
+    (&["--timeout-extension"], true, Scope::Tests),
+    (&["--duration-resolution"], true, Scope::Driver),
+    (&["--virtual-time"], false, Scope::Driver),
+    (&["--retries"], true, Scope::Tests),
+    (&["--max-concurrency"], true, Scope::Tests),
+    (&["--fd-leaks"], true, Scope::Tests),
+    (&["--execution-policy"], true, Scope::Tests),
+    (&["--deny-stderr"], false, Scope::Tests),
+    (&["--coverage-dir"], true, Scope::Tests),
+    (&["--core-dir"], true, Scope::Tests),
+    (&["--baseline"], true, Scope::Driver),
+    (&["--snapshot-dir"], true, Scope::Tests),
+    (&["--bless"], false, Scope::Tests),
+    (&["--history-db"], true, Scope::Driver),
+    (&["--suggest-split"], true, Scope::Driver),
+    (&["--record"], true, Scope::Driver),
+    (&["--logfile"], true, Scope::Driver),
+    (&["--junit"], true, Scope::Driver),
+    (&["--checkpoint"], true, Scope::Driver),
+    (&["--resume"], false, Scope::Driver),
+    (&["--shard"], true, Scope::Driver),
+    (&["--tmp-dir"], true, Scope::Tests),
+    (&["--log-level"], true, Scope::Tests),
+    (&["--record-env"], true, Scope::Tests),
+    (&["--worker"], true, Scope::Driver),
+    (&["--remote"], true, Scope::Driver),
+    (&["--notify"], false, Scope::Driver),
+];
+
 #[derive(Debug)]
 pub enum ConfigParseError {
     HelpRequested,
@@ -78,38 +389,304 @@ pub const HELP_STR: &str = r#"
       --skip FILTER        Skip tests whose names contain FILTER
                            (this flag can be used multiple times)
 
+      --tag TAG            Execute only tests tagged with TAG (this
+                           flag can be used multiple times, the tests
+                           need all the TAGs)
+
+      --exact              Match TESTNAMEs and FILTERs against full test
+                           names exactly instead of their parts
+
+      --ignored            Execute only the skipped tests
+
+      --include-ignored    Execute the skipped tests too
+
+      --forbid-only        Fail if some tests are marked with only(),
+                           e.g. on CI
+
       --nocapture          Print output of each task directly as soon
                            as it arrives
 
+      --transcript         Show the output of failed tests as a single
+                           transcript of both streams, in the order it
+                           arrived
+
+      --timestamps         Show the time each line of output arrived
+                           at in the transcripts (implies --transcript)
+
+      --report-time        Show the execution time of each test in the
+                           libtest report, colored when exceeding the
+                           thresholds in RUST_TEST_TIME_INTEGRATION
+
+      --aligned            Align the statuses of the tests in the
+                           libtest report in a column
+
+      --stage-times        Show the durations of the stages of each
+                           test and the slowest stages overall in the
+                           libtest report
+
+      --duration-resolution MS
+                           Round the durations in the reports down to a
+                           multiple of MS milliseconds, e.g. to compare
+                           the reports of two runs
+
+      --virtual-time       Report the tests as starting at virtual
+                           times from the Unix epoch, one second apart
+                           in the order of the plan, instead of the
+                           time of the system
+
+      --ci SYSTEM          Fold the details of each failed test in the
+                           libtest report into a section of the log of
+                           SYSTEM (buildkite, gitlab or github), and
+                           annotate the failures on github
+
+      --max-output-bytes N Keep only the first and last N/2 bytes of
+                           each stream of the output of a test
+
+      --output-hard-cap N  Kill and fail tests writing more than N
+                           bytes of output
+
+      --memory-limit MIB   Kill and fail tests whose resident memory
+                           exceeds MIB mebibytes (Linux only)
+
+      --splice-output      Move the output of tests to memory files
+                           with splice(2) rather than reading it, for
+                           tests writing hundreds of megabytes
+
+      --shm-reports        Send the stages and metrics of tests through
+                           a ring buffer in shared memory rather than
+                           the report pipe, for tests reporting
+                           thousands of metrics per second
+
+      --compress-output    Compress the output of the completed tests
+                           kept in memory until the end of the run
+                           (requires the compression feature)
+
+      --keep-output TESTS  Which tests keep their output in memory once
+                           reported, TESTS can be
+                             'all'    (default)
+                             'failed' (discard the output of passing
+                                       tests)
+                             'none'
+
   -t, --timeout NSEC       Specify test execution timeout to be NSEC seconds
 
+      --retries N          Execute failing tests up to N more times,
+                           tests passing when retried are flaky
+
+      --timeout-signal SIG Send SIG (default SIGUSR1) to tests exceeding
+                           the timeout shortly before killing them, SIG
+                           can be a signal name or 'none'
+
+      --timeout-extension NSEC
+                           Let each test extend its timeout by up to NSEC
+                           seconds in total (default 0)
+
+      --heartbeat NSEC     Print the tests that are still running and
+                           their elapsed times to stderr every NSEC
+                           seconds, to keep CI logs alive; the JSON
+                           report prints "running" events instead
+
+      --launch-delay MS    Wait MS milliseconds after starting a test
+                           before starting the next one, so that tests
+                           don't all hit shared services at once
+
+      --nice N             Run the tests with the nice value N
+
+      --cpus LIST          Run the tests on the CPUs in LIST, e.g.
+                           0-3,8
+
+      --pin-jobs           Give each parallel job CPUs of its own, out
+                           of those of --cpus or of this process
+
+      --io-priority PRIORITY
+                           Run the tests with the I/O PRIORITY, one of
+                           idle, best-effort:N or realtime:N with N
+                           from 0 (highest) to 7
+
+      --poll-timeout MS    Check the status of the tests at least every
+                           MS milliseconds (default: 100)
+
+      --poll-events N      Handle at most N events of the test pipes at
+                           a time (default: 3 per job)
+
+      --read-buffer BYTES  Read the output of the tests BYTES at a time
+                           (default: 4096)
+
   -c, --color WHEN         Colorize the output, WHEN can be
                            'auto' (default), 'always' or 'never'
 
   -f, --format FMT         Output the test report in the specified format,
-                           FMT can be
-                             'auto'    (default)
-                             'libtest' (emulate the output produced by cargo test)
-                             'json'    (libtest JSON format)
-                             'tap'     (Test Anything Protocol, http://testanything.org)
+                           see Formats below for the values of FMT
+
+      --base64-output      Encode the output of tests that is not valid
+                           UTF-8 in base64 in JSON reports instead of
+                           replacing the invalid bytes
+
+      --diagnostics STREAM Print human-readable reports (progress and
+                           failures) to STREAM, 'stdout' (default) or
+                           'stderr'; machine-readable formats always
+                           go to stdout
+
+      --ui                 Show a full-screen dashboard of the running
+                           tests instead of the libtest report, and
+                           browse the failures at the end (requires
+                           the ui feature)
+
+  -j, --jobs NJOBS         Run at most NJOBS tests in parallel; if started
+                           by make with a jobserver, also take a job
+                           token for every additional parallel test
+                           (--test-threads is an alias)
+
+      --max-concurrency TAG=N
+                           Run at most N tests tagged with TAG in parallel
+                           (this flag can be used multiple times)
+
+      --prefork            Execute tests in a pool of NJOBS long-lived
+                           worker processes instead of forking a new
+                           process for each test
+
+      --zygote             Run the child initialization hook once in a
+                           zygote process and fork tests from it
+                           (Linux only)
+
+      --execution-policy POLICY
+                           Choose which tests share a process, POLICY
+                           can be
+                             'process-per-test'  (default)
+                             'process-per-suite' (the tests of each
+                                                  top-level suite, one
+                                                  after the other)
+
+      --fd-leaks POLICY    Check if tests leave file descriptors open,
+                           POLICY can be
+                             'ignore' (default)
+                             'warn'   (report leaked descriptors)
+                             'fail'   (fail tests leaking descriptors)
+
+      --deny-stderr        Fail tests that pass but write to stderr
+
+      --reap-daemons       Report and kill the processes tests leave
+                           running, even daemons that left the process
+                           group of the test
+
+      --coverage-dir DIR   Write LLVM coverage profiles of each test
+                           into DIR (requires an instrumented build)
+
+      --core-dir DIR       Enable core dumps for tests and move the dumps
+                           of crashed tests into DIR
+
+      --baseline FILE      Compare the results with the results of a
+                           previous run recorded with --format json in
+                           FILE and summarize the differences
+
+      --snapshot-dir DIR   Keep the snapshots compared by tests in DIR
+                           instead of the snapshots directory of the
+                           package
+
+      --bless              Write the snapshots instead of comparing
+                           them (also enabled by UPDATE_SNAPSHOTS=1)
+
+      --history-db FILE    Append the outcomes of the tests to the
+                           SQLite database FILE (requires the history
+                           feature)
+
+      --suggest-split N    Print how to split the tests between N jobs
+                           of similar durations, known from --history-db
+                           or --baseline, instead of executing them
+
+      --record FILE        Record the events of the run in FILE, which
+                           can be shown again in any format with
+                           report --from FILE
+
+      --logfile PATH       Write a line per test with its status, name
+                           and duration to PATH, whatever the format
+
+      --junit FILE         Write the results to FILE in the JUnit XML
+                           format, whatever the format (set by Bazel
+                           with XML_OUTPUT_FILE)
+
+      --checkpoint FILE    Write the result of each test to FILE as soon
+                           as it finished
+
+      --resume             Only execute the tests that didn't pass in
+                           the run that wrote the file of --checkpoint
+
+      --shard INDEX/TOTAL  Only execute every TOTAL-th test, starting
+                           with the test at INDEX, counting from 0 (set
+                           by Bazel with TEST_SHARD_INDEX and
+                           TEST_TOTAL_SHARDS)
+
+      --tmp-dir DIR        Give each test process a temporary directory
+                           of its own in DIR, as TMPDIR (set by Bazel
+                           with TEST_TMPDIR)
 
-  -j, --jobs NJOBS         Run at most NJOBS tests in parallel
+      --worker URL         Execute a shard of the tests on the host
+                           given by ssh://[USER@]HOST[:PORT][/PATH],
+                           with the test binary at PATH or a copy of
+                           this one (experimental, this flag can be
+                           used multiple times)
+
+      --remote ADDR        Execute a part of the tests on the worker
+                           listening on ADDR, see the worker command
+                           (experimental, this flag can be used
+                           multiple times)
+
+      --log-level LEVEL    Capture the records the tests log with the
+                           log crate at LEVEL (error, warn, info, debug
+                           or trace) and above, and show them for the
+                           failed tests
+
+      --record-env VAR     Record the command line and the value of
+                           VAR in the report, and show how to rerun
+                           each failed test (this flag can be used
+                           multiple times)
+
+      --notify             Send a desktop notification with the summary
+                           once the tests completed
 "#;
 
 pub(crate) fn produce_help() -> String {
     format!(
-        r#"Usage: {} [OPTIONS] [TESTNAME]
+        r#"Usage: {} [COMMAND] [OPTIONS] [TESTNAME...]
+
+Commands:
+  run                      Execute the tests (default)
+  list                     Print the tests that would be executed
+  report --from FILE       Print the results recorded with --format json
+                           or --record in FILE in the format given by
+                           --format
+  merge FILE...            Print the results recorded with --format json
+                           in several FILEs, e.g. by shards, as a single
+                           run in the format given by --format
+  worker --listen ADDR     Execute the tests sent by drivers connecting
+                           to ADDR with --remote (experimental, drivers
+                           aren't authenticated, keep ADDR private)
 
 Options:
 {}
 
   -h, --help               Display this help and exit
+
+Formats:
+{}
+Environment:
+  Options can also be set with RACLETTE_<OPTION> environment variables,
+  e.g. RACLETTE_TIMEOUT=60 or RACLETTE_NOCAPTURE=1.  Repeatable options
+  take comma-separated lists.  Command line flags take precedence.
 "#,
         std::env::args().next().unwrap(),
-        HELP_STR
+        HELP_STR,
+        formats_help()
     )
 }
 
+fn formats_help() -> String {
+    FORMATS
+        .iter()
+        .map(|(name, _, description)| format!("  {:<9} {}\n", name, description))
+        .collect()
+}
+
 fn parse_when(input: &str) -> Result<When, String> {
     match input {
         "auto" => Ok(When::Auto),
@@ -120,12 +697,153 @@ fn parse_when(input: &str) -> Result<When, String> {
 }
 
 fn parse_format(input: &str) -> Result<Format, String> {
+    FORMATS
+        .iter()
+        .find(|(name, _, _)| *name == input)
+        .map(|(_, format, _)| *format)
+        .ok_or_else(|| {
+            let names: Vec<&str> = FORMATS.iter().map(|(name, _, _)| *name).collect();
+            format!(
+                "unsupported FMT value: {}, expected one of: {}",
+                input,
+                names.join(", ")
+            )
+        })
+}
+
+fn parse_stream(input: &str) -> Result<Stream, String> {
+    match input {
+        "stdout" => Ok(Stream::Stdout),
+        "stderr" => Ok(Stream::Stderr),
+        _ => Err(format!("unsupported STREAM value: {}", input)),
+    }
+}
+
+fn parse_ci(input: &str) -> Result<Ci, String> {
+    match input {
+        "buildkite" => Ok(Ci::Buildkite),
+        "gitlab" => Ok(Ci::GitLab),
+        "github" => Ok(Ci::GitHub),
+        _ => Err(format!("unsupported SYSTEM value: {}", input)),
+    }
+}
+
+fn parse_shard(input: &str) -> Result<(usize, usize), String> {
+    let mut parts = input.splitn(2, '/');
+    let index = parts.next().unwrap_or_default().parse::<usize>();
+    match (index, parts.next().map(str::parse::<usize>)) {
+        (Ok(index), Some(Ok(total))) if index < total => Ok((index, total)),
+        _ => Err(format!(
+            "expected INDEX/TOTAL with INDEX < TOTAL, got: {}",
+            input
+        )),
+    }
+}
+
+/// Returns the shard `bazel test` asks to execute, if any.
+fn bazel_shard(
+    lookup: &dyn Fn(&str) -> Option<OsString>,
+) -> Result<Option<(usize, usize)>, ConfigParseError> {
+    let index = env_value(lookup, "TEST_SHARD_INDEX", parse_from_str::<usize>)?;
+    let total = env_value(lookup, "TEST_TOTAL_SHARDS", parse_from_str::<usize>)?;
+    match (index, total) {
+        (Some(index), Some(total)) if index < total => Ok(Some((index, total))),
+        (Some(_), Some(_)) => Err(ConfigParseError::OptionError(
+            "TEST_SHARD_INDEX must be below TEST_TOTAL_SHARDS".to_string(),
+        )),
+        _ => Ok(None),
+    }
+}
+
+fn parse_execution_policy(input: &str) -> Result<ExecutionPolicy, String> {
+    match input {
+        "process-per-test" => Ok(ExecutionPolicy::ProcessPerTest),
+        "process-per-suite" => Ok(ExecutionPolicy::ProcessPerSuite),
+        _ => Err(format!("unsupported POLICY value: {}", input)),
+    }
+}
+
+fn parse_leak_policy(input: &str) -> Result<LeakPolicy, String> {
+    match input {
+        "ignore" => Ok(LeakPolicy::Ignore),
+        "warn" => Ok(LeakPolicy::Warn),
+        "fail" => Ok(LeakPolicy::Fail),
+        _ => Err(format!("unsupported POLICY value: {}", input)),
+    }
+}
+
+/// The clock of --virtual-time.
+fn virtual_clock() -> Box<dyn Clock> {
+    Box::new(VirtualClock)
+}
+
+fn parse_keep_output(input: &str) -> Result<KeepOutput, String> {
     match input {
-        "auto" => Ok(Format::Auto),
-        "libtest" => Ok(Format::LibTest),
-        "json" => Ok(Format::Json),
-        "tap" => Ok(Format::Tap),
-        _ => Err(format!("unsupported FMT value: {}", input)),
+        "all" => Ok(KeepOutput::All),
+        "failed" => Ok(KeepOutput::Failed),
+        "none" => Ok(KeepOutput::None),
+        _ => Err(format!("unsupported TESTS value: {}", input)),
+    }
+}
+
+fn parse_io_priority(input: &str) -> Result<IoPriority, String> {
+    let error = || format!("unsupported PRIORITY value: {}", input);
+    let level = |level: &str| match level.parse::<u8>() {
+        Ok(level) if level <= 7 => Ok(level),
+        _ => Err(error()),
+    };
+    match input.split_once(':') {
+        None if input == "idle" => Ok(IoPriority::Idle),
+        Some(("realtime", n)) => level(n).map(IoPriority::RealTime),
+        Some(("best-effort", n)) => level(n).map(IoPriority::BestEffort),
+        _ => Err(error()),
+    }
+}
+
+fn parse_signal(input: &str) -> Result<Option<Signal>, String> {
+    if input == "none" {
+        return Ok(None);
+    }
+    let name = if input.starts_with("SIG") {
+        input.to_string()
+    } else {
+        format!("SIG{}", input)
+    };
+    name.parse::<Signal>()
+        .map(Some)
+        .map_err(|_| format!("unknown signal: {}", input))
+}
+
+/// Parses a list of CPUs as in cpuset(7), e.g. "0-3,8".
+fn parse_cpu_list(input: &str) -> Result<Vec<usize>, String> {
+    let error = || format!("expected CPUs such as 0-3,8, got: {}", input);
+    let mut cpus = Vec::new();
+    for range in input.split(',') {
+        let mut bounds = range.splitn(2, '-').map(str::parse::<usize>);
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(cpu)), None) => cpus.push(cpu),
+            (Some(Ok(first)), Some(Ok(last))) if first <= last => cpus.extend(first..=last),
+            _ => return Err(error()),
+        }
+    }
+    Ok(cpus)
+}
+
+/// Parses a positive number of milliseconds, see
+/// [Config::duration_resolution].
+fn parse_duration_resolution(input: &str) -> Result<Duration, String> {
+    match input.parse::<u64>() {
+        Ok(ms) if ms > 0 => Ok(Duration::from_millis(ms)),
+        _ => Err(format!("expected MS > 0, got: {}", input)),
+    }
+}
+
+fn parse_tag_limit(input: &str) -> Result<(String, usize), String> {
+    let mut parts = input.splitn(2, '=');
+    let tag = parts.next().unwrap_or_default();
+    match parts.next().map(str::parse::<usize>) {
+        Some(Ok(limit)) if !tag.is_empty() && limit > 0 => Ok((tag.to_string(), limit)),
+        _ => Err(format!("expected TAG=N with N > 0, got: {}", input)),
     }
 }
 
@@ -141,7 +859,225 @@ fn convert_error(err: ArgsError, what: &str) -> ConfigParseError {
     }
 }
 
+/// Looks up the environment variable `var` and parses its value.
+/// Unset and empty variables are ignored.
+fn env_value<T>(
+    lookup: &dyn Fn(&str) -> Option<OsString>,
+    var: &str,
+    parse: impl FnOnce(&str) -> Result<T, String>,
+) -> Result<Option<T>, ConfigParseError> {
+    let value = match lookup(var) {
+        Some(value) if !value.is_empty() => value,
+        _ => return Ok(None),
+    };
+    let value = value.to_str().ok_or_else(|| {
+        ConfigParseError::OptionError(format!("failed to parse {}: not valid UTF-8", var))
+    })?;
+    parse(value).map(Some).map_err(|cause| {
+        ConfigParseError::OptionError(format!("failed to parse {}: {}", var, cause))
+    })
+}
+
+/// Parses a comma-separated list of values.
+fn parse_list<T>(
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> impl FnOnce(&str) -> Result<Vec<T>, String> {
+    move |input| input.split(',').map(str::trim).map(&parse).collect()
+}
+
+fn parse_bool(input: &str) -> Result<bool, String> {
+    match input {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" => Ok(false),
+        _ => Err(format!("expected a boolean, got: {}", input)),
+    }
+}
+
+fn parse_from_str<T: std::str::FromStr>(input: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    input.parse().map_err(|err: T::Err| err.to_string())
+}
+
+impl Command {
+    /// Parses the command and its configuration from command line
+    /// flags.
+    pub fn from_args() -> Result<(Self, Config), ConfigParseError> {
+        Command::from_vec(std::env::args_os().skip(1).collect())
+    }
+
+    /// Like [Command::from_args], but parses a vector of
+    /// [OsString]s, see [Config::from_vec].
+    pub fn from_vec(mut vargs: Vec<OsString>) -> Result<(Self, Config), ConfigParseError> {
+        let name = vargs
+            .first()
+            .and_then(|arg| arg.to_str())
+            .filter(|arg| COMMANDS.contains(arg))
+            .map(str::to_string);
+        if name.is_some() {
+            vargs.remove(0);
+        }
+        let mut args = pico_args::Arguments::from_vec(vargs);
+        let command = match name.as_deref() {
+            Some("list") => Command::List,
+            Some("report") => Command::Report(
+                args.value_from_str("--from")
+                    .map_err(|err| convert_error(err, "from"))?,
+            ),
+            Some("worker") => Command::Worker(
+                args.value_from_str("--listen")
+                    .map_err(|err| convert_error(err, "listen"))?,
+            ),
+            Some("merge") => {
+                let (config, files) = Config::from_pico_args_and_free(args)?;
+                if files.is_empty() {
+                    return Err(ConfigParseError::OptionError(
+                        "Please specify the files to merge".to_string(),
+                    ));
+                }
+                let files = files.into_iter().map(PathBuf::from).collect();
+                return Ok((Command::Merge(files), config));
+            }
+            _ => Command::Run,
+        };
+        Ok((command, Config::from_pico_args(args)?))
+    }
+}
+
 impl Config {
+    /// Parses configuration from RACLETTE_* environment variables,
+    /// e.g. RACLETTE_TIMEOUT or RACLETTE_JOBS.  The variables are
+    /// named after the command line flags.
+    pub fn from_env() -> Result<Self, ConfigParseError> {
+        Config::from_env_vars(&|var| std::env::var_os(var))
+    }
+
+    pub(crate) fn from_env_vars(
+        lookup: &dyn Fn(&str) -> Option<OsString>,
+    ) -> Result<Self, ConfigParseError> {
+        let flag = |var| env_value(lookup, var, parse_bool).map(|v| v.unwrap_or(false));
+        let run_ignored = if flag("RACLETTE_IGNORED")? {
+            Some(RunIgnored::Only)
+        } else if flag("RACLETTE_INCLUDE_IGNORED")? {
+            Some(RunIgnored::Yes)
+        } else {
+            None
+        };
+        Ok(Self {
+            filters: Vec::new(),
+            skip_filters: env_value(lookup, "RACLETTE_SKIP", parse_list(parse_from_str))?
+                .unwrap_or_default(),
+            tag_filters: env_value(lookup, "RACLETTE_TAG", parse_list(parse_from_str))?
+                .unwrap_or_default(),
+            exact: flag("RACLETTE_EXACT")?,
+            forbid_only: flag("RACLETTE_FORBID_ONLY")?,
+            run_ignored,
+            timeout: env_value(lookup, "RACLETTE_TIMEOUT", parse_from_str)?
+                .map(Duration::from_secs),
+            color: env_value(lookup, "RACLETTE_COLOR", parse_when)?.unwrap_or(When::Auto),
+            jobs: env_value(lookup, "RACLETTE_JOBS", parse_from_str)?,
+            format: env_value(lookup, "RACLETTE_FORMAT", parse_format)?.unwrap_or(Format::Auto),
+            base64_output: flag("RACLETTE_BASE64_OUTPUT")?,
+            diagnostics: env_value(lookup, "RACLETTE_DIAGNOSTICS", parse_stream)?,
+            ui: flag("RACLETTE_UI")?,
+            nocapture: flag("RACLETTE_NOCAPTURE")?,
+            transcript: flag("RACLETTE_TRANSCRIPT")?,
+            timestamps: flag("RACLETTE_TIMESTAMPS")?,
+            report_time: flag("RACLETTE_REPORT_TIME")?,
+            aligned: flag("RACLETTE_ALIGNED")?,
+            stage_times: flag("RACLETTE_STAGE_TIMES")?,
+            ci: env_value(lookup, "RACLETTE_CI", parse_ci)?,
+            max_output_bytes: env_value(lookup, "RACLETTE_MAX_OUTPUT_BYTES", parse_from_str)?,
+            output_hard_cap: env_value(lookup, "RACLETTE_OUTPUT_HARD_CAP", parse_from_str)?,
+            memory_limit: env_value(lookup, "RACLETTE_MEMORY_LIMIT", parse_from_str::<u64>)?
+                .map(|mib| mib << 20),
+            fd_leaks: env_value(lookup, "RACLETTE_FD_LEAKS", parse_leak_policy)?,
+            execution_policy: env_value(
+                lookup,
+                "RACLETTE_EXECUTION_POLICY",
+                parse_execution_policy,
+            )?,
+            deny_stderr: flag("RACLETTE_DENY_STDERR")?,
+            coverage_dir: env_value(lookup, "RACLETTE_COVERAGE_DIR", parse_from_str)?,
+            core_dir: env_value(lookup, "RACLETTE_CORE_DIR", parse_from_str)?,
+            baseline: env_value(lookup, "RACLETTE_BASELINE", parse_from_str)?,
+            snapshot_dir: env_value(lookup, "RACLETTE_SNAPSHOT_DIR", parse_from_str)?,
+            bless: flag("RACLETTE_BLESS")? || flag("UPDATE_SNAPSHOTS")?,
+            history_db: env_value(lookup, "RACLETTE_HISTORY_DB", parse_from_str)?,
+            suggest_split: env_value(lookup, "RACLETTE_SUGGEST_SPLIT", parse_from_str)?,
+            record: env_value(lookup, "RACLETTE_RECORD", parse_from_str)?,
+            logfile: env_value(lookup, "RACLETTE_LOGFILE", parse_from_str)?,
+            checkpoint: env_value(lookup, "RACLETTE_CHECKPOINT", parse_from_str)?,
+            resume: flag("RACLETTE_RESUME")?,
+            // The variables set by `bazel test` are used unless
+            // overridden.
+            junit: match env_value(lookup, "RACLETTE_JUNIT", parse_from_str)? {
+                Some(file) => Some(file),
+                None => env_value(lookup, "XML_OUTPUT_FILE", parse_from_str)?,
+            },
+            shard: match env_value(lookup, "RACLETTE_SHARD", parse_shard)? {
+                Some(shard) => Some(shard),
+                None => bazel_shard(lookup)?,
+            },
+            shard_status_file: env_value(lookup, "TEST_SHARD_STATUS_FILE", parse_from_str)?,
+            tmp_dir: match env_value(lookup, "RACLETTE_TMP_DIR", parse_from_str)? {
+                Some(dir) => Some(dir),
+                None => env_value(lookup, "TEST_TMPDIR", parse_from_str)?,
+            },
+            log_level: env_value(lookup, "RACLETTE_LOG_LEVEL", parse_from_str)?,
+            record_env: env_value(lookup, "RACLETTE_RECORD_ENV", parse_list(parse_from_str))?
+                .unwrap_or_default(),
+            workers: env_value(lookup, "RACLETTE_WORKER", parse_list(parse_from_str))?
+                .unwrap_or_default(),
+            remotes: env_value(lookup, "RACLETTE_REMOTE", parse_list(parse_from_str))?
+                .unwrap_or_default(),
+            notify: flag("RACLETTE_NOTIFY")?,
+            prefork: flag("RACLETTE_PREFORK")?,
+            zygote: flag("RACLETTE_ZYGOTE")?,
+            child_init: None,
+            classifier: None,
+            tag_limits: env_value(
+                lookup,
+                "RACLETTE_MAX_CONCURRENCY",
+                parse_list(parse_tag_limit),
+            )?
+            .unwrap_or_default()
+            .into_iter()
+            .collect(),
+            resource_pools: HashMap::new(),
+            global_fixtures: HashMap::new(),
+            health_checks: Vec::new(),
+            timeout_signal: env_value(lookup, "RACLETTE_TIMEOUT_SIGNAL", parse_signal)?,
+            heartbeat: env_value(lookup, "RACLETTE_HEARTBEAT", parse_from_str)?
+                .map(Duration::from_secs),
+            launch_delay: env_value(lookup, "RACLETTE_LAUNCH_DELAY", parse_from_str)?
+                .map(Duration::from_millis),
+            nice: env_value(lookup, "RACLETTE_NICE", parse_from_str)?,
+            cpus: env_value(lookup, "RACLETTE_CPUS", parse_cpu_list)?,
+            pin_jobs: flag("RACLETTE_PIN_JOBS")?,
+            io_priority: env_value(lookup, "RACLETTE_IO_PRIORITY", parse_io_priority)?,
+            reap_daemons: flag("RACLETTE_REAP_DAEMONS")?,
+            poll_timeout: env_value(lookup, "RACLETTE_POLL_TIMEOUT", parse_from_str)?
+                .map(Duration::from_millis),
+            poll_events: env_value(lookup, "RACLETTE_POLL_EVENTS", parse_from_str)?,
+            read_buffer: env_value(lookup, "RACLETTE_READ_BUFFER", parse_from_str)?,
+            splice_output: flag("RACLETTE_SPLICE_OUTPUT")?,
+            shm_reports: flag("RACLETTE_SHM_REPORTS")?,
+            compress_output: flag("RACLETTE_COMPRESS_OUTPUT")?,
+            keep_output: env_value(lookup, "RACLETTE_KEEP_OUTPUT", parse_keep_output)?,
+            timeout_extension: env_value(lookup, "RACLETTE_TIMEOUT_EXTENSION", parse_from_str)?
+                .map(Duration::from_secs),
+            duration_resolution: env_value(
+                lookup,
+                "RACLETTE_DURATION_RESOLUTION",
+                parse_duration_resolution,
+            )?,
+            clock: flag("RACLETTE_VIRTUAL_TIME")?.then(virtual_clock),
+            retries: env_value(lookup, "RACLETTE_RETRIES", parse_from_str)?,
+        })
+    }
+
     /// Parses configuration from command line flags.
     pub fn from_args() -> Result<Self, ConfigParseError> {
         let args = pico_args::Arguments::from_env();
@@ -159,6 +1095,19 @@ impl Config {
 
     // Parses arguments from whathever pico_arg::Arguments
     fn from_pico_args(args: pico_args::Arguments) -> Result<Self, ConfigParseError> {
+        let (config, positional_args) = Config::from_pico_args_and_free(args)?;
+
+        Ok(Config {
+            filters: positional_args,
+            ..config
+        })
+    }
+
+    // Parses the options and returns the positional arguments along
+    // with the configuration.
+    fn from_pico_args_and_free(
+        args: pico_args::Arguments,
+    ) -> Result<(Self, Vec<String>), ConfigParseError> {
         let mut args = args;
 
         if args.contains(["-h", "--help"]) {
@@ -180,77 +1129,487 @@ impl Config {
             .map_err(|err| convert_error(err, "format"))?
             .unwrap_or(Format::Auto);
 
-        let jobs = args
+        let base64_output = args.contains("--base64-output");
+
+        let diagnostics = args
+            .opt_value_from_fn("--diagnostics", parse_stream)
+            .map_err(|err| convert_error(err, "diagnostics"))?;
+
+        let ui = args.contains("--ui");
+
+        // --test-threads and -Z unstable-options are accepted for
+        // compatibility with libtest.
+        let jobs = match args
             .opt_value_from_str(["-j", "--jobs"])
-            .map_err(|err| convert_error(err, "jobs"))?;
+            .map_err(|err| convert_error(err, "jobs"))?
+        {
+            Some(jobs) => Some(jobs),
+            None => args
+                .opt_value_from_str("--test-threads")
+                .map_err(|err| convert_error(err, "test-threads"))?,
+        };
+
+        let unstable_features: Vec<String> = args
+            .values_from_str("-Z")
+            .map_err(|err| convert_error(err, "-Z"))?;
+        if let Some(feature) = unstable_features.iter().find(|f| *f != "unstable-options") {
+            return Err(ConfigParseError::OptionError(format!(
+                "unsupported unstable feature: {}",
+                feature
+            )));
+        }
 
         let skip_filters = args
             .values_from_str("--skip")
             .map_err(|err| convert_error(err, "skip"))?;
+        let tag_filters = args
+            .values_from_str("--tag")
+            .map_err(|err| convert_error(err, "tag"))?;
+
+        let exact = args.contains("--exact");
+        let forbid_only = args.contains("--forbid-only");
+
+        let run_ignored = if args.contains("--ignored") {
+            Some(RunIgnored::Only)
+        } else if args.contains("--include-ignored") {
+            Some(RunIgnored::Yes)
+        } else {
+            None
+        };
 
         let nocapture = args.contains("--nocapture");
+        let transcript = args.contains("--transcript");
+        let timestamps = args.contains("--timestamps");
+        let report_time = args.contains("--report-time");
+        let aligned = args.contains("--aligned");
+        let stage_times = args.contains("--stage-times");
+        let ci = args
+            .opt_value_from_fn("--ci", parse_ci)
+            .map_err(|err| convert_error(err, "ci"))?;
+
+        let max_output_bytes = args
+            .opt_value_from_str("--max-output-bytes")
+            .map_err(|err| convert_error(err, "max-output-bytes"))?;
+
+        let output_hard_cap = args
+            .opt_value_from_str("--output-hard-cap")
+            .map_err(|err| convert_error(err, "output-hard-cap"))?;
+        let memory_limit = args
+            .opt_value_from_str::<_, u64>("--memory-limit")
+            .map_err(|err| convert_error(err, "memory-limit"))?
+            .map(|mib| mib << 20);
+
+        let prefork = args.contains("--prefork");
+
+        let zygote = args.contains("--zygote");
+
+        let timeout_signal = args
+            .opt_value_from_fn("--timeout-signal", parse_signal)
+            .map_err(|err| convert_error(err, "timeout-signal"))?;
+
+        let heartbeat = args
+            .opt_value_from_str("--heartbeat")
+            .map_err(|err| convert_error(err, "heartbeat"))?
+            .map(Duration::from_secs);
+
+        let launch_delay = args
+            .opt_value_from_str("--launch-delay")
+            .map_err(|err| convert_error(err, "launch-delay"))?
+            .map(Duration::from_millis);
+
+        let nice = args
+            .opt_value_from_str("--nice")
+            .map_err(|err| convert_error(err, "nice"))?;
+
+        let cpus = args
+            .opt_value_from_fn("--cpus", parse_cpu_list)
+            .map_err(|err| convert_error(err, "cpus"))?;
+
+        let pin_jobs = args.contains("--pin-jobs");
+
+        let io_priority = args
+            .opt_value_from_fn("--io-priority", parse_io_priority)
+            .map_err(|err| convert_error(err, "io-priority"))?;
+
+        let reap_daemons = args.contains("--reap-daemons");
+
+        let poll_timeout = args
+            .opt_value_from_str("--poll-timeout")
+            .map_err(|err| convert_error(err, "poll-timeout"))?
+            .map(Duration::from_millis);
+
+        let poll_events = args
+            .opt_value_from_str("--poll-events")
+            .map_err(|err| convert_error(err, "poll-events"))?;
+
+        let read_buffer = args
+            .opt_value_from_str("--read-buffer")
+            .map_err(|err| convert_error(err, "read-buffer"))?;
+
+        let splice_output = args.contains("--splice-output");
+        let shm_reports = args.contains("--shm-reports");
+        let compress_output = args.contains("--compress-output");
+        let keep_output = args
+            .opt_value_from_fn("--keep-output", parse_keep_output)
+            .map_err(|err| convert_error(err, "keep-output"))?;
+        let timeout_extension = args
+            .opt_value_from_str("--timeout-extension")
+            .map_err(|err| convert_error(err, "timeout-extension"))?
+            .map(Duration::from_secs);
+        let duration_resolution = args
+            .opt_value_from_fn("--duration-resolution", parse_duration_resolution)
+            .map_err(|err| convert_error(err, "duration-resolution"))?;
+        let clock = args.contains("--virtual-time").then(virtual_clock);
+
+        let retries = args
+            .opt_value_from_str("--retries")
+            .map_err(|err| convert_error(err, "retries"))?;
+
+        let tag_limits = args
+            .values_from_fn("--max-concurrency", parse_tag_limit)
+            .map_err(|err| convert_error(err, "max-concurrency"))?
+            .into_iter()
+            .collect();
+
+        let fd_leaks = args
+            .opt_value_from_fn("--fd-leaks", parse_leak_policy)
+            .map_err(|err| convert_error(err, "fd-leaks"))?;
+
+        let execution_policy = args
+            .opt_value_from_fn("--execution-policy", parse_execution_policy)
+            .map_err(|err| convert_error(err, "execution-policy"))?;
+
+        let deny_stderr = args.contains("--deny-stderr");
+
+        let coverage_dir = args
+            .opt_value_from_str("--coverage-dir")
+            .map_err(|err| convert_error(err, "coverage-dir"))?;
+
+        let core_dir = args
+            .opt_value_from_str("--core-dir")
+            .map_err(|err| convert_error(err, "core-dir"))?;
+
+        let baseline = args
+            .opt_value_from_str("--baseline")
+            .map_err(|err| convert_error(err, "baseline"))?;
+
+        let snapshot_dir = args
+            .opt_value_from_str("--snapshot-dir")
+            .map_err(|err| convert_error(err, "snapshot-dir"))?;
+
+        let bless = args.contains("--bless");
+
+        let history_db = args
+            .opt_value_from_str("--history-db")
+            .map_err(|err| convert_error(err, "history-db"))?;
+
+        let suggest_split = args
+            .opt_value_from_str("--suggest-split")
+            .map_err(|err| convert_error(err, "suggest-split"))?;
+
+        let record = args
+            .opt_value_from_str("--record")
+            .map_err(|err| convert_error(err, "record"))?;
+
+        let logfile = args
+            .opt_value_from_str("--logfile")
+            .map_err(|err| convert_error(err, "logfile"))?;
+
+        let junit = args
+            .opt_value_from_str("--junit")
+            .map_err(|err| convert_error(err, "junit"))?;
+
+        let checkpoint = args
+            .opt_value_from_str("--checkpoint")
+            .map_err(|err| convert_error(err, "checkpoint"))?;
+
+        let resume = args.contains("--resume");
+
+        let shard = args
+            .opt_value_from_fn("--shard", parse_shard)
+            .map_err(|err| convert_error(err, "shard"))?;
+
+        let tmp_dir = args
+            .opt_value_from_str("--tmp-dir")
+            .map_err(|err| convert_error(err, "tmp-dir"))?;
+
+        let log_level = args
+            .opt_value_from_str("--log-level")
+            .map_err(|err| convert_error(err, "log-level"))?;
+
+        let record_env = args
+            .values_from_str("--record-env")
+            .map_err(|err| convert_error(err, "record-env"))?;
+
+        let workers = args
+            .values_from_str("--worker")
+            .map_err(|err| convert_error(err, "worker"))?;
+
+        let remotes = args
+            .values_from_str("--remote")
+            .map_err(|err| convert_error(err, "remote"))?;
+
+        let notify = args.contains("--notify");
 
         let positional_args = args.free().map_err(|err| match err {
             ArgsError::UnusedArgsLeft(args) => ConfigParseError::UnknownArgs(args),
             other => convert_error(other, "filter"),
         })?;
 
-        let filter = match positional_args.len() {
-            0 => Ok(None),
-            1 => Ok(Some(positional_args[0].clone())),
-            more => Err(ConfigParseError::OptionError(format!(
-                "At most one TESTNAME can be specified, got {}: {}",
-                more,
-                positional_args.join(" ")
-            ))),
-        }?;
-
-        Ok(Self {
-            filter,
+        let config = Self {
+            filters: Vec::new(),
             skip_filters,
+            tag_filters,
+            exact,
+            run_ignored,
+            forbid_only,
             timeout,
             color,
             jobs,
             format,
+            base64_output,
+            diagnostics,
+            ui,
             nocapture,
-        })
+            transcript,
+            timestamps,
+            report_time,
+            aligned,
+            stage_times,
+            ci,
+            max_output_bytes,
+            output_hard_cap,
+            memory_limit,
+            fd_leaks,
+            execution_policy,
+            deny_stderr,
+            coverage_dir,
+            core_dir,
+            baseline,
+            snapshot_dir,
+            bless,
+            history_db,
+            suggest_split,
+            record,
+            logfile,
+            junit,
+            checkpoint,
+            resume,
+            shard,
+            shard_status_file: None,
+            tmp_dir,
+            log_level,
+            record_env,
+            workers,
+            remotes,
+            notify,
+            prefork,
+            zygote,
+            child_init: None,
+            classifier: None,
+            tag_limits,
+            resource_pools: HashMap::new(),
+            global_fixtures: HashMap::new(),
+            health_checks: Vec::new(),
+            timeout_signal,
+            heartbeat,
+            launch_delay,
+            nice,
+            cpus,
+            pin_jobs,
+            io_priority,
+            reap_daemons,
+            poll_timeout,
+            poll_events,
+            read_buffer,
+            splice_output,
+            shm_reports,
+            compress_output,
+            keep_output,
+            timeout_extension,
+            duration_resolution,
+            clock,
+            retries,
+        };
+        Ok((config, positional_args))
     }
 
     /// Merges two configurations by copying values of all unset
     /// fields in `self` from `other`.
     pub fn merge(mut self, mut other: Config) -> Config {
         self.skip_filters.append(&mut other.skip_filters);
+        self.tag_filters.append(&mut other.tag_filters);
+        self.record_env.append(&mut other.record_env);
+        self.workers.append(&mut other.workers);
+        self.remotes.append(&mut other.remotes);
+        self.health_checks.append(&mut other.health_checks);
+        for (tag, limit) in other.tag_limits {
+            self.tag_limits.entry(tag).or_insert(limit);
+        }
+        for (pool, resources) in other.resource_pools {
+            self.resource_pools.entry(pool).or_insert(resources);
+        }
+        for (name, fixture) in other.global_fixtures {
+            self.global_fixtures.entry(name).or_insert(fixture);
+        }
 
         Config {
-            filter: self.filter.or(other.filter),
+            filters: if self.filters.is_empty() {
+                other.filters
+            } else {
+                self.filters
+            },
             skip_filters: self.skip_filters,
+            tag_filters: self.tag_filters,
+            exact: self.exact || other.exact,
+            forbid_only: self.forbid_only || other.forbid_only,
+            run_ignored: self.run_ignored.or(other.run_ignored),
             timeout: self.timeout.or(other.timeout),
             color: When::merge(self.color, other.color),
             jobs: self.jobs.or(other.jobs),
             format: Format::merge(self.format, other.format),
+            base64_output: self.base64_output || other.base64_output,
+            diagnostics: self.diagnostics.or(other.diagnostics),
+            ui: self.ui || other.ui,
             nocapture: self.nocapture || other.nocapture,
+            transcript: self.transcript || other.transcript,
+            timestamps: self.timestamps || other.timestamps,
+            report_time: self.report_time || other.report_time,
+            aligned: self.aligned || other.aligned,
+            stage_times: self.stage_times || other.stage_times,
+            ci: self.ci.or(other.ci),
+            max_output_bytes: self.max_output_bytes.or(other.max_output_bytes),
+            output_hard_cap: self.output_hard_cap.or(other.output_hard_cap),
+            memory_limit: self.memory_limit.or(other.memory_limit),
+            fd_leaks: self.fd_leaks.or(other.fd_leaks),
+            execution_policy: self.execution_policy.or(other.execution_policy),
+            deny_stderr: self.deny_stderr || other.deny_stderr,
+            coverage_dir: self.coverage_dir.or(other.coverage_dir),
+            core_dir: self.core_dir.or(other.core_dir),
+            baseline: self.baseline.or(other.baseline),
+            snapshot_dir: self.snapshot_dir.or(other.snapshot_dir),
+            bless: self.bless || other.bless,
+            history_db: self.history_db.or(other.history_db),
+            suggest_split: self.suggest_split.or(other.suggest_split),
+            record: self.record.or(other.record),
+            logfile: self.logfile.or(other.logfile),
+            junit: self.junit.or(other.junit),
+            checkpoint: self.checkpoint.or(other.checkpoint),
+            resume: self.resume || other.resume,
+            shard: self.shard.or(other.shard),
+            shard_status_file: self.shard_status_file.or(other.shard_status_file),
+            tmp_dir: self.tmp_dir.or(other.tmp_dir),
+            log_level: self.log_level.or(other.log_level),
+            record_env: self.record_env,
+            workers: self.workers,
+            remotes: self.remotes,
+            notify: self.notify || other.notify,
+            prefork: self.prefork || other.prefork,
+            zygote: self.zygote || other.zygote,
+            child_init: self.child_init.or(other.child_init),
+            classifier: self.classifier.or(other.classifier),
+            tag_limits: self.tag_limits,
+            resource_pools: self.resource_pools,
+            global_fixtures: self.global_fixtures,
+            health_checks: self.health_checks,
+            timeout_signal: self.timeout_signal.or(other.timeout_signal),
+            heartbeat: self.heartbeat.or(other.heartbeat),
+            launch_delay: self.launch_delay.or(other.launch_delay),
+            nice: self.nice.or(other.nice),
+            cpus: self.cpus.or(other.cpus),
+            pin_jobs: self.pin_jobs || other.pin_jobs,
+            io_priority: self.io_priority.or(other.io_priority),
+            reap_daemons: self.reap_daemons || other.reap_daemons,
+            poll_timeout: self.poll_timeout.or(other.poll_timeout),
+            poll_events: self.poll_events.or(other.poll_events),
+            read_buffer: self.read_buffer.or(other.read_buffer),
+            splice_output: self.splice_output || other.splice_output,
+            shm_reports: self.shm_reports || other.shm_reports,
+            compress_output: self.compress_output || other.compress_output,
+            keep_output: self.keep_output.or(other.keep_output),
+            timeout_extension: self.timeout_extension.or(other.timeout_extension),
+            duration_resolution: self.duration_resolution.or(other.duration_resolution),
+            clock: self.clock.or(other.clock),
+            retries: self.retries.or(other.retries),
         }
     }
 
-    /// Sets the filter controlling which tests are to be executed.
+    /// Sets the filter controlling which tests are to be executed,
+    /// replacing the previous filters.
     ///
-    /// If set, only tests having name containing the filter (in at
-    /// least one component of the name: either suite or test name)
-    /// will be executed.
+    /// If set, only tests whose full names (e.g. "suite::test")
+    /// contain the filter will be executed, see [Filter].
     pub fn filter(mut self, filter: String) -> Self {
-        self.filter = Some(filter);
+        self.filters = vec![filter];
+        self
+    }
+
+    /// Adds a filter to the filters controlling which tests are to be
+    /// executed: only tests whose full names contain all of them will
+    /// be executed, like with several TESTNAMEs on the command line.
+    pub fn add_filter(mut self, filter: impl ToString) -> Self {
+        self.filters.push(filter.to_string());
         self
     }
 
     /// Sets the filters controlling which tests DO NOT run.
     ///
-    /// If set, all the tests having name containing on of the filters
-    /// (in at least one component of the name) will be skipped.
+    /// If set, all the tests whose full names contain one of the
+    /// filters will be skipped, whatever the other filters.
     pub fn skip_filters(mut self, filters: Vec<String>) -> Self {
         self.skip_filters = filters;
         self
     }
 
+    /// Executes only the tests tagged with `tag` (see [crate::tag]),
+    /// on top of the other tags.
+    pub fn tag_filter(mut self, tag: impl ToString) -> Self {
+        self.tag_filters.push(tag.to_string());
+        self
+    }
+
+    /// Makes the filters and the skip filters match full test names
+    /// (e.g. "suite::test") exactly instead of their parts.
+    pub fn exact(mut self) -> Self {
+        self.exact = true;
+        self
+    }
+
+    /// Adds the conditions of `filter` to the filters of the
+    /// configuration.
+    pub fn select(mut self, filter: Filter) -> Self {
+        self.filters.extend(filter.names);
+        self.skip_filters.extend(filter.skip);
+        self.tag_filters.extend(filter.tags);
+        self.exact |= filter.exact;
+        self
+    }
+
+    /// The selection of the tests that the filters of the
+    /// configuration make.
+    pub fn test_filter(&self) -> Filter {
+        Filter {
+            names: self.filters.clone(),
+            skip: self.skip_filters.clone(),
+            tags: self.tag_filters.clone(),
+            exact: self.exact,
+        }
+    }
+
+    /// Controls whether tests marked with [crate::skip] are executed.
+    pub fn run_ignored(mut self, run_ignored: RunIgnored) -> Self {
+        self.run_ignored = Some(run_ignored);
+        self
+    }
+
+    /// Refuses to run if some tests are marked with [crate::only],
+    /// so that a focused tree committed by mistake fails on CI
+    /// instead of silently running a fraction of the tests.
+    pub fn forbid_only(mut self) -> Self {
+        self.forbid_only = true;
+        self
+    }
+
     /// Sets the time limit for execution of a single test.  If
     /// specified, this time limit is universal: all tests will
     /// inherit this time limit, even if some of them have a different
@@ -260,6 +1619,104 @@ impl Config {
         self
     }
 
+    /// Sets the signal sent to the process group of a test exceeding
+    /// the timeout, or whose stage exceeds its timeout (see
+    /// [crate::TestContext::begin_stage_with_timeout]), before it's
+    /// killed with SIGKILL, or disables the warning if `None`.  Tests
+    /// can install a handler for the signal to dump diagnostic
+    /// information, e.g. thread stacks, into their captured output.
+    /// The default is SIGUSR1.
+    pub fn timeout_signal(mut self, sig: Option<Signal>) -> Self {
+        self.timeout_signal = Some(sig);
+        self
+    }
+
+    /// Makes the driver print the tests that are still running and
+    /// how long they have been running to stderr every `interval`.
+    /// Keeps CI systems that kill silent jobs from killing runs of
+    /// long tests.  The JSON report prints them as "running" events
+    /// instead, see [crate::json::TestEvent::Running].
+    pub fn heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    /// Waits for `delay` after starting a test before starting the
+    /// next one, so that a suite whose tests all hit the same service
+    /// or cache right away ramps up instead of overloading it.
+    pub fn launch_delay(mut self, delay: Duration) -> Self {
+        self.launch_delay = Some(delay);
+        self
+    }
+
+    /// Wakes the driver up at least every `timeout` to check whether
+    /// the tests exited or timed out, 100ms by default.  A shorter
+    /// timeout makes timeouts more accurate, a longer one saves CPU
+    /// time of the driver with many idle tests.
+    pub fn poll_timeout(mut self, timeout: Duration) -> Self {
+        self.poll_timeout = Some(timeout);
+        self
+    }
+
+    /// Handles at most `events` events of the pipes of the tests per
+    /// wake up of the driver, 3 per job by default.
+    pub fn poll_events(mut self, events: usize) -> Self {
+        self.poll_events = Some(events);
+        self
+    }
+
+    /// Reads the output of the tests `bytes` at a time, 4096 by
+    /// default.  A larger buffer takes fewer reads for tests writing a
+    /// lot of output.
+    pub fn read_buffer(mut self, bytes: usize) -> Self {
+        self.read_buffer = Some(bytes);
+        self
+    }
+
+    /// Runs the test processes with the nice value `nice`, e.g. 10 to
+    /// leave the machine responsive.  Lowering it below the value of
+    /// the driver requires privileges.
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Runs the test processes on the given CPUs only.
+    pub fn cpus(mut self, cpus: Vec<usize>) -> Self {
+        self.cpus = Some(cpus);
+        self
+    }
+
+    /// Splits the CPUs of [Config::cpus], or those the driver may run
+    /// on, between the parallel jobs, so that the tests running at
+    /// the same time don't compete for CPUs, which makes the timings
+    /// of latency-sensitive tests more stable.  With more jobs than
+    /// CPUs, the jobs share them.  The driver restricts each test
+    /// process to the CPUs of its job right after it starts.
+    pub fn pin_jobs(mut self) -> Self {
+        self.pin_jobs = true;
+        self
+    }
+
+    /// Runs the test processes with the I/O `priority`, e.g.
+    /// [IoPriority::Idle] so that disk-heavy tests don't slow down the
+    /// driver and the rest of the machine.  Tests can override it with
+    /// [crate::with_io_priority].
+    pub fn io_priority(mut self, priority: IoPriority) -> Self {
+        self.io_priority = Some(priority);
+        self
+    }
+
+    /// Executes failing tests again, up to `retries` more times.  A
+    /// test passing when retried is reported as
+    /// [crate::Status::Flaky], and the failed attempts are kept in
+    /// [CompletedTask::previous_attempts].  Skipped tests and tests
+    /// canceled by Ctrl-C are not retried.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+
     /// Controls if colored output is used.
     pub fn color(mut self, when: When) -> Self {
         self.color = when;
@@ -280,9 +1737,700 @@ impl Config {
         self
     }
 
+    /// Makes JSON reports keep the output of tests that is not valid
+    /// UTF-8 intact, encoded in base64, see [crate::json::Encoding].
+    pub fn base64_output(mut self) -> Self {
+        self.base64_output = true;
+        self
+    }
+
+    /// Sets the stream human-readable reports are printed to.
+    /// Machine-readable formats are always printed to stdout, so
+    /// that they can be piped to other tools.
+    pub fn diagnostics(mut self, stream: Stream) -> Self {
+        self.diagnostics = Some(stream);
+        self
+    }
+
+    /// Replaces the libtest report with a full-screen dashboard of
+    /// the running tests and a browser of the failures once they
+    /// complete.  Requires the `ui` feature and a terminal; the
+    /// libtest report is used otherwise.
+    pub fn ui(mut self) -> Self {
+        self.ui = true;
+        self
+    }
+
     /// Enable printing of test output directly as soon as it arrives.
     pub fn nocapture(mut self) -> Self {
         self.nocapture = true;
         self
     }
+
+    /// Records the order in which the pieces of stdout and stderr of
+    /// the tests arrive, see [CompletedTask::output_chunks].  The
+    /// failure reports then show both streams of a test interleaved
+    /// in a single transcript, every line tagged with its stream.
+    ///
+    /// The streams are read from separate pipes, so output written
+    /// to both at nearly the same time may appear out of order.
+    pub fn transcript(mut self) -> Self {
+        self.transcript = true;
+        self
+    }
+
+    /// Like [Config::transcript], also showing the time since the
+    /// start of the test on every line of the transcripts.
+    pub fn timestamps(mut self) -> Self {
+        self.timestamps = true;
+        self
+    }
+
+    /// Appends the execution time to the line of each test in the
+    /// libtest report, like libtest's `--report-time`.  Times over
+    /// the warning and critical thresholds are colored; like libtest,
+    /// they are read in milliseconds from the
+    /// `RUST_TEST_TIME_INTEGRATION` variable, e.g. "500,1000", which
+    /// are also the defaults.
+    pub fn report_time(mut self) -> Self {
+        self.report_time = true;
+        self
+    }
+
+    /// Pads the test names in the libtest report to the longest name
+    /// in the plan, so that the statuses line up in a column.
+    pub fn aligned(mut self) -> Self {
+        self.aligned = true;
+        self
+    }
+
+    /// Prints a table of the stages below the line of each test that
+    /// reported stages with [crate::TestContext::report_stage_status]
+    /// in the libtest report, and the slowest stages of all tests at
+    /// the end.
+    pub fn stage_times(mut self) -> Self {
+        self.stage_times = true;
+        self
+    }
+
+    /// Folds the output and the other details of each failed test in
+    /// the libtest report into a collapsible section of the log of
+    /// the given CI system.  On GitHub, the failures are also shown
+    /// as error annotations of the job.
+    pub fn ci(mut self, system: Ci) -> Self {
+        self.ci = Some(system);
+        self
+    }
+
+    /// Keeps only the first and last `bytes / 2` bytes of stdout and
+    /// of stderr of every test, with a marker saying how much was
+    /// dropped in between.  Ignored with [Config::nocapture].
+    pub fn max_output_bytes(mut self, bytes: usize) -> Self {
+        self.max_output_bytes = Some(bytes);
+        self
+    }
+
+    /// Kills the tests that write more than `bytes` bytes to stdout
+    /// and stderr together, and reports them as failed.
+    pub fn output_hard_cap(mut self, bytes: usize) -> Self {
+        self.output_hard_cap = Some(bytes);
+        self
+    }
+
+    /// Kills the tests whose process uses more than `bytes` bytes of
+    /// resident memory, and reports them with
+    /// [crate::Status::MemoryExceeded].  The driver checks the memory
+    /// of the tests every [Config::poll_timeout], only on Linux.
+    pub fn memory_limit(mut self, bytes: u64) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Captures the output of the tests in memory files, moving it
+    /// from the pipes with splice(2) so that it doesn't go through the
+    /// driver until the test is done.  This saves CPU time and memory
+    /// of the driver with tests writing hundreds of megabytes of logs.
+    /// Ignored for the tests whose output must be inspected as it
+    /// arrives: with [Config::nocapture], [Config::transcript],
+    /// [Config::max_output_bytes] and in prefork workers.  Only
+    /// available on Linux, ignored on the other platforms.
+    pub fn splice_output(mut self) -> Self {
+        self.splice_output = true;
+        self
+    }
+
+    /// Sends the stages and metrics of the tests to the driver through
+    /// a ring buffer in shared memory, and only wakes the driver up
+    /// over the report pipe when it has read the previous ones.  This
+    /// keeps the driver responsive with tests reporting thousands of
+    /// metrics per second.  The tests forked by the zygote and those
+    /// executed by prefork workers use the report pipe, as do all the
+    /// tests on the platforms other than Linux.
+    pub fn shm_reports(mut self) -> Self {
+        self.shm_reports = true;
+        self
+    }
+
+    /// Compresses the output of the completed tests kept in memory
+    /// until the end of the run, by the driver and by the JUnit and
+    /// TRX reports, which saves memory with suites of verbose passing
+    /// tests.  Requires the compression feature.
+    pub fn compress_output(mut self) -> Self {
+        self.compress_output = true;
+        self
+    }
+
+    /// Discards the output of the tests selected by `keep` once they
+    /// have been reported, rather than keeping the output of the
+    /// whole run in memory until the end.  Their output is then
+    /// missing from the [crate::TestResults] of the run, the reports
+    /// get it as usual.
+    pub fn keep_output(mut self, keep: KeepOutput) -> Self {
+        self.keep_output = Some(keep);
+        self
+    }
+
+    /// Lets each test extend its timeout by up to `budget` in total
+    /// with [crate::TestContext::extend_timeout], so that a test
+    /// taking a rare slow path doesn't force a larger timeout on the
+    /// whole suite.  Tests can't extend their timeout by default.
+    pub fn timeout_extension(mut self, budget: Duration) -> Self {
+        self.timeout_extension = Some(budget);
+        self
+    }
+
+    /// Rounds the durations in the reports down to a multiple of
+    /// `resolution`, so that the reports of a run, e.g. those the
+    /// tests of a report parser compare with golden files, don't
+    /// change with the speed of the machine.
+    pub fn duration_resolution(mut self, resolution: Duration) -> Self {
+        assert!(
+            resolution > Duration::default(),
+            "the duration resolution must be positive"
+        );
+        self.duration_resolution = Some(resolution);
+        self
+    }
+
+    /// Reads the times the run and the tests start at from `clock`
+    /// instead of the clock of the system, e.g. a [VirtualClock] for
+    /// reports that are the same from one run to the next.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Limits the number of tests tagged with `tag` (see
+    /// [crate::tag]) that run simultaneously, e.g. to protect a
+    /// shared database from being overloaded.  The limit is applied
+    /// on top of the number of jobs.
+    pub fn max_concurrency_for_tag(mut self, tag: impl ToString, limit: usize) -> Self {
+        assert!(limit > 0, "the concurrency limit must be positive");
+        self.tag_limits.insert(tag.to_string(), limit);
+        self
+    }
+
+    /// Defines a pool of named resources (e.g. database instances)
+    /// that tests can lease exclusively using
+    /// [crate::TestContext::lease_resource].
+    pub fn resource_pool(mut self, pool: impl ToString, resources: Vec<String>) -> Self {
+        self.resource_pools.insert(pool.to_string(), resources);
+        self
+    }
+
+    /// Defines a service shared by the tests tagged with `name` (see
+    /// [crate::tag]), e.g. a local replica.  The driver calls `setup`
+    /// right before the first of these tests starts and `teardown`
+    /// once the last of them is done, so the service only runs while
+    /// it's needed.  If `setup` panics, the tests needing the fixture
+    /// fail without running.
+    ///
+    /// Both run on a thread of the driver, which keeps collecting the
+    /// output of the running tests and enforcing their timeouts, but
+    /// starts no test meanwhile.  The environment variables set by
+    /// `setup`, e.g. the address of the service, are therefore seen
+    /// by the test processes forked afterwards, but not by the
+    /// workers of the prefork and zygote modes, which start earlier.
+    pub fn global_fixture(
+        mut self,
+        name: impl ToString,
+        setup: impl Fn() + Send + Sync + 'static,
+        teardown: impl Fn() + Send + Sync + 'static,
+    ) -> Self {
+        let fixture = GlobalFixture {
+            setup: Arc::new(setup),
+            teardown: Arc::new(teardown),
+        };
+        self.global_fixtures.insert(name.to_string(), fixture);
+        self
+    }
+
+    /// Registers a check of the environment the tests need, e.g. that
+    /// the disk isn't full or that the docker daemon is reachable.
+    /// The driver runs the checks before starting a test, at most
+    /// once a second.  Once a check returns an error, no test starts
+    /// anymore: the driver prints the error, and the tests that
+    /// didn't start are skipped as "environment unhealthy" instead of
+    /// failing for a reason that has nothing to do with them.
+    pub fn health_check(
+        mut self,
+        name: impl ToString,
+        check: impl Fn() -> Result<(), String> + 'static,
+    ) -> Self {
+        self.health_checks.push(HealthCheck {
+            name: name.to_string(),
+            check: Box::new(check),
+        });
+        self
+    }
+
+    /// Enables the prefork execution mode.
+    ///
+    /// In this mode the driver starts a pool of worker processes (one
+    /// per job) that execute tests sequentially instead of forking a
+    /// new process for every test.  This avoids the cost of forking
+    /// for suites with thousands of tiny tests.  A test crashing or
+    /// timing out takes down only its worker, which gets replaced.
+    ///
+    /// Note that tests executed by the same worker share the process
+    /// state, and that coverage profiles of the worker processes
+    /// themselves are not split per test.
+    pub fn prefork(mut self) -> Self {
+        self.prefork = true;
+        self
+    }
+
+    /// Registers a function that initializes the state shared by all
+    /// tests, e.g. loads large fixtures or sets up global loggers.
+    ///
+    /// The hook runs in child processes only: by default in every
+    /// test process before the test body, once per worker in the
+    /// prefork mode, and once in total in the zygote mode.
+    pub fn child_init(mut self, init: fn()) -> Self {
+        self.child_init = Some(init);
+        self
+    }
+
+    /// Registers a function that assigns a category (e.g. "infra",
+    /// "product" or "flake") to each failed test, for example by
+    /// looking for known messages in its stderr.  Categories are
+    /// shown in the reports along with a breakdown of the failures.
+    pub fn classifier(mut self, classifier: fn(&CompletedTask) -> Option<String>) -> Self {
+        self.classifier = Some(classifier);
+        self
+    }
+
+    /// Enables the zygote execution mode.
+    ///
+    /// In this mode a single "zygote" process runs the
+    /// [Config::child_init] hook and forks every test process, so
+    /// that tests start with the initialized state instead of
+    /// repeating the initialization.  The mode relies on
+    /// `PR_SET_CHILD_SUBREAPER` and is only available on Linux; on
+    /// other systems it falls back to the default mode.  It has no
+    /// effect in the prefork mode.
+    pub fn zygote(mut self) -> Self {
+        self.zygote = true;
+        self
+    }
+
+    /// Chooses which tests share a process.
+    ///
+    /// With [ExecutionPolicy::ProcessPerSuite], a worker process is
+    /// forked for each suite directly under the root of the tree and
+    /// executes the tests of the suite one after the other, while
+    /// the suites run in parallel.  Tests directly under the root
+    /// share a worker too.  This trades isolation for speed on suites
+    /// with thousands of tiny tests, like [Config::prefork], but the
+    /// tests of a suite can rely on the order they run in.  A test
+    /// crashing or timing out takes down the worker of its suite,
+    /// which gets replaced.  The zygote mode has no effect with this
+    /// policy.
+    pub fn execution_policy(mut self, policy: ExecutionPolicy) -> Self {
+        self.execution_policy = Some(policy);
+        self
+    }
+
+    /// Enables tracking of file descriptors that tests leave open.
+    ///
+    /// The descriptors are listed from `/proc/self/fd` in the test
+    /// process right before and right after the test body runs, so
+    /// the detection is only available on systems with procfs.
+    pub fn fd_leaks(mut self, policy: LeakPolicy) -> Self {
+        self.fd_leaks = Some(policy);
+        self
+    }
+
+    /// Tracks the processes started by the tests, including daemons
+    /// that forked twice and left the process group of the test.  The
+    /// driver becomes a child subreaper and scans `/proc` regularly to
+    /// find them.  They are killed with the test when it times out, and
+    /// those still running once the test is done are reported as
+    /// leaks and killed.  Only available on Linux.
+    pub fn reap_daemons(mut self) -> Self {
+        self.reap_daemons = true;
+        self
+    }
+
+    /// Fails the tests that pass but write to stderr, like
+    /// [crate::deny_stderr] does for a subtree.
+    pub fn deny_stderr(mut self) -> Self {
+        self.deny_stderr = true;
+        self
+    }
+
+    /// Enables collection of per-test LLVM coverage profiles.
+    ///
+    /// Each test process (and every process it spawns) gets a
+    /// `LLVM_PROFILE_FILE` pointing to a unique file in `dir`, and
+    /// the paths of the produced `.profraw` files are recorded in
+    /// [crate::CompletedTask::profiles].  For the profile of the test
+    /// process itself to be redirected, the crate must be built with
+    /// the `coverage` feature and `-C instrument-coverage`.
+    pub fn coverage_dir(mut self, dir: PathBuf) -> Self {
+        self.coverage_dir = Some(dir);
+        self
+    }
+
+    /// Enables core dumps for test processes and preserves the dumps
+    /// of tests killed by a fatal signal in `dir`.
+    ///
+    /// The dump is looked up according to the system core_pattern,
+    /// so dumps handed over to a program (e.g. systemd-coredump) are
+    /// not collected.  If gdb is available, a backtrace extracted
+    /// from the dump is attached to the test result.
+    pub fn core_dir(mut self, dir: PathBuf) -> Self {
+        self.core_dir = Some(dir);
+        self
+    }
+
+    /// Compares the results with the results of a previous run
+    /// recorded with `--format json` in `file`.  The reports list
+    /// tests that started or stopped failing, appeared, disappeared
+    /// or became significantly slower.
+    pub fn baseline(mut self, file: PathBuf) -> Self {
+        self.baseline = Some(file);
+        self
+    }
+
+    /// Sets the directory of the snapshots compared by
+    /// [crate::TestContext::assert_snapshot].  Defaults to the
+    /// `snapshots` directory of the package when run by cargo.
+    pub fn snapshot_dir(mut self, dir: PathBuf) -> Self {
+        self.snapshot_dir = Some(dir);
+        self
+    }
+
+    /// Makes [crate::TestContext::assert_snapshot] write the
+    /// snapshots instead of comparing them, to accept intended
+    /// changes.
+    pub fn bless(mut self) -> Self {
+        self.bless = true;
+        self
+    }
+
+    /// Appends the outcome of every test to the database in `file`,
+    /// see [crate::history].  Requires the `history` feature; without
+    /// it the database is not written and a warning is printed.
+    pub fn history_db(mut self, file: PathBuf) -> Self {
+        self.history_db = Some(file);
+        self
+    }
+
+    /// Prints how to split the tests between `jobs` CI jobs instead of
+    /// executing them: the options selecting the tests of each job,
+    /// so that the jobs take about as long as each other according to
+    /// [Config::history_db] or [Config::baseline].  The tests added
+    /// after the split run in every job until it's suggested again.
+    pub fn suggest_split(mut self, jobs: usize) -> Self {
+        assert!(jobs > 0, "the tests can't be split between 0 jobs");
+        self.suggest_split = Some(jobs);
+        self
+    }
+
+    /// Records every event delivered to the report in `file`, see
+    /// [crate::recording].  `report --from FILE` shows the run again,
+    /// in the format given by the options of that command.
+    pub fn record(mut self, file: PathBuf) -> Self {
+        self.record = Some(file);
+        self
+    }
+
+    /// Writes a line per test to `file` as libtest does, with the
+    /// status ("ok", "failed" or "ignored"), the name and the duration
+    /// of the test, whatever the format of the report.
+    pub fn logfile(mut self, file: PathBuf) -> Self {
+        self.logfile = Some(file);
+        self
+    }
+
+    /// Writes the results to `file` in the JUnit XML format once the
+    /// run is done, whatever the format of the report.  Under `bazel
+    /// test`, this is the file named by `XML_OUTPUT_FILE`.
+    pub fn junit(mut self, file: PathBuf) -> Self {
+        self.junit = Some(file);
+        self
+    }
+
+    /// Writes the result of each test to `file` as soon as it
+    /// finished, so that an interrupted run can be resumed, see
+    /// [Config::resume].
+    pub fn checkpoint(mut self, file: PathBuf) -> Self {
+        self.checkpoint = Some(file);
+        self
+    }
+
+    /// Doesn't execute the tests that passed according to the file of
+    /// [Config::checkpoint], e.g. written by an interrupted run, and
+    /// shows their results as if they had been executed.  Without
+    /// this file, all the tests are executed.
+    pub fn resume(mut self) -> Self {
+        self.resume = true;
+        self
+    }
+
+    /// Only executes the tests of the shard `index` out of `total`,
+    /// i.e. every `total`-th test of the plan starting with the test
+    /// at `index`, counting from 0.
+    pub fn shard(mut self, index: usize, total: usize) -> Self {
+        assert!(index < total, "the shard index must be below the total");
+        self.shard = Some((index, total));
+        self
+    }
+
+    /// Creates a directory in `dir` for each test process and points
+    /// `TMPDIR` to it, so that the temporary files of the tests don't
+    /// collide and can be inspected after the run.
+    pub fn tmp_dir(mut self, dir: PathBuf) -> Self {
+        self.tmp_dir = Some(dir);
+        self
+    }
+
+    /// Captures the records the tests log with the `log` crate at
+    /// `level` and above.  They are kept in [CompletedTask::logs] and
+    /// shown for the failed tests.  Tests can't log anywhere else
+    /// while capturing, and nothing is captured if the test binary
+    /// installs a logger in the test processes itself.
+    pub fn log_level(mut self, level: log::Level) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    /// Records the command line and the values of the environment
+    /// variables `vars` in the description of the run, see
+    /// [crate::json::RunInfo].  The reports then show a command
+    /// rerunning each failed test with the same variables.
+    pub fn record_env(mut self, vars: Vec<String>) -> Self {
+        self.record_env = vars;
+        self
+    }
+
+    /// Sends a desktop notification with the number of passed and
+    /// failed tests once the run completes, using `notify-send` on
+    /// Linux and `osascript` on macOS.
+    pub fn notify(mut self) -> Self {
+        self.notify = true;
+        self
+    }
+
+    /// Adds a host executing a shard of the tests, parsed from
+    /// `ssh://[USER@]HOST[:PORT][/PATH]`, e.g.
+    /// `config.worker("ssh://ci@build-1".parse()?)`.  This mode is
+    /// experimental.
+    ///
+    /// With workers, the driver executes no test itself: each worker
+    /// runs the test binary at PATH, or a copy of the binary of the
+    /// driver without a PATH, in its home directory with the same
+    /// command line options and prints JSON events, see [crate::json].  The
+    /// results are shown once all the workers are done.  The driver
+    /// needs `ssh` and `scp` with keys authorizing it on the hosts.
+    pub fn worker(mut self, worker: Worker) -> Self {
+        self.workers.push(worker);
+        self
+    }
+
+    /// Adds a worker started with `worker --listen ADDR` to execute a
+    /// part of the tests.  This mode is experimental.
+    ///
+    /// The driver splits the plan between the remotes, sends each of
+    /// them the names of its tests and shows the results as they come
+    /// in.  The workers execute the tests with their own options and
+    /// keep running after the driver is done, so that orchestration
+    /// systems can keep a pool of them for several drivers.  The tests
+    /// of a worker that can't be reached or stays silent for 30
+    /// seconds end with a driver error.
+    ///
+    /// Workers execute the tests of any driver connecting to them, so
+    /// their port must only be reachable from trusted hosts.
+    pub fn remote(mut self, addr: impl ToString) -> Self {
+        self.remotes.push(addr.to_string());
+        self
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn from_env_vars_parses_variables() {
+        let mut env = HashMap::new();
+        env.insert("RACLETTE_TIMEOUT", "60");
+        env.insert("RACLETTE_NOCAPTURE", "1");
+        env.insert("RACLETTE_INCLUDE_IGNORED", "1");
+        env.insert("RACLETTE_PREFORK", "");
+        env.insert("RACLETTE_SKIP", "slow, flaky");
+        env.insert("RACLETTE_TAG", "db");
+        env.insert("RACLETTE_MAX_CONCURRENCY", "db=1,net=2");
+        env.insert("RACLETTE_CI", "gitlab");
+        env.insert("RACLETTE_CPUS", "0-2,8");
+        env.insert("RACLETTE_IO_PRIORITY", "best-effort:7");
+        env.insert("RACLETTE_KEEP_OUTPUT", "failed");
+        env.insert("RACLETTE_TIMEOUT_EXTENSION", "30");
+        env.insert("RACLETTE_DURATION_RESOLUTION", "100");
+        env.insert("RACLETTE_VIRTUAL_TIME", "1");
+        env.insert("TEST_SHARD_INDEX", "1");
+        env.insert("TEST_TOTAL_SHARDS", "3");
+        env.insert("XML_OUTPUT_FILE", "test.xml");
+        env.insert("RACLETTE_WORKER", "ssh://build-1, ssh://build-2");
+        env.insert("RACLETTE_REMOTE", "build-3:7357");
+        env.insert("RACLETTE_MEMORY_LIMIT", "512");
+        let lookup = |var: &str| env.get(var).map(OsString::from);
+
+        let config = Config::from_env_vars(&lookup).unwrap();
+        assert_eq!(config.timeout, Some(Duration::from_secs(60)));
+        assert!(config.nocapture);
+        assert!(!config.prefork);
+        assert_eq!(config.skip_filters, vec!["slow", "flaky"]);
+        assert_eq!(config.tag_filters, vec!["db"]);
+        assert_eq!(config.tag_limits.get("net"), Some(&2));
+        assert_eq!(config.ci, Some(Ci::GitLab));
+        assert_eq!(config.cpus, Some(vec![0, 1, 2, 8]));
+        assert_eq!(config.io_priority, Some(IoPriority::BestEffort(7)));
+        assert_eq!(config.keep_output, Some(KeepOutput::Failed));
+        assert_eq!(config.timeout_extension, Some(Duration::from_secs(30)));
+        assert_eq!(config.duration_resolution, Some(Duration::from_millis(100)));
+        assert!(config.clock.is_some());
+        assert_eq!(config.shard, Some((1, 3)));
+        assert_eq!(config.junit, Some(PathBuf::from("test.xml")));
+        assert_eq!(config.workers.len(), 2);
+        assert_eq!(config.remotes, vec!["build-3:7357"]);
+        assert_eq!(config.memory_limit, Some(512 << 20));
+        assert_eq!(config.run_ignored, Some(RunIgnored::Yes));
+
+        env.insert("RACLETTE_JOBS", "many");
+        let lookup = |var: &str| env.get(var).map(OsString::from);
+        assert!(Config::from_env_vars(&lookup).is_err());
+    }
+
+    #[test]
+    fn filter_replaces_and_add_filter_composes() {
+        let config = Config::default()
+            .filter("a".to_string())
+            .filter("b".to_string());
+        assert_eq!(config.filters, vec!["b"]);
+        let config = config.add_filter("c");
+        assert_eq!(config.filters, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn zero_duration_resolutions_are_rejected() {
+        let mut env = HashMap::new();
+        env.insert("RACLETTE_DURATION_RESOLUTION", "0");
+        let lookup = |var: &str| env.get(var).map(OsString::from);
+        assert!(Config::from_env_vars(&lookup).is_err());
+
+        let args = ["--duration-resolution", "0"];
+        assert!(Config::from_vec(args.iter().map(OsString::from).collect()).is_err());
+        let args = ["--duration-resolution", "10"];
+        let config = Config::from_vec(args.iter().map(OsString::from).collect()).unwrap();
+        assert_eq!(config.duration_resolution, Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn bazel_variables_yield_to_raclette_ones() {
+        let mut env = HashMap::new();
+        env.insert("TEST_SHARD_INDEX", "0");
+        env.insert("TEST_TOTAL_SHARDS", "2");
+        env.insert("TEST_TMPDIR", "/bazel/tmp");
+        env.insert("XML_OUTPUT_FILE", "/bazel/test.xml");
+        env.insert("TEST_SHARD_STATUS_FILE", "/bazel/shard");
+        let lookup = |var: &str| env.get(var).map(OsString::from);
+        let config = Config::from_env_vars(&lookup).unwrap();
+        assert_eq!(config.shard, Some((0, 2)));
+        assert_eq!(
+            config.shard_status_file,
+            Some(PathBuf::from("/bazel/shard"))
+        );
+        assert_eq!(config.tmp_dir, Some(PathBuf::from("/bazel/tmp")));
+        assert_eq!(config.junit, Some(PathBuf::from("/bazel/test.xml")));
+
+        env.insert("RACLETTE_SHARD", "2/3");
+        env.insert("RACLETTE_TMP_DIR", "/tmp");
+        env.insert("RACLETTE_JUNIT", "junit.xml");
+        let lookup = |var: &str| env.get(var).map(OsString::from);
+        let config = Config::from_env_vars(&lookup).unwrap();
+        assert_eq!(config.shard, Some((2, 3)));
+        assert_eq!(config.tmp_dir, Some(PathBuf::from("/tmp")));
+        assert_eq!(config.junit, Some(PathBuf::from("junit.xml")));
+
+        env.remove("RACLETTE_SHARD");
+        env.insert("TEST_SHARD_INDEX", "2");
+        let lookup = |var: &str| env.get(var).map(OsString::from);
+        assert!(Config::from_env_vars(&lookup).is_err());
+    }
+
+    #[test]
+    fn option_table_matches_help() {
+        let mut documented: Vec<&str> = HELP_STR
+            .lines()
+            .filter(|line| line.len() - line.trim_start().len() < 8)
+            .flat_map(|line| {
+                line.split_whitespace()
+                    .map(|word| word.trim_end_matches(','))
+                    .take_while(|word| word.starts_with('-'))
+            })
+            .collect();
+        let mut listed: Vec<&str> = OPTIONS
+            .iter()
+            .flat_map(|(names, _, _)| names.iter().copied())
+            .filter(|name| !["--test-threads", "-Z"].contains(name))
+            .collect();
+        documented.sort_unstable();
+        listed.sort_unstable();
+        assert_eq!(documented, listed);
+    }
+
+    #[test]
+    fn command_from_vec_parses_commands() {
+        let parse = |args: &[&str]| {
+            Command::from_vec(args.iter().map(OsString::from).collect()).map(|(cmd, _)| cmd)
+        };
+        assert_eq!(parse(&["list", "-f", "json"]).unwrap(), Command::List);
+        assert_eq!(
+            parse(&["report", "--from", "results.json"]).unwrap(),
+            Command::Report(PathBuf::from("results.json"))
+        );
+        assert!(parse(&["report"]).is_err());
+        assert_eq!(
+            parse(&["merge", "-f", "tap", "a.json", "b.json"]).unwrap(),
+            Command::Merge(vec![PathBuf::from("a.json"), PathBuf::from("b.json")])
+        );
+        assert!(parse(&["merge"]).is_err());
+        assert_eq!(
+            parse(&["worker", "--listen", "0.0.0.0:7357"]).unwrap(),
+            Command::Worker("0.0.0.0:7357".to_string())
+        );
+        let (cmd, config) = Command::from_vec(vec!["run".into(), "list".into()]).unwrap();
+        assert_eq!(cmd, Command::Run);
+        assert_eq!(config.filters, vec!["list"]);
+    }
+
+    #[test]
+    fn parse_format_accepts_all_formats() {
+        for (name, format, _) in FORMATS {
+            assert!(parse_format(name) == Ok(*format));
+        }
+        assert!(parse_format("xml").is_err());
+    }
 }
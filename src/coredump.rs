@@ -0,0 +1,149 @@
+//! Preservation of core dumps of crashed tests and extraction of
+//! backtraces from them.
+
+use nix::libc;
+use nix::unistd::{self, Pid};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Raises the soft limit on the core file size of the current
+/// process to the hard limit so that the kernel writes a core dump
+/// if the process is killed by a fatal signal.
+pub fn enable_in_child() {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: the pointer refers to a valid, properly aligned rlimit.
+    unsafe {
+        if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) != 0 {
+            return;
+        }
+        limit.rlim_cur = limit.rlim_max;
+        libc::setrlimit(libc::RLIMIT_CORE, &limit);
+    }
+}
+
+/// Computes the path of the core file the kernel produced for the
+/// process `pid` killed by signal `signo`, according to the
+/// `core_pattern` syntax described in core(5).
+///
+/// Returns None if the dump is not written to a file (the pattern
+/// is a pipe) or the pattern uses specifiers we can't reproduce.
+fn core_file_path(
+    pattern: &str,
+    uses_pid: bool,
+    pid: Pid,
+    signo: i32,
+    exe: &Path,
+) -> Option<PathBuf> {
+    if pattern.starts_with('|') {
+        return None;
+    }
+
+    let mut path = String::new();
+    let mut has_pid = false;
+    let mut chars = pattern.trim_end().chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '%' => path.push('%'),
+            'p' | 'P' => {
+                has_pid = true;
+                path.push_str(&pid.to_string());
+            }
+            's' => path.push_str(&signo.to_string()),
+            'u' => path.push_str(&unistd::getuid().to_string()),
+            'g' => path.push_str(&unistd::getgid().to_string()),
+            'e' => {
+                // The kernel uses the "comm" value which is truncated
+                // to 15 bytes.
+                let name = exe.file_name()?.to_string_lossy();
+                path.extend(name.chars().take(15));
+            }
+            'E' => path.push_str(&exe.to_string_lossy().replace('/', "!")),
+            'h' => {
+                let mut buf = [0u8; 256];
+                let host = unistd::gethostname(&mut buf).ok()?;
+                path.push_str(&host.to_string_lossy());
+            }
+            _ => return None,
+        }
+    }
+
+    if uses_pid && !has_pid {
+        path.push_str(&format!(".{}", pid));
+    }
+    Some(PathBuf::from(path))
+}
+
+/// Moves the core dump of the process `pid` into `dir` under the
+/// name `<stem>.core`.  Returns the new path of the dump, or None if
+/// the dump couldn't be found.
+pub fn collect(dir: &Path, stem: &str, pid: Pid, signo: i32) -> Option<PathBuf> {
+    let pattern = std::fs::read_to_string("/proc/sys/kernel/core_pattern").ok()?;
+    let uses_pid = std::fs::read_to_string("/proc/sys/kernel/core_uses_pid")
+        .map(|s| s.trim() == "1")
+        .unwrap_or(false);
+    let exe = std::env::current_exe().ok()?;
+
+    let core = core_file_path(&pattern, uses_pid, pid, signo, &exe)?;
+    let dest = dir.join(format!("{}.core", stem));
+    if std::fs::rename(&core, &dest).is_err() {
+        // The core might be on a different file system.
+        std::fs::copy(&core, &dest).ok()?;
+        let _ = std::fs::remove_file(&core);
+    }
+    Some(dest)
+}
+
+/// Extracts a symbolized backtrace of all threads from a core dump
+/// of the current executable using gdb.  Returns None if gdb is not
+/// available or fails.
+pub fn backtrace(core: &Path) -> Option<String> {
+    let exe = std::env::current_exe().ok()?;
+    let output = Command::new("gdb")
+        .arg("--batch")
+        .arg("--quiet")
+        .args(["-ex", "thread apply all bt"])
+        .arg(&exe)
+        .arg(core)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn core_file_path_expands_pattern() {
+        let exe = Path::new("/target/debug/integration_tests-1234");
+        let pid = Pid::from_raw(42);
+
+        assert_eq!(
+            core_file_path("core\n", false, pid, 11, exe),
+            Some(PathBuf::from("core"))
+        );
+        assert_eq!(
+            core_file_path("core", true, pid, 11, exe),
+            Some(PathBuf::from("core.42"))
+        );
+        assert_eq!(
+            core_file_path("/cores/%e.%p.%s.%%", true, pid, 6, exe),
+            Some(PathBuf::from("/cores/integration_tes.42.6.%"))
+        );
+        assert_eq!(core_file_path("core.%t", false, pid, 6, exe), None);
+        assert_eq!(
+            core_file_path("|/usr/lib/systemd/systemd-coredump %P", false, pid, 6, exe),
+            None
+        );
+    }
+}
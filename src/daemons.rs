@@ -0,0 +1,354 @@
+//! The processes started by the tests, see
+//! [crate::Config::reap_daemons].
+//!
+//! A daemon forks twice and leaves the process group of the test with
+//! setsid(2), so killing the group of the test doesn't reach it, and
+//! once the intermediate process exits it is no longer a descendant
+//! of the test either.  The driver and the test processes become
+//! child subreapers, so that such orphans are adopted by the test
+//! process while it runs and then by the driver, rather than by init.
+//! The driver scans `/proc` regularly to remember the descendants of
+//! each test process: those in its process group, their children, and
+//! those in a session or group led by one of them.  The orphans the
+//! driver adopted when the test process exited are the descendants of
+//! the test if they hold one of its pipes.
+
+use crate::execution::LeakedProcess;
+use crate::zygote;
+use nix::libc;
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::sys::stat;
+use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::unistd::{self, Pid};
+use std::collections::{HashMap, HashSet};
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+/// How often the driver looks for new descendants.
+const SCAN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What `/proc/PID/stat` tells about a process.
+struct ProcessInfo {
+    ppid: Pid,
+    pgid: Pid,
+    sid: Pid,
+    zombie: bool,
+}
+
+/// Parses the fields following the command in `/proc/PID/stat`.
+fn parse_stat(stat: &str) -> Option<ProcessInfo> {
+    // The command may contain spaces and parentheses, it ends at the
+    // last parenthesis.
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace();
+    let state = fields.next()?;
+    let mut pid = || fields.next()?.parse().ok().map(Pid::from_raw);
+    Some(ProcessInfo {
+        ppid: pid()?,
+        pgid: pid()?,
+        sid: pid()?,
+        zombie: state == "Z",
+    })
+}
+
+/// Lists the processes of the system.
+fn processes() -> HashMap<Pid, ProcessInfo> {
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return HashMap::new(),
+    };
+    entries
+        .filter_map(|entry| {
+            let pid = entry.ok()?.file_name().to_str()?.parse().ok()?;
+            // The process may have exited since.
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            Some((Pid::from_raw(pid), parse_stat(&stat)?))
+        })
+        .collect()
+}
+
+/// Kills the process `groups` the driver started and reaps their
+/// leaders, e.g. when the driver panicked.  The test processes, the
+/// workers and the zygote each lead a group, and a group ID isn't
+/// reused while its leader isn't reaped.  The other processes of the
+/// program embedding the driver are left alone.
+pub(crate) fn kill_groups(groups: &HashSet<Pid>) {
+    for pgid in groups {
+        let _ = killpg(*pgid, Signal::SIGKILL);
+    }
+    for pgid in groups {
+        // Fails for the leaders that were reaped already.
+        let _ = waitpid(*pgid, None);
+    }
+}
+
+/// Returns the running processes of `table` in one of the process
+/// `groups` of the session `sid`, with their group.  Once a group is
+/// empty, its ID may be reused by a process outside the session.
+fn stragglers<T>(
+    table: &HashMap<Pid, ProcessInfo>,
+    groups: &HashMap<Pid, T>,
+    sid: Pid,
+) -> Vec<(Pid, Pid)> {
+    let mut found: Vec<(Pid, Pid)> = table
+        .iter()
+        .filter(|(_, info)| !info.zombie && info.sid == sid && groups.contains_key(&info.pgid))
+        .map(|(pid, info)| (*pid, info.pgid))
+        .collect();
+    found.sort_unstable();
+    found
+}
+
+/// Kills the processes left in the process `groups` of the test
+/// processes at the end of the run, and returns them with the name
+/// of the test whose group they were in.
+pub(crate) fn sweep(groups: &HashMap<Pid, Vec<String>>) -> Vec<(Vec<String>, LeakedProcess)> {
+    let driver = unistd::getpid();
+    let sid = match unistd::getsid(None) {
+        Ok(sid) => sid,
+        Err(_) => return Vec::new(),
+    };
+    let table = processes();
+    let found = stragglers(&table, groups, sid);
+    let leaked = found
+        .iter()
+        .map(|(pid, pgid)| {
+            let process = LeakedProcess {
+                pid: pid.as_raw(),
+                command: command(*pid),
+            };
+            (groups[pgid].clone(), process)
+        })
+        .collect();
+    for (pid, pgid) in &found {
+        let _ = killpg(*pgid, Signal::SIGKILL);
+        if table[pid].ppid == driver {
+            let _ = waitpid(*pid, None);
+        }
+    }
+    leaked
+}
+
+/// Returns the command line of a process, or its name if it has none.
+fn command(pid: Pid) -> String {
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let args: Vec<_> = cmdline
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect();
+    if args.is_empty() {
+        std::fs::read_to_string(format!("/proc/{}/comm", pid))
+            .map(|comm| format!("[{}]", comm.trim_end()))
+            .unwrap_or_default()
+    } else {
+        args.join(" ")
+    }
+}
+
+/// Returns the inode of the pipe `fd` refers to.
+fn pipe_inode(fd: RawFd) -> Option<libc::ino_t> {
+    stat::fstat(fd).ok().map(|stat| stat.st_ino)
+}
+
+/// Returns whether the process `pid` has one of the pipes `inodes`
+/// open.
+fn holds_pipe(pid: Pid, inodes: &[libc::ino_t]) -> bool {
+    let entries = match std::fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(Result::ok).any(|entry| {
+        let target = std::fs::read_link(entry.path()).unwrap_or_default();
+        inodes
+            .iter()
+            .any(|inode| target.to_str() == Some(&format!("pipe:[{}]", inode)))
+    })
+}
+
+/// Adds the descendants of `root` found in `table` to `known`.
+fn add_descendants(table: &HashMap<Pid, ProcessInfo>, root: Pid, known: &mut HashSet<Pid>) {
+    let belongs = |pid: Pid, known: &HashSet<Pid>| pid == root || known.contains(&pid);
+    loop {
+        let found: Vec<Pid> = table
+            .iter()
+            .filter(|(pid, info)| {
+                **pid != root
+                    && !known.contains(pid)
+                    && (belongs(info.ppid, known)
+                        || belongs(info.pgid, known)
+                        || belongs(info.sid, known))
+            })
+            .map(|(pid, _)| *pid)
+            .collect();
+        if found.is_empty() {
+            return;
+        }
+        known.extend(found);
+    }
+}
+
+/// The descendants of each running test process.
+pub(crate) struct Descendants {
+    driver: Pid,
+    of: HashMap<Pid, HashSet<Pid>>,
+    scanned_at: Option<Instant>,
+}
+
+impl Descendants {
+    /// Makes the driver adopt the orphaned descendants of the tests.
+    pub(crate) fn new() -> Self {
+        zygote::become_subreaper();
+        Self {
+            driver: unistd::getpid(),
+            of: HashMap::new(),
+            scanned_at: None,
+        }
+    }
+
+    /// Looks for new descendants of the test processes `running`, at
+    /// most every [SCAN_INTERVAL], and reaps those adopted by the
+    /// driver that exited.
+    pub(crate) fn scan(&mut self, running: impl Iterator<Item = Pid>) {
+        if self.scanned_at.is_some_and(|t| t.elapsed() < SCAN_INTERVAL) {
+            return;
+        }
+        self.scanned_at = Some(Instant::now());
+        let table = processes();
+        for pid in running {
+            add_descendants(&table, pid, self.of.entry(pid).or_default());
+        }
+        // The descendants that exited stay known, the daemons they
+        // started are still in their session.
+        for pid in self.of.values().flatten() {
+            if let Some(info) = table.get(pid).filter(|info| info.zombie) {
+                if info.ppid == self.driver {
+                    let _ = waitpid(*pid, Some(WaitPidFlag::WNOHANG));
+                }
+            }
+        }
+    }
+
+    /// Kills the descendants of the test process `pid` that are still
+    /// running, e.g. when it times out.
+    pub(crate) fn kill(&mut self, pid: Pid) {
+        let table = processes();
+        let known = self.of.entry(pid).or_default();
+        add_descendants(&table, pid, known);
+        for descendant in known.iter().filter(|pid| table.contains_key(pid)) {
+            let _ = kill(*descendant, Signal::SIGKILL);
+        }
+    }
+
+    /// Returns the descendants of the test process `pid`, which just
+    /// exited, that are still running, and kills them.  `pipes` are
+    /// the driver's ends of the pipes of the test.
+    pub(crate) fn finish(&mut self, pid: Pid, pipes: &[RawFd]) -> Vec<LeakedProcess> {
+        let mut known = self.of.remove(&pid).unwrap_or_default();
+        let table = processes();
+        let inodes: Vec<libc::ino_t> = pipes.iter().filter_map(|fd| pipe_inode(*fd)).collect();
+        let adopted: Vec<Pid> = table
+            .iter()
+            .filter(|(other, info)| {
+                info.ppid == self.driver && !known.contains(other) && holds_pipe(**other, &inodes)
+            })
+            .map(|(other, _)| *other)
+            .collect();
+        known.extend(adopted);
+        add_descendants(&table, pid, &mut known);
+        let mut running: Vec<Pid> = known
+            .into_iter()
+            .filter(|pid| table.get(pid).is_some_and(|info| !info.zombie))
+            .collect();
+        running.sort_unstable();
+        let leaked = running
+            .iter()
+            .map(|pid| LeakedProcess {
+                pid: pid.as_raw(),
+                command: command(*pid),
+            })
+            .collect();
+        for pid in &running {
+            let _ = kill(*pid, Signal::SIGKILL);
+        }
+        for pid in &running {
+            if table[pid].ppid == self.driver {
+                let _ = waitpid(*pid, None);
+            }
+        }
+        leaked
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn descendants_include_escaped_daemons() {
+        let process = |ppid: i32, pgid: i32, sid: i32| ProcessInfo {
+            ppid: Pid::from_raw(ppid),
+            pgid: Pid::from_raw(pgid),
+            sid: Pid::from_raw(sid),
+            zombie: false,
+        };
+        let mut table = HashMap::new();
+        // The test 10 and a child in its group.
+        table.insert(Pid::from_raw(10), process(1, 10, 1));
+        table.insert(Pid::from_raw(11), process(10, 10, 1));
+        // A daemon of 11 in a session of its own, whose intermediate
+        // 12 exited, adopted by the driver 1.
+        table.insert(Pid::from_raw(13), process(1, 12, 12));
+        // Unrelated processes.
+        table.insert(Pid::from_raw(20), process(1, 20, 1));
+        table.insert(Pid::from_raw(21), process(20, 20, 1));
+
+        let mut known = HashSet::new();
+        add_descendants(&table, Pid::from_raw(10), &mut known);
+        assert_eq!(known, [11].iter().map(|p| Pid::from_raw(*p)).collect());
+        // Seen while the intermediate was alive.
+        known.insert(Pid::from_raw(12));
+        add_descendants(&table, Pid::from_raw(10), &mut known);
+        assert!(known.contains(&Pid::from_raw(13)));
+        assert!(!known.contains(&Pid::from_raw(21)));
+
+        // At the end of the run, the test 10 exited but 11 is still in
+        // its group, and 21 in that of 20, which isn't a test.
+        table.remove(&Pid::from_raw(10));
+        let mut groups = HashMap::new();
+        groups.insert(Pid::from_raw(10), ());
+        assert_eq!(
+            stragglers(&table, &groups, Pid::from_raw(1)),
+            vec![(Pid::from_raw(11), Pid::from_raw(10))]
+        );
+        assert!(stragglers(&table, &groups, Pid::from_raw(12)).is_empty());
+
+        let info = parse_stat("13 (a (b)) c) S 1 12 12 0 -1").unwrap();
+        assert_eq!(
+            (info.ppid, info.pgid, info.sid),
+            (Pid::from_raw(1), Pid::from_raw(12), Pid::from_raw(12))
+        );
+    }
+
+    #[test]
+    fn processes_left_in_the_group_of_a_test_are_killed() {
+        use crate::{test_case, Config, Status};
+        let pid_file = std::env::temp_dir().join(format!("raclette-left-{}", std::process::id()));
+        let written = pid_file.clone();
+        let tree = test_case("leaves_a_process", move || {
+            let status = std::process::Command::new("sh")
+                .arg("-c")
+                .arg("sleep 60 >/dev/null 2>&1 & printf %s $! > \"$0\"")
+                .arg(&written)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        });
+        let tasks = crate::execution::test::run(&Config::default(), tree);
+        assert_eq!(tasks[0].status, Status::Success);
+
+        let pid = Pid::from_raw(std::fs::read_to_string(&pid_file).unwrap().parse().unwrap());
+        let _ = std::fs::remove_file(&pid_file);
+        // Init may not have reaped it yet.
+        assert!(processes().get(&pid).is_none_or(|info| info.zombie));
+    }
+}
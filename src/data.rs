@@ -0,0 +1,185 @@
+//! Data-driven tests: suites with one test per record of a data file.
+//!
+//! Suites are named after the file without its extension, and each
+//! test after the index of its record, followed by the value of the
+//! `name` field of the record if it has one, e.g. `0-empty input`.
+//! A record that doesn't deserialize into the type expected by the
+//! test makes that test fail, and a file that can't be read makes the
+//! suite consist of a single failing `load` test.
+
+use crate::{test_case, test_suite, TestTree};
+use serde::de::DeserializeOwned;
+use std::path::Path;
+use std::rc::Rc;
+
+/// A record of a data file: its name, if any, and its value.
+type Record<T> = (Option<String>, Result<T, String>);
+
+fn cases_suite<T: 'static>(
+    path: &Path,
+    records: Result<Vec<Record<T>>, String>,
+    test: impl Fn(T) + 'static,
+) -> TestTree {
+    let suite_name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let records = match records {
+        Ok(records) => records,
+        Err(err) => {
+            let msg = format!("failed to load test cases from {}: {}", path.display(), err);
+            return test_suite(
+                suite_name,
+                vec![test_case("load", move || panic!("{}", msg))],
+            );
+        }
+    };
+    let test = Rc::new(test);
+    let tests = records
+        .into_iter()
+        .enumerate()
+        .map(|(i, (name, record))| {
+            let test = test.clone();
+            let name = match name {
+                Some(name) if !name.is_empty() => format!("{}-{}", i, name),
+                _ => i.to_string(),
+            };
+            test_case(name, move || match record {
+                Ok(record) => test(record),
+                Err(err) => panic!("failed to parse the record: {}", err),
+            })
+        })
+        .collect();
+    test_suite(suite_name, tests)
+}
+
+/// Reads the test cases from a JSON file containing an array of
+/// records.
+pub fn test_cases_from_json<T: DeserializeOwned + 'static>(
+    path: impl AsRef<Path>,
+    test: impl Fn(T) + 'static,
+) -> TestTree {
+    let path = path.as_ref();
+    let records = std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| {
+            serde_json::from_str::<Vec<serde_json::Value>>(&text).map_err(|err| err.to_string())
+        })
+        .map(|values| {
+            values
+                .into_iter()
+                .map(|value| {
+                    let name = value
+                        .get("name")
+                        .and_then(|n| n.as_str())
+                        .map(str::to_string);
+                    (
+                        name,
+                        serde_json::from_value(value).map_err(|err| err.to_string()),
+                    )
+                })
+                .collect()
+        });
+    cases_suite(path, records, test)
+}
+
+/// Reads the test cases from a CSV file whose first line names the
+/// fields.
+#[cfg(feature = "data-files")]
+pub fn test_cases_from_csv<T: DeserializeOwned + 'static>(
+    path: impl AsRef<Path>,
+    test: impl Fn(T) + 'static,
+) -> TestTree {
+    let path = path.as_ref();
+    let read = || -> Result<Vec<Record<T>>, csv::Error> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let headers = reader.headers()?.clone();
+        let name_column = headers.iter().position(|h| h == "name");
+        reader
+            .records()
+            .map(|row| {
+                let row = row?;
+                let name = name_column.and_then(|i| row.get(i)).map(str::to_string);
+                Ok((
+                    name,
+                    row.deserialize(Some(&headers))
+                        .map_err(|err| err.to_string()),
+                ))
+            })
+            .collect()
+    };
+    cases_suite(path, read().map_err(|err| err.to_string()), test)
+}
+
+/// Reads the test cases from a TOML file containing an array of
+/// tables named `cases`, i.e. a `[[cases]]` section per record.
+#[cfg(feature = "data-files")]
+pub fn test_cases_from_toml<T: DeserializeOwned + 'static>(
+    path: impl AsRef<Path>,
+    test: impl Fn(T) + 'static,
+) -> TestTree {
+    let path = path.as_ref();
+    let records = std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| text.parse::<toml::Value>().map_err(|err| err.to_string()))
+        .and_then(|value| match value.get("cases") {
+            Some(toml::Value::Array(cases)) => Ok(cases.clone()),
+            _ => Err("expected an array of tables named cases".to_string()),
+        })
+        .map(|values| {
+            values
+                .into_iter()
+                .map(|value| {
+                    let name = value
+                        .get("name")
+                        .and_then(|n| n.as_str())
+                        .map(str::to_string);
+                    (name, value.try_into().map_err(|err| err.to_string()))
+                })
+                .collect()
+        });
+    cases_suite(path, records, test)
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[cfg(feature = "data-files")]
+    #[test]
+    fn test_cases_are_named_after_records() {
+        use crate::{config::Config, execution::make_plan};
+
+        #[derive(serde::Deserialize)]
+        struct Case {
+            #[allow(dead_code)]
+            input: u32,
+        }
+
+        let dir = std::env::temp_dir().join(format!("raclette-data-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let csv = dir.join("cases.csv");
+        std::fs::write(&csv, "name,input\nzero,0\n,1\nbad,x\n").unwrap();
+        let toml = dir.join("cases.toml");
+        std::fs::write(&toml, "[[cases]]\ninput = 3\n").unwrap();
+        let json = dir.join("missing.json");
+
+        let names = |tree| -> Vec<String> {
+            make_plan(&Config::default(), tree)
+                .unwrap()
+                .into_iter()
+                .map(|task| task.full_name[1..].join("::"))
+                .collect()
+        };
+        assert_eq!(
+            names(test_cases_from_csv(&csv, |_: Case| ())),
+            vec!["0-zero", "1", "2-bad"]
+        );
+        assert_eq!(names(test_cases_from_toml(&toml, |_: Case| ())), vec!["0"]);
+        assert_eq!(
+            names(test_cases_from_json(&json, |_: Case| ())),
+            vec!["load"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+//! Diffs of the values compared by failed assertions, shown in the
+//! failure sections of the reports.
+
+/// Sequences longer than this many elements in total are not diffed,
+/// the table used by [diff] would get too large.
+const MAX_DIFF_LEN: usize = 4000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Computes a shortest edit script turning `old` into `new` from the
+/// table of the longest common subsequences of their suffixes.
+pub fn diff<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Change<'a>> {
+    if old.len() + new.len() > MAX_DIFF_LEN {
+        let removed = old.iter().map(|s| Change::Removed(s));
+        return removed
+            .chain(new.iter().map(|s| Change::Added(s)))
+            .collect();
+    }
+    let (n, m) = (old.len(), new.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            changes.push(Change::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            changes.push(Change::Removed(old[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Added(new[j]));
+            j += 1;
+        }
+    }
+    changes
+}
+
+/// Splits a value into runs of alphanumeric characters, runs of
+/// whitespace and single punctuation characters, for word diffs.
+pub fn words(s: &str) -> Vec<&str> {
+    fn class(c: char) -> u8 {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    }
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let continues = chars
+            .peek()
+            .is_some_and(|&(_, next)| class(c) != 2 && class(next) == class(c));
+        if !continues {
+            words.push(&s[start..i + c.len_utf8()]);
+            start = i + c.len_utf8();
+        }
+    }
+    words
+}
+
+/// Extracts the compared values from the message of a failed
+/// `assert_eq!`, i.e. the lines starting with `left:` and `right:`.
+pub fn left_right(message: &str) -> Option<(&str, &str)> {
+    // Older versions of Rust quote the values in backticks and put
+    // the location after the message.
+    fn value<'a>(line: &'a str, label: &str) -> Option<&'a str> {
+        let value = line.trim_start().strip_prefix(label)?;
+        match value.rfind('`') {
+            Some(end) if end > 0 && value.starts_with('`') => Some(&value[1..end]),
+            _ => Some(value),
+        }
+    }
+    let mut lines = message.lines();
+    while let Some(line) = lines.next() {
+        if let Some(left) = value(line, "left: ") {
+            return Some((left, value(lines.next()?, "right: ")?));
+        }
+    }
+    None
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn diff_finds_shortest_edit() {
+        use Change::*;
+        let old = words("Point { x: 1, y: 2 }");
+        let new = words("Point { x: 1, y: 3 }");
+        let changes = diff(&old, &new);
+        assert_eq!(changes.iter().filter(|c| !matches!(c, Same(_))).count(), 2);
+        assert!(changes.contains(&Removed("2")) && changes.contains(&Added("3")));
+
+        assert_eq!(
+            diff(&["a", "b", "c"], &["a", "c", "d"]),
+            vec![Same("a"), Removed("b"), Same("c"), Added("d")]
+        );
+    }
+
+    #[test]
+    fn left_right_parses_panic_messages() {
+        let new = "assertion `left == right` failed\n  left: [1, 2]\n right: [1, 3]\n";
+        assert_eq!(left_right(new), Some(("[1, 2]", "[1, 3]")));
+        let old =
+            "assertion failed: `(left == right)`\n  left: `1`,\n right: `2`', src/main.rs:1:1";
+        assert_eq!(left_right(old), Some(("1", "2")));
+        assert_eq!(left_right("boom"), None);
+    }
+}
@@ -1,55 +1,155 @@
-use crate::{config::Config, Options, TestTree, TreeNode};
-use mio::unix::pipe;
+use crate::{
+    baseline::Comparison,
+    compress::RetainedTasks,
+    config::{self, Config, ExecutionPolicy, LeakPolicy, RunIgnored},
+    coredump,
+    daemons::{self, Descendants},
+    filter::Filter,
+    jobserver::Jobserver,
+    json::RunInfo,
+    lease::{Lease, LeaseAllocator, LeaseRequest},
+    logger,
+    prefork::{self, WorkerCommand, PANIC_EXIT_CODE},
+    ring::{ReportRing, RING_SIZE},
+    sanitizer::{self, SanitizerError},
+    scheduling::{self, CpuSlots},
+    spool::{self, Spool},
+    zygote, GenericAssertion, Options, TestTree, TreeNode,
+};
+use mio::unix::{pipe, SourceFd};
 use mio::{Events, Interest, Poll, Token};
 use mio_signals as msig;
+use nix::fcntl;
+use nix::poll::{PollFd, PollFlags};
 use nix::sys::signal::{killpg, Signal};
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::{self, fork, ForkResult, Pid};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fs::File;
 use std::io::{self, Read, Write};
+use std::marker::PhantomData;
 use std::mem::size_of;
-use std::os::unix::io::AsRawFd;
-use std::time::{Duration, Instant};
-use std::{collections::HashMap, convert::TryInto};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+};
 
-const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the driver checks the status of the tests by default, see
+/// [Config::poll_timeout].
+const DEFAULT_POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long before the timeout a task is asked to cancel, and how
+/// long tasks get to wind down after a Ctrl-C.
+const CANCELLATION_NOTICE: Duration = Duration::from_secs(1);
+
+/// How long a timed-out task gets to handle the warning signal before
+/// it's killed.
+const TIMEOUT_SIGNAL_GRACE: Duration = Duration::from_millis(500);
+
+/// How often the health checks run at most, see
+/// [Config::health_check].
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 /// The token used to catch signals.
 const SIGNAL_TOKEN: Token = Token(0);
 
-#[derive(Clone, Debug, PartialEq)]
+/// The exit code of a test process that skipped itself, see
+/// [TestContext::skip].  Same as the one used by automake.
+const SKIP_EXIT_CODE: i32 = 77;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Status {
     Success,
     Failure(i32),
-    Signaled(&'static str),
+    Signaled(#[serde(deserialize_with = "deserialize_signal_name")] SignalName),
     Timeout,
     Skipped(String),
+    /// The test passed but left the given number of file descriptors
+    /// open, and the leak policy asked to fail such tests.
+    LeakedFds(usize),
+    /// The test process crashed or failed because a sanitizer
+    /// detected a problem.
+    SanitizerError(SanitizerError),
+    /// The test failed at first but passed when retried; carries the
+    /// total number of attempts.
+    Flaky {
+        attempts: usize,
+    },
+    /// The test failed, and it was expected to, see
+    /// [crate::expect_failure].
+    ExpectedFailure,
+    /// The driver couldn't execute the test, e.g. failed to spawn
+    /// its process.
+    DriverError(String),
+    /// The test process exceeded its memory limit and was killed, see
+    /// [crate::Config::memory_limit].
+    MemoryExceeded,
+    /// The test wrote more output than allowed and was killed, see
+    /// [crate::Config::output_hard_cap].
+    OutputExceeded,
+    /// The test passed but wrote the given number of bytes to stderr,
+    /// and it was asked not to, see [crate::deny_stderr].
+    WroteStderr(usize),
+}
+
+/// The name of a signal, e.g. "SIGSEGV".  The alias hides the
+/// lifetime from serde, which would otherwise only deserialize
+/// [Status] from `'static` data.
+pub type SignalName = &'static str;
+
+/// Deserializes the name of a signal as the static string used by
+/// [Status::Signaled].
+fn deserialize_signal_name<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> Result<&'static str, D::Error> {
+    let name = String::deserialize(d)?;
+    name.parse::<Signal>()
+        .map(Signal::as_str)
+        .map_err(|_| serde::de::Error::custom(format!("unknown signal: {}", name)))
 }
 
 impl Status {
     /// Returns whether a [Status] represents a non-failure. This includes
-    /// [Status::Success] and [Status::Skipped]. Anything else is a failure
-    /// of some sort.
+    /// [Status::Success], [Status::Skipped], [Status::Flaky] and
+    /// [Status::ExpectedFailure]. Anything else is a failure of some sort.
     pub fn is_ok(&self) -> bool {
-        match self {
-            Status::Success => true,
-            Status::Skipped(_) => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            Status::Success | Status::Skipped(_) | Status::Flaky { .. } | Status::ExpectedFailure
+        )
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// The pipes and the control socket of a task polled by the driver.
+#[derive(Debug, PartialEq, Clone, Copy)]
 enum InputSource {
-    Stdout,
-    Stderr,
-    Report,
+    Stdout = 0,
+    Stderr = 1,
+    Report = 2,
+    Control = 3,
+}
+
+impl InputSource {
+    const ALL: [InputSource; 4] = [
+        InputSource::Stdout,
+        InputSource::Stderr,
+        InputSource::Report,
+        InputSource::Control,
+    ];
 }
 
 /// A task to be executed as a test.
 pub struct Task {
     pub full_name: Vec<String>,
-    work: super::GenericAssertion,
+    pub(crate) work: GenericAssertion,
     options: Options,
 }
 
@@ -57,6 +157,17 @@ impl Task {
     pub fn name(&self) -> String {
         self.full_name.join("::")
     }
+
+    /// The tags of the task, including those inherited from its
+    /// suites.
+    pub fn tags(&self) -> &[String] {
+        &self.options.tags
+    }
+
+    /// Why the task will be skipped, None if it will run.
+    pub fn skip_reason(&self) -> Option<&str> {
+        self.options.skip_reason.as_deref()
+    }
 }
 
 /// A task that has just been spawned and started executing.
@@ -69,13 +180,30 @@ struct RunningTask {
     report_pipe: pipe::Receiver,
     stdout_buf: Vec<u8>,
     stderr_buf: Vec<u8>,
+    control: UnixStream,
+    ring: Option<ReportRing>,
 }
 
 /// A task that is being observed by the test driver.
 struct ObservedTask {
+    // The ID of the task in the tokens of its pipes, see make_token.
+    id: usize,
+    // The index of the task in the plan.
+    index: usize,
     full_name: Vec<String>,
+    tags: Vec<String>,
+    metadata: Metadata,
+    // The suite of the task with the process per suite policy, its
+    // other tasks have to wait.
+    suite: Option<String>,
+    // Set if the task must not write to stderr, see
+    // [Config::deny_stderr].
+    deny_stderr: bool,
+    // Whether the task is expected to fail, see [crate::expect_failure].
+    expect_failure: bool,
     pid: Pid,
     started_at: Instant,
+    start_time: SystemTime,
     stdout_pipe: Option<pipe::Receiver>,
     stderr_pipe: Option<pipe::Receiver>,
     status_and_duration: Option<(Status, Duration)>,
@@ -90,20 +218,383 @@ struct ObservedTask {
     stderr_offset: usize,
     // Similarly to stdout/stderr; tasks have a dedicate pipe to send
     report_pipe: Option<pipe::Receiver>,
-    report_decoder: StreamDecoder,
+    report_decoder: StreamDecoder<ReportMessage>,
+    // The socket the task sends its requests over, and the driver
+    // its replies.
+    control: UnixStream,
+    control_decoder: StreamDecoder<ControlRequest>,
+    // Set once the task has been asked to cancel.
+    cancelled: bool,
+    // The time the task added to its timeout, see
+    // [Config::timeout_extension].
+    timeout_extension: Duration,
+    // Set when the task exceeds the timeout and gets the warning
+    // signal.
+    timeout_warned_at: Option<Instant>,
+    // Snapshots of the open file descriptors of the task, only sent
+    // if leak detection is enabled.
+    fds_at_start: Option<Vec<OpenFd>>,
+    fds_at_exit: Option<Vec<OpenFd>>,
+    // The signal that killed the task if the kernel dumped its core.
+    core_signal: Option<Signal>,
+    // The reason the task gave when it skipped itself.
+    skip_reason: Option<String>,
+    // The failed assertion the task reported.
+    assertion: Option<AssertionFailure>,
+    // The metrics the task reported.
+    metrics: Vec<Metric>,
+    // The stages the task reported, in order.
+    stages: Vec<StageReport>,
+    // The stage the task started with a timeout, when it started and
+    // its timeout, see [TestContext::begin_stage_with_timeout].
+    timed_stage: Option<(String, Instant, Duration)>,
+    // Set once the timed stage exceeded its timeout, which times the
+    // task out like exceeding the timeout of the test does.
+    stage_timed_out: bool,
+    // The stage the task reported as in progress and when it started,
+    // see [TestContext::report_stage_in_progress].
+    current_stage: Option<(String, Instant)>,
+    // The records the task logged, see [Config::log_level].
+    logs: Vec<LogRecord>,
+    // When the output of the task arrived, see [Config::transcript].
+    output_chunks: Vec<OutputChunk>,
+    // The number of bytes the task wrote to stdout and stderr, and
+    // the number of bytes dropped from the middle of each, see
+    // [Config::max_output_bytes].
+    output_bytes: usize,
+    // The number of bytes the task wrote to stderr.
+    stderr_bytes: usize,
+    stdout_dropped: usize,
+    stderr_dropped: usize,
+    // Set when the task has been killed for exceeding
+    // [Config::output_hard_cap].
+    output_exceeded: bool,
+    // Set when the task has been killed for exceeding
+    // [Config::memory_limit].
+    memory_exceeded: bool,
+    // The processes the task left running, see [Config::reap_daemons].
+    leaked_processes: Vec<LeakedProcess>,
+    // Set once a pipe of the task closed, it probably exited.
+    pipe_closed: bool,
+    // Where stdout and stderr go with [Config::splice_output].
+    stdout_spool: Option<Spool>,
+    stderr_spool: Option<Spool>,
+    // Where the stages and metrics arrive with [Config::shm_reports].
+    ring: Option<ReportRing>,
+    // Set if the task is executed by a prefork worker.
+    worker: Option<WorkerState>,
+}
+
+/// A prefork worker process waiting for a task.
+struct IdleWorker {
+    pid: Pid,
+    // The suite the worker executes the tests of, see
+    // [ExecutionPolicy::ProcessPerSuite].
+    suite: Option<String>,
+    commands: pipe::Sender,
+    control: UnixStream,
+    stdout_pipe: pipe::Receiver,
+    stderr_pipe: pipe::Receiver,
+    report_pipe: pipe::Receiver,
+    // Output the worker produced after the previous task has been
+    // completed.
+    stdout_buf: Vec<u8>,
+    stderr_buf: Vec<u8>,
+    report_decoder: StreamDecoder<ReportMessage>,
+    control_decoder: StreamDecoder<ControlRequest>,
+}
+
+/// The state of a prefork worker executing a task.
+struct WorkerState {
+    commands: pipe::Sender,
+    suite: Option<String>,
+    // Set when the worker reports that the task returned.
+    task_done: bool,
+    // Set when the end of task marker arrives on stdout/stderr.
+    stdout_done: bool,
+    stderr_done: bool,
+    // Output that arrived after the marker, it belongs to the next
+    // task executed by the worker.
+    stdout_rest: Vec<u8>,
+    stderr_rest: Vec<u8>,
+}
+
+impl ObservedTask {
+    /// Accounts for the output of `stream` that has just been
+    /// captured, `start` being the length of its buffer before.
+    fn captured(&mut self, config: &Config, stream: config::Stream, start: usize) {
+        let (buf, dropped) = match stream {
+            config::Stream::Stdout => (&mut self.stdout_buf, &mut self.stdout_dropped),
+            config::Stream::Stderr => (&mut self.stderr_buf, &mut self.stderr_dropped),
+        };
+        if buf.len() <= start {
+            return;
+        }
+        let added = buf.len() - start;
+        if config.transcript || config.timestamps {
+            self.output_chunks.push(OutputChunk {
+                stream,
+                at: self.started_at.elapsed(),
+                end: buf.len(),
+            });
+        }
+        // The output is only displayed as it arrives with nocapture,
+        // so it can't be truncated.
+        match config.max_output_bytes {
+            // Truncating only once the buffer gets twice as large keeps
+            // the cost of dropping the middle amortized.
+            Some(limit) if !config.nocapture && buf.len() > 2 * limit => {
+                truncate_output(buf, limit, dropped, stream, &mut self.output_chunks)
+            }
+            _ => (),
+        }
+        self.count_output(config, stream, added);
+    }
+
+    /// Counts `bytes` more bytes of output, and kills the task if it
+    /// exceeds [Config::output_hard_cap].
+    fn count_output(&mut self, config: &Config, stream: config::Stream, bytes: usize) {
+        self.output_bytes += bytes;
+        if stream == config::Stream::Stderr {
+            self.stderr_bytes += bytes;
+        }
+        match config.output_hard_cap {
+            Some(cap) if self.output_bytes > cap && !self.output_exceeded => {
+                // The task might have died already.
+                let _ = killpg(self.pid, Signal::SIGKILL);
+                self.output_exceeded = true;
+            }
+            _ => (),
+        }
+    }
+
+    /// Asks the task to stop, see [TestContext::is_cancelled].
+    fn cancel(&mut self) {
+        if !self.cancelled {
+            self.cancelled = true;
+            // The task might have died already.
+            let _ = serialize_and_write(&mut self.control, &ControlMessage::Cancel);
+        }
+    }
+
+    /// Returns true if the status of the task is known and all its
+    /// output has been received.
+    fn is_complete(&self) -> bool {
+        if self.status_and_duration.is_none() {
+            return false;
+        }
+        match self.worker {
+            Some(ref w) if w.task_done => {
+                (w.stdout_done || self.stdout_pipe.is_none())
+                    && (w.stderr_done || self.stderr_pipe.is_none())
+            }
+            _ => {
+                self.stdout_pipe.is_none()
+                    && self.stderr_pipe.is_none()
+                    && self.report_pipe.is_none()
+            }
+        }
+    }
+}
+
+/// The source of the times the run and the tests start at, see
+/// [Config::clock].
+pub trait Clock {
+    /// The time the run starts at.
+    fn now(&self) -> SystemTime;
+
+    /// The time the task at `index` in the plan starts at, the
+    /// current time by default.
+    fn task_start(&self, _index: usize) -> SystemTime {
+        self.now()
+    }
+}
+
+/// The clock of the system, used by default.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock for reports that show the same times from one run to the
+/// next: the run starts at the Unix epoch and the task at `index` in
+/// the plan `index + 1` seconds later, whatever the order the tasks
+/// run in.  Only the start times are virtual, the durations are
+/// measured, see [Config::duration_resolution] to round them.
+#[derive(Default)]
+pub struct VirtualClock;
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        std::time::UNIX_EPOCH
+    }
+
+    fn task_start(&self, index: usize) -> SystemTime {
+        std::time::UNIX_EPOCH + Duration::from_secs(index as u64 + 1)
+    }
+}
+
+/// Reads the time the run starts at from the clock of `config`.
+pub(crate) fn now(config: &Config) -> SystemTime {
+    match config.clock {
+        Some(ref clock) => clock.now(),
+        None => SystemClock.now(),
+    }
+}
+
+/// Reads the time the task at `index` in the plan starts at from the
+/// clock of `config`.
+pub(crate) fn task_start(config: &Config, index: usize) -> SystemTime {
+    match config.clock {
+        Some(ref clock) => clock.task_start(index),
+        None => SystemClock.task_start(index),
+    }
 }
 
 /// A task that finished executing and is ready to be reported.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CompletedTask {
     pub full_name: Vec<String>,
     pub duration: Duration,
     pub stdout: Vec<u8>,
     pub stderr: Vec<u8>,
     pub status: Status,
+    /// File descriptors that were opened by the test and still open
+    /// when it returned.  Always empty if leak detection is disabled.
+    pub leaked_fds: Vec<OpenFd>,
+    /// Processes started by the test and still running when it was
+    /// done.  Always empty unless [crate::Config::reap_daemons].
+    pub leaked_processes: Vec<LeakedProcess>,
+    /// Coverage profiles written by the test process and its
+    /// subprocesses.  Always empty if coverage mode is disabled.
+    pub profiles: Vec<PathBuf>,
+    /// Location of the preserved core dump of a crashed test.
+    pub core_dump: Option<PathBuf>,
+    /// Backtrace extracted from the core dump.
+    pub backtrace: Option<String>,
+    /// Wall-clock time at which the test started.
+    pub start_time: SystemTime,
+    /// The number of the attempt to execute the test, starting at 1.
+    pub attempt: usize,
+    /// PID of the process that executed the test, None if the test
+    /// didn't run.
+    pub pid: Option<i32>,
+    /// The timeout the test ran with, None if the test didn't run.
+    pub timeout: Option<Duration>,
+    /// Tags of the test, see [crate::tag].
+    pub tags: Vec<String>,
+    pub metadata: Metadata,
+    /// The category assigned to a failed test by the classifier, see
+    /// [crate::Config::classifier].
+    pub category: Option<String>,
+    /// The values compared by the failed assertion, see
+    /// [TestContext::fail_with].
+    pub assertion: Option<AssertionFailure>,
+    /// Measurements reported by the test, see
+    /// [TestContext::report_metric].
+    pub metrics: Vec<Metric>,
+    /// The stages reported by the test, in order, see
+    /// [TestContext::report_stage_status].
+    pub stages: Vec<StageReport>,
+    /// Records logged by the test, only captured with
+    /// [crate::Config::log_level].
+    pub logs: Vec<LogRecord>,
+    /// The pieces of stdout and stderr in the order the driver read
+    /// them.  Only recorded with [crate::Config::transcript] or
+    /// [crate::Config::timestamps].
+    pub output_chunks: Vec<OutputChunk>,
+    /// The failed attempts that preceded this one, oldest first, see
+    /// [crate::Config::retries].
+    pub previous_attempts: Vec<CompletedTask>,
+}
+
+/// A piece of the output of a test read by the driver.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputChunk {
+    pub stream: config::Stream,
+    /// The time since the start of the test at which the chunk was
+    /// read.
+    pub at: Duration,
+    /// The offset of the end of the chunk in the captured output of
+    /// the stream.  The chunk starts where the previous chunk of the
+    /// same stream ends.
+    pub end: usize,
+}
+
+/// A named measurement reported by a test, e.g. a throughput.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Metric {
+    pub name: String,
+    pub value: f64,
+}
+
+/// A record logged by a test with the `log` crate.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub level: log::Level,
+    pub target: String,
+    pub message: String,
+}
+
+/// A failed comparison reported by a test, with the values formatted
+/// with `{:#?}`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AssertionFailure {
+    pub message: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Information about a test for the people looking at its results,
+/// see [crate::describe], [crate::owner] and [crate::link].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub description: Option<String>,
+    pub owner: Option<String>,
+    /// URLs of related documents, e.g. the issue tracking a failure.
+    pub links: Vec<String>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
 }
 
 impl CompletedTask {
+    /// A task that completed with `status` without running, so without
+    /// any output or duration.
+    pub(crate) fn new(full_name: Vec<String>, status: Status, start_time: SystemTime) -> Self {
+        CompletedTask {
+            full_name,
+            duration: Duration::default(),
+            stdout: vec![],
+            stderr: vec![],
+            status,
+            leaked_fds: vec![],
+            leaked_processes: Vec::new(),
+            profiles: vec![],
+            core_dump: None,
+            backtrace: None,
+            start_time,
+            attempt: 1,
+            pid: None,
+            timeout: None,
+            tags: Vec::new(),
+            metadata: Metadata::default(),
+            category: None,
+            assertion: None,
+            metrics: Vec::new(),
+            stages: Vec::new(),
+            logs: Vec::new(),
+            output_chunks: Vec::new(),
+            previous_attempts: Vec::new(),
+        }
+    }
+
     pub fn stdout_as_string(&self) -> std::borrow::Cow<'_, str> {
         String::from_utf8_lossy(&self.stdout)
     }
@@ -117,38 +608,177 @@ impl CompletedTask {
     }
 }
 
+/// Something that happened in a run, see [Report::on_event].
+#[derive(Clone, Copy, Debug)]
+pub enum ReportEvent<'a> {
+    /// The names of the tasks in the plan, before the suite starts.
+    Plan(&'a [String]),
+    SuiteStarted {
+        test_count: usize,
+    },
+    /// The description of the run, right after the suite started.
+    Header(&'a RunInfo),
+    TestStarted {
+        full_name: &'a [String],
+    },
+    TestFinished(&'a CompletedTask),
+    /// A failed attempt of a task that is going to be retried.
+    Retried(&'a CompletedTask),
+    Stage {
+        full_name: &'a [String],
+        stage: &'a StageReport,
+    },
+    /// A stage a running task reports as still in progress and how
+    /// long it has been running, see
+    /// [TestContext::report_stage_in_progress].  Sent again before
+    /// each [ReportEvent::Heartbeat] with the time the stage has been
+    /// running by then.
+    StageInProgress {
+        full_name: &'a [String],
+        stage: &'a str,
+        elapsed: Duration,
+    },
+    /// The running tasks and how long they have been running.
+    Heartbeat(&'a [(Vec<String>, Duration)]),
+    Tick,
+    RunFinished,
+    /// The differences from the baseline, after the run finished.
+    Compared(&'a Comparison),
+}
+
+/// Receives the events of a run.  The driver only calls
+/// [Report::on_event], which calls the method corresponding to the
+/// event by default, so reports can either override it and handle
+/// all events in one place or implement the methods they care about.
 pub trait Report {
-    fn init(&mut self, plan: &[Task]);
-    fn start(&mut self, task_name: String);
-    fn report(&mut self, result: &CompletedTask);
-    fn done(&mut self);
+    fn on_event(&mut self, event: &ReportEvent) {
+        match *event {
+            ReportEvent::Plan(names) => self.plan(names),
+            ReportEvent::SuiteStarted { test_count } => self.init(test_count),
+            ReportEvent::Header(info) => self.header(info),
+            ReportEvent::TestStarted { full_name } => self.start(full_name),
+            ReportEvent::TestFinished(task) => self.report(task),
+            ReportEvent::Retried(task) => self.retry(task),
+            ReportEvent::Stage { full_name, stage } => self.stage(full_name, stage.clone()),
+            ReportEvent::StageInProgress {
+                full_name,
+                stage,
+                elapsed,
+            } => self.stage_in_progress(full_name, stage, elapsed),
+            ReportEvent::Heartbeat(running) => self.heartbeat(running),
+            ReportEvent::Tick => self.tick(),
+            ReportEvent::RunFinished => self.done(),
+            ReportEvent::Compared(comparison) => self.compare(comparison),
+        }
+    }
+
+    /// Called before [Report::init] with the names of the tasks in
+    /// the plan, e.g. to lay out their results.
+    fn plan(&mut self, _names: &[String]) {}
+    fn init(&mut self, _test_count: usize) {}
+
+    /// Called after [Report::init] with the description of the run.
+    fn header(&mut self, _info: &RunInfo) {}
+    fn start(&mut self, _full_name: &[String]) {}
+    fn report(&mut self, _result: &CompletedTask) {}
+    fn done(&mut self) {}
+
+    /// Summarizes the differences from the baseline, called after
+    /// [Report::done].
+    fn compare(&mut self, _comparison: &Comparison) {}
+
+    /// Called regularly while tests run, e.g. to refresh a display of
+    /// the running tests.
+    fn tick(&mut self) {}
+
+    /// Called with a failed attempt of a task that is going to be
+    /// retried, see [crate::Config::retries].  The last attempt is
+    /// passed to [Report::report].
+    fn retry(&mut self, _attempt: &CompletedTask) {}
+
+    /// Called every [crate::Config::heartbeat] interval with the names
+    /// of the running tasks and how long they have been running,
+    /// longest running first.  Prints them to stderr by default.
+    fn heartbeat(&mut self, running: &[(Vec<String>, Duration)]) {
+        eprint!("{}", heartbeat_message(running, |_| None));
+    }
 
+    /// Called with the stages still in progress that the running
+    /// tasks report, see [TestContext::report_stage_in_progress],
+    /// with how long the stage has been running.
+    fn stage_in_progress(&mut self, _full_name: &[String], _stage: &str, _elapsed: Duration) {}
+
+    /// Reports a finished stage as a task of its own by default.
     fn stage(&mut self, full_name: &[String], stage_rep: StageReport) {
         let mut full_name: Vec<String> = Vec::from(full_name);
         full_name.push(stage_rep.stage_name);
         let completed_task = CompletedTask {
-            full_name,
             duration: stage_rep.duration,
-            stdout: Vec::new(),
-            stderr: Vec::new(),
-            status: Status::from(stage_rep.status),
+            stderr: stage_rep.message.unwrap_or_default().into_bytes(),
+            ..CompletedTask::new(
+                full_name,
+                Status::from(stage_rep.status),
+                SystemTime::now() - stage_rep.duration,
+            )
         };
         self.report(&completed_task);
     }
 }
 
+/// Formats the running tasks the way [Report::heartbeat] prints them,
+/// `annotate` returns what to add after the elapsed time of a task
+/// given its name, e.g. the stage it is in.
+pub(crate) fn heartbeat_message(
+    running: &[(Vec<String>, Duration)],
+    annotate: impl Fn(&str) -> Option<String>,
+) -> String {
+    let mut msg = format!("{} test(s) still running:\n", running.len());
+    for (full_name, elapsed) in running {
+        let name = full_name.join("::");
+        msg.push_str(&format!("    {} ({:.1}s)", name, elapsed.as_secs_f64()));
+        if let Some(annotation) = annotate(&name) {
+            msg.push_str(&format!(", {}", annotation));
+        }
+        msg.push('\n');
+    }
+    msg
+}
+
 pub struct TestContext {
     sender: pipe::Sender,
+    control: UnixStream,
+    control_decoder: StreamDecoder<ControlMessage>,
+    deadline: Instant,
+    cancelled: bool,
     // Since we define the stages to be linear, we just need to
     // keep one timestamp to report a stage's duration.
     started_at: Instant,
+    // The resources used when the current stage started.
+    usage_at: ResourceSnapshot,
+    full_name: Vec<String>,
+    snapshot_dir: PathBuf,
+    bless: bool,
+    artifacts_dir: Option<PathBuf>,
+    ring: Option<ReportRing>,
+    // The stages declared with [TestContext::plan_stages] that haven't
+    // been reported yet.
+    planned_stages: Vec<String>,
+    // Sent with the report of the current stage.
+    attachments: Vec<Attachment>,
 }
 
+/// The reason of the planned stages skipped after a stage failed, see
+/// [TestContext::plan_stages].
+const NOT_RUN: &str = "not run";
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum StageStatus {
     Success,
     Failure(i32),
     Skipped(String),
+    /// The stage exceeded the timeout it started with, see
+    /// [TestContext::begin_stage_with_timeout].
+    Timeout,
 }
 
 impl From<StageStatus> for Status {
@@ -157,60 +787,667 @@ impl From<StageStatus> for Status {
             StageStatus::Success => Status::Success,
             StageStatus::Failure(code) => Status::Failure(code),
             StageStatus::Skipped(reason) => Status::Skipped(reason),
+            StageStatus::Timeout => Status::Timeout,
         }
     }
 }
 
-#[derive(PartialEq, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct StageReport {
-    stage_name: String,
-    status: StageStatus,
-    duration: Duration,
+    pub stage_name: String,
+    pub status: StageStatus,
+    /// The time since the previous stage ended, or since the test
+    /// started for the first stage.  For a stage run with
+    /// [TestContext::stage], the time its body took.
+    pub duration: Duration,
+    /// Why the stage failed, e.g. the error returned by the body of
+    /// [TestContext::try_stage] or the message of a panic.
+    #[serde(default)]
+    pub message: Option<String>,
+    /// The diagnostics the test attached to the stage, see
+    /// [TestContext::attach_text].
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// The resources the test process used during the stage, unknown
+    /// for the stages the driver reports, e.g. on a timeout.
+    #[serde(default)]
+    pub usage: Option<StageUsage>,
+}
+
+/// A small payload attached to a stage.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Attachment {
+    /// A text, e.g. the logs of the stage, and its name.
+    Text { name: String, text: String },
+    /// A reference to a file, e.g. an artifact the stage wrote.
+    File(PathBuf),
+}
+
+/// The resources used by the test process during a stage, measured
+/// with getrusage(2) at the boundaries of the stage.
+#[derive(Clone, Copy, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct StageUsage {
+    /// The CPU time spent in user and system mode, including the time
+    /// of the children the test waited for.
+    pub cpu_time: Duration,
+    /// How much the peak resident set size grew, in kibibytes.  It is
+    /// 0 for a stage that used no more memory than the ones before.
+    pub max_rss_growth_kb: u64,
+}
+
+/// The resources used by the test process so far.
+#[derive(Clone, Copy)]
+struct ResourceSnapshot {
+    cpu_time: Duration,
+    max_rss_kb: u64,
+}
+
+impl ResourceSnapshot {
+    fn now() -> Self {
+        let (cpu_time, max_rss_kb) = rusage(nix::libc::RUSAGE_SELF);
+        let (children_cpu_time, _) = rusage(nix::libc::RUSAGE_CHILDREN);
+        Self {
+            cpu_time: cpu_time + children_cpu_time,
+            max_rss_kb,
+        }
+    }
+
+    fn usage_since(&self, start: &ResourceSnapshot) -> StageUsage {
+        StageUsage {
+            cpu_time: self.cpu_time.saturating_sub(start.cpu_time),
+            max_rss_growth_kb: self.max_rss_kb.saturating_sub(start.max_rss_kb),
+        }
+    }
+}
+
+/// Returns the resident set size of the process `pid` in bytes, None
+/// if it can't be read, e.g. off Linux.
+fn resident_bytes(pid: Pid) -> Option<u64> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    let statm = std::fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions.
+    let page_size = unsafe { nix::libc::sysconf(nix::libc::_SC_PAGESIZE) };
+    Some(pages * page_size as u64)
+}
+
+/// Returns the CPU time and the peak resident set size in kibibytes
+/// of `who`, zero if getrusage(2) fails.
+fn rusage(who: nix::libc::c_int) -> (Duration, u64) {
+    // SAFETY: rusage is a plain C struct for which zeroes are valid.
+    let mut usage: nix::libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: getrusage only writes to the struct it is given.
+    if unsafe { nix::libc::getrusage(who, &mut usage) } != 0 {
+        return (Duration::default(), 0);
+    }
+    let time = |tv: nix::libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    (
+        time(usage.ru_utime) + time(usage.ru_stime),
+        usage.ru_maxrss as u64,
+    )
 }
 
 impl TestContext {
     pub fn report_stage_status<N: ToString>(&mut self, stage_name: N, status: StageStatus) {
-        let stage_name = stage_name.to_string();
+        self.send_stage(stage_name.to_string(), status, None);
+    }
+
+    fn send_stage(&mut self, stage_name: String, status: StageStatus, message: Option<String>) {
         let end = Instant::now();
         let start = self.started_at;
+        let usage = ResourceSnapshot::now();
+        let usage_at = self.usage_at;
+        let attachments = std::mem::take(&mut self.attachments);
         self.started_at = end;
+        self.usage_at = usage;
+        if let Some(i) = self.planned_stages.iter().position(|s| *s == stage_name) {
+            self.planned_stages.remove(i);
+        }
 
         let payload = StageReport {
             stage_name,
             status,
             duration: end.duration_since(start),
+            message,
+            attachments,
+            usage: Some(usage.usage_since(&usage_at)),
+        };
+
+        self.send_buffered(ReportMessage::Stage(payload));
+    }
+
+    /// Reports that the stage `stage_name` is still running.  Tests
+    /// can call it periodically during long stages so that the
+    /// reports show where they are, and a test timing out is reported
+    /// as timing out in this stage.  The stage is reported with its
+    /// outcome once it's over, e.g. with
+    /// [TestContext::report_stage_status].
+    pub fn report_stage_in_progress(&mut self, stage_name: impl ToString) {
+        self.send_buffered(ReportMessage::StageInProgress {
+            stage_name: stage_name.to_string(),
+            elapsed: self.started_at.elapsed(),
+        });
+    }
+
+    /// Attaches `text` to the current stage under `name`, e.g. the
+    /// logs of a phase of the test.  The attachments are sent with
+    /// the next stage report, they are meant to be small.
+    pub fn attach_text(&mut self, name: impl ToString, text: impl ToString) {
+        self.attachments.push(Attachment::Text {
+            name: name.to_string(),
+            text: text.to_string(),
+        });
+    }
+
+    /// Attaches a reference to the file at `path` to the current
+    /// stage, see [TestContext::attach_text].
+    pub fn attach_file(&mut self, path: impl Into<PathBuf>) {
+        self.attachments.push(Attachment::File(path.into()));
+    }
+
+    /// Declares the stages the test runs, in order, so that the ones
+    /// left when a stage run with [TestContext::stage] or
+    /// [TestContext::try_stage] fails are reported as skipped rather
+    /// than missing from the reports.
+    pub fn plan_stages(&mut self, stage_names: &[&str]) {
+        self.planned_stages = stage_names.iter().map(|name| name.to_string()).collect();
+    }
+
+    /// Runs `body` as the stage `stage_name`, reported as successful
+    /// if it returns and as failed if it panics, with the time it
+    /// took.  The panic then goes on unwinding, failing the test.
+    #[track_caller]
+    pub fn stage<T>(&mut self, stage_name: impl ToString, body: impl FnOnce() -> T) -> T {
+        self.run_stage(stage_name.to_string(), || Ok(body()))
+    }
+
+    /// Runs `body` as the stage `stage_name` like [TestContext::stage],
+    /// but an error returned by `body` fails the stage too, with the
+    /// error as the message of the stage, and then fails the test.
+    #[track_caller]
+    pub fn try_stage<T, E: std::fmt::Display>(
+        &mut self,
+        stage_name: impl ToString,
+        body: impl FnOnce() -> Result<T, E>,
+    ) -> T {
+        self.run_stage(stage_name.to_string(), || {
+            body().map_err(|err| err.to_string())
+        })
+    }
+
+    #[track_caller]
+    fn run_stage<T>(&mut self, stage_name: String, body: impl FnOnce() -> Result<T, String>) -> T {
+        self.started_at = Instant::now();
+        self.usage_at = ResourceSnapshot::now();
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)) {
+            Ok(Ok(value)) => {
+                self.send_stage(stage_name, StageStatus::Success, None);
+                value
+            }
+            Ok(Err(message)) => {
+                let panic_message = format!("stage {} failed: {}", stage_name, message);
+                self.stage_failed(stage_name, Some(message));
+                panic!("{}", panic_message)
+            }
+            Err(payload) => {
+                let message = crate::try_get_panic_msg(&payload).map(str::to_string);
+                self.stage_failed(stage_name, message);
+                std::panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    /// Reports a failed stage and the planned stages after it, before
+    /// the test panics.
+    fn stage_failed(&mut self, stage_name: String, message: Option<String>) {
+        self.send_stage(stage_name, StageStatus::Failure(PANIC_EXIT_CODE), message);
+        for name in std::mem::take(&mut self.planned_stages) {
+            self.send_stage(name, StageStatus::Skipped(NOT_RUN.to_string()), None);
+        }
+    }
+
+    /// Starts a stage that must be reported with
+    /// [TestContext::report_stage_status] within `timeout`.  Otherwise
+    /// the driver times the test out as if it exceeded the timeout of
+    /// the test: it sends the [crate::Config::timeout_signal] before
+    /// killing it, the test fails with [Status::Timeout], and the
+    /// stage is reported with [StageStatus::Timeout].
+    pub fn begin_stage_with_timeout(&mut self, stage_name: impl ToString, timeout: Duration) {
+        self.send_buffered(ReportMessage::StageStarted {
+            stage_name: stage_name.to_string(),
+            timeout,
+        });
+    }
+
+    /// Sends a stage or a metric through the ring if there is one,
+    /// otherwise through the report pipe.
+    fn send_buffered(&mut self, message: ReportMessage) {
+        let sender = &mut self.sender;
+        let wake = || {
+            write_report(sender, &ReportMessage::Drain).expect("Couldn't send");
+        };
+        if !self
+            .ring
+            .as_mut()
+            .is_some_and(|ring| ring.push(&message, wake))
+        {
+            write_report(&mut self.sender, &message).expect("Couldn't send");
+        }
+    }
+
+    /// Stops the test and reports it as skipped with the given
+    /// reason, e.g. if it finds out that a prerequisite is missing.
+    /// The test process exits right away, without unwinding, so
+    /// destructors and teardown hooks don't run.
+    pub fn skip(&mut self, reason: impl ToString) -> ! {
+        write_report(
+            &mut self.sender,
+            &ReportMessage::Skipped(reason.to_string()),
+        )
+        .expect("Couldn't send");
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        std::process::exit(SKIP_EXIT_CODE)
+    }
+
+    /// Records a measurement, e.g. a throughput, in the result of the
+    /// test.
+    pub fn report_metric(&mut self, name: impl ToString, value: f64) {
+        let metric = Metric {
+            name: name.to_string(),
+            value,
         };
+        self.send_buffered(ReportMessage::Metric(metric));
+    }
+
+    /// Fails the test, sending the compared values to the driver so
+    /// that the reports can show them side by side instead of only
+    /// in the panic message.
+    #[track_caller]
+    pub fn fail_with(
+        &mut self,
+        message: impl ToString,
+        expected: impl std::fmt::Debug,
+        actual: impl std::fmt::Debug,
+    ) -> ! {
+        self.fail_with_failure(AssertionFailure {
+            message: message.to_string(),
+            expected: format!("{:#?}", expected),
+            actual: format!("{:#?}", actual),
+        })
+    }
+
+    #[track_caller]
+    fn fail_with_failure(&mut self, failure: AssertionFailure) -> ! {
+        write_report(
+            &mut self.sender,
+            &ReportMessage::AssertionFailed(failure.clone()),
+        )
+        .expect("Couldn't send");
+        panic!(
+            "{}\nexpected: {}\n  actual: {}",
+            failure.message, failure.expected, failure.actual
+        )
+    }
+
+    /// Fails the test with [TestContext::fail_with] if the values
+    /// differ.
+    #[track_caller]
+    pub fn assert_eq<T: PartialEq + std::fmt::Debug>(&mut self, expected: T, actual: T) {
+        if expected != actual {
+            self.fail_with("assertion failed: expected == actual", expected, actual)
+        }
+    }
+
+    /// Compares `content` with the snapshot file with the given name,
+    /// see [crate::Config::snapshot_dir], and fails the test with a
+    /// diff if they differ.  In the bless mode (see
+    /// [crate::Config::bless]) the snapshot is written instead.
+    #[track_caller]
+    pub fn assert_snapshot(&mut self, name: &str, content: impl AsRef<str>) {
+        let content = content.as_ref();
+        let path = self.snapshot_dir.join(format!(
+            "{}__{}.snap",
+            file_name_safe(&self.full_name.join("::")),
+            file_name_safe(name)
+        ));
+        if self.bless {
+            std::fs::create_dir_all(&self.snapshot_dir)
+                .and_then(|()| std::fs::write(&path, content))
+                .unwrap_or_else(|e| panic!("failed to write {}: {}", path.display(), e));
+            return;
+        }
+        match std::fs::read_to_string(&path) {
+            Ok(expected) if expected == content => (),
+            Ok(expected) => self.fail_with_failure(AssertionFailure {
+                message: format!("snapshot {} doesn't match", path.display()),
+                expected,
+                actual: content.to_string(),
+            }),
+            Err(e) => panic!(
+                "failed to read snapshot {}: {} (run with --bless to create it)",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    /// The directory shared by the steps of the enclosing pipeline
+    /// suite, see [crate::pipeline_suite], where a step finds the
+    /// files written by the previous ones.  It's removed once the
+    /// run is done, unless a step of the pipeline failed, and then by
+    /// the next run with pipelines.
+    ///
+    /// Panics if the test isn't part of a pipeline suite.
+    pub fn artifacts_dir(&self) -> &Path {
+        self.artifacts_dir
+            .as_deref()
+            .expect("the test is not part of a pipeline suite")
+    }
+
+    /// Reads a reply from the control socket.  If `wait` is false,
+    /// returns None instead of blocking.  Cancellation requests are
+    /// recorded rather than returned.
+    fn receive_control(&mut self, wait: bool) -> Option<ControlMessage> {
+        let mut buf = [0u8; 256];
+        loop {
+            match self.control_decoder.try_decode() {
+                Some(ControlMessage::Cancel) => {
+                    self.cancelled = true;
+                    continue;
+                }
+                Some(msg) => return Some(msg),
+                None => (),
+            }
+            let mut fds = [PollFd::new(self.control.as_raw_fd(), PollFlags::POLLIN)];
+            match nix::poll::poll(&mut fds, if wait { -1 } else { 0 }) {
+                Ok(0) => return None,
+                Ok(_) | Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => (),
+                Err(e) => panic!("failed to poll the control socket: {}", e),
+            }
+            match self.control.read(&mut buf) {
+                Ok(0) if wait => panic!("the driver closed the control socket"),
+                Ok(0) => return None,
+                Ok(n) => self.control_decoder.append(&buf[0..n]),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => panic!("failed to read the control socket: {}", e),
+            }
+        }
+    }
+
+    fn lease(&mut self, req: LeaseRequest) -> Lease {
+        serialize_and_write(&mut self.control, &ControlRequest::Lease(req)).expect("Couldn't send");
+        match self.receive_control(true) {
+            Some(ControlMessage::Leased(Ok(lease))) => lease,
+            Some(ControlMessage::Leased(Err(e))) => panic!("failed to lease: {}", e),
+            other => panic!("unexpected control message: {:?}", other),
+        }
+    }
+
+    /// Returns the time left until the test is killed for exceeding
+    /// the timeout.
+    pub fn remaining_time(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Asks the driver to wait `extension` longer before killing the
+    /// test for exceeding the timeout, e.g. when it takes a rare slow
+    /// path.  The driver grants at most what is left of the budget of
+    /// the test, see [crate::Config::timeout_extension].  Returns the
+    /// granted extension.  A test asked to stop as its timeout
+    /// approached stays cancelled, so tests should ask as soon as they
+    /// know they need more time.
+    pub fn extend_timeout(&mut self, extension: Duration) -> Duration {
+        serialize_and_write(&mut self.control, &ControlRequest::ExtendTimeout(extension))
+            .expect("Couldn't send");
+        match self.receive_control(true) {
+            Some(ControlMessage::TimeoutExtended(granted)) => {
+                self.deadline += granted;
+                granted
+            }
+            other => panic!("unexpected control message: {:?}", other),
+        }
+    }
+
+    /// Returns true if the driver asked the test to stop, either
+    /// because the timeout is about to expire or because the run was
+    /// interrupted.  Long-running tests can check this to wind down
+    /// cleanly before they are killed.
+    pub fn is_cancelled(&mut self) -> bool {
+        while self.receive_control(false).is_some() {}
+        self.cancelled
+    }
+
+    /// Leases a TCP port that no other test of this run holds and
+    /// nobody listened on at the moment of the lease.  The lease is
+    /// held until the test process exits.
+    pub fn lease_port(&mut self) -> u16 {
+        match self.lease(LeaseRequest::Port) {
+            Lease::Port(port) => port,
+            other => panic!("unexpected lease: {:?}", other),
+        }
+    }
+
+    /// Leases a resource from the pool with the given name (see
+    /// [crate::Config::resource_pool]), blocking until one is
+    /// available.  The lease is held until the test process exits.
+    pub fn lease_resource(&mut self, pool: &str) -> String {
+        match self.lease(LeaseRequest::Resource(pool.to_string())) {
+            Lease::Resource { name, .. } => name,
+            other => panic!("unexpected lease: {:?}", other),
+        }
+    }
+}
+
+/// A file descriptor open in a test process.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OpenFd {
+    pub fd: i32,
+    /// The file the descriptor points to, as reported by
+    /// `/proc/self/fd`, e.g. `/tmp/foo` or `socket:[1234]`.
+    pub target: String,
+}
+
+impl std::fmt::Display for OpenFd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.fd, self.target)
+    }
+}
+
+/// A process a test left running, see [crate::Config::reap_daemons].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LeakedProcess {
+    pub pid: i32,
+    /// The command line of the process.
+    pub command: String,
+}
 
-        serialize_and_write(&mut self.sender, &payload).expect("Couldn't send");
+impl std::fmt::Display for LeakedProcess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.pid, self.command)
     }
 }
 
-fn serialize_and_write<W: Write, A: Serialize>(w: &mut W, payload: &A) -> io::Result<usize> {
+/// Lists file descriptors open in the current process.  Returns an
+/// empty list on systems without `/proc`.
+fn open_fds() -> Vec<OpenFd> {
+    let names: Vec<_> = match std::fs::read_dir("/proc/self/fd") {
+        Ok(dir) => dir.filter_map(|e| e.ok()).map(|e| e.file_name()).collect(),
+        Err(_) => return Vec::new(),
+    };
+    // The descriptor used to read the directory is closed at this
+    // point, so reading its link fails and it's not included.
+    let mut fds: Vec<OpenFd> = names
+        .into_iter()
+        .filter_map(|name| {
+            let fd = name.to_str()?.parse().ok()?;
+            let target = std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()?;
+            Some(OpenFd {
+                fd,
+                target: target.to_string_lossy().into_owned(),
+            })
+        })
+        .collect();
+    fds.sort_by_key(|fd| fd.fd);
+    fds
+}
+
+/// Messages sent by a test process to the driver over the report pipe.
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+pub(crate) enum ReportMessage {
+    Stage(StageReport),
+    /// Sent when a stage with a timeout starts, the next stage report
+    /// ends it.
+    StageStarted {
+        stage_name: String,
+        timeout: Duration,
+    },
+    /// See [TestContext::report_stage_in_progress].
+    StageInProgress {
+        stage_name: String,
+        elapsed: Duration,
+    },
+    FdsAtStart(Vec<OpenFd>),
+    FdsAtExit(Vec<OpenFd>),
+    /// Sent by a prefork worker when a task returns, carries the exit
+    /// code the task would have in a process of its own.
+    TaskDone(i32),
+    /// Sent right before the test process exits with
+    /// [SKIP_EXIT_CODE], carries the skip reason.
+    Skipped(String),
+    /// Sent right before the test panics because of a failed
+    /// assertion.
+    AssertionFailed(AssertionFailure),
+    /// Sent by [TestContext::report_metric].
+    Metric(Metric),
+    /// Sent for every record logged by the test, see
+    /// [Config::log_level].
+    Log(LogRecord),
+    /// Tells the driver to read the messages in the ring of the test,
+    /// see [Config::shm_reports].
+    Drain,
+}
+
+/// Messages sent by the driver to a test process over the control
+/// socket.
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+pub(crate) enum ControlMessage {
+    Leased(Result<Lease, String>),
+    /// Asks the test to stop, see [TestContext::is_cancelled].
+    Cancel,
+    /// The extension of the timeout the driver granted, see
+    /// [TestContext::extend_timeout].
+    TimeoutExtended(Duration),
+}
+
+/// Requests sent by a test process to the driver over the control
+/// socket.
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+pub(crate) enum ControlRequest {
+    /// Asks for a lease, the reply is a [ControlMessage::Leased].
+    Lease(LeaseRequest),
+    /// Asks for more time, the reply is a
+    /// [ControlMessage::TimeoutExtended].
+    ExtendTimeout(Duration),
+}
+
+/// The size of the largest message sent over the pipes between the
+/// driver and the test processes, e.g. a stage report.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// The size the driver asks for the report pipes, so that a test
+/// sending many reports rarely waits for the driver to read them.
+#[cfg(target_os = "linux")]
+const REPORT_PIPE_SIZE: i32 = 1 << 20;
+
+/// Writes `payload` preceded by its size.  The message is written at
+/// once, so that the messages of several threads don't interleave.
+/// Messages larger than [MAX_MESSAGE_SIZE] are refused.
+pub(crate) fn serialize_and_write<W: Write, A: Serialize>(
+    w: &mut W,
+    payload: &A,
+) -> io::Result<usize> {
     let payload = bincode::serialize(payload).unwrap();
-    let n = w.write(&(payload.len() as usize).to_be_bytes())?;
-    let m = w.write(&payload)?;
-    Ok(n + m)
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "a message of {} bytes exceeds the maximum of {} bytes",
+                payload.len(),
+                MAX_MESSAGE_SIZE
+            ),
+        ));
+    }
+    let mut message = Vec::with_capacity(size_of::<usize>() + payload.len());
+    message.extend_from_slice(&payload.len().to_be_bytes());
+    message.extend_from_slice(&payload);
+    w.write_all(&message)?;
+    Ok(message.len())
 }
 
-struct StreamDecoder {
+/// Enlarges the buffer of a report pipe to [REPORT_PIPE_SIZE].  The
+/// pipe keeps its size if the system doesn't allow it.
+#[cfg(target_os = "linux")]
+pub(crate) fn enlarge_report_pipe(pipe: &pipe::Receiver) {
+    let _ = fcntl::fcntl(
+        pipe.as_raw_fd(),
+        fcntl::FcntlArg::F_SETPIPE_SZ(REPORT_PIPE_SIZE),
+    );
+}
+
+/// Only Linux can resize pipes, they keep their size elsewhere.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn enlarge_report_pipe(_pipe: &pipe::Receiver) {}
+
+pub(crate) struct StreamDecoder<T> {
     buf: Vec<u8>,
     offset: usize,
+    // Set once the stream turned out not to be a sequence of messages,
+    // the rest of it is ignored.
+    corrupt: bool,
+    _message: PhantomData<T>,
 }
 
-impl StreamDecoder {
-    fn new() -> Self {
+impl<T: DeserializeOwned> StreamDecoder<T> {
+    pub(crate) fn new() -> Self {
         StreamDecoder {
             buf: Vec::new(),
             offset: 0,
+            corrupt: false,
+            _message: PhantomData,
         }
     }
 
-    fn append(&mut self, data: &[u8]) {
+    pub(crate) fn append(&mut self, data: &[u8]) {
+        if self.corrupt {
+            return;
+        }
+        // Only the incomplete message is kept.
+        self.buf.drain(..self.offset);
+        self.offset = 0;
         self.buf.extend_from_slice(data);
     }
 
+    /// Gives up on the stream, e.g. if the test process wrote to the
+    /// report pipe directly.
+    fn give_up(&mut self, reason: &str) {
+        eprintln!(
+            "Failed to decode a message: {}; ignoring the rest of the stream",
+            reason
+        );
+        self.corrupt = true;
+        self.buf = Vec::new();
+        self.offset = 0;
+    }
+
     // Decode a message if there is enough data in the buffer.
-    fn try_decode(&mut self) -> Option<StageReport> {
+    pub(crate) fn try_decode(&mut self) -> Option<T> {
         let avail = self.buf.len() - self.offset;
 
         if avail < size_of::<usize>() {
@@ -219,6 +1456,10 @@ impl StreamDecoder {
 
         let payload_size = &self.buf[self.offset..self.offset + size_of::<usize>()];
         let payload_size = usize::from_be_bytes(payload_size.try_into().unwrap());
+        if payload_size > MAX_MESSAGE_SIZE {
+            self.give_up(&format!("the size {} is too large", payload_size));
+            return None;
+        }
 
         if avail < size_of::<usize>() + payload_size {
             return None;
@@ -226,43 +1467,66 @@ impl StreamDecoder {
 
         let payload_offset = self.offset + size_of::<usize>();
         let payload = &self.buf[payload_offset..payload_offset + payload_size];
-        let res: StageReport =
-            bincode::deserialize(&payload).expect("failed to deserialize a bincode message");
-        // Update the offset
-        self.offset = payload_offset + payload_size;
-        Some(res)
+        match bincode::deserialize(payload) {
+            Ok(res) => {
+                // Update the offset
+                self.offset = payload_offset + payload_size;
+                Some(res)
+            }
+            Err(err) => {
+                self.give_up(&err.to_string());
+                None
+            }
+        }
     }
 }
 
-pub fn make_plan(config: &Config, t: TestTree) -> Vec<Task> {
-    fn matches(name: &str, filter: &Option<String>) -> bool {
-        filter.as_ref().map(|f| name.contains(f)).unwrap_or(true)
-    }
+/// Why no plan could be made of a test tree, see [make_plan].
+#[derive(Debug, PartialEq)]
+pub enum PlanError {
+    /// Tests marked with [crate::only] although [Config::forbid_only]
+    /// is set, with their names.
+    OnlyForbidden(Vec<String>),
+}
 
-    fn go(
-        filter: &Option<String>,
+impl std::fmt::Display for PlanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlanError::OnlyForbidden(names) => write!(
+                f,
+                "some tests are marked with only(), which is forbidden: {}",
+                names.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PlanError {}
+
+pub fn make_plan(config: &Config, t: TestTree) -> Result<Vec<Task>, PlanError> {
+    fn go(
+        filter: &Filter,
         config: &Config,
         t: TestTree,
         mut path: Vec<String>,
         buf: &mut Vec<Task>,
         parent_opts: Options,
     ) {
-        let skip_filter_applies = config.skip_filters.iter().any(|f| t.name().contains(f));
-
         match t {
             TestTree(TreeNode::Leaf {
                 name,
                 assertion,
                 options,
             }) => {
-                if !matches(&name, filter) || skip_filter_applies {
+                path.push(name);
+                let options = options.inherit(parent_opts);
+                if !filter.matches(&path, &options.tags) {
                     return;
                 }
-                path.push(name);
                 buf.push(Task {
                     work: assertion,
                     full_name: path,
-                    options: options.inherit(parent_opts),
+                    options,
                 })
             }
             TestTree(TreeNode::Fork {
@@ -270,86 +1534,426 @@ pub fn make_plan(config: &Config, t: TestTree) -> Vec<Task> {
                 tests,
                 options,
             }) => {
-                let effective_opts = options.inherit(parent_opts);
-                if matches(&name, filter) && !skip_filter_applies {
-                    path.push(name);
-                    for t in tests {
-                        go(&None, config, t, path.clone(), buf, effective_opts.clone());
-                    }
-                } else if !skip_filter_applies {
-                    path.push(name);
-                    for t in tests {
-                        go(filter, config, t, path.clone(), buf, effective_opts.clone());
-                    }
+                let mut effective_opts = options.inherit(parent_opts);
+                if effective_opts.pipeline {
+                    let mut pipeline = path.clone();
+                    pipeline.push(name.clone());
+                    effective_opts.artifacts_dir = Some(artifacts_dir(config, &pipeline));
+                }
+                path.push(name);
+                if filter.skips_suite(&path) {
+                    return;
+                }
+                for t in tests {
+                    go(filter, config, t, path.clone(), buf, effective_opts.clone());
                 }
             }
         }
     }
 
     let mut plan = Vec::new();
+    let filter = config.test_filter();
     go(
-        &config.filter,
-        &config,
+        &filter,
+        config,
         t,
         Vec::new(),
         &mut plan,
         Options::default(),
     );
-    plan
+
+    if plan.iter().any(|task| task.options.focused) {
+        if config.forbid_only {
+            return Err(PlanError::OnlyForbidden(
+                plan.iter()
+                    .filter(|task| task.options.focused)
+                    .map(Task::name)
+                    .collect(),
+            ));
+        }
+        let total = plan.len();
+        plan.retain(|task| task.options.focused);
+        eprintln!(
+            "warning: only() is in effect, executing {} of {} tests",
+            plan.len(),
+            total
+        );
+    }
+    // Only the tests marked with skip() count as ignored, the skip
+    // conditions still hold when they are run.
+    match config.run_ignored.unwrap_or_default() {
+        RunIgnored::No => (),
+        RunIgnored::Yes => plan
+            .iter_mut()
+            .for_each(|task| task.options.skip_reason = None),
+        RunIgnored::Only => {
+            plan.retain(|task| task.options.skip_reason.is_some());
+            plan.iter_mut()
+                .for_each(|task| task.options.skip_reason = None);
+        }
+    }
+    if let Some((index, total)) = config.shard {
+        plan = plan.into_iter().skip(index).step_by(total).collect();
+    }
+    // Tests left out of the plan by the filters, only() or sharding
+    // don't evaluate their skip conditions.
+    for task in plan.iter_mut() {
+        task.options.check_skip_conditions();
+    }
+    Ok(plan)
+}
+
+/// Returns the prefix of names of files (coverage profiles, core
+/// dumps) produced by the test process with the given name and PID.
+/// All characters that might be problematic in file names are
+/// replaced with underscores, so the dash reliably separates the
+/// components.
+pub(crate) fn file_stem(full_name: &[String], pid: Pid) -> String {
+    format!("{}-{}", file_name_safe(&full_name.join("::")), pid)
+}
+
+/// The prefix of the directories holding the artifacts of the
+/// pipelines of a run, followed by the PID of the driver.
+const ARTIFACTS_PREFIX: &str = "raclette-artifacts-";
+
+/// The directory shared by the steps of a pipeline suite, see
+/// [crate::pipeline_suite], in the directory of [Config::tmp_dir] if
+/// set.  The directories of a run are in a directory of their own.
+fn artifacts_dir(config: &Config, pipeline: &[String]) -> PathBuf {
+    config
+        .tmp_dir
+        .clone()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("{}{}", ARTIFACTS_PREFIX, std::process::id()))
+        .join(file_name_safe(&pipeline.join("::")))
+}
+
+/// Removes the artifacts that the runs of drivers which are gone left
+/// in `dir`, i.e. those of their failed pipelines.
+fn remove_stale_artifacts(dir: &Path) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let pid = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(ARTIFACTS_PREFIX))
+            .and_then(|pid| pid.parse().ok());
+        let gone = |pid| {
+            nix::sys::signal::kill(Pid::from_raw(pid), None)
+                == Err(nix::Error::Sys(nix::errno::Errno::ESRCH))
+        };
+        if pid.is_some_and(gone) {
+            let _ = std::fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+/// Points TMPDIR to a new directory in `dir` for the test process
+/// with the given name and PID, see [Config::tmp_dir].
+pub(crate) fn use_tmp_dir(dir: &Path, full_name: &[String], pid: Pid) {
+    let tmp = dir.join(file_stem(full_name, pid));
+    match std::fs::create_dir_all(&tmp) {
+        Ok(()) => std::env::set_var("TMPDIR", &tmp),
+        Err(err) => eprintln!(
+            "Failed to create the temporary directory {}: {}",
+            tmp.display(),
+            err
+        ),
+    }
+}
+
+fn file_name_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// The `snapshots` directory of the package being tested if run by
+/// cargo, of the working directory otherwise.
+fn default_snapshot_dir() -> PathBuf {
+    std::env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join("snapshots")
+}
+
+/// Makes the LLVM profiler runtime of the current process write its
+/// profile to a file matching `pattern` and forget the counters
+/// accumulated by the driver before the fork.
+#[cfg(feature = "coverage")]
+fn redirect_coverage_profile(pattern: &Path) {
+    use std::os::unix::ffi::OsStrExt;
+
+    extern "C" {
+        fn __llvm_profile_reset_counters();
+        fn __llvm_profile_set_filename(name: *const std::os::raw::c_char);
+    }
+
+    let pattern = std::ffi::CString::new(pattern.as_os_str().as_bytes())
+        .expect("child: coverage profile path contains a NUL byte");
+    // SAFETY: both functions are provided by the profiler runtime of
+    // instrumented builds, and the runtime copies the file name.
+    unsafe {
+        __llvm_profile_reset_counters();
+        __llvm_profile_set_filename(pattern.as_ptr());
+    }
+}
+
+#[cfg(not(feature = "coverage"))]
+fn redirect_coverage_profile(_pattern: &Path) {}
+
+/// Lists coverage profiles in `dir` produced by the test process
+/// with the given stem and its subprocesses.
+fn collect_profiles(dir: &Path, stem: &str) -> Vec<PathBuf> {
+    let prefix = format!("{}-", stem);
+    let mut profiles: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .map(|n| n.starts_with(&prefix) && n.ends_with(".profraw"))
+                    .unwrap_or(false)
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    profiles.sort();
+    profiles
+}
+
+/// Redirects stdout and stderr of the current process to the given
+/// pipes.  The pipes are switched to blocking mode so that tests that
+/// print a lot don't fail on a full pipe.
+fn redirect_output(stdout_sender: &pipe::Sender, stderr_sender: &pipe::Sender) {
+    stdout_sender.set_nonblocking(false).unwrap();
+    stderr_sender.set_nonblocking(false).unwrap();
+
+    let stdout_fd = std::io::stdout().as_raw_fd();
+    let stderr_fd = std::io::stderr().as_raw_fd();
+
+    unistd::close(stdout_fd).expect("child: failed to close stdout");
+    unistd::dup2(stdout_sender.as_raw_fd(), stdout_fd).unwrap();
+
+    unistd::close(stderr_fd).expect("child: failed to close stderr");
+    unistd::dup2(stderr_sender.as_raw_fd(), stderr_fd).unwrap();
+}
+
+/// Serializes the writes of reports by the threads of a test process,
+/// so that messages too large to be written to a pipe at once don't
+/// interleave.
+static REPORT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Writes a report to the report pipe of the test process, see
+/// [serialize_and_write].
+pub(crate) fn write_report<W: Write>(w: &mut W, msg: &ReportMessage) -> io::Result<usize> {
+    let _guard = REPORT_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    serialize_and_write(w, msg)
+}
+
+/// Writes a report to `report_fd`, the descriptor of the report pipe
+/// of the test process, see [write_report].
+pub(crate) fn send_report(report_fd: RawFd, msg: &ReportMessage) -> io::Result<usize> {
+    // SAFETY: the descriptor is open, and ManuallyDrop prevents the
+    // file from closing it.
+    let mut w = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(report_fd) });
+    write_report(&mut *w, msg)
+}
+
+/// Runs the body of a test in the current process.  `report_fd` and
+/// `control_fd` are descriptors of the report pipe and the control
+/// socket that stay open after the test.  The stages and metrics go
+/// to `ring`, if any, see [Config::shm_reports].
+pub(crate) fn run_work(
+    config: &Config,
+    task: Task,
+    report_fd: RawFd,
+    control_fd: RawFd,
+    ring: Option<ReportRing>,
+) {
+    let track_fds = config.fd_leaks.unwrap_or_default() != LeakPolicy::Ignore;
+    if track_fds {
+        send_report(report_fd, &ReportMessage::FdsAtStart(open_fds()))
+            .expect("child: failed to send a report");
+    }
+    if let Some(level) = config.log_level {
+        logger::capture(report_fd, level);
+    }
+    scheduling::apply_in_child(config, task.options.io_priority.or(config.io_priority));
+    if config.reap_daemons {
+        zygote::become_subreaper();
+    }
+
+    if let Some(dir) = &task.options.artifacts_dir {
+        std::fs::create_dir_all(dir).unwrap_or_else(|err| {
+            panic!(
+                "failed to create the artifacts directory {}: {}",
+                dir.display(),
+                err
+            )
+        });
+    }
+
+    // The test consumes the context and closes its ends of the pipes,
+    // so the context gets its own copies of the descriptors.  They are
+    // closed on exec, so that the processes the test starts don't keep
+    // the pipes open after the test exits.
+    let dup = |fd| fcntl::fcntl(fd, fcntl::FcntlArg::F_DUPFD_CLOEXEC(0));
+    let fd = dup(report_fd).expect("child: failed to duplicate the report pipe");
+    // SAFETY: the descriptor has just been created and is not owned
+    // by anything else.
+    let sender = unsafe { pipe::Sender::from_raw_fd(fd) };
+    let fd = dup(control_fd).expect("child: failed to duplicate the control socket");
+    // SAFETY: same as above.
+    let control = unsafe { UnixStream::from_raw_fd(fd) };
+    let stage_reporter = TestContext {
+        sender,
+        control,
+        control_decoder: StreamDecoder::new(),
+        deadline: Instant::now() + config.timeout.unwrap_or(DEFAULT_TIMEOUT),
+        cancelled: false,
+        started_at: Instant::now(),
+        usage_at: ResourceSnapshot::now(),
+        full_name: task.full_name,
+        snapshot_dir: config
+            .snapshot_dir
+            .clone()
+            .unwrap_or_else(default_snapshot_dir),
+        bless: config.bless,
+        artifacts_dir: task.options.artifacts_dir,
+        ring,
+        planned_stages: Vec::new(),
+        attachments: Vec::new(),
+    };
+    (task.work)(stage_reporter);
+
+    if track_fds {
+        send_report(report_fd, &ReportMessage::FdsAtExit(open_fds()))
+            .expect("child: failed to send a report");
+    }
+}
+
+/// Executes a task in a freshly forked test process whose ends of the
+/// pipes are `stdout_sender`, `stderr_sender` and `report_sender`,
+/// and whose end of the control socket is `control`.
+pub(crate) fn run_in_child(
+    config: &Config,
+    task: Task,
+    stdout_sender: &pipe::Sender,
+    stderr_sender: &pipe::Sender,
+    report_sender: &pipe::Sender,
+    control: &UnixStream,
+    ring: Option<ReportRing>,
+) -> ! {
+    let self_pid = unistd::getpid();
+    unistd::setpgid(self_pid, self_pid).expect("child: failed to set PGID");
+
+    if config.core_dir.is_some() {
+        coredump::enable_in_child();
+    }
+
+    if let Some(ref dir) = config.coverage_dir {
+        // %p is expanded by the profiler runtime, so every
+        // process spawned by the test gets its own file.
+        let pattern = dir.join(format!(
+            "{}-%p.profraw",
+            file_stem(&task.full_name, self_pid)
+        ));
+        std::env::set_var("LLVM_PROFILE_FILE", &pattern);
+        redirect_coverage_profile(&pattern);
+    }
+
+    redirect_output(stdout_sender, stderr_sender);
+    // Reports wait for the driver to read the previous ones rather
+    // than fail on a full pipe.
+    report_sender.set_nonblocking(false).unwrap();
+    if let Some(ref dir) = config.tmp_dir {
+        use_tmp_dir(dir, &task.full_name, self_pid);
+    }
+    // A panic must not unwind into the frames of the driver the
+    // process was forked from, which could outlive it if the driver
+    // doesn't run on the main thread.
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        run_work(
+            config,
+            task,
+            report_sender.as_raw_fd(),
+            control.as_raw_fd(),
+            ring,
+        )
+    }));
+    std::process::exit(if result.is_ok() { 0 } else { PANIC_EXIT_CODE })
+}
+
+/// Moves a freshly forked child to a process group of its own, so
+/// that we can kill all the processes spawned by a test if it times
+/// out.
+pub(crate) fn set_child_pgid(child: Pid) {
+    match unistd::setpgid(child, child) {
+        // It might happen that the child process completes before
+        // parent calls setpgid.  In this case the call will fail with
+        // ESRCH errno, which can be safely ignored.
+        Err(nix::Error::Sys(nix::errno::Errno::ESRCH)) => (),
+        Err(e) => panic!("failed to set PGID of the child: {}", e),
+        Ok(()) => (),
+    }
 }
 
-fn launch(task: Task) -> RunningTask {
+/// Forks a process executing the task.  The task stays in the plan of
+/// the driver, so that it can be executed again if it's retried.
+fn launch(config: &Config, task: &mut Option<Task>) -> RunningTask {
     let (stdout_sender, stdout_receiver) = pipe::new().unwrap();
     let (stderr_sender, stderr_receiver) = pipe::new().unwrap();
     let (report_sender, report_receiver) = pipe::new().unwrap();
+    let (control, child_control) = UnixStream::pair().expect("failed to create a socket pair");
 
     stdout_receiver.set_nonblocking(true).unwrap();
     stderr_receiver.set_nonblocking(true).unwrap();
     report_receiver.set_nonblocking(true).unwrap();
+    control.set_nonblocking(true).unwrap();
+    enlarge_report_pipe(&report_receiver);
+    // Without a ring, the messages go through the report pipe.
+    let ring = if config.shm_reports {
+        ReportRing::new(RING_SIZE).ok()
+    } else {
+        None
+    };
 
-    let full_name = task.full_name;
+    let full_name = task
+        .as_ref()
+        .expect("task executed twice")
+        .full_name
+        .clone();
 
     io::stdout().lock().flush().unwrap();
     io::stderr().lock().flush().unwrap();
 
     let pid = match fork().expect("failed to fork") {
         ForkResult::Child => {
-            let self_pid = unistd::getpid();
-            unistd::setpgid(self_pid, self_pid).expect("child: failed to set PGID");
-
             std::mem::drop(stdout_receiver);
             std::mem::drop(stderr_receiver);
             std::mem::drop(report_receiver);
+            std::mem::drop(control);
 
-            let stdout_fd = std::io::stdout().as_raw_fd();
-            let stderr_fd = std::io::stderr().as_raw_fd();
-
-            unistd::close(stdout_fd).expect("child: failed to close stdout");
-            unistd::dup2(stdout_sender.as_raw_fd(), stdout_fd).unwrap();
-
-            unistd::close(stderr_fd).expect("child: failed to close stderr");
-            unistd::dup2(stderr_sender.as_raw_fd(), stderr_fd).unwrap();
-
-            let stage_reporter = TestContext {
-                sender: report_sender,
-                started_at: Instant::now(),
-            };
-            (task.work)(stage_reporter);
-            std::process::exit(0)
+            if let Some(init) = config.child_init {
+                init();
+            }
+            run_in_child(
+                config,
+                task.take().unwrap(),
+                &stdout_sender,
+                &stderr_sender,
+                &report_sender,
+                &child_control,
+                ring,
+            )
         }
         ForkResult::Parent { child, .. } => {
-            // We create a new process group for the child to be able
-            // to kill all the processes spawned by the test if the
-            // test times out.
-            match unistd::setpgid(child, child) {
-                // It might happen that the child process completes
-                // before parent calls setpgid.  In this case the call
-                // will fail with ESRCH errno, which can be safely
-                // ignored.
-                Err(nix::Error::Sys(nix::errno::Errno::ESRCH)) => (),
-                Err(e) => panic!("failed to set PGID of the child: {}", e),
-                Ok(()) => (),
-            }
+            set_child_pgid(child);
             child
         }
     };
@@ -363,66 +1967,362 @@ fn launch(task: Task) -> RunningTask {
         report_pipe: report_receiver,
         stdout_buf: Vec::new(),
         stderr_buf: Vec::new(),
+        control,
+        ring,
     }
 }
 
-fn make_token(pid: Pid, source: InputSource) -> Token {
-    match source {
-        InputSource::Stdout => Token((pid.as_raw() as usize) << 2),
-        InputSource::Stderr => Token((pid.as_raw() as usize) << 2 | 1),
-        InputSource::Report => Token((pid.as_raw() as usize) << 2 | 2),
-    }
-}
+/// Forks the zygote process.  The zygote inherits the whole plan.
+fn launch_zygote(config: &Config, tasks: &mut [Option<Task>]) -> Zygote {
+    let (control, zygote_control) = UnixStream::pair().expect("failed to create a socket pair");
 
-fn split_token(token: Token) -> (Pid, InputSource) {
-    let src = if token.0 & 2 == 2 {
-        InputSource::Report
-    } else if token.0 & 1 == 1 {
-        InputSource::Stderr
-    } else {
-        InputSource::Stdout
+    // Test processes are forked by the zygote, the driver adopts
+    // them so that it can wait for them as usual.
+    zygote::become_subreaper();
+
+    io::stdout().lock().flush().unwrap();
+    io::stderr().lock().flush().unwrap();
+
+    let pid = match fork().expect("failed to fork") {
+        ForkResult::Child => {
+            let self_pid = unistd::getpid();
+            unistd::setpgid(self_pid, self_pid).expect("zygote: failed to set PGID");
+            #[cfg(target_os = "linux")]
+            // SAFETY: prctl with PR_SET_PDEATHSIG only takes integers.
+            unsafe {
+                nix::libc::prctl(nix::libc::PR_SET_PDEATHSIG, nix::libc::SIGKILL);
+            }
+            std::mem::drop(control);
+            zygote::zygote_main(config, tasks, zygote_control)
+        }
+        ForkResult::Parent { child, .. } => {
+            set_child_pgid(child);
+            child
+        }
     };
-    (Pid::from_raw((token.0 >> 2) as i32), src)
+
+    Zygote { pid, control }
 }
 
-fn observe(task: RunningTask, poll: &mut Poll) -> ObservedTask {
-    let RunningTask {
+/// Asks the zygote to fork a process for the task with the given
+/// index.
+fn launch_from_zygote(
+    zygote: &mut Zygote,
+    index: usize,
+    full_name: Vec<String>,
+) -> Result<RunningTask, String> {
+    serialize_and_write(&mut zygote.control, &WorkerCommand::Run(index))
+        .map_err(|e| format!("failed to send a command to the zygote: {}", e))?;
+    let (pid, [stdout_pipe, stderr_pipe, report_pipe], control) =
+        zygote::receive_task(&zygote.control)
+            .map_err(|e| format!("failed to receive a task from the zygote: {}", e))?;
+
+    stdout_pipe.set_nonblocking(true).unwrap();
+    stderr_pipe.set_nonblocking(true).unwrap();
+    report_pipe.set_nonblocking(true).unwrap();
+    control.set_nonblocking(true).unwrap();
+
+    Ok(RunningTask {
         full_name,
         pid,
-        started_at,
+        started_at: Instant::now(),
+        stdout_pipe,
+        stderr_pipe,
+        report_pipe,
+        stdout_buf: Vec::new(),
+        stderr_buf: Vec::new(),
+        control,
+        ring: None,
+    })
+}
+
+/// Forks a new prefork worker.  The worker inherits all the tasks of
+/// the plan that the driver hasn't skipped.
+fn launch_worker(config: &Config, tasks: &mut [Option<Task>]) -> IdleWorker {
+    let (stdout_sender, stdout_receiver) = pipe::new().unwrap();
+    let (stderr_sender, stderr_receiver) = pipe::new().unwrap();
+    let (report_sender, report_receiver) = pipe::new().unwrap();
+    let (commands_sender, commands_receiver) = pipe::new().unwrap();
+    let (control, child_control) = UnixStream::pair().expect("failed to create a socket pair");
+
+    stdout_receiver.set_nonblocking(true).unwrap();
+    stderr_receiver.set_nonblocking(true).unwrap();
+    report_receiver.set_nonblocking(true).unwrap();
+    enlarge_report_pipe(&report_receiver);
+
+    io::stdout().lock().flush().unwrap();
+    io::stderr().lock().flush().unwrap();
+
+    let pid = match fork().expect("failed to fork") {
+        ForkResult::Child => {
+            let self_pid = unistd::getpid();
+            unistd::setpgid(self_pid, self_pid).expect("child: failed to set PGID");
+
+            // Other workers might hold the write end of our command
+            // pipe, so we can't rely on it being closed if the driver
+            // dies.
+            #[cfg(target_os = "linux")]
+            // SAFETY: prctl with PR_SET_PDEATHSIG only takes integers.
+            unsafe {
+                nix::libc::prctl(nix::libc::PR_SET_PDEATHSIG, nix::libc::SIGKILL);
+            }
+
+            if config.core_dir.is_some() {
+                coredump::enable_in_child();
+            }
+
+            std::mem::drop(stdout_receiver);
+            std::mem::drop(stderr_receiver);
+            std::mem::drop(report_receiver);
+            std::mem::drop(commands_sender);
+            std::mem::drop(control);
+
+            commands_receiver.set_nonblocking(false).unwrap();
+            report_sender.set_nonblocking(false).unwrap();
+
+            redirect_output(&stdout_sender, &stderr_sender);
+            prefork::worker_main(
+                config,
+                tasks,
+                commands_receiver,
+                report_sender,
+                child_control,
+            )
+        }
+        ForkResult::Parent { child, .. } => {
+            set_child_pgid(child);
+            child
+        }
+    };
+
+    commands_sender.set_nonblocking(false).unwrap();
+    control.set_nonblocking(true).unwrap();
+
+    IdleWorker {
+        pid,
+        suite: None,
+        commands: commands_sender,
+        control,
+        stdout_pipe: stdout_receiver,
+        stderr_pipe: stderr_receiver,
+        report_pipe: report_receiver,
+        stdout_buf: Vec::new(),
+        stderr_buf: Vec::new(),
+        report_decoder: StreamDecoder::new(),
+        control_decoder: StreamDecoder::new(),
+    }
+}
+
+/// Hands the task with the given index over to an idle worker.  If
+/// the worker turns out to be dead, returns its PID.
+fn dispatch(
+    worker: IdleWorker,
+    index: usize,
+    full_name: Vec<String>,
+    task_id: usize,
+    start_time: SystemTime,
+    poll: &mut Poll,
+) -> Result<ObservedTask, Pid> {
+    let IdleWorker {
+        pid,
+        suite,
+        mut commands,
+        control,
         mut stdout_pipe,
         mut stderr_pipe,
         mut report_pipe,
         stdout_buf,
         stderr_buf,
-    } = task;
+        report_decoder,
+        control_decoder,
+    } = worker;
+
+    if serialize_and_write(&mut commands, &WorkerCommand::Run(index)).is_err() {
+        return Err(pid);
+    }
+
+    register_pipes(
+        poll,
+        task_id,
+        &mut stdout_pipe,
+        &mut stderr_pipe,
+        &mut report_pipe,
+        &control,
+    );
+
+    Ok(ObservedTask {
+        id: task_id,
+        index,
+        tags: Vec::new(),
+        metadata: Metadata::default(),
+        suite: None,
+        deny_stderr: false,
+        expect_failure: false,
+        stderr_bytes: 0,
+        full_name,
+        pid,
+        started_at: Instant::now(),
+        start_time,
+        stdout_pipe: Some(stdout_pipe),
+        stderr_pipe: Some(stderr_pipe),
+        report_pipe: Some(report_pipe),
+        status_and_duration: None,
+        stdout_buf,
+        stderr_buf,
+        stdout_offset: 0,
+        stderr_offset: 0,
+        report_decoder,
+        control,
+        control_decoder,
+        cancelled: false,
+        timeout_extension: Duration::ZERO,
+        timeout_warned_at: None,
+        fds_at_start: None,
+        fds_at_exit: None,
+        core_signal: None,
+        skip_reason: None,
+        assertion: None,
+        metrics: Vec::new(),
+        stages: Vec::new(),
+        timed_stage: None,
+        stage_timed_out: false,
+        current_stage: None,
+        logs: Vec::new(),
+        output_chunks: Vec::new(),
+        output_bytes: 0,
+        stdout_dropped: 0,
+        stderr_dropped: 0,
+        output_exceeded: false,
+        memory_exceeded: false,
+        leaked_processes: Vec::new(),
+        pipe_closed: false,
+        stdout_spool: None,
+        stderr_spool: None,
+        ring: None,
+        worker: Some(WorkerState {
+            commands,
+            suite,
+            task_done: false,
+            stdout_done: false,
+            stderr_done: false,
+            stdout_rest: Vec::new(),
+            stderr_rest: Vec::new(),
+        }),
+    })
+}
+
+/// Asks an idle worker to exit and waits for it.
+fn retire_worker(mut worker: IdleWorker) {
+    let _ = serialize_and_write(&mut worker.commands, &WorkerCommand::Exit);
+    let _ = waitpid(worker.pid, None);
+}
 
+/// The process forking test processes in the zygote mode.
+struct Zygote {
+    pid: Pid,
+    control: UnixStream,
+}
+
+/// Kills a worker that can't be reused and waits for it to exit.
+fn reap_worker(pid: Pid) {
+    let _ = killpg(pid, Signal::SIGKILL);
+    let _ = waitpid(pid, None);
+}
+
+/// Returns the token of a pipe or the control socket of the task
+/// `task_id`.  The IDs are never reused during a run, unlike the
+/// PIDs, so that an event of a task that completed can't be taken for
+/// an event of another task.  They start at 1, the token 0 is
+/// [SIGNAL_TOKEN].
+fn make_token(task_id: usize, source: InputSource) -> Token {
+    Token(task_id * InputSource::ALL.len() + source as usize)
+}
+
+fn split_token(token: Token) -> (usize, InputSource) {
+    let sources = InputSource::ALL.len();
+    (token.0 / sources, InputSource::ALL[token.0 % sources])
+}
+
+fn register_pipes(
+    poll: &mut Poll,
+    task_id: usize,
+    stdout_pipe: &mut pipe::Receiver,
+    stderr_pipe: &mut pipe::Receiver,
+    report_pipe: &mut pipe::Receiver,
+    control: &UnixStream,
+) {
+    poll.registry()
+        .register(
+            stdout_pipe,
+            make_token(task_id, InputSource::Stdout),
+            Interest::READABLE,
+        )
+        .unwrap();
     poll.registry()
         .register(
-            &mut stdout_pipe,
-            make_token(pid, InputSource::Stdout),
+            stderr_pipe,
+            make_token(task_id, InputSource::Stderr),
             Interest::READABLE,
         )
         .unwrap();
     poll.registry()
         .register(
-            &mut stderr_pipe,
-            make_token(pid, InputSource::Stderr),
+            report_pipe,
+            make_token(task_id, InputSource::Report),
             Interest::READABLE,
         )
         .unwrap();
     poll.registry()
         .register(
-            &mut report_pipe,
-            make_token(pid, InputSource::Report),
+            &mut SourceFd(&control.as_raw_fd()),
+            make_token(task_id, InputSource::Control),
             Interest::READABLE,
         )
         .unwrap();
+}
+
+fn observe(
+    task: RunningTask,
+    index: usize,
+    task_id: usize,
+    start_time: SystemTime,
+    poll: &mut Poll,
+) -> ObservedTask {
+    let RunningTask {
+        full_name,
+        pid,
+        started_at,
+        mut stdout_pipe,
+        mut stderr_pipe,
+        mut report_pipe,
+        stdout_buf,
+        stderr_buf,
+        control,
+        ring,
+    } = task;
+
+    register_pipes(
+        poll,
+        task_id,
+        &mut stdout_pipe,
+        &mut stderr_pipe,
+        &mut report_pipe,
+        &control,
+    );
 
     ObservedTask {
+        id: task_id,
+        index,
+        tags: Vec::new(),
+        metadata: Metadata::default(),
+        suite: None,
+        deny_stderr: false,
+        expect_failure: false,
+        stderr_bytes: 0,
         full_name,
         pid,
         started_at,
+        start_time,
         stdout_pipe: Some(stdout_pipe),
         stderr_pipe: Some(stderr_pipe),
         report_pipe: Some(report_pipe),
@@ -432,50 +2332,624 @@ fn observe(task: RunningTask, poll: &mut Poll) -> ObservedTask {
         stdout_offset: 0,
         stderr_offset: 0,
         report_decoder: StreamDecoder::new(),
+        control,
+        control_decoder: StreamDecoder::new(),
+        cancelled: false,
+        timeout_extension: Duration::ZERO,
+        timeout_warned_at: None,
+        fds_at_start: None,
+        fds_at_exit: None,
+        core_signal: None,
+        skip_reason: None,
+        assertion: None,
+        metrics: Vec::new(),
+        stages: Vec::new(),
+        timed_stage: None,
+        stage_timed_out: false,
+        current_stage: None,
+        logs: Vec::new(),
+        output_chunks: Vec::new(),
+        output_bytes: 0,
+        stdout_dropped: 0,
+        stderr_dropped: 0,
+        output_exceeded: false,
+        memory_exceeded: false,
+        leaked_processes: Vec::new(),
+        pipe_closed: false,
+        stdout_spool: None,
+        stderr_spool: None,
+        ring,
+        worker: None,
+    }
+}
+
+fn skip_task(config: &Config, index: usize, task: Task, reason: String) -> CompletedTask {
+    unexecuted_task(config, index, task, Status::Skipped(reason))
+}
+
+/// Assigns a category to the task if it failed, see
+/// [Config::classifier].
+pub(crate) fn classify(config: &Config, mut task: CompletedTask) -> CompletedTask {
+    if !task.status.is_ok() {
+        task.category = config.classifier.and_then(|classifier| classifier(&task));
     }
+    task
 }
 
-fn skip_task(task: Task, reason: String) -> CompletedTask {
+/// Builds the result of the task at `index` in the plan that didn't
+/// run.
+pub(crate) fn unexecuted_task(
+    config: &Config,
+    index: usize,
+    task: Task,
+    status: Status,
+) -> CompletedTask {
     CompletedTask {
-        full_name: task.full_name,
-        duration: Duration::default(),
-        stdout: vec![],
-        stderr: vec![],
-        status: Status::Skipped(reason),
+        tags: task.options.tags,
+        metadata: task.options.metadata,
+        ..CompletedTask::new(task.full_name, status, task_start(config, index))
     }
 }
 
-/// Displays as many complete lines from "buf" as possible starting
-/// from "pos".  The pos is advanced to the beginning of the last
-/// incomplete line.
-fn display_lines(wrt: &mut dyn Write, buf: &[u8], pos: &mut usize) {
-    for i in (*pos..buf.len()).rev() {
-        if buf[i] == b'\n' {
-            write!(wrt, "{}", String::from_utf8_lossy(&buf[*pos..=i])).expect("Failed to write");
-            *pos = i + 1;
+/// Returns the descriptors that are open at exit but were not open
+/// when the test started.
+fn leaked_fds(at_start: Option<Vec<OpenFd>>, at_exit: Option<Vec<OpenFd>>) -> Vec<OpenFd> {
+    match (at_start, at_exit) {
+        (Some(at_start), Some(at_exit)) => at_exit
+            .into_iter()
+            .filter(|fd| !at_start.contains(fd))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Appends a chunk of task output to the buffer.  For tasks executed
+/// by a prefork worker, `marker` tracks whether the end of task marker
+/// has been seen; everything after the marker is set aside as it
+/// belongs to the next task.
+fn capture(buf: &mut Vec<u8>, marker: Option<(&mut bool, &mut Vec<u8>)>, data: &[u8]) {
+    let (seen, rest) = match marker {
+        Some(m) => m,
+        None => {
+            buf.extend_from_slice(data);
             return;
         }
+    };
+    if *seen {
+        rest.extend_from_slice(data);
+        return;
+    }
+    // The marker might be split between chunks.
+    let from = buf.len().saturating_sub(prefork::END_OF_TASK.len() - 1);
+    buf.extend_from_slice(data);
+    if let Some(pos) = prefork::find(&buf[from..], prefork::END_OF_TASK) {
+        let pos = from + pos;
+        rest.extend_from_slice(&buf[pos + prefork::END_OF_TASK.len()..]);
+        buf.truncate(pos);
+        *seen = true;
     }
 }
 
-/// Output the remaining part of the buffer, assuming that it ends
-/// with an incomplete line.
-fn flush_output(wrt: &mut dyn Write, buf: &[u8], pos: &mut usize) {
-    let n = buf.len();
-    if *pos < n {
-        writeln!(wrt, "{}", String::from_utf8_lossy(&buf[*pos..n])).expect("Failed to writeln");
-        *pos = n;
+/// Drops the middle of `buf`, keeping its first and last `limit / 2`
+/// bytes.  Adds the number of dropped bytes to `dropped` and moves the
+/// ends of the `chunks` of `stream` along with the bytes.
+fn truncate_output(
+    buf: &mut Vec<u8>,
+    limit: usize,
+    dropped: &mut usize,
+    stream: config::Stream,
+    chunks: &mut [OutputChunk],
+) {
+    let head = limit / 2;
+    let tail_start = buf.len().saturating_sub(limit - head);
+    if tail_start <= head {
+        return;
+    }
+    buf.drain(head..tail_start);
+    let n = tail_start - head;
+    *dropped += n;
+    for chunk in chunks.iter_mut().filter(|c| c.stream == stream) {
+        chunk.end = if chunk.end > tail_start {
+            chunk.end - n
+        } else {
+            chunk.end.min(head)
+        };
+    }
+}
+
+/// Truncates the complete output of a task to `limit` bytes, and
+/// marks the place where output was dropped.
+fn finish_truncation(
+    buf: &mut Vec<u8>,
+    limit: usize,
+    mut dropped: usize,
+    stream: config::Stream,
+    chunks: &mut [OutputChunk],
+) {
+    truncate_output(buf, limit, &mut dropped, stream, chunks);
+    if dropped == 0 {
+        return;
+    }
+    let head = limit / 2;
+    let newline = if buf[..head].ends_with(b"\n") {
+        ""
+    } else {
+        "\n"
+    };
+    let marker = format!("{}[... {} bytes truncated ...]\n", newline, dropped);
+    buf.splice(head..head, marker.bytes());
+    for chunk in chunks.iter_mut().filter(|c| c.stream == stream) {
+        if chunk.end > head {
+            chunk.end += marker.len();
+        }
     }
 }
 
-pub fn execute(
+/// Removes from `pending` the first task that can be started without
+/// exceeding the concurrency limits of its tags.  Skipped tasks are
+/// never throttled.  If that task is exclusive, it's only returned
+/// once no tasks are `running`, and no other task is started before
+/// it.
+fn next_runnable(
+    pending: &mut Vec<usize>,
+    tasks: &[Option<Task>],
     config: &Config,
-    mut tasks: Vec<Task>,
+    tag_counts: &HashMap<String, usize>,
+    observed_tasks: &HashMap<Pid, ObservedTask>,
+    dependencies: &Dependencies,
+    running: usize,
+) -> Option<usize> {
+    let runnable = |index: &usize| {
+        let task = tasks[*index].as_ref().expect("task executed twice");
+        let options = &task.options;
+        if !dependencies.are_done(*index) {
+            return false;
+        }
+        options.skip_reason.is_some()
+            || (options
+                .tags
+                .iter()
+                .all(|tag| match config.tag_limits.get(tag) {
+                    Some(limit) => tag_counts.get(tag).copied().unwrap_or(0) < *limit,
+                    None => true,
+                })
+                && !observed_tasks
+                    .values()
+                    .filter_map(|observed_task| observed_task.suite.as_ref())
+                    .any(|suite| *suite == suite_of(&task.full_name)))
+    };
+    // The pending tasks are stored in reverse order.
+    let pos = pending.iter().rposition(runnable)?;
+    let options = &tasks[pending[pos]].as_ref().unwrap().options;
+    if options.exclusive && options.skip_reason.is_none() && running > 0 {
+        return None;
+    }
+    Some(pending.remove(pos))
+}
+
+/// The dependencies between the selected tasks, see
+/// [crate::depends_on], and the outcomes of the tasks they depend on.
+#[derive(Default)]
+struct Dependencies {
+    // The indices and names of the tasks each task depends on.
+    of: HashMap<usize, Vec<(usize, String)>>,
+    // Whether each finished task passed.
+    passed: HashMap<usize, bool>,
+}
+
+impl Dependencies {
+    /// Resolves the dependencies of the selected tasks.  The tasks
+    /// depending on themselves, directly or not, are skipped.
+    fn resolve(tasks: &mut [Option<Task>], selected: &[usize]) -> Self {
+        let names: Vec<(usize, String)> = selected
+            .iter()
+            .filter_map(|i| Some((*i, tasks[*i].as_ref()?.name())))
+            .collect();
+        let mut of = HashMap::new();
+        for i in selected {
+            let Some(task) = &tasks[*i] else { continue };
+            let mut resolved = Vec::new();
+            for dependency in &task.options.dependencies {
+                // The innermost suite with a matching test or suite.
+                for depth in (0..task.full_name.len()).rev() {
+                    let mut prefix = task.full_name[..depth].to_vec();
+                    prefix.push(dependency.clone());
+                    let prefix = prefix.join("::");
+                    let suite = format!("{}::", prefix);
+                    let matching: Vec<_> = names
+                        .iter()
+                        .filter(|(_, name)| *name == prefix || name.starts_with(&suite))
+                        .cloned()
+                        .collect();
+                    if !matching.is_empty() {
+                        resolved.extend(matching);
+                        break;
+                    }
+                }
+            }
+            if !resolved.is_empty() {
+                of.insert(*i, resolved);
+            }
+        }
+
+        let mut dependencies = Dependencies {
+            of,
+            passed: HashMap::new(),
+        };
+        for i in selected {
+            if dependencies.in_cycle(*i) {
+                if let Some(task) = tasks[*i].as_mut() {
+                    task.options.skip_reason = Some("dependency cycle".to_string());
+                }
+            }
+        }
+        for i in selected {
+            if tasks[*i]
+                .as_ref()
+                .is_some_and(|t| t.options.skip_reason.is_some())
+            {
+                // Skipped anyway, no need to wait.
+                dependencies.of.remove(i);
+            }
+        }
+        dependencies
+    }
+
+    /// Whether the task depends on itself, directly or not.
+    fn in_cycle(&self, index: usize) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![index];
+        while let Some(i) = stack.pop() {
+            for (dependency, _) in self.of.get(&i).into_iter().flatten() {
+                if *dependency == index {
+                    return true;
+                }
+                if seen.insert(*dependency) {
+                    stack.push(*dependency);
+                }
+            }
+        }
+        false
+    }
+
+    /// Whether all the dependencies of the task are done.
+    fn are_done(&self, index: usize) -> bool {
+        self.of
+            .get(&index)
+            .into_iter()
+            .flatten()
+            .all(|(dependency, _)| self.passed.contains_key(dependency))
+    }
+
+    /// Returns the name of a dependency of the task that didn't pass.
+    fn failed(&self, index: usize) -> Option<&str> {
+        self.of
+            .get(&index)
+            .into_iter()
+            .flatten()
+            .find(|(dependency, _)| self.passed.get(dependency) == Some(&false))
+            .map(|(_, name)| name.as_str())
+    }
+
+    fn finish(&mut self, index: usize, status: &Status) {
+        let passed = status.is_ok() && !matches!(status, Status::Skipped(_));
+        self.passed.insert(index, passed);
+    }
+}
+
+/// The global fixtures of the run, see [Config::global_fixture], with
+/// the number of selected tasks needing each of them that aren't done.
+struct GlobalFixtures<'a> {
+    config: &'a Config,
+    remaining: HashMap<&'a str, usize>,
+    // The fixtures that are set up, or why they failed to.
+    started: HashMap<&'a str, Result<(), String>>,
+    // The setups and teardowns running on threads of their own.
+    running: Vec<(&'a str, FixtureStep, std::thread::JoinHandle<()>)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FixtureStep {
+    Setup,
+    Teardown,
+}
+
+impl<'a> GlobalFixtures<'a> {
+    fn new(config: &'a Config, tasks: &[Option<Task>], selected: &[usize]) -> Self {
+        let mut remaining = HashMap::new();
+        for task in selected.iter().filter_map(|i| tasks[*i].as_ref()) {
+            for name in config.global_fixtures.keys() {
+                if task.options.tags.contains(name) {
+                    *remaining.entry(name.as_str()).or_default() += 1;
+                }
+            }
+        }
+        Self {
+            config,
+            remaining,
+            started: HashMap::new(),
+            running: Vec::new(),
+        }
+    }
+
+    /// Whether a fixture is being set up or torn down.  No test starts
+    /// meanwhile, as the forked process could inherit a lock held by
+    /// the thread of the fixture.
+    fn busy(&mut self) -> bool {
+        let (done, running) = std::mem::take(&mut self.running)
+            .into_iter()
+            .partition(|(_, _, thread)| thread.is_finished());
+        self.running = running;
+        for (name, step, thread) in done {
+            self.join(name, step, thread);
+        }
+        !self.running.is_empty()
+    }
+
+    fn join(&mut self, name: &'a str, step: FixtureStep, thread: std::thread::JoinHandle<()>) {
+        // The panic hook already printed the message of a teardown.
+        let result = thread.join();
+        if step == FixtureStep::Setup {
+            let result = result.map_err(|err| {
+                let message = crate::try_get_panic_msg(&err).unwrap_or("panicked");
+                format!("global fixture {} failed to set up: {}", name, message)
+            });
+            self.started.insert(name, result);
+        }
+    }
+
+    /// Starts setting up the fixtures needed by a task about to start
+    /// that aren't up, and returns whether there were none.
+    fn prepare(&mut self, tags: &[String]) -> bool {
+        let mut ready = true;
+        for (name, fixture) in &self.config.global_fixtures {
+            if !tags.contains(name)
+                || self.started.contains_key(name.as_str())
+                || self.running.iter().any(|(running, _, _)| running == name)
+            {
+                continue;
+            }
+            let setup = fixture.setup.clone();
+            let thread = std::thread::spawn(move || setup());
+            self.running
+                .push((name.as_str(), FixtureStep::Setup, thread));
+            ready = false;
+        }
+        ready
+    }
+
+    /// Whether the fixtures needed by a task about to start are up,
+    /// see [GlobalFixtures::prepare].
+    fn acquire(&self, tags: &[String]) -> Result<(), String> {
+        for name in self.config.global_fixtures.keys() {
+            if tags.contains(name) {
+                if let Some(started) = self.started.get(name.as_str()) {
+                    started.clone()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a task that is done, and tears down the fixtures no
+    /// task needs anymore.
+    fn release(&mut self, tags: &[String]) {
+        for (name, fixture) in &self.config.global_fixtures {
+            if !tags.contains(name) {
+                continue;
+            }
+            let Some(remaining) = self.remaining.get_mut(name.as_str()) else {
+                continue;
+            };
+            *remaining = remaining.saturating_sub(1);
+            if *remaining == 0 {
+                if let Some(Ok(())) = self.started.remove(name.as_str()) {
+                    let teardown = fixture.teardown.clone();
+                    let thread = std::thread::spawn(move || teardown());
+                    self.running
+                        .push((name.as_str(), FixtureStep::Teardown, thread));
+                }
+            }
+        }
+    }
+
+    /// Waits for the fixtures being set up or torn down, and tears
+    /// down those that are still up, e.g. when the run is interrupted.
+    fn release_all(&mut self) {
+        for (name, step, thread) in std::mem::take(&mut self.running) {
+            self.join(name, step, thread);
+        }
+        for (name, fixture) in &self.config.global_fixtures {
+            if let Some(Ok(())) = self.started.remove(name.as_str()) {
+                (fixture.teardown)();
+            }
+        }
+    }
+}
+
+/// Runs the health checks of the configuration and returns the skip
+/// reason of the remaining tasks if one of them fails.
+fn check_health(config: &Config) -> Option<String> {
+    for health_check in &config.health_checks {
+        if let Err(err) = (health_check.check)() {
+            eprintln!(
+                "Health check {} failed: {}; skipping the tests that didn't start",
+                health_check.name, err
+            );
+            return Some(format!(
+                "environment unhealthy: {}: {}",
+                health_check.name, err
+            ));
+        }
+    }
+    None
+}
+
+/// Returns the suite whose tests share a worker with
+/// [ExecutionPolicy::ProcessPerSuite]: the suite directly under the
+/// root of the tree, or the root for the tests directly under it.
+fn suite_of(full_name: &[String]) -> String {
+    let depth = full_name.len().saturating_sub(1).min(2);
+    full_name[..depth].join("::")
+}
+
+/// Displays as many complete lines from "buf" as possible starting
+/// from "pos".  The pos is advanced to the beginning of the last
+/// incomplete line.
+fn display_lines(wrt: &mut dyn Write, buf: &[u8], pos: &mut usize) {
+    for i in (*pos..buf.len()).rev() {
+        if buf[i] == b'\n' {
+            write!(wrt, "{}", String::from_utf8_lossy(&buf[*pos..=i])).expect("Failed to write");
+            *pos = i + 1;
+            return;
+        }
+    }
+}
+
+/// Reads everything available in a pipe.  The pipes are polled in
+/// edge-triggered mode, so data left in a pipe wouldn't be signaled
+/// again until more arrives, which never happens for an idle prefork
+/// worker.  Reads interrupted by a signal are retried, and the data
+/// read before an error is returned along with it.
+fn read_available(pipe: &mut impl Read, buf: &mut [u8]) -> (Vec<u8>, io::Result<()>) {
+    let mut data = Vec::new();
+    loop {
+        match pipe.read(buf) {
+            Ok(0) => return (data, Ok(())),
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return (data, Ok(())),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return (data, Err(err)),
+        }
+    }
+}
+
+/// Returns the next signal received by the driver, if any.
+fn receive_signal(signals: &mut msig::Signals) -> Option<msig::Signal> {
+    loop {
+        match signals.receive() {
+            Ok(signal) => return signal,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => {
+                eprintln!("Failed to receive a signal: {}", err);
+                return None;
+            }
+        }
+    }
+}
+
+/// Reports an error reading the `stream` of a task, the driver then
+/// treats the pipe as closed rather than giving up on the whole run.
+fn pipe_failed(full_name: &[String], stream: &str, err: io::Error) {
+    eprintln!(
+        "Failed to read the {} of {}: {}; ignoring the rest of it",
+        stream,
+        full_name.join("::"),
+        err
+    );
+}
+
+/// Output the remaining part of the buffer, assuming that it ends
+/// with an incomplete line.
+fn flush_output(wrt: &mut dyn Write, buf: &[u8], pos: &mut usize) {
+    let n = buf.len();
+    if *pos < n {
+        writeln!(wrt, "{}", String::from_utf8_lossy(&buf[*pos..n])).expect("Failed to writeln");
+        *pos = n;
+    }
+}
+
+/// The names of the running tasks and their elapsed times, longest
+/// running first.
+fn running_tasks(observed_tasks: &HashMap<Pid, ObservedTask>) -> Vec<(Vec<String>, Duration)> {
+    let mut running: Vec<_> = observed_tasks
+        .values()
+        .map(|t| (t.full_name.clone(), t.started_at.elapsed()))
+        .collect();
+    running.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    running
+}
+
+pub fn execute(config: &Config, tasks: Vec<Task>, report: &mut dyn Report) -> Vec<CompletedTask> {
+    let mut tasks: Vec<Option<Task>> = tasks.into_iter().map(Some).collect();
+    let selected: Vec<usize> = (0..tasks.len()).collect();
+    execute_selected(config, &mut tasks, &selected, report)
+}
+
+/// Executes the tasks of the plan at the `selected` indices.  The
+/// tasks executed by test processes stay in the plan, so that they
+/// can be executed again, e.g. by a worker of a remote driver.
+///
+/// If the driver panics, it kills the processes it started, tears
+/// down the global fixtures and finishes the report with the tasks
+/// completed so far before exiting, rather than leaving orphaned test
+/// processes, running services and no output.
+pub(crate) fn execute_selected(
+    config: &Config,
+    tasks: &mut [Option<Task>],
+    selected: &[usize],
     report: &mut dyn Report,
 ) -> Vec<CompletedTask> {
+    let driver = unistd::getpid();
+    let mut task_results = RetainedTasks::new(config.compress_output)
+        .keep_output(config.keep_output.unwrap_or_default());
+    let mut fixtures = GlobalFixtures::new(config, tasks, selected);
+    // The process groups of the processes the driver started.
+    let mut leaders = HashSet::new();
+    let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        drive(
+            config,
+            tasks,
+            selected,
+            &mut *report,
+            &mut task_results,
+            &mut fixtures,
+            &mut leaders,
+        )
+    }));
+    match outcome {
+        Ok(()) => task_results.into_vec(),
+        // A test process unwinding out of the test body.
+        Err(panic) if unistd::getpid() != driver => std::panic::resume_unwind(panic),
+        Err(_) => {
+            // The panic hook already printed the message.
+            daemons::kill_groups(&leaders);
+            fixtures.release_all();
+            eprintln!(
+                "The driver panicked after {} of {} tests completed",
+                task_results.len(),
+                selected.len()
+            );
+            report.on_event(&ReportEvent::RunFinished);
+            std::process::exit(101)
+        }
+    }
+}
+
+/// The loop of [execute_selected], which pushes the results of the
+/// tasks to `task_results` as they complete.
+fn drive(
+    config: &Config,
+    tasks: &mut [Option<Task>],
+    selected: &[usize],
+    report: &mut dyn Report,
+    task_results: &mut RetainedTasks,
+    fixtures: &mut GlobalFixtures,
+    leaders: &mut HashSet<Pid>,
+) {
     let timeout = config.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let extension_budget = config.timeout_extension.unwrap_or_default();
     let jobs = config.jobs.unwrap_or_else(num_cpus::get);
-    let poll_timeout = Duration::from_millis(100);
+    let fd_leaks = config.fd_leaks.unwrap_or_default();
+    let timeout_signal = config.timeout_signal.unwrap_or(Some(Signal::SIGUSR1));
+
+    if let Some(ref dir) = config.coverage_dir {
+        std::fs::create_dir_all(dir).expect("failed to create the coverage directory");
+    }
+    if let Some(ref dir) = config.core_dir {
+        std::fs::create_dir_all(dir).expect("failed to create the core dump directory");
+    }
+    let poll_timeout = config.poll_timeout.unwrap_or(DEFAULT_POLL_TIMEOUT);
 
     let mut poll = Poll::new().expect("failed to create poll");
     let mut signals = msig::Signals::new(msig::SignalSet::all())
@@ -485,56 +2959,350 @@ pub fn execute(
         .register(&mut signals, SIGNAL_TOKEN, Interest::READABLE)
         .expect("failed to register signal handler in a Poll registry");
 
-    let mut events = Events::with_capacity(jobs * 2);
-    let mut buf = vec![0u8; 4096];
+    // Each test has three pipes.
+    let mut events = Events::with_capacity(config.poll_events.unwrap_or(jobs * 3).max(1));
+    let mut buf = vec![0u8; config.read_buffer.unwrap_or(4096).max(1)];
+    // When the status of all the tests was last checked.
+    let mut checked_at = Instant::now();
+    // The output of the tests is inspected as it arrives otherwise.
+    let splice_output = config.splice_output
+        && spool::is_supported()
+        && !config.nocapture
+        && !config.transcript
+        && !config.timestamps
+        && config.max_output_bytes.is_none();
+
+    let names: Vec<String> = selected
+        .iter()
+        .map(|i| tasks[*i].as_ref().expect("task executed twice").name())
+        .collect();
+    report.on_event(&ReportEvent::Plan(&names));
+    report.on_event(&ReportEvent::SuiteStarted {
+        test_count: selected.len(),
+    });
+    report.on_event(&ReportEvent::Header(&RunInfo::collect(config)));
+
+    // Tasks are addressed by their index in the plan, so that prefork
+    // workers forked at any point can find them in their copy of the
+    // plan.  The driver only takes the tasks that don't run out of the
+    // plan, the others stay in it in case they are retried.
+    let mut pending: Vec<usize> = selected.iter().rev().copied().collect();
 
-    report.init(&tasks);
+    let per_suite = config.execution_policy == Some(ExecutionPolicy::ProcessPerSuite);
+    let mut zygote = if config.zygote && !config.prefork && !per_suite && zygote::is_supported() {
+        Some(launch_zygote(config, tasks))
+    } else {
+        None
+    };
+    leaders.extend(zygote.as_ref().map(|zygote| zygote.pid));
+
+    let mut leases = LeaseAllocator::new(&config.resource_pools);
+    let mut jobserver = Jobserver::from_env();
+    // The job tokens we hold; the first running task doesn't need one.
+    let mut tokens = Vec::<u8>::new();
 
     let mut observed_tasks = HashMap::<Pid, ObservedTask>::new();
+    let mut idle_workers = Vec::<IdleWorker>::new();
     let mut completed_pids = Vec::<Pid>::new();
-    let mut task_results = Vec::<CompletedTask>::new();
-
-    tasks.reverse();
+    // The number of running tasks with each tag.
+    let mut tag_counts = HashMap::<String, usize>::new();
+    let mut next_task_id = 1;
+    // The process groups of the test processes, swept at the end.
+    let mut groups = HashMap::<Pid, Vec<String>>::new();
+    // The failed attempts of the tasks that are going to be retried,
+    // by the index of the task.
+    let mut failed_attempts = HashMap::<usize, Vec<CompletedTask>>::new();
+    let mut dependencies = Dependencies::resolve(tasks, selected);
+    // The reason to skip the remaining tasks once a health check
+    // failed.
+    let mut unhealthy: Option<String> = None;
+    let mut health_checked_at: Option<Instant> = None;
+    // When the last task started, see [Config::launch_delay].
+    let mut launched_at: Option<Instant> = None;
+    let mut cpu_slots = CpuSlots::new(config, jobs);
+    let mut descendants = if config.reap_daemons {
+        Some(Descendants::new())
+    } else {
+        None
+    };
+    // The artifacts directories of the tasks in pipelines, by name.
+    let artifacts_dirs: HashMap<Vec<String>, PathBuf> = selected
+        .iter()
+        .filter_map(|i| {
+            let task = tasks[*i].as_ref()?;
+            Some((task.full_name.clone(), task.options.artifacts_dir.clone()?))
+        })
+        .collect();
+    let run_artifacts_dir = artifacts_dirs
+        .values()
+        .next()
+        .and_then(|dir| dir.parent())
+        .map(Path::to_path_buf);
+    if let Some(dir) = run_artifacts_dir.as_deref().and_then(Path::parent) {
+        remove_stale_artifacts(dir);
+    }
+    // The exclusive task that is running, if any.
+    let mut exclusive_pid = None;
+    // Set on the first Ctrl-C, running tasks get some time to stop
+    // before they are killed.
+    let mut interrupted_at: Option<Instant> = None;
+    let mut kill_all = false;
+    let mut heartbeat_at = Instant::now();
 
-    while !tasks.is_empty() || !observed_tasks.is_empty() {
-        while observed_tasks.len() < jobs {
-            match tasks.pop() {
-                Some(mut task) => {
-                    report.start(task.name());
-                    if let Some(reason) = task.options.skip_reason.take() {
-                        report.report(&skip_task(task, reason));
-                        continue;
+    while !pending.is_empty() || !observed_tasks.is_empty() {
+        while observed_tasks.len() < jobs && !pending.is_empty() {
+            if let (Some(delay), Some(at)) = (config.launch_delay, launched_at) {
+                if at.elapsed() < delay {
+                    break;
+                }
+            }
+            if let Some(ref mut js) = jobserver {
+                if observed_tasks.len() > tokens.len() {
+                    match js.try_acquire() {
+                        Some(token) => tokens.push(token),
+                        // Try again after the next poll.
+                        None => break,
                     }
+                }
+            }
 
-                    let running_task = launch(task);
-                    let observed_task = observe(running_task, &mut poll);
-                    observed_tasks.insert(observed_task.pid, observed_task);
+            if exclusive_pid.is_some() || fixtures.busy() {
+                break;
+            }
+            let running = observed_tasks.len();
+            let index = match next_runnable(
+                &mut pending,
+                tasks,
+                config,
+                &tag_counts,
+                &observed_tasks,
+                &dependencies,
+                running,
+            ) {
+                Some(index) => index,
+                // All pending tasks are throttled.
+                None => break,
+            };
+            let task = tasks[index].as_mut().expect("task executed twice");
+            // The task waits for its fixtures while the loop goes on,
+            // until the next poll finds them up.
+            if task.options.skip_reason.is_none()
+                && dependencies.failed(index).is_none()
+                && !fixtures.prepare(&task.options.tags)
+            {
+                pending.push(index);
+                break;
+            }
+            report.on_event(&ReportEvent::TestStarted {
+                full_name: &task.full_name,
+            });
+            if let Some(dependency) = dependencies.failed(index) {
+                task.options.skip_reason = Some(format!("dependency failed: {}", dependency));
+            }
+            if task.options.skip_reason.is_none()
+                && unhealthy.is_none()
+                && health_checked_at.is_none_or(|at| at.elapsed() >= HEALTH_CHECK_INTERVAL)
+            {
+                health_checked_at = Some(Instant::now());
+                unhealthy = check_health(config);
+            }
+            if let Some(reason) = &unhealthy {
+                task.options.skip_reason = Some(reason.clone());
+            }
+            if let Some(reason) = task.options.skip_reason.take() {
+                let completed_task = skip_task(config, index, tasks[index].take().unwrap(), reason);
+                dependencies.finish(index, &completed_task.status);
+                report.on_event(&ReportEvent::TestFinished(&completed_task));
+                fixtures.release(&completed_task.tags);
+                task_results.push(completed_task);
+                continue;
+            }
+            let tags = task.options.tags.clone();
+            if let Err(e) = fixtures.acquire(&tags) {
+                let task = tasks[index].take().unwrap();
+                let completed_task = classify(
+                    config,
+                    unexecuted_task(config, index, task, Status::DriverError(e)),
+                );
+                dependencies.finish(index, &completed_task.status);
+                report.on_event(&ReportEvent::TestFinished(&completed_task));
+                fixtures.release(&completed_task.tags);
+                task_results.push(completed_task);
+                continue;
+            }
+            let metadata = task.options.metadata.clone();
+            let exclusive = task.options.exclusive;
+            let deny_stderr = config.deny_stderr || task.options.deny_stderr;
+            let expect_failure = task.options.expect_failure;
+
+            let retried = failed_attempts.contains_key(&index);
+
+            let suite = per_suite.then(|| suite_of(&task.full_name));
+
+            let task_id = next_task_id;
+            next_task_id += 1;
+            let mut observed_task = if config.prefork || per_suite {
+                let full_name = task.full_name.clone();
+                loop {
+                    let worker = if let Some(ref suite) = suite {
+                        let idle = idle_workers
+                            .iter()
+                            .position(|w| w.suite.as_ref() == Some(suite))
+                            .map(|pos| idle_workers.swap_remove(pos));
+                        match idle {
+                            // A retried task needs a fresh worker, as
+                            // below.
+                            Some(idle) if retried => {
+                                retire_worker(idle);
+                                None
+                            }
+                            idle => idle,
+                        }
+                        .unwrap_or_else(|| IdleWorker {
+                            suite: Some(suite.clone()),
+                            ..launch_worker(config, tasks)
+                        })
+                    } else if retried {
+                        // The worker that executed the task has no
+                        // copy of it anymore, so a fresh worker
+                        // replaces an idle one.
+                        if let Some(idle) = idle_workers.pop() {
+                            retire_worker(idle);
+                        }
+                        launch_worker(config, tasks)
+                    } else {
+                        idle_workers
+                            .pop()
+                            .unwrap_or_else(|| launch_worker(config, tasks))
+                    };
+                    match dispatch(
+                        worker,
+                        index,
+                        full_name.clone(),
+                        task_id,
+                        task_start(config, index),
+                        &mut poll,
+                    ) {
+                        Ok(observed_task) => break observed_task,
+                        // The worker died while waiting for a task.
+                        Err(pid) => reap_worker(pid),
+                    }
                 }
-                None => {
-                    break;
+            } else if let Some(ref mut zygote) = zygote {
+                match launch_from_zygote(zygote, index, task.full_name.clone()) {
+                    Ok(running_task) => observe(
+                        running_task,
+                        index,
+                        task_id,
+                        task_start(config, index),
+                        &mut poll,
+                    ),
+                    Err(e) => {
+                        let task = tasks[index].take().unwrap();
+                        let mut completed_task = classify(
+                            config,
+                            unexecuted_task(config, index, task, Status::DriverError(e)),
+                        );
+                        completed_task.previous_attempts =
+                            failed_attempts.remove(&index).unwrap_or_default();
+                        dependencies.finish(index, &completed_task.status);
+                        report.on_event(&ReportEvent::TestFinished(&completed_task));
+                        fixtures.release(&completed_task.tags);
+                        task_results.push(completed_task);
+                        continue;
+                    }
                 }
+            } else {
+                let running_task = launch(config, &mut tasks[index]);
+                observe(
+                    running_task,
+                    index,
+                    task_id,
+                    task_start(config, index),
+                    &mut poll,
+                )
+            };
+            for tag in tags.iter() {
+                *tag_counts.entry(tag.clone()).or_default() += 1;
+            }
+            observed_task.tags = tags;
+            observed_task.metadata = metadata;
+            observed_task.suite = suite;
+            observed_task.deny_stderr = deny_stderr;
+            observed_task.expect_failure = expect_failure;
+            if splice_output && observed_task.worker.is_none() {
+                observed_task.stdout_spool = Some(Spool::new().expect("failed to create a spool"));
+                observed_task.stderr_spool = Some(Spool::new().expect("failed to create a spool"));
             }
+            leaders.insert(observed_task.pid);
+            if observed_task.worker.is_none() {
+                groups.insert(observed_task.pid, observed_task.full_name.clone());
+            }
+            if let Some(slots) = &mut cpu_slots {
+                slots.assign(observed_task.pid);
+            }
+            if exclusive {
+                exclusive_pid = Some(observed_task.pid);
+            }
+            observed_tasks.insert(observed_task.pid, observed_task);
+            launched_at = Some(Instant::now());
         }
 
-        poll.poll(&mut events, Some(poll_timeout))
-            .expect("failed to poll");
+        let wait = match (config.launch_delay, launched_at) {
+            (Some(delay), Some(at)) if !pending.is_empty() => {
+                poll_timeout.min(delay.saturating_sub(at.elapsed()))
+            }
+            _ => poll_timeout,
+        };
+        // A signal interrupting the wait, e.g. when a debugger or
+        // strace attaches, leaves no events and the loop goes on.
+        match poll.poll(&mut events, Some(wait)) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => panic!("failed to poll: {}", err),
+        }
+        report.on_event(&ReportEvent::Tick);
+        if let Some(interval) = config.heartbeat {
+            if heartbeat_at.elapsed() >= interval && !observed_tasks.is_empty() {
+                // The reports learn how long the stages have been
+                // running from the driver, rather than measuring it.
+                let mut in_stages: Vec<_> = observed_tasks
+                    .values()
+                    .filter_map(|t| Some((&t.full_name, t.current_stage.as_ref()?)))
+                    .collect();
+                in_stages.sort();
+                for (full_name, (stage, started_at)) in in_stages {
+                    report.on_event(&ReportEvent::StageInProgress {
+                        full_name,
+                        stage,
+                        elapsed: started_at.elapsed(),
+                    });
+                }
+                report.on_event(&ReportEvent::Heartbeat(&running_tasks(&observed_tasks)));
+                heartbeat_at = Instant::now();
+            }
+        }
 
         for event in &events {
             if event.token() == SIGNAL_TOKEN {
-                match signals.receive().expect("failed to receive signal") {
-                    Some(sig) => {
+                match receive_signal(&mut signals) {
+                    Some(sig) if interrupted_at.is_none() => {
                         eprintln!(
                             "Received signal {:?}, canceling {} tasks...",
                             sig,
                             observed_tasks.len()
                         );
-
-                        for pid in observed_tasks.keys() {
-                            eprintln!("Killing process group {:?}...", *pid);
-                            let _ = killpg(*pid, Signal::SIGKILL);
+                        interrupted_at = Some(Instant::now());
+                        pending.clear();
+                        for observed_task in observed_tasks.values_mut() {
+                            observed_task.cancel();
                         }
-
-                        std::process::exit(1)
+                        continue;
+                    }
+                    Some(_) => {
+                        // Stop waiting for the tasks on the second signal.
+                        kill_all = true;
+                        continue;
                     }
                     None => {
                         continue;
@@ -542,18 +3310,49 @@ pub fn execute(
                 }
             }
 
-            let (pid, src) = split_token(event.token());
-
-            let observed_task = observed_tasks
-                .get_mut(&pid)
-                .expect("received an event for a process that is not observed");
+            let (task_id, src) = split_token(event.token());
+            let observed_task = match observed_tasks
+                .values_mut()
+                .find(|observed_task| observed_task.id == task_id)
+            {
+                Some(observed_task) => observed_task,
+                // An event that arrived along with the completion of
+                // the task.
+                None => continue,
+            };
 
             match src {
                 InputSource::Stdout => {
+                    let mut failed = false;
                     if event.is_readable() {
-                        if let Some(ref mut pipe) = observed_task.stdout_pipe {
-                            let n = pipe.read(&mut buf).expect("failed to read STDOUT");
-                            observed_task.stdout_buf.extend_from_slice(&buf[0..n]);
+                        if let (Some(pipe), Some(spool)) =
+                            (&observed_task.stdout_pipe, &mut observed_task.stdout_spool)
+                        {
+                            match spool.splice_from(pipe.as_raw_fd()) {
+                                Ok(n) => {
+                                    observed_task.count_output(config, config::Stream::Stdout, n)
+                                }
+                                Err(err) => {
+                                    failed = true;
+                                    pipe_failed(&observed_task.full_name, "stdout", err);
+                                }
+                            }
+                        } else if let Some(ref mut pipe) = observed_task.stdout_pipe {
+                            let (data, result) = read_available(pipe, &mut buf);
+                            if let Err(err) = result {
+                                failed = true;
+                                pipe_failed(&observed_task.full_name, "stdout", err);
+                            }
+                            let start = observed_task.stdout_buf.len();
+                            capture(
+                                &mut observed_task.stdout_buf,
+                                observed_task
+                                    .worker
+                                    .as_mut()
+                                    .map(|w| (&mut w.stdout_done, &mut w.stdout_rest)),
+                                &data,
+                            );
+                            observed_task.captured(config, config::Stream::Stdout, start);
                             if config.nocapture {
                                 display_lines(
                                     &mut std::io::stdout(),
@@ -563,22 +3362,42 @@ pub fn execute(
                             }
                         }
                     }
-                    if event.is_read_closed() {
-                        if config.nocapture {
-                            flush_output(
-                                &mut std::io::stdout(),
-                                &observed_task.stderr_buf,
-                                &mut observed_task.stdout_offset,
-                            );
-                        }
+                    if event.is_read_closed() || failed {
                         observed_task.stdout_pipe = None;
+                        observed_task.pipe_closed = true;
                     }
                 }
                 InputSource::Stderr => {
+                    let mut failed = false;
                     if event.is_readable() {
-                        if let Some(ref mut pipe) = observed_task.stderr_pipe {
-                            let n = pipe.read(&mut buf).expect("failed to read STDERR");
-                            observed_task.stderr_buf.extend_from_slice(&buf[0..n]);
+                        if let (Some(pipe), Some(spool)) =
+                            (&observed_task.stderr_pipe, &mut observed_task.stderr_spool)
+                        {
+                            match spool.splice_from(pipe.as_raw_fd()) {
+                                Ok(n) => {
+                                    observed_task.count_output(config, config::Stream::Stderr, n)
+                                }
+                                Err(err) => {
+                                    failed = true;
+                                    pipe_failed(&observed_task.full_name, "stderr", err);
+                                }
+                            }
+                        } else if let Some(ref mut pipe) = observed_task.stderr_pipe {
+                            let (data, result) = read_available(pipe, &mut buf);
+                            if let Err(err) = result {
+                                failed = true;
+                                pipe_failed(&observed_task.full_name, "stderr", err);
+                            }
+                            let start = observed_task.stderr_buf.len();
+                            capture(
+                                &mut observed_task.stderr_buf,
+                                observed_task
+                                    .worker
+                                    .as_mut()
+                                    .map(|w| (&mut w.stderr_done, &mut w.stderr_rest)),
+                                &data,
+                            );
+                            observed_task.captured(config, config::Stream::Stderr, start);
                             if config.nocapture {
                                 display_lines(
                                     &mut std::io::stderr(),
@@ -588,73 +3407,422 @@ pub fn execute(
                             }
                         }
                     }
-                    if event.is_read_closed() {
-                        if config.nocapture {
-                            flush_output(
-                                &mut std::io::stderr(),
-                                &observed_task.stderr_buf,
-                                &mut observed_task.stderr_offset,
-                            );
-                        }
+                    if event.is_read_closed() || failed {
                         observed_task.stderr_pipe = None;
+                        observed_task.pipe_closed = true;
                     }
                 }
                 InputSource::Report => {
+                    let mut failed = false;
                     if event.is_readable() {
                         if let Some(ref mut pipe) = observed_task.report_pipe {
-                            let n = pipe.read(&mut buf).expect("failed to read REPORT");
-                            observed_task.report_decoder.append(&buf[0..n]);
-                            while let Some(stage_rep) = observed_task.report_decoder.try_decode() {
-                                report.stage(&observed_task.full_name, stage_rep);
+                            let (data, result) = read_available(pipe, &mut buf);
+                            if let Err(err) = result {
+                                failed = true;
+                                pipe_failed(&observed_task.full_name, "reports", err);
+                            }
+                            observed_task.report_decoder.append(&data);
+                            let mut messages = Vec::new();
+                            while let Some(msg) = observed_task.report_decoder.try_decode() {
+                                match (msg, &mut observed_task.ring) {
+                                    (ReportMessage::Drain, Some(ring)) => {
+                                        messages.extend(ring.drain())
+                                    }
+                                    (msg, _) => messages.push(msg),
+                                }
+                            }
+                            for msg in messages {
+                                match msg {
+                                    ReportMessage::Stage(stage_rep) => {
+                                        report.on_event(&ReportEvent::Stage {
+                                            full_name: &observed_task.full_name,
+                                            stage: &stage_rep,
+                                        });
+                                        observed_task.stages.push(stage_rep);
+                                        observed_task.timed_stage = None;
+                                        observed_task.current_stage = None;
+                                    }
+                                    ReportMessage::StageInProgress {
+                                        stage_name,
+                                        elapsed,
+                                    } => {
+                                        report.on_event(&ReportEvent::StageInProgress {
+                                            full_name: &observed_task.full_name,
+                                            stage: &stage_name,
+                                            elapsed,
+                                        });
+                                        let started_at = Instant::now()
+                                            .checked_sub(elapsed)
+                                            .unwrap_or_else(Instant::now);
+                                        observed_task.current_stage =
+                                            Some((stage_name, started_at));
+                                    }
+                                    ReportMessage::StageStarted {
+                                        stage_name,
+                                        timeout,
+                                    } => {
+                                        observed_task.timed_stage =
+                                            Some((stage_name, Instant::now(), timeout))
+                                    }
+                                    ReportMessage::FdsAtStart(fds) => {
+                                        observed_task.fds_at_start = Some(fds)
+                                    }
+                                    ReportMessage::FdsAtExit(fds) => {
+                                        observed_task.fds_at_exit = Some(fds)
+                                    }
+                                    ReportMessage::TaskDone(code) => {
+                                        let status = if code == 0 {
+                                            Status::Success
+                                        } else {
+                                            Status::Failure(code)
+                                        };
+                                        observed_task.status_and_duration =
+                                            Some((status, observed_task.started_at.elapsed()));
+                                        if let Some(ref mut w) = observed_task.worker {
+                                            w.task_done = true;
+                                        }
+                                    }
+                                    ReportMessage::Skipped(reason) => {
+                                        observed_task.skip_reason = Some(reason)
+                                    }
+                                    ReportMessage::AssertionFailed(failure) => {
+                                        observed_task.assertion = Some(failure)
+                                    }
+                                    ReportMessage::Metric(metric) => {
+                                        observed_task.metrics.push(metric)
+                                    }
+                                    ReportMessage::Log(record) => observed_task.logs.push(record),
+                                    ReportMessage::Drain => (),
+                                }
                             }
                         }
                     }
-                    if event.is_read_closed() {
+                    if event.is_read_closed() || failed {
                         observed_task.report_pipe = None;
+                        observed_task.pipe_closed = true;
+                    }
+                }
+                // The socket stays open until the task completes, the
+                // driver may still have to cancel it.
+                InputSource::Control if event.is_readable() => {
+                    let (data, result) = read_available(&mut observed_task.control, &mut buf);
+                    match result {
+                        // The task exited without reading a message of
+                        // the driver, e.g. a cancellation.
+                        Err(err) if err.kind() == io::ErrorKind::ConnectionReset => (),
+                        Err(err) => pipe_failed(&observed_task.full_name, "control requests", err),
+                        Ok(()) => (),
+                    }
+                    observed_task.control_decoder.append(&data);
+                    while let Some(req) = observed_task.control_decoder.try_decode() {
+                        match req {
+                            ControlRequest::Lease(req) => {
+                                if let Some(reply) = leases.request(observed_task.pid, req) {
+                                    // The task might have died already.
+                                    let _ = serialize_and_write(
+                                        &mut observed_task.control,
+                                        &ControlMessage::Leased(reply),
+                                    );
+                                }
+                            }
+                            ControlRequest::ExtendTimeout(extension) => {
+                                let granted = extension.min(
+                                    extension_budget
+                                        .saturating_sub(observed_task.timeout_extension),
+                                );
+                                observed_task.timeout_extension += granted;
+                                let _ = serialize_and_write(
+                                    &mut observed_task.control,
+                                    &ControlMessage::TimeoutExtended(granted),
+                                );
+                            }
+                        }
                     }
                 }
+                InputSource::Control => (),
             }
         }
 
+        if kill_all
+            || interrupted_at
+                .map(|t| t.elapsed() >= CANCELLATION_NOTICE)
+                .unwrap_or(false)
+        {
+            for pid in observed_tasks
+                .keys()
+                .chain(idle_workers.iter().map(|w| &w.pid))
+                .chain(zygote.iter().map(|z| &z.pid))
+            {
+                eprintln!("Killing process group {:?}...", *pid);
+                let _ = killpg(*pid, Signal::SIGKILL);
+            }
+
+            if let Some(ref mut js) = jobserver {
+                for token in tokens.drain(..) {
+                    js.release(token);
+                }
+            }
+
+            std::process::exit(1)
+        }
+
+        if let Some(descendants) = &mut descendants {
+            descendants.scan(
+                observed_tasks
+                    .values()
+                    .filter(|task| task.worker.is_none())
+                    .map(|task| task.pid),
+            );
+        }
+
+        // Waiting for each of hundreds of tests after every event
+        // would keep the driver busy, so only the tests whose pipes
+        // closed are checked, and all of them every poll timeout.
+        let check_all = checked_at.elapsed() >= poll_timeout;
+        if check_all {
+            checked_at = Instant::now();
+        }
         for (pid, observed_task) in observed_tasks.iter_mut() {
-            if observed_task.status_and_duration.is_none() {
+            if observed_task.status_and_duration.is_none()
+                && (check_all || observed_task.pipe_closed)
+            {
                 let duration = observed_task.started_at.elapsed();
+                let task_timeout = timeout + observed_task.timeout_extension;
 
                 let mut maybe_status =
-                    match waitpid(Some(observed_task.pid), Some(WaitPidFlag::WNOHANG)).unwrap() {
-                        WaitStatus::Exited(_, code) => Some(if code == 0 {
+                    match waitpid(Some(observed_task.pid), Some(WaitPidFlag::WNOHANG)) {
+                        Ok(WaitStatus::Exited(_, code)) => Some(if code == 0 {
                             (Status::Success, duration)
                         } else {
                             (Status::Failure(code), duration)
                         }),
-                        WaitStatus::Signaled(_, sig, _) => {
+                        Ok(WaitStatus::Signaled(_, sig, core_dumped)) => {
+                            if core_dumped {
+                                observed_task.core_signal = Some(sig);
+                            }
                             Some((Status::Signaled(sig.as_str()), duration))
                         }
-                        _ => None,
+                        // Checked again at the next iteration.
+                        Ok(_) | Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => None,
+                        Err(err) => panic!("failed to wait for {}: {}", observed_task.pid, err),
                     };
 
-                if maybe_status.is_none() && duration >= timeout {
-                    killpg(observed_task.pid, Signal::SIGKILL).unwrap();
-                    maybe_status = Some((Status::Timeout, duration));
+                if let (None, Some((_, started_at, stage_timeout))) =
+                    (&maybe_status, &observed_task.timed_stage)
+                {
+                    if started_at.elapsed() >= *stage_timeout {
+                        observed_task.stage_timed_out = true;
+                        let (stage_name, started_at, _) = observed_task.timed_stage.take().unwrap();
+                        observed_task.current_stage = None;
+                        let stage_rep = StageReport {
+                            stage_name,
+                            status: StageStatus::Timeout,
+                            duration: started_at.elapsed(),
+                            message: None,
+                            attachments: Vec::new(),
+                            usage: None,
+                        };
+                        report.on_event(&ReportEvent::Stage {
+                            full_name: &observed_task.full_name,
+                            stage: &stage_rep,
+                        });
+                        observed_task.stages.push(stage_rep);
+                    }
                 }
 
+                if let (None, Some(limit)) = (&maybe_status, config.memory_limit) {
+                    if !observed_task.memory_exceeded
+                        && resident_bytes(observed_task.pid).is_some_and(|bytes| bytes > limit)
+                    {
+                        // The task might have died already.
+                        let _ = killpg(observed_task.pid, Signal::SIGKILL);
+                        observed_task.memory_exceeded = true;
+                    }
+                }
+
+                if maybe_status.is_none() && duration + CANCELLATION_NOTICE >= task_timeout {
+                    observed_task.cancel();
+                }
+
+                if maybe_status.is_none()
+                    && (duration >= task_timeout || observed_task.stage_timed_out)
+                {
+                    match (timeout_signal, observed_task.timeout_warned_at) {
+                        (Some(sig), None) => {
+                            let _ = killpg(observed_task.pid, sig);
+                            observed_task.timeout_warned_at = Some(Instant::now());
+                        }
+                        (Some(_), Some(t)) if t.elapsed() < TIMEOUT_SIGNAL_GRACE => (),
+                        _ => {
+                            killpg(observed_task.pid, Signal::SIGKILL).unwrap();
+                            if let Some(descendants) = &mut descendants {
+                                descendants.kill(observed_task.pid);
+                            }
+                            maybe_status = Some((Status::Timeout, duration));
+                        }
+                    }
+                }
+
+                // The leaked processes are killed as soon as the task
+                // exits, they may hold its pipes open.
+                if let (Some(descendants), Some(_), None) =
+                    (&mut descendants, &maybe_status, &observed_task.worker)
+                {
+                    let pipes: Vec<RawFd> = [
+                        &observed_task.stdout_pipe,
+                        &observed_task.stderr_pipe,
+                        &observed_task.report_pipe,
+                    ]
+                    .iter()
+                    .filter_map(|pipe| pipe.as_ref().map(AsRawFd::as_raw_fd))
+                    .collect();
+                    observed_task.leaked_processes = descendants.finish(observed_task.pid, &pipes);
+                }
                 observed_task.status_and_duration = maybe_status;
             }
 
-            if let ObservedTask {
-                status_and_duration: Some(_),
-                stdout_pipe: None,
-                stderr_pipe: None,
-                ..
-            } = observed_task
-            {
+            if observed_task.is_complete() {
                 completed_pids.push(*pid);
             }
         }
 
         for pid in completed_pids.iter() {
-            let observed_task = observed_tasks.remove(pid).unwrap();
+            for (waiting_pid, lease) in leases.release(*pid) {
+                if let Some(waiting) = observed_tasks.get_mut(&waiting_pid) {
+                    let _ = serialize_and_write(
+                        &mut waiting.control,
+                        &ControlMessage::Leased(Ok(lease)),
+                    );
+                }
+            }
+            if exclusive_pid == Some(*pid) {
+                exclusive_pid = None;
+            }
+            if let Some(slots) = &mut cpu_slots {
+                slots.release(*pid);
+            }
+            let mut observed_task = observed_tasks.remove(pid).unwrap();
+            for tag in observed_task.tags.iter() {
+                *tag_counts.get_mut(tag).unwrap() -= 1;
+            }
+            if let Some(spool) = observed_task.stdout_spool.take() {
+                let bytes = spool.into_bytes().expect("failed to read STDOUT");
+                observed_task.stdout_buf.extend_from_slice(&bytes);
+            }
+            if let Some(spool) = observed_task.stderr_spool.take() {
+                let bytes = spool.into_bytes().expect("failed to read STDERR");
+                observed_task.stderr_buf.extend_from_slice(&bytes);
+            }
+            if config.nocapture {
+                flush_output(
+                    &mut std::io::stdout(),
+                    &observed_task.stdout_buf,
+                    &mut observed_task.stdout_offset,
+                );
+                flush_output(
+                    &mut std::io::stderr(),
+                    &observed_task.stderr_buf,
+                    &mut observed_task.stderr_offset,
+                );
+            }
             let (status, duration) = observed_task.status_and_duration.unwrap();
+            let leaked_fds = leaked_fds(observed_task.fds_at_start, observed_task.fds_at_exit);
+            let profiles = match config.coverage_dir {
+                Some(ref dir) => {
+                    collect_profiles(dir, &file_stem(&observed_task.full_name, observed_task.pid))
+                }
+                None => Vec::new(),
+            };
+            let core_dump = match (&config.core_dir, observed_task.core_signal) {
+                (Some(dir), Some(sig)) => coredump::collect(
+                    dir,
+                    &file_stem(&observed_task.full_name, observed_task.pid),
+                    observed_task.pid,
+                    sig as i32,
+                ),
+                _ => None,
+            };
+            let backtrace = core_dump.as_deref().and_then(coredump::backtrace);
+
+            let status = match (status, observed_task.skip_reason.take()) {
+                _ if observed_task.output_exceeded => Status::OutputExceeded,
+                _ if observed_task.memory_exceeded => Status::MemoryExceeded,
+                // The task might have exited on the warning signal.
+                _ if observed_task.timeout_warned_at.is_some() => Status::Timeout,
+                (Status::Failure(SKIP_EXIT_CODE), Some(reason)) => Status::Skipped(reason),
+                (status, _) => status,
+            };
+            // The stage the task was in when it timed out.
+            if let (Status::Timeout, Some((stage_name, started_at))) =
+                (&status, observed_task.current_stage.take())
+            {
+                let stage_rep = StageReport {
+                    stage_name,
+                    status: StageStatus::Timeout,
+                    duration: started_at.elapsed(),
+                    message: None,
+                    attachments: Vec::new(),
+                    usage: None,
+                };
+                report.on_event(&ReportEvent::Stage {
+                    full_name: &observed_task.full_name,
+                    stage: &stage_rep,
+                });
+                observed_task.stages.push(stage_rep);
+            }
+            let status = match status {
+                Status::Signaled("SIGABRT") | Status::Signaled("SIGSEGV") | Status::Failure(_) => {
+                    match sanitizer::parse(&String::from_utf8_lossy(&observed_task.stderr_buf)) {
+                        Some(err) => Status::SanitizerError(err),
+                        None => status,
+                    }
+                }
+                Status::Success if fd_leaks == LeakPolicy::Fail && !leaked_fds.is_empty() => {
+                    Status::LeakedFds(leaked_fds.len())
+                }
+                Status::Success if observed_task.deny_stderr && observed_task.stderr_bytes > 0 => {
+                    Status::WroteStderr(observed_task.stderr_bytes)
+                }
+                status => status,
+            };
+            let status = match status {
+                Status::Failure(_) | Status::Signaled(_) | Status::SanitizerError(_)
+                    if observed_task.expect_failure =>
+                {
+                    Status::ExpectedFailure
+                }
+                Status::Success if observed_task.expect_failure => {
+                    observed_task
+                        .stderr_buf
+                        .extend_from_slice(b"\nthe test passed but was expected to fail\n");
+                    Status::Failure(0)
+                }
+                status => status,
+            };
+            if let Some(limit) = config.max_output_bytes.filter(|_| !config.nocapture) {
+                finish_truncation(
+                    &mut observed_task.stdout_buf,
+                    limit,
+                    observed_task.stdout_dropped,
+                    config::Stream::Stdout,
+                    &mut observed_task.output_chunks,
+                );
+                finish_truncation(
+                    &mut observed_task.stderr_buf,
+                    limit,
+                    observed_task.stderr_dropped,
+                    config::Stream::Stderr,
+                    &mut observed_task.output_chunks,
+                );
+            }
+
+            let index = observed_task.index;
+            let mut previous_attempts = failed_attempts.remove(&index).unwrap_or_default();
+            let attempt = previous_attempts.len() + 1;
+            let status = match status {
+                Status::Success if attempt > 1 => Status::Flaky { attempts: attempt },
+                status => status,
+            };
 
             let completed_task = CompletedTask {
                 full_name: observed_task.full_name,
@@ -662,65 +3830,1268 @@ pub fn execute(
                 stdout: observed_task.stdout_buf,
                 stderr: observed_task.stderr_buf,
                 status,
+                leaked_fds,
+                leaked_processes: observed_task.leaked_processes,
+                profiles,
+                core_dump,
+                backtrace,
+                start_time: observed_task.start_time,
+                attempt,
+                pid: Some(pid.as_raw()),
+                timeout: Some(timeout + observed_task.timeout_extension),
+                tags: observed_task.tags,
+                metadata: observed_task.metadata,
+                category: None,
+                assertion: observed_task.assertion,
+                metrics: observed_task.metrics,
+                stages: observed_task.stages,
+                logs: observed_task.logs,
+                output_chunks: observed_task.output_chunks,
+                previous_attempts: Vec::new(),
             };
+            let mut completed_task = classify(config, completed_task);
 
-            report.report(&completed_task);
-            task_results.push(completed_task);
+            if !completed_task.status.is_ok()
+                && attempt <= config.retries.unwrap_or(0)
+                && interrupted_at.is_none()
+            {
+                report.on_event(&ReportEvent::Retried(&completed_task));
+                previous_attempts.push(completed_task);
+                failed_attempts.insert(index, previous_attempts);
+                pending.push(index);
+            } else {
+                completed_task.previous_attempts = previous_attempts;
+                dependencies.finish(index, &completed_task.status);
+                report.on_event(&ReportEvent::TestFinished(&completed_task));
+                fixtures.release(&completed_task.tags);
+                task_results.push(completed_task);
+            }
+
+            if let Some(w) = observed_task.worker {
+                match (
+                    w.task_done,
+                    observed_task.stdout_pipe,
+                    observed_task.stderr_pipe,
+                    observed_task.report_pipe,
+                ) {
+                    (true, Some(mut stdout_pipe), Some(mut stderr_pipe), Some(mut report_pipe)) => {
+                        // Idle workers are not polled, their output is
+                        // picked up once they get a new task.
+                        let registry = poll.registry();
+                        registry.deregister(&mut stdout_pipe).unwrap();
+                        registry.deregister(&mut stderr_pipe).unwrap();
+                        registry.deregister(&mut report_pipe).unwrap();
+                        registry
+                            .deregister(&mut SourceFd(&observed_task.control.as_raw_fd()))
+                            .unwrap();
+                        let worker = IdleWorker {
+                            pid: *pid,
+                            suite: w.suite,
+                            commands: w.commands,
+                            control: observed_task.control,
+                            stdout_pipe,
+                            stderr_pipe,
+                            report_pipe,
+                            stdout_buf: w.stdout_rest,
+                            stderr_buf: w.stderr_rest,
+                            report_decoder: observed_task.report_decoder,
+                            control_decoder: observed_task.control_decoder,
+                        };
+                        // The worker of a suite isn't needed anymore
+                        // once the suite is done.
+                        match worker.suite {
+                            Some(ref suite)
+                                if !pending.iter().any(|i| {
+                                    let task = tasks[*i].as_ref().unwrap();
+                                    suite_of(&task.full_name) == *suite
+                                }) =>
+                            {
+                                retire_worker(worker)
+                            }
+                            _ => idle_workers.push(worker),
+                        }
+                    }
+                    _ => reap_worker(*pid),
+                }
+            }
         }
 
         completed_pids.clear();
+
+        if let Some(ref mut js) = jobserver {
+            while tokens.len() > observed_tasks.len().saturating_sub(1) {
+                js.release(tokens.pop().unwrap());
+            }
+        }
+    }
+
+    for worker in idle_workers {
+        retire_worker(worker);
     }
 
-    report.done();
-    task_results
+    if let Some(mut zygote) = zygote {
+        let _ = serialize_and_write(&mut zygote.control, &WorkerCommand::Exit);
+        let _ = waitpid(zygote.pid, None);
+    }
+
+    fixtures.release_all();
+
+    // Nothing started by the tests survives the run, e.g. on shared CI
+    // machines.
+    for (full_name, process) in daemons::sweep(&groups) {
+        eprintln!(
+            "Killed the process {} ({}) left running by {}",
+            process.pid,
+            process.command,
+            full_name.join("::")
+        );
+    }
+
+    // The artifacts of failed pipelines are kept for inspection.
+    let failed_pipelines: HashSet<&PathBuf> = task_results
+        .iter()
+        .filter(|task| !task.status.is_ok())
+        .filter_map(|task| artifacts_dirs.get(&task.full_name))
+        .collect();
+    for dir in artifacts_dirs.values().collect::<HashSet<_>>() {
+        if !failed_pipelines.contains(dir) {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+    if let Some(dir) = run_artifacts_dir {
+        let _ = std::fs::remove_dir(dir);
+    }
+
+    report.on_event(&ReportEvent::RunFinished);
+    if interrupted_at.is_some() {
+        std::process::exit(1)
+    }
 }
 
-mod test {
+pub(crate) mod test {
     #[allow(unused_imports)]
     use super::*;
 
+    /// Executes the tests of `tree` without reporting them, and returns
+    /// the completed tasks.  Test bodies write to the descriptors
+    /// directly, as the test harness captures `print!` of the thread.
+    #[allow(dead_code)]
+    pub(crate) fn run(config: &Config, tree: TestTree) -> Vec<CompletedTask> {
+        struct Silent;
+        impl Report for Silent {}
+        execute(config, make_plan(config, tree).unwrap(), &mut Silent)
+    }
+
     #[test]
-    fn make_token_is_correct() {
-        for src in vec![
-            InputSource::Stdout,
-            InputSource::Stderr,
-            InputSource::Report,
+    fn denied_stderr_counts_all_the_bytes_written() {
+        use crate::test_case;
+        let config = Config::default().deny_stderr().max_output_bytes(100);
+        let tasks = run(
+            &config,
+            test_case("noisy", || {
+                io::stderr().write_all(&[b'x'; 1000]).unwrap();
+            }),
+        );
+        assert_eq!(tasks[0].status, Status::WroteStderr(1000));
+        assert!(tasks[0].stderr.len() < 1000);
+    }
+
+    #[test]
+    fn small_polls_and_reads_capture_the_whole_output() {
+        use crate::{test_case, test_suite};
+        let config = Config::default()
+            .jobs(4)
+            .poll_timeout(Duration::from_millis(10))
+            .poll_events(1)
+            .read_buffer(3);
+        let mut tests: Vec<TestTree> = (0..4)
+            .map(|i| {
+                test_case(format!("t{}", i), move || {
+                    io::stdout()
+                        .write_all(format!("output of test {}\n", i).as_bytes())
+                        .unwrap();
+                })
+            })
+            .collect();
+        // The pipes close long before the test exits.
+        tests.push(test_case("closed", || {
+            // SAFETY: the test writes nothing after closing them.
+            unsafe {
+                nix::libc::close(1);
+                nix::libc::close(2);
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }));
+        let tasks = run(&config, test_suite("s", tests));
+        assert_eq!(tasks.len(), 5);
+        for task in tasks {
+            assert_eq!(task.status, Status::Success, "{:?}", task.full_name);
+            match task.full_name[1].strip_prefix('t') {
+                Some(i) => assert_eq!(task.stdout, format!("output of test {}\n", i).as_bytes()),
+                None => assert!(task.duration >= Duration::from_millis(200)),
+            }
+        }
+    }
+
+    #[test]
+    fn a_panicking_driver_kills_the_processes_it_started() {
+        use crate::{tag, test_case};
+        let dir = std::env::temp_dir().join(format!("raclette-panic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (pid_file, other_file) = (dir.join("pid"), dir.join("other"));
+        let torn_down = dir.join("torn-down");
+        let read_pid =
+            |path: &Path| Pid::from_raw(std::fs::read_to_string(path).unwrap().parse().unwrap());
+
+        // Panics once the test process is running.
+        struct Panicking(PathBuf);
+        impl Report for Panicking {
+            fn on_event(&mut self, event: &ReportEvent) {
+                if let (ReportEvent::Tick, true) = (event, self.0.exists()) {
+                    panic!("the report failed");
+                }
+            }
+        }
+        match fork().unwrap() {
+            ForkResult::Child => {
+                let other = std::process::Command::new("sleep")
+                    .arg("60")
+                    .spawn()
+                    .unwrap();
+                std::fs::write(&other_file, other.id().to_string()).unwrap();
+                let teardown_file = torn_down.clone();
+                let config = Config::default().global_fixture(
+                    "db",
+                    || (),
+                    move || std::fs::write(&teardown_file, "").unwrap(),
+                );
+                let written = pid_file.clone();
+                let tree = tag(
+                    "db",
+                    test_case("a", move || {
+                        let tmp = written.with_extension("tmp");
+                        std::fs::write(&tmp, std::process::id().to_string()).unwrap();
+                        std::fs::rename(&tmp, &written).unwrap();
+                        std::thread::sleep(Duration::from_secs(60));
+                    }),
+                );
+                execute(
+                    &config,
+                    make_plan(&config, tree).unwrap(),
+                    &mut Panicking(pid_file.clone()),
+                );
+                std::process::exit(0)
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(
+                    waitpid(child, None).unwrap(),
+                    WaitStatus::Exited(child, 101)
+                );
+            }
+        }
+        let gone = |pid| nix::sys::signal::kill(pid, None).is_err();
+        assert!(gone(read_pid(&pid_file)));
+        assert!(torn_down.exists());
+        let other = read_pid(&other_file);
+        assert!(!gone(other));
+        let _ = nix::sys::signal::kill(other, Signal::SIGKILL);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn tasks_sharing_a_worker_get_their_own_output() {
+        use crate::{test_case, test_suite};
+        let config = Config::default().prefork().jobs(1);
+        let tests = (0..5)
+            .map(|i| {
+                test_case(format!("t{}", i), move || {
+                    io::stdout()
+                        .write_all(format!("output of test {}\n", i).as_bytes())
+                        .unwrap();
+                    io::stdout().flush().unwrap();
+                })
+            })
+            .collect();
+        let tasks = run(&config, test_suite("s", tests));
+        assert_eq!(tasks.len(), 5);
+        // The worker executed all of them.
+        assert!(tasks.iter().all(|task| task.pid == tasks[0].pid));
+        for task in tasks {
+            let i = task.full_name[1].strip_prefix('t').unwrap();
+            assert_eq!(task.stdout, format!("output of test {}\n", i).as_bytes());
+        }
+    }
+
+    #[test]
+    fn tests_make_requests_over_the_control_socket() {
+        use crate::{test_case_ctx, test_suite};
+        let configure = |config: Config| {
+            config
+                .jobs(2)
+                .resource_pool("db", vec!["one".to_string()])
+                .timeout_extension(Duration::from_secs(1))
+        };
+        for config in [
+            configure(Config::default()),
+            configure(Config::default().prefork()),
+            configure(Config::default().zygote()),
         ] {
-            assert_eq!(
-                split_token(make_token(Pid::this(), src.clone())),
-                (Pid::this(), src)
-            )
+            let tests = (0..2)
+                .map(|i| {
+                    test_case_ctx(format!("t{}", i), |mut ctx| {
+                        let resource = ctx.lease_resource("db");
+                        let granted = ctx.extend_timeout(Duration::from_secs(5));
+                        io::stdout()
+                            .write_all(format!("{} {:?}", resource, granted).as_bytes())
+                            .unwrap();
+                    })
+                })
+                .collect();
+            let tasks = run(&config, test_suite("s", tests));
+            assert_eq!(tasks.len(), 2);
+            for task in tasks {
+                assert_eq!(task.status, Status::Success);
+                assert_eq!(task.stdout, b"one 1s");
+            }
         }
     }
 
+    #[test]
+    fn tests_extending_their_timeout_outlive_it() {
+        use crate::{test_case_ctx, test_suite};
+        let timeout = Duration::from_millis(300);
+        let config = Config::default()
+            .timeout(timeout)
+            .timeout_extension(Duration::from_secs(5));
+        let tree = test_suite(
+            "s",
+            vec![
+                test_case_ctx("extended", move |mut ctx| {
+                    assert_eq!(
+                        ctx.extend_timeout(Duration::from_secs(1)),
+                        Duration::from_secs(1)
+                    );
+                    std::thread::sleep(timeout * 2);
+                }),
+                test_case_ctx("not_extended", move |_| std::thread::sleep(timeout * 2)),
+            ],
+        );
+        let mut tasks = run(&config, tree);
+        tasks.sort_by(|a, b| a.full_name.cmp(&b.full_name));
+        assert_eq!(tasks[0].status, Status::Success);
+        assert!(tasks[0].duration >= timeout * 2);
+        assert_eq!(tasks[1].status, Status::Timeout);
+    }
+
+    #[test]
+    fn timed_out_stages_get_the_warning_signal() {
+        use crate::test_case_ctx;
+        use nix::sys::signal::{signal, SigHandler};
+        extern "C" fn warned(_: nix::libc::c_int) {
+            // SAFETY: write(2) is async-signal-safe.
+            unsafe { nix::libc::write(2, b"warned".as_ptr().cast(), 6) };
+        }
+        let tree = test_case_ctx("slow_stage", |mut ctx| {
+            // SAFETY: the handler only calls write(2).
+            unsafe { signal(Signal::SIGUSR1, SigHandler::Handler(warned)) }.unwrap();
+            ctx.begin_stage_with_timeout("stage", Duration::from_millis(100));
+            std::thread::sleep(Duration::from_secs(10));
+        });
+        let tasks = run(&Config::default(), tree);
+        assert_eq!(tasks[0].status, Status::Timeout);
+        assert_eq!(tasks[0].stderr, b"warned");
+        assert_eq!(tasks[0].stages.len(), 1);
+        assert_eq!(tasks[0].stages[0].status, StageStatus::Timeout);
+        assert!(tasks[0].duration >= TIMEOUT_SIGNAL_GRACE);
+    }
+
+    #[test]
+    fn stages_run_with_the_context_report_their_outcome() {
+        use crate::test_case_ctx;
+        let tree = test_case_ctx("staged", |mut ctx| {
+            let artifact = ctx.stage("build", || {
+                std::thread::sleep(Duration::from_millis(50));
+                "binary"
+            });
+            assert_eq!(artifact, "binary");
+            ctx.try_stage("check", || Err::<(), _>("checksum mismatch"));
+        });
+        let tasks = run(&Config::default(), tree);
+        assert_eq!(tasks[0].status, Status::Failure(PANIC_EXIT_CODE));
+        let stages = &tasks[0].stages;
+        assert_eq!(stages.len(), 2);
+        assert_eq!(stages[0].stage_name, "build");
+        assert_eq!(stages[0].status, StageStatus::Success);
+        assert!(stages[0].duration >= Duration::from_millis(50));
+        assert_eq!(stages[1].stage_name, "check");
+        assert_eq!(stages[1].status, StageStatus::Failure(PANIC_EXIT_CODE));
+        assert_eq!(stages[1].message.as_deref(), Some("checksum mismatch"));
+    }
+
+    #[test]
+    fn planned_stages_after_a_failed_stage_are_not_run() {
+        use crate::test_case_ctx;
+        let tree = test_case_ctx("planned", |mut ctx| {
+            ctx.plan_stages(&["build", "test", "deploy", "verify"]);
+            ctx.stage("build", || ());
+            ctx.stage("test", || panic!("1 test failed"));
+        });
+        let tasks = run(&Config::default(), tree);
+        assert_eq!(tasks[0].status, Status::Failure(PANIC_EXIT_CODE));
+        let stages: Vec<_> = tasks[0]
+            .stages
+            .iter()
+            .map(|stage| (stage.stage_name.as_str(), stage.status.clone()))
+            .collect();
+        let not_run = StageStatus::Skipped(NOT_RUN.to_string());
+        assert_eq!(
+            stages,
+            vec![
+                ("build", StageStatus::Success),
+                ("test", StageStatus::Failure(PANIC_EXIT_CODE)),
+                ("deploy", not_run.clone()),
+                ("verify", not_run),
+            ]
+        );
+    }
+
+    #[test]
+    fn attachments_are_sent_with_the_stage_they_belong_to() {
+        use crate::test_case_ctx;
+        let tree = test_case_ctx("attached", |mut ctx| {
+            ctx.stage("build", || ());
+            ctx.attach_text("log", "linking\ndone");
+            ctx.attach_file("/tmp/image.tar");
+            ctx.report_stage_status("deploy", StageStatus::Success);
+            ctx.stage("verify", || ());
+        });
+        let tasks = run(&Config::default(), tree);
+        assert_eq!(tasks[0].status, Status::Success);
+        let attachments: Vec<_> = tasks[0]
+            .stages
+            .iter()
+            .map(|stage| stage.attachments.clone())
+            .collect();
+        assert_eq!(
+            attachments,
+            vec![
+                vec![],
+                vec![
+                    Attachment::Text {
+                        name: "log".to_string(),
+                        text: "linking\ndone".to_string(),
+                    },
+                    Attachment::File(PathBuf::from("/tmp/image.tar")),
+                ],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn stages_in_progress_are_not_reported_as_finished() {
+        use crate::test_case_ctx;
+        #[derive(Default)]
+        struct Stages {
+            finished: Vec<(String, StageStatus)>,
+            in_progress: Vec<String>,
+        }
+        impl Report for Stages {
+            fn stage(&mut self, _full_name: &[String], stage_rep: StageReport) {
+                self.finished.push((stage_rep.stage_name, stage_rep.status));
+            }
+            fn stage_in_progress(&mut self, _full_name: &[String], stage: &str, _: Duration) {
+                self.in_progress.push(stage.to_string());
+            }
+        }
+        let tree = test_case_ctx("stuck", |mut ctx| {
+            ctx.report_stage_in_progress("build");
+            ctx.report_stage_status("build", StageStatus::Success);
+            ctx.report_stage_in_progress("deploy");
+            std::thread::sleep(Duration::from_secs(10));
+        });
+        let config = Config::default()
+            .timeout(Duration::from_millis(300))
+            .timeout_signal(None);
+        let mut report = Stages::default();
+        let tasks = execute(&config, make_plan(&config, tree).unwrap(), &mut report);
+        assert_eq!(tasks[0].status, Status::Timeout);
+        assert_eq!(report.in_progress, vec!["build", "deploy"]);
+        let expected = vec![
+            ("build".to_string(), StageStatus::Success),
+            ("deploy".to_string(), StageStatus::Timeout),
+        ];
+        assert_eq!(report.finished, expected);
+    }
+
+    #[test]
+    fn virtual_start_times_follow_the_plan() {
+        use crate::{depends_on, test_case, test_suite};
+        // The dependency makes "a" start after "b" and "c".
+        let tree = test_suite(
+            "s",
+            vec![
+                depends_on("b", test_case("a", || ())),
+                test_case("b", || std::thread::sleep(Duration::from_millis(100))),
+                test_case("c", || ()),
+            ],
+        );
+        let config = Config::default().jobs(2).clock(VirtualClock);
+        let mut tasks = run(&config, tree);
+        assert_eq!(tasks[0].name(), "s::c");
+        tasks.sort_by_key(|task| task.name());
+        let start_times: Vec<_> = tasks.iter().map(|task| task.start_time).collect();
+        let epoch = std::time::UNIX_EPOCH;
+        let expected: Vec<_> = (1..=3).map(|s| epoch + Duration::from_secs(s)).collect();
+        assert_eq!(start_times, expected);
+    }
+
+    #[test]
+    fn failed_assertions_are_reported_with_their_values() {
+        use crate::{test_case_ctx, test_suite};
+        let tree = test_suite(
+            "s",
+            vec![
+                test_case_ctx("eq", |mut ctx| ctx.assert_eq(1, 2)),
+                test_case_ctx("fail_with", |mut ctx| {
+                    ctx.fail_with("too slow", "< 1s", "3s")
+                }),
+            ],
+        );
+        let tasks = run(&Config::default(), tree);
+        let failure = |message: &str, expected: &str, actual: &str| {
+            Some(AssertionFailure {
+                message: message.to_string(),
+                expected: expected.to_string(),
+                actual: actual.to_string(),
+            })
+        };
+        assert_eq!(tasks[0].status, Status::Failure(PANIC_EXIT_CODE));
+        assert_eq!(
+            tasks[0].assertion,
+            failure("assertion failed: expected == actual", "1", "2")
+        );
+        assert_eq!(
+            tasks[1].assertion,
+            failure("too slow", "\"< 1s\"", "\"3s\"")
+        );
+    }
+
+    #[test]
+    fn the_classifier_categorizes_failed_tests() {
+        use crate::{test_case, test_suite};
+        let tree = test_suite(
+            "s",
+            vec![
+                test_case("infra", || {
+                    io::stderr().write_all(b"connection refused\n").unwrap();
+                    std::process::exit(1)
+                }),
+                test_case("product", || std::process::exit(1)),
+                test_case("passes", || {
+                    io::stderr().write_all(b"connection refused\n").unwrap()
+                }),
+            ],
+        );
+        let config = Config::default().classifier(|task| {
+            let infra = task.stderr_as_string().contains("connection refused");
+            Some(if infra { "infra" } else { "product" }.to_string())
+        });
+        let tasks = run(&config, tree);
+        let categories: Vec<_> = tasks.iter().map(|task| task.category.as_deref()).collect();
+        assert_eq!(categories, vec![Some("infra"), Some("product"), None]);
+    }
+
+    #[test]
+    fn tests_are_cancelled_before_their_timeout() {
+        use crate::test_case_ctx;
+        let timeout = Duration::from_secs(3);
+        let tree = test_case_ctx("winds_down", move |mut ctx| {
+            let remaining = ctx.remaining_time();
+            assert!(remaining <= timeout && remaining > CANCELLATION_NOTICE);
+            assert!(!ctx.is_cancelled());
+            while !ctx.is_cancelled() {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert!(ctx.remaining_time() <= CANCELLATION_NOTICE);
+        });
+        let config = Config::default()
+            .timeout(timeout)
+            .poll_timeout(Duration::from_millis(10));
+        let tasks = run(&config, tree);
+        assert_eq!(tasks[0].status, Status::Success);
+        assert!(tasks[0].duration < timeout);
+    }
+
+    #[test]
+    fn snapshots_are_compared_or_blessed() {
+        use crate::test_case_ctx;
+        let dir = std::env::temp_dir().join(format!("raclette-snapshots-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let snapshot = |content: &'static str| {
+            test_case_ctx("t", move |mut ctx| ctx.assert_snapshot("out", content))
+        };
+        let config = || Config::default().snapshot_dir(dir.clone());
+        let written = || {
+            let mut files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+            assert_eq!(files.len(), 1);
+            std::fs::read_to_string(files.pop().unwrap().unwrap().path()).unwrap()
+        };
+
+        let missing = run(&config(), snapshot("one"));
+        assert_eq!(missing[0].status, Status::Failure(PANIC_EXIT_CODE));
+        assert_eq!(missing[0].assertion, None);
+        assert!(!dir.exists());
+
+        let blessed = run(&config().bless(), snapshot("one"));
+        assert_eq!(blessed[0].status, Status::Success);
+        assert_eq!(written(), "one");
+
+        let matching = run(&config(), snapshot("one"));
+        assert_eq!(matching[0].status, Status::Success);
+
+        let mismatch = run(&config(), snapshot("two"));
+        assert_eq!(mismatch[0].status, Status::Failure(PANIC_EXIT_CODE));
+        let assertion = mismatch[0].assertion.as_ref().unwrap();
+        assert!(assertion.message.ends_with("doesn't match"));
+        assert_eq!((&*assertion.expected, &*assertion.actual), ("one", "two"));
+        assert_eq!(written(), "one");
+
+        let update = |var: &str| (var == "UPDATE_SNAPSHOTS").then(|| "1".into());
+        let updating = Config::from_env_vars(&update)
+            .unwrap()
+            .snapshot_dir(dir.clone());
+        assert_eq!(run(&updating, snapshot("two"))[0].status, Status::Success);
+        assert_eq!(written(), "two");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_tests_that_report_a_reason_are_skipped() {
+        use crate::{test_case, test_case_ctx, test_suite};
+        let tree = test_suite(
+            "s",
+            vec![
+                test_case_ctx("skipped", |mut ctx| ctx.skip("no docker")),
+                test_case("exits", || std::process::exit(SKIP_EXIT_CODE)),
+            ],
+        );
+        let tasks = run(&Config::default(), tree);
+        assert_eq!(tasks[0].status, Status::Skipped("no docker".to_string()));
+        assert_eq!(tasks[1].status, Status::Failure(SKIP_EXIT_CODE));
+    }
+
+    #[test]
+    fn tests_expected_to_fail_pass_only_if_they_fail() {
+        use crate::{expect_failure, test_case, test_suite};
+        let tree = expect_failure(test_suite(
+            "known",
+            vec![
+                test_case("broken", || panic!("still broken")),
+                test_case("fixed", || ()),
+            ],
+        ));
+        let tasks = run(&Config::default(), tree);
+        assert_eq!(tasks[0].status, Status::ExpectedFailure);
+        assert!(tasks[0].status.is_ok());
+        assert_eq!(tasks[1].status, Status::Failure(0));
+        assert!(tasks[1]
+            .stderr_as_string()
+            .ends_with("the test passed but was expected to fail\n"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tests_exceeding_the_memory_limit_are_killed() {
+        use crate::test_case;
+        let tree = test_case("hungry", || {
+            let memory = vec![1u8; 256 << 20];
+            std::thread::sleep(Duration::from_secs(10));
+            drop(memory);
+        });
+        let config = Config::default()
+            .memory_limit(64 << 20)
+            .poll_timeout(Duration::from_millis(10));
+        let tasks = run(&config, tree);
+        assert_eq!(tasks[0].status, Status::MemoryExceeded);
+        assert!(tasks[0].duration < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn make_token_is_correct() {
+        for task_id in [1, 2, 1 << 40] {
+            for src in InputSource::ALL {
+                assert_eq!(split_token(make_token(task_id, src)), (task_id, src));
+            }
+        }
+        assert_ne!(make_token(1, InputSource::Stdout), SIGNAL_TOKEN);
+    }
+
     #[test]
     fn stream_decoder_is_correct() {
         let s1 = StageReport {
             stage_name: "s1".to_string(),
             status: StageStatus::Success,
             duration: Duration::from_millis(111),
+            message: None,
+            attachments: Vec::new(),
+            usage: None,
         };
         let s2 = StageReport {
             stage_name: "s2".to_string(),
             status: StageStatus::Success,
             duration: Duration::from_millis(222),
+            message: None,
+            attachments: Vec::new(),
+            usage: None,
         };
         let s3 = StageReport {
             stage_name: "s3".to_string(),
             status: StageStatus::Failure(42),
             duration: Duration::from_millis(333),
+            message: None,
+            attachments: Vec::new(),
+            usage: None,
         };
 
+        let msgs = vec![
+            ReportMessage::Stage(s1),
+            ReportMessage::Stage(s2),
+            ReportMessage::Stage(s3),
+        ];
+
         let mut dec = StreamDecoder::new();
         let mut buf = Vec::new();
-        for s in vec![&s1, &s2, &s3] {
-            serialize_and_write(&mut buf, s).unwrap();
+        for m in msgs.iter() {
+            serialize_and_write(&mut buf, m).unwrap();
         }
 
         dec.append(&buf);
-        assert_eq!(dec.try_decode(), Some(s1));
-        assert_eq!(dec.try_decode(), Some(s2));
-        assert_eq!(dec.try_decode(), Some(s3));
+        for m in msgs {
+            assert_eq!(dec.try_decode(), Some(m));
+        }
         assert_eq!(dec.try_decode(), None);
     }
+
+    #[test]
+    fn read_available_survives_interruptions() {
+        // A reader returning the scripted results one by one, then
+        // reporting that nothing more is available.
+        struct Faulty(Vec<io::Result<&'static [u8]>>);
+        impl Read for Faulty {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                let bytes = self.0.remove(0)?;
+                buf[..bytes.len()].copy_from_slice(bytes);
+                Ok(bytes.len())
+            }
+        }
+        let interrupted = || Err(io::ErrorKind::Interrupted.into());
+
+        let mut buf = [0; 8];
+        let mut pipe = Faulty(vec![Ok(b"par"), interrupted(), Ok(b"tial"), interrupted()]);
+        let (data, result) = read_available(&mut pipe, &mut buf);
+        assert_eq!(data, b"partial");
+        assert!(result.is_ok());
+
+        let mut pipe = Faulty(vec![Ok(b"lost"), Err(io::ErrorKind::BrokenPipe.into())]);
+        let (data, result) = read_available(&mut pipe, &mut buf);
+        assert_eq!(data, b"lost");
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+
+        // The messages arrive in pieces of any size.
+        let mut stream = Vec::new();
+        serialize_and_write(&mut stream, &ReportMessage::TaskDone(3)).unwrap();
+        serialize_and_write(&mut stream, &ReportMessage::Skipped("no".to_string())).unwrap();
+        let mut decoder = StreamDecoder::<ReportMessage>::new();
+        let mut decoded = Vec::new();
+        for byte in stream.chunks(1) {
+            decoder.append(byte);
+            decoded.extend(decoder.try_decode());
+        }
+        assert_eq!(
+            decoded,
+            vec![
+                ReportMessage::TaskDone(3),
+                ReportMessage::Skipped("no".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn oversized_messages_are_refused() {
+        let mut buf = Vec::new();
+        let name = "x".repeat(MAX_MESSAGE_SIZE);
+        let err = serialize_and_write(&mut buf, &ReportMessage::Skipped(name)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+
+        // The decoder gives up on a stream that isn't made of messages.
+        let mut dec = StreamDecoder::<ReportMessage>::new();
+        dec.append(&[0xff; 64]);
+        assert_eq!(dec.try_decode(), None);
+        serialize_and_write(&mut buf, &ReportMessage::TaskDone(0)).unwrap();
+        dec.append(&buf);
+        assert_eq!(dec.try_decode(), None);
+    }
+
+    #[test]
+    fn make_plan_supports_exact_and_ignored() {
+        use crate::{skip, skip_if, tag, test_case, test_suite};
+        let tree = || {
+            test_suite(
+                "s",
+                vec![
+                    test_case("a", || ()),
+                    tag("db", test_case("ab", || ())),
+                    skip("slow", test_case("b", || ())),
+                    skip("slow", skip_if(|| true, "no docker", test_case("c", || ()))),
+                ],
+            )
+        };
+        let names = |config: &Config| -> Vec<String> {
+            make_plan(config, tree())
+                .unwrap()
+                .into_iter()
+                .map(|task| {
+                    let name = task.full_name.join("::");
+                    match task.options.skip_reason {
+                        Some(_) => format!("{} (skipped)", name),
+                        None => name,
+                    }
+                })
+                .collect()
+        };
+
+        let exact = Config::default().filter("s::a".to_string()).exact();
+        assert_eq!(names(&exact), vec!["s::a"]);
+        let not_exact = Config::default().filter("a".to_string());
+        assert_eq!(names(&not_exact), vec!["s::a", "s::ab"]);
+        let tagged = Config::default().filter("s::".to_string()).tag_filter("db");
+        assert_eq!(names(&tagged), vec!["s::ab"]);
+        let ignored = Config::default().run_ignored(RunIgnored::Only);
+        assert_eq!(names(&ignored), vec!["s::b", "s::c (skipped)"]);
+        let included = Config::default().run_ignored(RunIgnored::Yes);
+        assert_eq!(
+            names(&included),
+            vec!["s::a", "s::ab", "s::b", "s::c (skipped)"]
+        );
+        assert_eq!(
+            names(&Config::default()),
+            vec!["s::a", "s::ab", "s::b (skipped)", "s::c (skipped)"]
+        );
+        let shard = |index| names(&Config::default().shard(index, 3));
+        assert_eq!(shard(0), vec!["s::a", "s::c (skipped)"]);
+        assert_eq!(shard(1), vec!["s::ab"]);
+        assert_eq!(shard(2), vec!["s::b (skipped)"]);
+    }
+
+    #[test]
+    fn make_plan_evaluates_skip_conditions() {
+        use crate::{skip_if, test_case, test_suite};
+        let tree = test_suite(
+            "s",
+            vec![
+                skip_if(|| true, "no docker", test_case("a", || ())),
+                skip_if(|| false, "never", test_case("b", || ())),
+                skip_if(|| panic!("filtered out"), "", test_case("c", || ())),
+            ],
+        );
+        let config = Config::default().skip_filters(vec!["c".to_string()]);
+        let reasons: Vec<_> = make_plan(&config, tree)
+            .unwrap()
+            .into_iter()
+            .map(|task| task.options.skip_reason)
+            .collect();
+        assert_eq!(reasons, vec![Some("no docker".to_string()), None]);
+    }
+
+    #[test]
+    fn truncation_keeps_head_and_tail() {
+        use config::Stream::*;
+        let chunk = |stream, end| OutputChunk {
+            stream,
+            at: Duration::ZERO,
+            end,
+        };
+        let mut buf = b"0123456789abcdefghij".to_vec();
+        let mut chunks = vec![chunk(Stdout, 5), chunk(Stderr, 7), chunk(Stdout, 20)];
+        let mut dropped = 0;
+        truncate_output(&mut buf, 8, &mut dropped, Stdout, &mut chunks);
+        assert_eq!((buf.as_slice(), dropped), (&b"0123ghij"[..], 12));
+        finish_truncation(&mut buf, 8, dropped, Stdout, &mut chunks);
+        let marker = "\n[... 12 bytes truncated ...]\n";
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            format!("0123{}ghij", marker)
+        );
+        let ends: Vec<usize> = chunks.iter().map(|c| c.end).collect();
+        assert_eq!(ends, vec![4, 7, 8 + marker.len()]);
+    }
+
+    #[test]
+    fn make_plan_restricts_to_focused_tests() {
+        use crate::skip_if;
+        use crate::{only, test_case, test_suite};
+        let tree = || {
+            test_suite(
+                "s",
+                vec![
+                    skip_if(|| panic!("not focused"), "", test_case("a", || ())),
+                    only(test_suite(
+                        "t",
+                        vec![test_case("b", || ()), test_case("c", || ())],
+                    )),
+                ],
+            )
+        };
+        let names: Vec<_> = make_plan(&Config::default(), tree())
+            .unwrap()
+            .into_iter()
+            .map(|task| task.full_name.join("::"))
+            .collect();
+        assert_eq!(names, vec!["s::t::b", "s::t::c"]);
+        assert_eq!(
+            make_plan(&Config::default().forbid_only(), tree()).err(),
+            Some(PlanError::OnlyForbidden(vec![
+                "s::t::b".to_string(),
+                "s::t::c".to_string()
+            ]))
+        );
+    }
+
+    #[test]
+    fn pipeline_steps_share_artifacts() {
+        use crate::{pipeline_suite, test_case, test_suite};
+        let tree = test_suite(
+            "s",
+            vec![
+                pipeline_suite("p", vec![test_case("a", || ()), test_case("b", || ())]),
+                test_case("c", || ()),
+            ],
+        );
+        let config = Config::default().tmp_dir(PathBuf::from("/tmp/t"));
+        let plan = make_plan(&config, tree).unwrap();
+        let dir = PathBuf::from(format!(
+            "/tmp/t/raclette-artifacts-{}/s__p",
+            std::process::id()
+        ));
+        assert_eq!(plan[0].options.artifacts_dir.as_ref(), Some(&dir));
+        assert_eq!(plan[1].options.artifacts_dir.as_ref(), Some(&dir));
+        assert_eq!(plan[1].options.dependencies, vec!["a"]);
+        assert_eq!(plan[2].options.artifacts_dir, None);
+    }
+
+    #[test]
+    fn only_the_artifacts_of_failed_pipelines_are_kept() {
+        use crate::{pipeline_suite, test_case, test_suite};
+        let tmp = std::env::temp_dir().join(format!("raclette-kept-{}", std::process::id()));
+        // A driver that is gone, and a directory that isn't a run's.
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let stale = tmp.join(format!("{}{}", ARTIFACTS_PREFIX, child.id()));
+        let other = tmp.join(format!("{}other", ARTIFACTS_PREFIX));
+        std::fs::create_dir_all(&stale).unwrap();
+        std::fs::create_dir_all(&other).unwrap();
+
+        let tree = test_suite(
+            "s",
+            vec![
+                pipeline_suite("p", vec![test_case("a", || ())]),
+                pipeline_suite("q", vec![test_case("b", || panic!("failed"))]),
+            ],
+        );
+        let config = Config::default().tmp_dir(tmp.clone());
+        let tasks = run(&config, tree);
+        assert_eq!(tasks.len(), 2);
+
+        let run_dir = tmp.join(format!("{}{}", ARTIFACTS_PREFIX, std::process::id()));
+        assert!(!run_dir.join("s__p").exists());
+        assert!(run_dir.join("s__q").is_dir());
+        assert!(!stale.exists());
+        assert!(other.is_dir());
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn leaked_fds_only_reports_new_descriptors() {
+        let fd = |fd, target: &str| OpenFd {
+            fd,
+            target: target.to_string(),
+        };
+        let at_start = vec![fd(0, "/dev/null"), fd(3, "pipe:[1]")];
+        let at_exit = vec![fd(0, "/dev/null"), fd(4, "/tmp/leak"), fd(5, "socket:[2]")];
+
+        assert_eq!(
+            leaked_fds(Some(at_start.clone()), Some(at_exit.clone())),
+            vec![fd(4, "/tmp/leak"), fd(5, "socket:[2]")]
+        );
+        assert_eq!(leaked_fds(None, Some(at_exit)), vec![]);
+        assert_eq!(leaked_fds(Some(at_start.clone()), Some(at_start)), vec![]);
+    }
+
+    #[test]
+    fn suite_of_stops_below_the_root() {
+        let name = |parts: &[&str]| parts.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(suite_of(&name(&["all", "db", "sql", "t"])), "all::db");
+        assert_eq!(suite_of(&name(&["all", "t"])), "all");
+        assert_eq!(suite_of(&name(&["t"])), "");
+    }
+
+    #[test]
+    fn next_runnable_respects_tag_limits() {
+        let task = |tags: &[&str]| {
+            Some(Task {
+                full_name: vec!["t".to_string()],
+                work: Box::new(|_| ()),
+                options: Options {
+                    tags: tags.iter().map(|t| t.to_string()).collect(),
+                    ..Options::default()
+                },
+            })
+        };
+        let tasks = [task(&["db"]), task(&["db"]), task(&[])];
+        let config = Config::default().max_concurrency_for_tag("db", 1);
+        let mut pending = vec![2, 1, 0];
+
+        let mut counts = HashMap::new();
+        assert_eq!(
+            next_runnable(
+                &mut pending,
+                &tasks,
+                &config,
+                &counts,
+                &HashMap::new(),
+                &Dependencies::default(),
+                1
+            ),
+            Some(0)
+        );
+        counts.insert("db".to_string(), 1);
+        assert_eq!(
+            next_runnable(
+                &mut pending,
+                &tasks,
+                &config,
+                &counts,
+                &HashMap::new(),
+                &Dependencies::default(),
+                1
+            ),
+            Some(2)
+        );
+        assert_eq!(
+            next_runnable(
+                &mut pending,
+                &tasks,
+                &config,
+                &counts,
+                &HashMap::new(),
+                &Dependencies::default(),
+                1
+            ),
+            None
+        );
+        counts.insert("db".to_string(), 0);
+        assert_eq!(
+            next_runnable(
+                &mut pending,
+                &tasks,
+                &config,
+                &counts,
+                &HashMap::new(),
+                &Dependencies::default(),
+                1
+            ),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn next_runnable_runs_exclusive_tasks_alone() {
+        let task = |exclusive, skip_reason: Option<&str>| {
+            Some(Task {
+                full_name: vec!["t".to_string()],
+                work: Box::new(|_| ()),
+                options: Options {
+                    exclusive,
+                    skip_reason: skip_reason.map(String::from),
+                    ..Options::default()
+                },
+            })
+        };
+        let tasks = [task(true, None), task(false, None), task(true, Some("no"))];
+        let config = Config::default();
+        let mut pending = vec![1, 0];
+        let next = |pending: &mut Vec<usize>, running| {
+            next_runnable(
+                pending,
+                &tasks,
+                &config,
+                &HashMap::new(),
+                &HashMap::new(),
+                &Dependencies::default(),
+                running,
+            )
+        };
+
+        // The other task doesn't overtake the exclusive one.
+        assert_eq!(next(&mut pending, 1), None);
+        assert_eq!(pending, vec![1, 0]);
+        assert_eq!(next(&mut pending, 0), Some(0));
+        assert_eq!(next(&mut pending, 1), Some(1));
+        // Skipped tasks don't run anyway.
+        assert_eq!(next(&mut vec![2], 1), Some(2));
+    }
+
+    #[test]
+    fn dependencies_wait_for_their_tests() {
+        let task = |name: &[&str], dependencies: &[&str]| {
+            Some(Task {
+                full_name: name.iter().map(|n| n.to_string()).collect(),
+                work: Box::new(|_| ()),
+                options: Options {
+                    dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+                    ..Options::default()
+                },
+            })
+        };
+        let mut tasks = [
+            task(&["s", "provision", "vm"], &[]),
+            task(&["s", "deploy"], &["provision"]),
+            task(&["s", "smoke"], &["deploy"]),
+            task(&["s", "loop"], &["s::loop"]),
+        ];
+        let mut dependencies = Dependencies::resolve(&mut tasks, &[0, 1, 2, 3]);
+        assert_eq!(
+            tasks[3].as_ref().unwrap().skip_reason(),
+            Some("dependency cycle")
+        );
+        assert!(dependencies.are_done(0) && dependencies.are_done(3));
+        assert!(!dependencies.are_done(1));
+        dependencies.finish(0, &Status::Failure(1));
+        assert!(dependencies.are_done(1));
+        assert_eq!(dependencies.failed(1), Some("s::provision::vm"));
+        dependencies.finish(1, &Status::Skipped("dependency failed".to_string()));
+        assert_eq!(dependencies.failed(2), Some("s::deploy"));
+    }
+
+    #[test]
+    fn global_fixtures_live_while_needed() {
+        use std::sync::Arc;
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (up, down) = (log.clone(), log.clone());
+        let config = Config::default()
+            .global_fixture(
+                "db",
+                move || up.lock().unwrap().push("up"),
+                move || down.lock().unwrap().push("down"),
+            )
+            .global_fixture("net", || panic!("no network"), || ());
+        let task = |tags: &[&str]| {
+            Some(Task {
+                full_name: vec!["t".to_string()],
+                work: Box::new(|_| ()),
+                options: Options {
+                    tags: tags.iter().map(|t| t.to_string()).collect(),
+                    ..Options::default()
+                },
+            })
+        };
+        let tasks = [task(&["db"]), task(&[]), task(&["db"])];
+        let db = vec!["db".to_string()];
+        let mut fixtures = GlobalFixtures::new(&config, &tasks, &[0, 1, 2]);
+        let wait = |fixtures: &mut GlobalFixtures| {
+            while fixtures.busy() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        };
+        assert!(!fixtures.prepare(&db));
+        wait(&mut fixtures);
+        assert!(fixtures.prepare(&db));
+        fixtures.acquire(&db).unwrap();
+        fixtures.acquire(&db).unwrap();
+        fixtures.release(&db);
+        wait(&mut fixtures);
+        assert_eq!(*log.lock().unwrap(), vec!["up"]);
+        fixtures.release(&[]);
+        fixtures.release(&db);
+        wait(&mut fixtures);
+        assert_eq!(*log.lock().unwrap(), vec!["up", "down"]);
+        fixtures.release_all();
+        assert_eq!(log.lock().unwrap().len(), 2);
+
+        let net = vec!["net".to_string()];
+        assert!(!fixtures.prepare(&net));
+        wait(&mut fixtures);
+        assert_eq!(
+            fixtures.acquire(&net),
+            Err("global fixture net failed to set up: no network".to_string())
+        );
+    }
+
+    #[test]
+    fn launches_are_staggered_by_the_launch_delay() {
+        use crate::{test_case, test_suite};
+        let delay = Duration::from_millis(200);
+        let config = Config::default().jobs(3).launch_delay(delay);
+        let tree = test_suite(
+            "s",
+            vec![
+                test_case("a", || ()),
+                test_case("b", || ()),
+                test_case("c", || ()),
+            ],
+        );
+        let mut starts: Vec<SystemTime> = run(&config, tree)
+            .iter()
+            .map(|task| task.start_time)
+            .collect();
+        starts.sort();
+        for pair in starts.windows(2) {
+            assert!(pair[1].duration_since(pair[0]).unwrap() >= delay);
+        }
+    }
+
+    #[test]
+    fn timeouts_fire_while_a_fixture_is_set_up() {
+        use crate::{tag, test_case, test_suite};
+        let config = Config::default()
+            .jobs(2)
+            .timeout(Duration::from_millis(200))
+            .timeout_signal(None)
+            .global_fixture("db", || std::thread::sleep(Duration::from_secs(2)), || ());
+        let tree = test_suite(
+            "s",
+            vec![
+                test_case("a", || std::thread::sleep(Duration::from_secs(10))),
+                tag("db", test_case("b", || ())),
+            ],
+        );
+        let tasks = run(&config, tree);
+        let task = |name: &str| {
+            tasks
+                .iter()
+                .find(|task| task.full_name.last().unwrap() == name)
+                .unwrap()
+        };
+        assert_eq!(task("a").status, Status::Timeout);
+        assert!(task("a").duration < Duration::from_secs(1));
+        assert_eq!(task("b").status, Status::Success);
+    }
+
+    #[test]
+    fn failed_health_checks_give_the_skip_reason() {
+        let config = Config::default()
+            .health_check("net", || Ok(()))
+            .health_check("disk", || Err("full".to_string()));
+        assert_eq!(
+            check_health(&config).as_deref(),
+            Some("environment unhealthy: disk: full")
+        );
+        assert_eq!(check_health(&Config::default()), None);
+    }
 }
@@ -0,0 +1,125 @@
+//! The selection of the tests to execute, see [Filter].
+
+/// Selects tests by their full names, e.g. "suite::test", and their
+/// tags.  A test is selected if, in this order of precedence:
+///
+/// 1. its name matches none of the skip filters,
+/// 2. its name matches all the name filters,
+/// 3. it is tagged with all the tags.
+///
+/// A filter matches the names it is a part of, e.g. both "suite::te"
+/// and "test" match "suite::test", or only the name itself once the
+/// filter is [Filter::exact].  An empty filter selects all the tests.
+///
+/// ```
+/// # use raclette::Filter;
+/// let filter = Filter::new().name("db").skip("slow").tag("nightly");
+/// let name = |s: &str| s.split("::").map(String::from).collect::<Vec<_>>();
+/// let tags = vec!["nightly".to_string()];
+/// assert!(filter.matches(&name("db::insert"), &tags));
+/// assert!(!filter.matches(&name("db::slow_insert"), &tags));
+/// assert!(!filter.matches(&name("db::insert"), &[]));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub(crate) names: Vec<String>,
+    pub(crate) skip: Vec<String>,
+    pub(crate) tags: Vec<String>,
+    pub(crate) exact: bool,
+}
+
+impl Filter {
+    /// A filter selecting all the tests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Selects only the tests whose names match `pattern`, on top of
+    /// the other name filters.
+    pub fn name(mut self, pattern: impl ToString) -> Self {
+        self.names.push(pattern.to_string());
+        self
+    }
+
+    /// Leaves out the tests whose names match `pattern`, whatever the
+    /// other filters.
+    pub fn skip(mut self, pattern: impl ToString) -> Self {
+        self.skip.push(pattern.to_string());
+        self
+    }
+
+    /// Selects only the tests tagged with `tag` (see [crate::tag]),
+    /// on top of the other tags.
+    pub fn tag(mut self, tag: impl ToString) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    /// Makes the name and skip filters match full test names exactly
+    /// instead of their parts.
+    pub fn exact(mut self) -> Self {
+        self.exact = true;
+        self
+    }
+
+    /// Whether the test named `full_name` and tagged with `tags` is
+    /// selected.
+    pub fn matches(&self, full_name: &[String], tags: &[String]) -> bool {
+        let name = full_name.join("::");
+        !self.skips(&name)
+            && self
+                .names
+                .iter()
+                .all(|pattern| self.name_matches(pattern, &name))
+            && self.tags.iter().all(|tag| tags.contains(tag))
+    }
+
+    /// Whether all the tests of the suite named `path` are left out,
+    /// so that the suite needs no further inspection.
+    pub(crate) fn skips_suite(&self, path: &[String]) -> bool {
+        !self.exact && self.skips(&path.join("::"))
+    }
+
+    fn skips(&self, name: &str) -> bool {
+        self.skip
+            .iter()
+            .any(|pattern| self.name_matches(pattern, name))
+    }
+
+    fn name_matches(&self, pattern: &str, name: &str) -> bool {
+        if self.exact {
+            name == pattern
+        } else {
+            name.contains(pattern)
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn filters_match_full_names() {
+        let name = |s: &str| s.split("::").map(String::from).collect::<Vec<_>>();
+        let filter = Filter::new().name("s::a");
+        assert!(filter.matches(&name("s::a"), &[]));
+        assert!(filter.matches(&name("s::ab"), &[]));
+        assert!(!filter.matches(&name("s::b::a"), &[]));
+
+        let both = Filter::new().name("s::").name("b");
+        assert!(both.matches(&name("s::b"), &[]));
+        assert!(!both.matches(&name("s::a"), &[]));
+        assert!(!both.matches(&name("b"), &[]));
+
+        let skipped = Filter::new().name("a").skip("s::a");
+        assert!(!skipped.matches(&name("s::a"), &[]));
+        assert!(skipped.matches(&name("t::a"), &[]));
+        assert!(skipped.skips_suite(&name("s::a")));
+        assert!(!skipped.exact().skips_suite(&name("s::a")));
+
+        let exact = Filter::new().name("s::a").exact();
+        assert!(exact.matches(&name("s::a"), &[]));
+        assert!(!exact.matches(&name("s::ab"), &[]));
+    }
+}
@@ -0,0 +1,133 @@
+//! Tests executing their body with random inputs until a time budget
+//! runs out, see [fuzz_case].
+
+#[cfg(feature = "fuzz")]
+use crate::{test_case_ctx, TestTree};
+#[cfg(feature = "fuzz")]
+use std::any::Any;
+#[cfg(feature = "fuzz")]
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+#[cfg(feature = "fuzz")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "fuzz")]
+pub use fastrand::Rng;
+
+/// Runs only the iteration with the given seed, to reproduce a
+/// failure.
+pub(crate) const SEED_VAR: &str = "RACLETTE_FUZZ_SEED";
+
+/// Creates a test that calls `body` with freshly seeded random number
+/// generators until `budget` elapses, the test is cancelled or an
+/// iteration fails.  On failure the seed of the failing iteration is
+/// printed, setting RACLETTE_FUZZ_SEED to it runs only that
+/// iteration.  The number of iterations per second is reported as
+/// the `iterations_per_sec` metric.
+#[cfg(feature = "fuzz")]
+pub fn fuzz_case(
+    name: impl ToString,
+    budget: Duration,
+    body: impl Fn(&mut Rng) + 'static,
+) -> TestTree {
+    test_case_ctx(name, move |mut ctx| {
+        if let Some(seed) = fixed_seed(&|var| std::env::var(var).ok()) {
+            body(&mut Rng::with_seed(seed));
+            return;
+        }
+
+        let started_at = Instant::now();
+        let keep_going =
+            |iterations| iterations == 0 || (started_at.elapsed() < budget && !ctx.is_cancelled());
+        match iterate(&mut Rng::new(), keep_going, &body) {
+            Ok(iterations) => {
+                let elapsed = started_at.elapsed().as_secs_f64();
+                ctx.report_metric("iterations_per_sec", iterations as f64 / elapsed);
+            }
+            Err(failure) => {
+                eprintln!("{}", failure);
+                resume_unwind(failure.payload)
+            }
+        }
+    })
+}
+
+/// The seed set with RACLETTE_FUZZ_SEED, looked up with `var`.
+#[cfg(feature = "fuzz")]
+fn fixed_seed(var: &dyn Fn(&str) -> Option<String>) -> Option<u64> {
+    var(SEED_VAR).and_then(|s| s.parse().ok())
+}
+
+/// A failed iteration of a fuzz test.
+#[cfg(feature = "fuzz")]
+struct Failure {
+    /// The number of iterations that passed before.
+    iterations: u64,
+    seed: u64,
+    payload: Box<dyn Any + Send>,
+}
+
+#[cfg(feature = "fuzz")]
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "fuzz_case failed after {} iterations with seed {}, rerun with {}={}",
+            self.iterations, self.seed, SEED_VAR, self.seed
+        )
+    }
+}
+
+/// Calls `body` with generators seeded from `seeds` as long as
+/// `keep_going` holds for the number of iterations so far.  Returns
+/// that number, or the first iteration that panicked.
+#[cfg(feature = "fuzz")]
+fn iterate(
+    seeds: &mut Rng,
+    mut keep_going: impl FnMut(u64) -> bool,
+    body: &impl Fn(&mut Rng),
+) -> Result<u64, Failure> {
+    let mut iterations = 0u64;
+    while keep_going(iterations) {
+        let seed = seeds.u64(..);
+        if let Err(payload) = catch_unwind(AssertUnwindSafe(|| body(&mut Rng::with_seed(seed)))) {
+            return Err(Failure {
+                iterations,
+                seed,
+                payload,
+            });
+        }
+        iterations += 1;
+    }
+    Ok(iterations)
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[cfg(feature = "fuzz")]
+    #[test]
+    fn failing_seeds_are_reported_and_reproduced() {
+        let body = |rng: &mut Rng| assert_ne!(rng.u8(..), 0, "unlucky input");
+        let failure = match iterate(&mut Rng::with_seed(42), |i| i < 100_000, &body) {
+            Err(failure) => failure,
+            Ok(_) => panic!("no iteration drew a zero"),
+        };
+        assert_eq!(
+            failure.to_string(),
+            format!(
+                "fuzz_case failed after {} iterations with seed {}, rerun with RACLETTE_FUZZ_SEED={}",
+                failure.iterations, failure.seed, failure.seed
+            )
+        );
+
+        let var = |name: &str| (name == SEED_VAR).then(|| failure.seed.to_string());
+        let seed = fixed_seed(&var).unwrap();
+        assert_eq!(seed, failure.seed);
+        assert!(catch_unwind(|| body(&mut Rng::with_seed(seed))).is_err());
+        assert_eq!(
+            iterate(&mut Rng::with_seed(42), |i| i < 3, &|_| ()).ok(),
+            Some(3)
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! A database of test outcomes accumulated over many runs, used to
+//! compute flake rates and duration trends.
+//!
+//! The database is an SQLite file with a single `outcomes` table, so
+//! it can also be queried directly.  Only available with the
+//! `history` feature.
+
+use crate::execution::{CompletedTask, Status};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use rusqlite::Error;
+
+/// The outcome of a test in a single run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outcome {
+    /// When the run the outcome belongs to was recorded.
+    pub recorded_at: SystemTime,
+    /// One of "passed", "failed", "flaky" or "skipped".
+    pub outcome: String,
+    pub duration: Duration,
+}
+
+/// The statistics of a test over the recorded runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestHistory {
+    pub name: String,
+    /// The number of runs the test wasn't skipped in.
+    pub runs: usize,
+    pub failed: usize,
+    pub flaky: usize,
+    /// The number of times the test passed after failing in the
+    /// previous run or vice versa.
+    pub flips: usize,
+    pub mean_duration: Duration,
+}
+
+impl TestHistory {
+    /// The fraction of runs in which the test changed its outcome or
+    /// only passed when retried.
+    pub fn flake_rate(&self) -> f64 {
+        if self.runs == 0 {
+            return 0.0;
+        }
+        (self.flips + self.flaky) as f64 / self.runs as f64
+    }
+}
+
+fn outcome(status: &Status) -> &'static str {
+    match status {
+        Status::Flaky { .. } => "flaky",
+        Status::Skipped(_) => "skipped",
+        status if status.is_ok() => "passed",
+        _ => "failed",
+    }
+}
+
+fn to_secs(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+pub struct History {
+    conn: Connection,
+}
+
+impl History {
+    /// Opens the database at `path`, creating it if necessary.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS outcomes (
+                 name TEXT NOT NULL,
+                 recorded_at REAL NOT NULL,
+                 outcome TEXT NOT NULL,
+                 duration REAL NOT NULL,
+                 attempt INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS outcomes_by_name ON outcomes (name, recorded_at);",
+        )?;
+        Ok(Self { conn })
+    }
+
+    /// Records the outcomes of the tasks of a run.
+    pub fn record(
+        &mut self,
+        recorded_at: SystemTime,
+        tasks: &[CompletedTask],
+    ) -> Result<(), Error> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO outcomes (name, recorded_at, outcome, duration, attempt)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for task in tasks {
+                insert.execute(params![
+                    task.name(),
+                    to_secs(recorded_at),
+                    outcome(&task.status),
+                    task.duration.as_secs_f64(),
+                    task.attempt as i64,
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Returns the recorded outcomes of a test, oldest first.
+    pub fn outcomes(&self, name: &str) -> Result<Vec<Outcome>, Error> {
+        let mut query = self.conn.prepare(
+            "SELECT recorded_at, outcome, duration FROM outcomes
+             WHERE name = ?1 ORDER BY recorded_at",
+        )?;
+        let rows = query.query_map(params![name], |row| {
+            Ok(Outcome {
+                recorded_at: UNIX_EPOCH + Duration::from_secs_f64(row.get(0)?),
+                outcome: row.get(1)?,
+                duration: Duration::from_secs_f64(row.get(2)?),
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Returns the statistics of every test recorded since `since`,
+    /// ordered by name.
+    pub fn tests(&self, since: SystemTime) -> Result<Vec<TestHistory>, Error> {
+        let mut query = self.conn.prepare(
+            "SELECT name, outcome, duration FROM outcomes
+             WHERE recorded_at >= ?1 AND outcome != 'skipped'
+             ORDER BY name, recorded_at",
+        )?;
+        let mut rows = query.query(params![to_secs(since)])?;
+
+        let mut tests: Vec<TestHistory> = Vec::new();
+        let mut previous: Option<String> = None;
+        let mut total_duration = 0.0;
+        while let Some(row) = rows.next()? {
+            let name: String = row.get(0)?;
+            let outcome: String = row.get(1)?;
+            let duration: f64 = row.get(2)?;
+            if tests.last().map(|t| &t.name) != Some(&name) {
+                total_duration = 0.0;
+                previous = None;
+                tests.push(TestHistory {
+                    name,
+                    runs: 0,
+                    failed: 0,
+                    flaky: 0,
+                    flips: 0,
+                    mean_duration: Duration::ZERO,
+                });
+            }
+            let test = tests.last_mut().unwrap();
+            test.runs += 1;
+            match outcome.as_str() {
+                "failed" => test.failed += 1,
+                "flaky" => test.flaky += 1,
+                _ => (),
+            }
+            let failed = outcome == "failed";
+            if previous.is_some_and(|p| (p == "failed") != failed) {
+                test.flips += 1;
+            }
+            previous = Some(outcome);
+            total_duration += duration;
+            test.mean_duration = Duration::from_secs_f64(total_duration / test.runs as f64);
+        }
+        Ok(tests)
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn history_tracks_flips() {
+        let path = std::env::temp_dir().join(format!("raclette-history-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut history = History::open(&path).unwrap();
+
+        let task = |status: Status, secs: u64| CompletedTask {
+            duration: Duration::from_secs(secs),
+            ..CompletedTask::new(vec!["t".to_string()], status, UNIX_EPOCH)
+        };
+        let runs = vec![
+            task(Status::Success, 1),
+            task(Status::Failure(1), 3),
+            task(Status::Skipped("later".to_string()), 0),
+            task(Status::Success, 2),
+        ];
+        for (i, run) in runs.into_iter().enumerate() {
+            let at = UNIX_EPOCH + Duration::from_secs(1000 + i as u64);
+            history.record(at, &[run]).unwrap();
+        }
+
+        assert_eq!(history.outcomes("t").unwrap().len(), 4);
+        let tests = history.tests(UNIX_EPOCH).unwrap();
+        assert_eq!(
+            tests,
+            vec![TestHistory {
+                name: "t".to_string(),
+                runs: 3,
+                failed: 1,
+                flaky: 0,
+                flips: 2,
+                mean_duration: Duration::from_secs(2),
+            }]
+        );
+        assert!((tests[0].flake_rate() - 2.0 / 3.0).abs() < 1e-9);
+        let _ = std::fs::remove_file(&path);
+    }
+}
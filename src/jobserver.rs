@@ -0,0 +1,127 @@
+//! A client of the GNU make jobserver.
+//!
+//! If raclette is started by make (or cargo) with a jobserver, the
+//! driver holds a job token for every test it runs in parallel
+//! except the first one, which uses the implicit token every process
+//! started by make has.  See "Job Slots" in the GNU make manual.
+
+use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
+use nix::sys::stat::Mode;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+/// The location of the jobserver advertised in MAKEFLAGS.
+#[derive(PartialEq, Debug)]
+enum Auth {
+    Fds(RawFd, RawFd),
+    Fifo(String),
+}
+
+/// Extracts the jobserver location from the value of MAKEFLAGS.
+fn parse_makeflags(flags: &str) -> Option<Auth> {
+    // Only the last occurrence counts if there are several.
+    let value = flags
+        .split_whitespace()
+        .filter_map(|arg| {
+            arg.strip_prefix("--jobserver-auth=")
+                .or_else(|| arg.strip_prefix("--jobserver-fds="))
+        })
+        .next_back()?;
+    if let Some(path) = value.strip_prefix("fifo:") {
+        return Some(Auth::Fifo(path.to_string()));
+    }
+    let mut fds = value.splitn(2, ',');
+    let r = fds.next()?.parse().ok()?;
+    let w = fds.next()?.parse().ok()?;
+    Some(Auth::Fds(r, w))
+}
+
+pub struct Jobserver {
+    read: File,
+    write: File,
+}
+
+impl Jobserver {
+    /// Connects to the jobserver advertised in the environment, if
+    /// any.
+    pub fn from_env() -> Option<Self> {
+        let flags = std::env::var("CARGO_MAKEFLAGS")
+            .or_else(|_| std::env::var("MAKEFLAGS"))
+            .ok()?;
+        let (read, write) = match parse_makeflags(&flags)? {
+            Auth::Fifo(path) => {
+                let read = open(
+                    path.as_str(),
+                    OFlag::O_RDONLY | OFlag::O_NONBLOCK,
+                    Mode::empty(),
+                );
+                let write = open(path.as_str(), OFlag::O_WRONLY, Mode::empty());
+                (read.ok()?, write.ok()?)
+            }
+            Auth::Fds(r, w) => {
+                // make doesn't pass the descriptors to commands it
+                // doesn't consider recursive invocations.
+                if fcntl(r, FcntlArg::F_GETFD).is_err() || fcntl(w, FcntlArg::F_GETFD).is_err() {
+                    return None;
+                }
+                // The pipe is shared with other processes, so we
+                // can't make it nonblocking.  Opening it anew gives
+                // us a file description of our own.
+                let path = format!("/proc/self/fd/{}", r);
+                let read = open(
+                    path.as_str(),
+                    OFlag::O_RDONLY | OFlag::O_NONBLOCK,
+                    Mode::empty(),
+                );
+                let write = nix::unistd::dup(w);
+                (read.ok()?, write.ok()?)
+            }
+        };
+        // SAFETY: both descriptors have just been opened by us.
+        unsafe {
+            Some(Self {
+                read: File::from_raw_fd(read),
+                write: File::from_raw_fd(write),
+            })
+        }
+    }
+
+    /// Takes a token from the jobserver if one is available.
+    pub fn try_acquire(&mut self) -> Option<u8> {
+        let mut token = [0u8; 1];
+        loop {
+            match self.read.read(&mut token) {
+                Ok(1) => return Some(token[0]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Returns a token to the jobserver.
+    pub fn release(&mut self, token: u8) {
+        self.write
+            .write_all(&[token])
+            .expect("failed to return a token to the jobserver");
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn parse_makeflags_finds_jobserver() {
+        assert_eq!(
+            parse_makeflags("-j --jobserver-fds=3,4 -j"),
+            Some(Auth::Fds(3, 4))
+        );
+        assert_eq!(
+            parse_makeflags("-j8 --jobserver-auth=3,4 --jobserver-auth=fifo:/tmp/GMfifo1"),
+            Some(Auth::Fifo("/tmp/GMfifo1".to_string()))
+        );
+        assert_eq!(parse_makeflags("-k -j4"), None);
+        assert_eq!(parse_makeflags("--jobserver-auth=3"), None);
+    }
+}
@@ -0,0 +1,1126 @@
+//! The events printed by the JSON report, one per line.
+//!
+//! Every event is an object with a "type" field (suite, test or
+//! stage) and an "event" field saying what happened.  The first event
+//! of a run carries the version of the schema, which is bumped on
+//! every incompatible change of these types.  Consumers can
+//! deserialize the events with the same types:
+//!
+//! ```
+//! use raclette::json::Event;
+//!
+//! let line = r#"{"type":"suite","event":"started","schema_version":2,"test_count":3}"#;
+//! let event: Event = serde_json::from_str(line).unwrap();
+//! ```
+
+use crate::baseline::Comparison;
+use crate::config::Config;
+use crate::execution::{
+    AssertionFailure, Attachment, CompletedTask, LeakedProcess, LogRecord, Metadata, Metric,
+    OpenFd, Report, ReportEvent, StageReport, StageStatus, StageUsage, Status, DEFAULT_TIMEOUT,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// The version of the schema described by the types in this module.
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Event {
+    Suite(SuiteEvent),
+    Test(TestEvent),
+    Stage(StageEvent),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum SuiteEvent {
+    Started {
+        schema_version: u32,
+        test_count: usize,
+    },
+    /// Describes the run, printed right after [SuiteEvent::Started].
+    Info(Box<RunInfo>),
+    Ok(SuiteSummary),
+    Failed(SuiteSummary),
+    /// The differences from [crate::Config::baseline], printed after
+    /// the end of the run.
+    Compared(BaselineComparison),
+}
+
+/// Where, when and how the tests were run, so that archived reports
+/// describe themselves.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RunInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub start_time: f64,
+    /// The version of raclette.
+    pub version: String,
+    pub jobs: usize,
+    /// The default timeout of the tests.
+    #[serde(with = "seconds")]
+    pub timeout: Duration,
+    /// The [RunInfo::filters] separated by spaces, only kept for the
+    /// readers of the reports of earlier versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// The filters of the test names, see [crate::Config::filter].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip_filters: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tag_filters: Vec<String>,
+    /// The seed of the fuzz tests, if fixed with RACLETTE_FUZZ_SEED.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// The commit checked out in the working directory, if it's in a
+    /// git repository.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_sha: Option<String>,
+    /// The command line of the test binary, only recorded with
+    /// [crate::Config::record_env].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command_line: Vec<String>,
+    /// The values of the variables selected with
+    /// [crate::Config::record_env] that are set.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+impl RunInfo {
+    /// Describes a run starting now with `config`.
+    pub(crate) fn collect(config: &Config) -> Self {
+        let mut buf = [0u8; 256];
+        let hostname = nix::unistd::gethostname(&mut buf)
+            .ok()
+            .map(|name| name.to_string_lossy().into_owned());
+        let git_sha = std::process::Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .stderr(std::process::Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+        let command_line = if config.record_env.is_empty() {
+            Vec::new()
+        } else {
+            std::env::args().collect()
+        };
+        let env = config
+            .record_env
+            .iter()
+            .filter_map(|var| Some((var.clone(), std::env::var(var).ok()?)))
+            .collect();
+        RunInfo {
+            hostname,
+            start_time: crate::execution::now(config)
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            jobs: config.jobs.unwrap_or_else(num_cpus::get),
+            timeout: config.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            filter: (!config.filters.is_empty()).then(|| config.filters.join(" ")),
+            filters: config.filters.clone(),
+            skip_filters: config.skip_filters.clone(),
+            tag_filters: config.tag_filters.clone(),
+            seed: std::env::var(crate::fuzz::SEED_VAR)
+                .ok()
+                .and_then(|s| s.parse().ok()),
+            git_sha,
+            command_line,
+            env,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SuiteSummary {
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+    pub flaky: usize,
+}
+
+/// The same lists as [Comparison], with the durations of the slower
+/// tests in seconds.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BaselineComparison {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub newly_failing: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub newly_passing: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub newly_skipped: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub slower: Vec<SlowerTest>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SlowerTest {
+    pub name: String,
+    #[serde(with = "seconds")]
+    pub baseline: Duration,
+    #[serde(with = "seconds")]
+    pub exec_time: Duration,
+}
+
+impl From<&Comparison> for BaselineComparison {
+    fn from(comparison: &Comparison) -> Self {
+        BaselineComparison {
+            newly_failing: comparison.newly_failing.clone(),
+            newly_passing: comparison.newly_passing.clone(),
+            newly_skipped: comparison.newly_skipped.clone(),
+            added: comparison.added.clone(),
+            removed: comparison.removed.clone(),
+            slower: comparison
+                .slower
+                .iter()
+                .map(|(name, baseline, exec_time)| SlowerTest {
+                    name: name.clone(),
+                    baseline: *baseline,
+                    exec_time: *exec_time,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl BaselineComparison {
+    pub fn to_comparison(&self) -> Comparison {
+        Comparison {
+            newly_failing: self.newly_failing.clone(),
+            newly_passing: self.newly_passing.clone(),
+            newly_skipped: self.newly_skipped.clone(),
+            added: self.added.clone(),
+            removed: self.removed.clone(),
+            slower: self
+                .slower
+                .iter()
+                .map(|test| (test.name.clone(), test.baseline, test.exec_time))
+                .collect(),
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum TestEvent {
+    /// The test is in the plan, printed when listing tests.
+    Discovered {
+        name: String,
+        /// The components of the name, which may contain "::"
+        /// themselves.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        name_parts: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<String>,
+        /// The timeout the test would run with.
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "opt_seconds")]
+        timeout: Option<Duration>,
+        /// Why the test would be skipped, None if it would run.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        skip_reason: Option<String>,
+    },
+    Started {
+        name: String,
+        /// The components of the name, which may contain "::"
+        /// themselves.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        name_parts: Vec<String>,
+    },
+    /// The test is still running, printed every
+    /// [crate::Config::heartbeat] interval.
+    Running {
+        name: String,
+        /// The components of the name, which may contain "::"
+        /// themselves.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        name_parts: Vec<String>,
+        #[serde(with = "seconds")]
+        elapsed: Duration,
+    },
+    Ok(TestResult),
+    Failed(TestResult),
+    Ignored(TestResult),
+    /// A failed attempt of a test that was retried, see
+    /// [crate::Config::retries].  The last attempt is reported as
+    /// usual.
+    Retried(TestResult),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TestResult {
+    pub name: String,
+    /// The components of the name, which may contain "::"
+    /// themselves.  Missing in reports of older versions, whose
+    /// names are split at "::" instead.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub name_parts: Vec<String>,
+    /// The exact outcome of the test, the event and the reason are
+    /// derived from it.
+    pub status: Status,
+    #[serde(with = "seconds")]
+    pub exec_time: Duration,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub stdout: String,
+    /// Set if `stdout` is encoded, only with `--base64-output`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout_encoding: Option<Encoding>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub stderr: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr_encoding: Option<Encoding>,
+    /// Why the test failed or was ignored, or how it passed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<f64>,
+    pub attempt: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "opt_seconds")]
+    pub timeout: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub leaked_fds: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub leaked_processes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub core_dump: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backtrace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assertion: Option<AssertionFailure>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub metrics: Vec<Metric>,
+    /// The stages the test reported, also printed as stage events
+    /// while the test runs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stages: Vec<StageResult>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub logs: Vec<LogRecord>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum StageEvent {
+    Ok(StageResult),
+    Failed(StageResult),
+    Ignored(StageResult),
+    /// A stage still in progress, see
+    /// [crate::TestContext::report_stage_in_progress].
+    Running {
+        /// The name of the test followed by the name of the stage.
+        name: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        name_parts: Vec<String>,
+        #[serde(with = "seconds")]
+        elapsed: Duration,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StageResult {
+    /// The name of the test followed by the name of the stage.
+    pub name: String,
+    /// The components of the name of the test followed by the name
+    /// of the stage.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub name_parts: Vec<String>,
+    pub status: StageStatus,
+    #[serde(with = "seconds")]
+    pub exec_time: Duration,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Why the stage failed, see [StageReport::message].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// See [StageUsage::cpu_time].
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "opt_seconds")]
+    pub cpu_time: Option<Duration>,
+    /// See [StageUsage::max_rss_growth_kb].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rss_growth_kb: Option<u64>,
+}
+
+/// Returns the event ("ok", "failed" or "ignored") and the reason
+/// corresponding to a status.
+pub(crate) fn outcome(status: &Status) -> (&'static str, Option<String>) {
+    match status {
+        Status::Success => ("ok", None),
+        Status::Failure(code) => (
+            "failed",
+            Some(format!("test process exited with code {}", code)),
+        ),
+        Status::Signaled(signame) => ("failed", Some(format!("killed by signal {}", signame))),
+        Status::Timeout => ("failed", Some("time limit exceeded".to_string())),
+        Status::Skipped(reason) => ("ignored", Some(reason.clone())),
+        Status::LeakedFds(n) => ("failed", Some(format!("leaked {} file descriptor(s)", n))),
+        Status::SanitizerError(err) => ("failed", Some(err.to_string())),
+        Status::Flaky { attempts } => ("ok", Some(format!("passed after {} attempts", attempts))),
+        Status::ExpectedFailure => ("ok", Some("failed as expected".to_string())),
+        Status::DriverError(err) => ("failed", Some(format!("driver error: {}", err))),
+        Status::MemoryExceeded => ("failed", Some("memory limit exceeded".to_string())),
+        Status::OutputExceeded => ("failed", Some("output limit exceeded".to_string())),
+        Status::WroteStderr(n) => ("failed", Some(format!("wrote {} byte(s) to stderr", n))),
+    }
+}
+
+/// How the output of a test is encoded in a string.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Base64,
+}
+
+/// Converts the output of a test to a string, replacing invalid UTF-8
+/// sequences unless `base64` is set.
+fn encode_output(output: &[u8], base64: bool) -> (String, Option<Encoding>) {
+    match std::str::from_utf8(output) {
+        Ok(s) => (s.to_string(), None),
+        Err(_) if base64 => (BASE64.encode(output), Some(Encoding::Base64)),
+        Err(_) => (String::from_utf8_lossy(output).into_owned(), None),
+    }
+}
+
+fn decode_output(output: &str, encoding: Option<Encoding>) -> Vec<u8> {
+    match encoding {
+        Some(Encoding::Base64) => BASE64
+            .decode(output)
+            .unwrap_or_else(|_| output.as_bytes().to_vec()),
+        None => output.as_bytes().to_vec(),
+    }
+}
+
+impl From<&CompletedTask> for TestEvent {
+    fn from(task: &CompletedTask) -> Self {
+        Self::new(task, false)
+    }
+}
+
+impl TestEvent {
+    /// Converts the result of a task, with the output that is not
+    /// valid UTF-8 encoded in base64 if `base64_output` is set.
+    pub fn new(task: &CompletedTask, base64_output: bool) -> Self {
+        let result = TestResult::new(task, base64_output);
+        match outcome(&task.status).0 {
+            "ok" => TestEvent::Ok(result),
+            "ignored" => TestEvent::Ignored(result),
+            _ => TestEvent::Failed(result),
+        }
+    }
+
+    /// Converts a failed attempt of a task that was retried.
+    pub fn retried(task: &CompletedTask, base64_output: bool) -> Self {
+        TestEvent::Retried(TestResult::new(task, base64_output))
+    }
+}
+
+impl TestResult {
+    fn new(task: &CompletedTask, base64_output: bool) -> Self {
+        let (_, reason) = outcome(&task.status);
+        let (stdout, stdout_encoding) = encode_output(&task.stdout, base64_output);
+        let (stderr, stderr_encoding) = encode_output(&task.stderr, base64_output);
+        TestResult {
+            name: task.name(),
+            name_parts: task.full_name.clone(),
+            status: task.status.clone(),
+            exec_time: task.duration,
+            stdout,
+            stdout_encoding,
+            stderr,
+            stderr_encoding,
+            reason,
+            start_time: task
+                .start_time
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|t| t.as_secs_f64()),
+            attempt: task.attempt,
+            pid: task.pid,
+            timeout: task.timeout,
+            tags: task.tags.clone(),
+            leaked_fds: task.leaked_fds.iter().map(|fd| fd.to_string()).collect(),
+            leaked_processes: task
+                .leaked_processes
+                .iter()
+                .map(|process| process.to_string())
+                .collect(),
+            profiles: task
+                .profiles
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect(),
+            core_dump: task
+                .core_dump
+                .as_ref()
+                .map(|p| p.to_string_lossy().into_owned()),
+            backtrace: task.backtrace.clone(),
+            description: task.metadata.description.clone(),
+            owner: task.metadata.owner.clone(),
+            links: task.metadata.links.clone(),
+            category: task.category.clone(),
+            assertion: task.assertion.clone(),
+            metrics: task.metrics.clone(),
+            stages: task
+                .stages
+                .iter()
+                .map(|stage| StageResult::new(&task.full_name, stage.clone()))
+                .collect(),
+            logs: task.logs.clone(),
+        }
+    }
+}
+
+impl StageEvent {
+    pub(crate) fn new(full_name: &[String], stage_rep: StageReport) -> Self {
+        let result = StageResult::new(full_name, stage_rep);
+        match outcome(&Status::from(result.status.clone())).0 {
+            "ok" => StageEvent::Ok(result),
+            "ignored" => StageEvent::Ignored(result),
+            _ => StageEvent::Failed(result),
+        }
+    }
+
+    pub(crate) fn running(full_name: &[String], stage: &str, elapsed: Duration) -> Self {
+        let mut name_parts = full_name.to_vec();
+        name_parts.push(stage.to_string());
+        StageEvent::Running {
+            name: name_parts.join("::"),
+            name_parts,
+            elapsed,
+        }
+    }
+}
+
+impl StageResult {
+    fn new(full_name: &[String], stage_rep: StageReport) -> Self {
+        let reason = outcome(&Status::from(stage_rep.status.clone())).1;
+        let mut name_parts = full_name.to_vec();
+        name_parts.push(stage_rep.stage_name.clone());
+        StageResult {
+            name: name_parts.join("::"),
+            name_parts,
+            status: stage_rep.status,
+            exec_time: stage_rep.duration,
+            reason,
+            message: stage_rep.message,
+            attachments: stage_rep.attachments,
+            cpu_time: stage_rep.usage.map(|usage| usage.cpu_time),
+            max_rss_growth_kb: stage_rep.usage.map(|usage| usage.max_rss_growth_kb),
+        }
+    }
+
+    /// Splits the name into the full name of the test and the report
+    /// of the stage.
+    fn to_stage_report(&self) -> (Vec<String>, StageReport) {
+        let mut full_name = name_parts(&self.name, &self.name_parts);
+        let stage = full_name.pop().unwrap_or_default();
+        let stage_rep = StageReport {
+            stage_name: stage,
+            status: self.status.clone(),
+            duration: self.exec_time,
+            message: self.message.clone(),
+            attachments: self.attachments.clone(),
+            usage: self.cpu_time.map(|cpu_time| StageUsage {
+                cpu_time,
+                max_rss_growth_kb: self.max_rss_growth_kb.unwrap_or_default(),
+            }),
+        };
+        (full_name, stage_rep)
+    }
+}
+
+impl TestResult {
+    /// Rebuilds the completed task this result was made from.
+    pub fn to_completed_task(&self) -> CompletedTask {
+        let leaked_fds = self
+            .leaked_fds
+            .iter()
+            .filter_map(|fd| {
+                let (fd, target) = fd.split_once(" -> ")?;
+                Some(OpenFd {
+                    fd: fd.parse().ok()?,
+                    target: target.to_string(),
+                })
+            })
+            .collect();
+        let leaked_processes = self
+            .leaked_processes
+            .iter()
+            .filter_map(|process| {
+                let (pid, command) = process.split_once(' ')?;
+                Some(LeakedProcess {
+                    pid: pid.parse().ok()?,
+                    command: command.to_string(),
+                })
+            })
+            .collect();
+        CompletedTask {
+            full_name: name_parts(&self.name, &self.name_parts),
+            duration: self.exec_time,
+            stdout: decode_output(&self.stdout, self.stdout_encoding),
+            stderr: decode_output(&self.stderr, self.stderr_encoding),
+            status: self.status.clone(),
+            leaked_fds,
+            leaked_processes,
+            profiles: self.profiles.iter().map(PathBuf::from).collect(),
+            core_dump: self.core_dump.as_ref().map(PathBuf::from),
+            backtrace: self.backtrace.clone(),
+            start_time: UNIX_EPOCH + Duration::from_secs_f64(self.start_time.unwrap_or(0.0)),
+            attempt: self.attempt,
+            pid: self.pid,
+            timeout: self.timeout,
+            tags: self.tags.clone(),
+            metadata: Metadata {
+                description: self.description.clone(),
+                owner: self.owner.clone(),
+                links: self.links.clone(),
+            },
+            category: self.category.clone(),
+            assertion: self.assertion.clone(),
+            metrics: self.metrics.clone(),
+            stages: self
+                .stages
+                .iter()
+                .map(|stage| stage.to_stage_report().1)
+                .collect(),
+            logs: self.logs.clone(),
+            output_chunks: Vec::new(),
+            previous_attempts: Vec::new(),
+        }
+    }
+}
+
+/// Returns the components of a name, recovered by splitting it at
+/// "::" if they weren't recorded.
+fn name_parts(name: &str, parts: &[String]) -> Vec<String> {
+    if parts.is_empty() {
+        name.split("::").map(str::to_string).collect()
+    } else {
+        parts.to_vec()
+    }
+}
+
+/// Reads the events printed by a run with `--format json`.
+pub fn read_events(input: impl BufRead) -> Result<Vec<Event>, String> {
+    let mut events = Vec::new();
+    for (n, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| format!("failed to read events: {}", e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: Event =
+            serde_json::from_str(&line).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        if let Event::Suite(SuiteEvent::Started { schema_version, .. }) = event {
+            if schema_version != SCHEMA_VERSION {
+                return Err(format!(
+                    "line {}: unsupported schema version {}, expected {}",
+                    n + 1,
+                    schema_version,
+                    SCHEMA_VERSION
+                ));
+            }
+        }
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// Combines the events of several runs, e.g. of shards of a test
+/// suite, into the events of a single run.  Fails if a test was
+/// executed by more than one run.
+pub fn merge_events(runs: Vec<Vec<Event>>) -> Result<Vec<Event>, String> {
+    let names = runs.iter().flatten().filter_map(|event| match event {
+        Event::Test(TestEvent::Ok(result))
+        | Event::Test(TestEvent::Failed(result))
+        | Event::Test(TestEvent::Ignored(result)) => Some(result.name.clone()),
+        _ => None,
+    });
+    let duplicates = crate::find_duplicates(names);
+    if !duplicates.is_empty() {
+        return Err(format!(
+            "tests executed more than once: {}",
+            duplicates.join(", ")
+        ));
+    }
+
+    let mut test_count = 0;
+    let mut summary = SuiteSummary::default();
+    let mut body = Vec::new();
+    for event in runs.into_iter().flatten() {
+        match event {
+            Event::Suite(SuiteEvent::Started { test_count: n, .. }) => test_count += n,
+            Event::Suite(SuiteEvent::Ok(s)) | Event::Suite(SuiteEvent::Failed(s)) => {
+                summary.passed += s.passed;
+                summary.failed += s.failed;
+                summary.ignored += s.ignored;
+                summary.flaky += s.flaky;
+            }
+            event => body.push(event),
+        }
+    }
+
+    let mut events = vec![Event::Suite(SuiteEvent::Started {
+        schema_version: SCHEMA_VERSION,
+        test_count,
+    })];
+    events.append(&mut body);
+    events.push(Event::Suite(if summary.failed == 0 {
+        SuiteEvent::Ok(summary)
+    } else {
+        SuiteEvent::Failed(summary)
+    }));
+    Ok(events)
+}
+
+/// Returns the completed tasks recorded in events, with the attempts
+/// that were retried in [CompletedTask::previous_attempts].
+pub(crate) fn completed_tasks(events: Vec<Event>) -> Vec<CompletedTask> {
+    let mut retried = HashMap::<String, Vec<CompletedTask>>::new();
+    let mut completed = Vec::new();
+    for event in events {
+        match event {
+            Event::Test(TestEvent::Retried(result)) => retried
+                .entry(result.name.clone())
+                .or_default()
+                .push(result.to_completed_task()),
+            Event::Test(TestEvent::Ok(result))
+            | Event::Test(TestEvent::Failed(result))
+            | Event::Test(TestEvent::Ignored(result)) => {
+                let mut task = result.to_completed_task();
+                task.previous_attempts = retried.remove(&result.name).unwrap_or_default();
+                completed.push(task);
+            }
+            _ => (),
+        }
+    }
+    completed
+}
+
+/// Feeds events into `report` as if the run that printed them
+/// happened again, and returns the completed tasks.
+pub(crate) fn replay(events: Vec<Event>, report: &mut dyn Report) -> Vec<CompletedTask> {
+    let names: Vec<String> = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Test(TestEvent::Ok(result))
+            | Event::Test(TestEvent::Failed(result))
+            | Event::Test(TestEvent::Ignored(result)) => Some(result.name.clone()),
+            _ => None,
+        })
+        .collect();
+    report.on_event(&ReportEvent::Plan(&names));
+
+    let mut retried = HashMap::<String, Vec<CompletedTask>>::new();
+    let mut completed = Vec::new();
+    for event in events {
+        match event {
+            Event::Suite(SuiteEvent::Started { test_count, .. }) => {
+                report.on_event(&ReportEvent::SuiteStarted { test_count })
+            }
+            Event::Suite(SuiteEvent::Info(info)) => report.on_event(&ReportEvent::Header(&info)),
+            Event::Suite(SuiteEvent::Ok(_)) | Event::Suite(SuiteEvent::Failed(_)) => {
+                report.on_event(&ReportEvent::RunFinished)
+            }
+            Event::Suite(SuiteEvent::Compared(comparison)) => {
+                report.on_event(&ReportEvent::Compared(&comparison.to_comparison()))
+            }
+            Event::Test(TestEvent::Discovered { .. }) => (),
+            Event::Test(TestEvent::Running { .. }) => (),
+            Event::Stage(StageEvent::Running { .. }) => (),
+            Event::Test(TestEvent::Started {
+                name,
+                name_parts: parts,
+            }) => report.on_event(&ReportEvent::TestStarted {
+                full_name: &name_parts(&name, &parts),
+            }),
+            Event::Test(TestEvent::Retried(result)) => {
+                let task = result.to_completed_task();
+                report.on_event(&ReportEvent::Retried(&task));
+                retried.entry(result.name).or_default().push(task);
+            }
+            Event::Test(TestEvent::Ok(result))
+            | Event::Test(TestEvent::Failed(result))
+            | Event::Test(TestEvent::Ignored(result)) => {
+                let mut task = result.to_completed_task();
+                task.previous_attempts = retried.remove(&result.name).unwrap_or_default();
+                report.on_event(&ReportEvent::TestFinished(&task));
+                completed.push(task);
+            }
+            Event::Stage(StageEvent::Ok(result))
+            | Event::Stage(StageEvent::Failed(result))
+            | Event::Stage(StageEvent::Ignored(result)) => {
+                let (full_name, stage) = result.to_stage_report();
+                report.on_event(&ReportEvent::Stage {
+                    full_name: &full_name,
+                    stage: &stage,
+                });
+            }
+        }
+    }
+    completed
+}
+
+/// Durations are written as seconds with a unit, e.g. "1.2500s", like
+/// libtest does.
+mod seconds {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{:.4}s", d.as_secs_f64()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        let s = String::deserialize(d)?;
+        s.strip_suffix('s')
+            .and_then(|secs| secs.parse::<f64>().ok())
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)
+            .map(Duration::from_secs_f64)
+            .ok_or_else(|| de::Error::custom(format!("invalid duration: {}", s)))
+    }
+}
+
+mod opt_seconds {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        match d {
+            Some(d) => super::seconds::serialize(d, s),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(with = "super::seconds")] Duration);
+        Ok(Option::<Wrapper>::deserialize(d)?.map(|Wrapper(d)| d))
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn events_round_trip() {
+        let event = Event::Test(TestEvent::Failed(TestResult {
+            name: "suite::\"quoted\"\ttest".to_string(),
+            name_parts: Vec::new(),
+            status: Status::Signaled("SIGSEGV"),
+            exec_time: Duration::from_millis(1250),
+            stdout: "line\n\u{1b}[0m".to_string(),
+            stdout_encoding: None,
+            stderr: String::new(),
+            stderr_encoding: None,
+            reason: Some("killed by signal SIGSEGV".to_string()),
+            start_time: Some(1_600_000_000.5),
+            attempt: 1,
+            pid: Some(42),
+            timeout: Some(Duration::from_secs(10)),
+            tags: vec!["db".to_string()],
+            leaked_fds: Vec::new(),
+            leaked_processes: Vec::new(),
+            profiles: Vec::new(),
+            core_dump: None,
+            backtrace: None,
+            description: None,
+            owner: Some("storage team".to_string()),
+            links: Vec::new(),
+            category: Some("infra".to_string()),
+            assertion: None,
+            metrics: vec![Metric {
+                name: "iterations_per_sec".to_string(),
+                value: 1000.5,
+            }],
+            stages: vec![StageResult {
+                name: "suite::\"quoted\"\ttest::setup".to_string(),
+                name_parts: Vec::new(),
+                status: StageStatus::Success,
+                exec_time: Duration::from_millis(250),
+                reason: None,
+                message: None,
+                attachments: Vec::new(),
+                cpu_time: Some(Duration::from_millis(125)),
+                max_rss_growth_kb: Some(2048),
+            }],
+            logs: vec![LogRecord {
+                level: log::Level::Warn,
+                target: "db::pool".to_string(),
+                message: "connection lost".to_string(),
+            }],
+        }));
+        let line = serde_json::to_string(&event).unwrap();
+        assert!(line.starts_with(r#"{"type":"test","event":"failed","#));
+        assert!(line.contains(r#""exec_time":"1.2500s""#));
+        assert!(line.contains(r#""stages":[{"name":"#));
+        assert!(!line.contains("stderr"));
+        assert_eq!(serde_json::from_str::<Event>(&line).unwrap(), event);
+
+        let line = r#"{"type":"suite","event":"ok","passed":2,"failed":0,"ignored":1,"flaky":0}"#;
+        assert_eq!(
+            serde_json::from_str::<Event>(line).unwrap(),
+            Event::Suite(SuiteEvent::Ok(SuiteSummary {
+                passed: 2,
+                ignored: 1,
+                ..Default::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn name_parts_preserve_separators_in_names() {
+        let line = r#"{"name":"t","status":"success","exec_time":"0.1000s","attempt":1}"#;
+        let mut task = serde_json::from_str::<TestResult>(line)
+            .unwrap()
+            .to_completed_task();
+        task.full_name = vec!["suite".to_string(), "Vec::new".to_string()];
+
+        let line = serde_json::to_string(&TestEvent::from(&task)).unwrap();
+        assert!(line.contains(r#""name":"suite::Vec::new","name_parts":["suite","Vec::new"]"#));
+        let result = match serde_json::from_str(&line).unwrap() {
+            TestEvent::Ok(result) => result,
+            other => panic!("unexpected event {:?}", other),
+        };
+        assert_eq!(result.to_completed_task().full_name, task.full_name);
+    }
+
+    #[test]
+    fn binary_output_round_trips_in_base64() {
+        let result = |event| match event {
+            TestEvent::Ok(result) => result,
+            other => panic!("unexpected event {:?}", other),
+        };
+        let line = r#"{"name":"t","status":"success","exec_time":"0.1000s","attempt":1}"#;
+        let mut task = serde_json::from_str::<TestResult>(line)
+            .unwrap()
+            .to_completed_task();
+        task.stdout = b"text\n".to_vec();
+        task.stderr = vec![0xff, b'\n'];
+
+        let lossy = result(TestEvent::from(&task));
+        assert_eq!(lossy.stderr, "\u{fffd}\n");
+        assert_eq!(lossy.stderr_encoding, None);
+
+        let encoded = result(TestEvent::new(&task, true));
+        assert_eq!(
+            (encoded.stdout.as_str(), encoded.stdout_encoding),
+            ("text\n", None)
+        );
+        assert_eq!(encoded.stderr, "/wo=");
+        assert_eq!(encoded.stderr_encoding, Some(Encoding::Base64));
+        let line = serde_json::to_string(&encoded).unwrap();
+        assert!(line.contains(r#""stderr_encoding":"base64""#));
+        assert_eq!(encoded.to_completed_task().stderr, task.stderr);
+    }
+
+    #[test]
+    fn comparisons_are_printed_with_seconds() {
+        let comparison = Comparison {
+            newly_failing: vec!["broken".to_string()],
+            slower: vec![(
+                "slow".to_string(),
+                Duration::from_secs(2),
+                Duration::from_millis(4500),
+            )],
+            ..Default::default()
+        };
+        let event = Event::Suite(SuiteEvent::Compared((&comparison).into()));
+        let line = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            line,
+            r#"{"type":"suite","event":"compared","newly_failing":["broken"],"slower":[{"name":"slow","baseline":"2.0000s","exec_time":"4.5000s"}]}"#
+        );
+        match serde_json::from_str(&line).unwrap() {
+            Event::Suite(SuiteEvent::Compared(parsed)) => {
+                assert_eq!(parsed.to_comparison(), comparison)
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn merge_events_sums_runs_and_detects_duplicates() {
+        let run = |name: &str, status: Status| {
+            let result = TestResult {
+                name: name.to_string(),
+                name_parts: Vec::new(),
+                status,
+                exec_time: Duration::from_secs(1),
+                stdout: String::new(),
+                stdout_encoding: None,
+                stderr: String::new(),
+                stderr_encoding: None,
+                reason: None,
+                start_time: None,
+                attempt: 1,
+                pid: None,
+                timeout: None,
+                tags: Vec::new(),
+                leaked_fds: Vec::new(),
+                leaked_processes: Vec::new(),
+                profiles: Vec::new(),
+                core_dump: None,
+                backtrace: None,
+                description: None,
+                owner: None,
+                links: Vec::new(),
+                category: None,
+                assertion: None,
+                metrics: Vec::new(),
+                stages: Vec::new(),
+                logs: Vec::new(),
+            };
+            let (event, summary) = if result.status.is_ok() {
+                (
+                    TestEvent::Ok(result),
+                    SuiteEvent::Ok(SuiteSummary {
+                        passed: 1,
+                        ..Default::default()
+                    }),
+                )
+            } else {
+                (
+                    TestEvent::Failed(result),
+                    SuiteEvent::Failed(SuiteSummary {
+                        failed: 1,
+                        ..Default::default()
+                    }),
+                )
+            };
+            vec![
+                Event::Suite(SuiteEvent::Started {
+                    schema_version: SCHEMA_VERSION,
+                    test_count: 1,
+                }),
+                Event::Test(event),
+                Event::Suite(summary),
+            ]
+        };
+
+        let merged =
+            merge_events(vec![run("a", Status::Success), run("b", Status::Timeout)]).unwrap();
+        assert_eq!(merged.len(), 4);
+        assert_eq!(
+            merged[0],
+            Event::Suite(SuiteEvent::Started {
+                schema_version: SCHEMA_VERSION,
+                test_count: 2
+            })
+        );
+        assert_eq!(
+            merged[3],
+            Event::Suite(SuiteEvent::Failed(SuiteSummary {
+                passed: 1,
+                failed: 1,
+                ..Default::default()
+            }))
+        );
+
+        let err = merge_events(vec![run("a", Status::Success), run("a", Status::Timeout)]);
+        assert_eq!(err, Err("tests executed more than once: a".to_string()));
+    }
+
+    #[test]
+    fn replay_delivers_events_in_order() {
+        struct Recorder(Vec<String>);
+
+        impl Report for Recorder {
+            fn on_event(&mut self, event: &ReportEvent) {
+                self.0.push(match event {
+                    ReportEvent::Plan(names) => format!("plan {}", names.join(" ")),
+                    ReportEvent::SuiteStarted { test_count } => format!("started {}", test_count),
+                    ReportEvent::TestStarted { full_name } => format!("start {:?}", full_name),
+                    ReportEvent::Stage { full_name, stage } => {
+                        format!("stage {:?} {}", full_name, stage.stage_name)
+                    }
+                    ReportEvent::TestFinished(task) => format!("finished {}", task.name()),
+                    ReportEvent::RunFinished => "done".to_string(),
+                    other => format!("unexpected {:?}", other),
+                });
+            }
+        }
+
+        let lines = r#"
+{"type":"suite","event":"started","schema_version":2,"test_count":1}
+{"type":"test","event":"started","name":"s::t"}
+{"type":"stage","event":"ok","name":"s::t::build","status":"Success","exec_time":"0.5000s"}
+{"type":"test","event":"ok","name":"s::t","status":"success","exec_time":"1.0000s","attempt":1}
+{"type":"suite","event":"ok","passed":1,"failed":0,"ignored":0,"flaky":0}
+"#;
+        let mut recorder = Recorder(Vec::new());
+        replay(read_events(lines.as_bytes()).unwrap(), &mut recorder);
+        assert_eq!(
+            recorder.0,
+            vec![
+                "plan s::t",
+                "started 1",
+                r#"start ["s", "t"]"#,
+                r#"stage ["s", "t"] build"#,
+                "finished s::t",
+                "done",
+            ]
+        );
+    }
+
+    #[test]
+    fn retried_attempts_belong_to_the_last_attempt() {
+        let lines = r#"
+{"type":"test","event":"retried","name":"t","status":"timeout","exec_time":"1.0000s","stdout":"first","attempt":1}
+{"type":"test","event":"retried","name":"t","status":{"failure":1},"exec_time":"0.5000s","attempt":2}
+{"type":"test","event":"ok","name":"t","status":{"flaky":{"attempts":3}},"exec_time":"0.1000s","attempt":3}
+"#;
+        let tasks = completed_tasks(read_events(lines.as_bytes()).unwrap());
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].attempt, 3);
+        let attempts: Vec<_> = tasks[0]
+            .previous_attempts
+            .iter()
+            .map(|t| {
+                (
+                    t.attempt,
+                    t.status.clone(),
+                    t.stdout_as_string().into_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            attempts,
+            vec![
+                (1, Status::Timeout, "first".to_string()),
+                (2, Status::Failure(1), String::new())
+            ]
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! The JUnit XML format, see [crate::Config::junit], which most CI
+//! systems and `bazel test` understand.
+//!
+//! The results are written when the run is done, as a single test
+//! suite named after the test binary.  Each test becomes a test case
+//! whose class name is the path of the suites containing it.
+
+use crate::compress::RetainedTasks;
+use crate::execution::{CompletedTask, Report, ReportEvent, Status};
+use crate::json::{self, RunInfo};
+use crate::report::{format_utc, xml_escape};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// A report writing the results to a file in the JUnit XML format
+/// before passing the events on to another report.
+pub struct JUnitReport {
+    inner: Box<dyn Report>,
+    file: File,
+    info: Option<RunInfo>,
+    tasks: RetainedTasks,
+}
+
+impl JUnitReport {
+    /// Creates `path` right away, replacing any existing file, so
+    /// that a run doesn't fail to write its results only at the end.
+    pub fn create(path: &Path, inner: Box<dyn Report>) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            file: File::create(path)?,
+            info: None,
+            tasks: RetainedTasks::new(false),
+        })
+    }
+
+    /// Compresses the output of the tests until the file is written,
+    /// see [crate::Config::compress_output].
+    pub fn compress_output(mut self, enabled: bool) -> Self {
+        self.tasks = RetainedTasks::new(enabled);
+        self
+    }
+}
+
+fn test_case(task: &CompletedTask) -> String {
+    let (class, method) = match task.full_name.split_last() {
+        Some((method, suites)) => (suites.join("::"), method.clone()),
+        None => (String::new(), task.name()),
+    };
+    let mut xml = format!(
+        "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">\n",
+        xml_escape(&method),
+        xml_escape(&class),
+        task.duration.as_secs_f64()
+    );
+    let (_, reason) = json::outcome(&task.status);
+    let reason = xml_escape(&reason.unwrap_or_default());
+    match &task.status {
+        Status::Skipped(_) => xml.push_str(&format!("      <skipped message=\"{}\"/>\n", reason)),
+        status if !status.is_ok() => {
+            xml.push_str(&format!("      <failure message=\"{}\"/>\n", reason))
+        }
+        _ => (),
+    }
+    if !task.stdout.is_empty() {
+        let stdout = xml_escape(&task.stdout_as_string());
+        xml.push_str(&format!("      <system-out>{}</system-out>\n", stdout));
+    }
+    if !task.stderr.is_empty() {
+        let stderr = xml_escape(&task.stderr_as_string());
+        xml.push_str(&format!("      <system-err>{}</system-err>\n", stderr));
+    }
+    xml.push_str("    </testcase>\n");
+    xml
+}
+
+impl JUnitReport {
+    fn write(&mut self) -> io::Result<()> {
+        let name = std::env::args()
+            .next()
+            .as_deref()
+            .map(Path::new)
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let count =
+            |f: &dyn Fn(&Status) -> bool| self.tasks.iter().filter(|task| f(&task.status)).count();
+        let skipped = count(&|status| matches!(status, Status::Skipped(_)));
+        let failures = count(&|status| !status.is_ok());
+        let time: f64 = self.tasks.iter().map(|t| t.duration.as_secs_f64()).sum();
+        let mut attributes = format!(
+            "name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\" time=\"{:.3}\"",
+            xml_escape(&name),
+            self.tasks.len(),
+            failures,
+            skipped,
+            time
+        );
+        if let Some(info) = &self.info {
+            attributes.push_str(&format!(" timestamp=\"{}\"", format_utc(info.start_time)));
+            if let Some(hostname) = &info.hostname {
+                attributes.push_str(&format!(" hostname=\"{}\"", xml_escape(hostname)));
+            }
+        }
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str("<testsuites>\n");
+        xml.push_str(&format!("  <testsuite {}>\n", attributes));
+        let tasks = std::mem::replace(&mut self.tasks, RetainedTasks::new(false)).into_vec();
+        for task in &tasks {
+            xml.push_str(&test_case(task));
+        }
+        xml.push_str("  </testsuite>\n");
+        xml.push_str("</testsuites>\n");
+        self.file.write_all(xml.as_bytes())
+    }
+}
+
+impl Report for JUnitReport {
+    fn on_event(&mut self, event: &ReportEvent) {
+        match event {
+            ReportEvent::Header(info) => self.info = Some((*info).clone()),
+            ReportEvent::TestFinished(task) => self.tasks.push((*task).clone()),
+            ReportEvent::RunFinished => {
+                if let Err(err) = self.write() {
+                    eprintln!("Failed to write the JUnit results: {}", err);
+                }
+            }
+            _ => (),
+        }
+        self.inner.on_event(event);
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_cases_are_named_after_their_suites() {
+        let task = |name: &str, status| CompletedTask {
+            duration: std::time::Duration::from_millis(1500),
+            ..CompletedTask::new(
+                name.split("::").map(String::from).collect(),
+                status,
+                std::time::UNIX_EPOCH,
+            )
+        };
+        assert_eq!(
+            test_case(&task("a::b::c", Status::Success)),
+            "    <testcase name=\"c\" classname=\"a::b\" time=\"1.500\">\n    </testcase>\n"
+        );
+        let mut failed = task("t", Status::Failure(1));
+        failed.stderr = b"a < b".to_vec();
+        assert_eq!(
+            test_case(&failed),
+            "    <testcase name=\"t\" classname=\"\" time=\"1.500\">
+      <failure message=\"test process exited with code 1\"/>
+      <system-err>a &lt; b</system-err>
+    </testcase>
+"
+        );
+        assert!(test_case(&task("t", Status::Skipped("slow".to_string())))
+            .contains("<skipped message=\"slow\"/>"));
+    }
+}
@@ -0,0 +1,164 @@
+//! Allocation of TCP ports and named resources leased by tests.
+//!
+//! Tests request leases over their report pipe and the driver replies
+//! over their control pipe.  Leases are held until the test process
+//! completes.
+
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::TcpListener;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LeaseRequest {
+    /// A TCP port no other test holds.
+    Port,
+    /// A resource from the pool with the given name.
+    Resource(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Lease {
+    Port(u16),
+    Resource { pool: String, name: String },
+}
+
+/// The driver-side bookkeeping of leases.
+pub struct LeaseAllocator {
+    ports: HashSet<u16>,
+    // Resources of each pool that are not leased.
+    available: HashMap<String, Vec<String>>,
+    held: HashMap<Pid, Vec<Lease>>,
+    // Tasks waiting for a resource to be released, in the order of
+    // requests.
+    waiting: VecDeque<(Pid, String)>,
+}
+
+impl LeaseAllocator {
+    pub fn new(pools: &HashMap<String, Vec<String>>) -> Self {
+        let available = pools
+            .iter()
+            .map(|(pool, resources)| {
+                // Resources are handed out from the end.
+                let mut resources = resources.clone();
+                resources.reverse();
+                (pool.clone(), resources)
+            })
+            .collect();
+        Self {
+            ports: HashSet::new(),
+            available,
+            held: HashMap::new(),
+            waiting: VecDeque::new(),
+        }
+    }
+
+    fn free_port(&self) -> Result<u16, String> {
+        // Let the OS pick a port nobody listens on, and make sure it
+        // wasn't leased already.
+        for _ in 0..100 {
+            let listener = TcpListener::bind(("127.0.0.1", 0))
+                .map_err(|e| format!("failed to find a free port: {}", e))?;
+            let port = listener
+                .local_addr()
+                .map_err(|e| format!("failed to find a free port: {}", e))?
+                .port();
+            if !self.ports.contains(&port) {
+                return Ok(port);
+            }
+        }
+        Err("failed to find a free port".to_string())
+    }
+
+    fn grant(&mut self, pid: Pid, lease: Lease) -> Lease {
+        if let Lease::Port(port) = lease {
+            self.ports.insert(port);
+        }
+        self.held.entry(pid).or_default().push(lease.clone());
+        lease
+    }
+
+    /// Handles a request of the task with the given PID.  Returns None
+    /// if the task has to wait until a resource is released.
+    pub fn request(&mut self, pid: Pid, req: LeaseRequest) -> Option<Result<Lease, String>> {
+        match req {
+            LeaseRequest::Port => Some(
+                self.free_port()
+                    .map(|port| self.grant(pid, Lease::Port(port))),
+            ),
+            LeaseRequest::Resource(pool) => {
+                let name = match self.available.get_mut(&pool) {
+                    None => return Some(Err(format!("unknown resource pool: {}", pool))),
+                    Some(resources) => resources.pop(),
+                };
+                match name {
+                    Some(name) => Some(Ok(self.grant(pid, Lease::Resource { pool, name }))),
+                    None => {
+                        self.waiting.push_back((pid, pool));
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Releases the leases held by the task with the given PID and
+    /// cancels its pending request.  Returns the leases granted to
+    /// waiting tasks as a result.
+    pub fn release(&mut self, pid: Pid) -> Vec<(Pid, Lease)> {
+        self.waiting.retain(|(p, _)| *p != pid);
+        for lease in self.held.remove(&pid).unwrap_or_default() {
+            match lease {
+                Lease::Port(port) => {
+                    self.ports.remove(&port);
+                }
+                Lease::Resource { pool, name } => {
+                    self.available.get_mut(&pool).unwrap().push(name);
+                }
+            }
+        }
+
+        let mut granted = Vec::new();
+        let mut still_waiting = VecDeque::new();
+        while let Some((p, pool)) = self.waiting.pop_front() {
+            match self.available.get_mut(&pool).unwrap().pop() {
+                Some(name) => granted.push((p, self.grant(p, Lease::Resource { pool, name }))),
+                None => still_waiting.push_back((p, pool)),
+            }
+        }
+        self.waiting = still_waiting;
+        granted
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn resources_are_granted_in_request_order() {
+        let mut pools = HashMap::new();
+        pools.insert("db".to_string(), vec!["db1".to_string()]);
+        let mut leases = LeaseAllocator::new(&pools);
+        let (p1, p2, p3) = (Pid::from_raw(1), Pid::from_raw(2), Pid::from_raw(3));
+        let db1 = Lease::Resource {
+            pool: "db".to_string(),
+            name: "db1".to_string(),
+        };
+
+        let req = || LeaseRequest::Resource("db".to_string());
+        assert_eq!(leases.request(p1, req()), Some(Ok(db1.clone())));
+        assert_eq!(leases.request(p2, req()), None);
+        assert_eq!(leases.request(p3, req()), None);
+        assert!(leases
+            .request(p1, LeaseRequest::Resource("nope".to_string()))
+            .unwrap()
+            .is_err());
+
+        assert_eq!(leases.release(p1), vec![(p2, db1.clone())]);
+        // p3 gives up, e.g. times out.
+        assert_eq!(leases.release(p3), vec![]);
+        assert_eq!(leases.release(p2), vec![]);
+        assert_eq!(leases.request(p3, req()), Some(Ok(db1)));
+    }
+}
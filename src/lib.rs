@@ -1,15 +1,80 @@
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+mod async_case;
+mod baseline;
+mod checkpoint;
+mod command;
+mod compress;
 pub mod config;
+mod coredump;
+mod daemons;
+mod data;
+mod diff;
 mod execution;
+mod filter;
+mod fuzz;
+#[cfg(feature = "history")]
+pub mod history;
+mod jobserver;
+pub mod json;
+mod junit;
+mod lease;
+mod libtest;
+mod logger;
+mod notify;
+mod prefork;
+mod prerequisite;
+pub mod recording;
+mod remote;
 mod report;
+mod ring;
+mod sanitizer;
+mod scheduling;
+mod split;
+mod spool;
+mod trx;
+#[cfg(feature = "ui")]
+mod ui;
+mod zygote;
 
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub use async_case::async_test_case;
+pub use baseline::Comparison;
+pub use command::command_test;
 pub use config::Config;
+pub use data::test_cases_from_json;
+#[cfg(feature = "data-files")]
+pub use data::{test_cases_from_csv, test_cases_from_toml};
+pub use execution::AssertionFailure;
 pub use execution::CompletedTask;
+pub use execution::LeakedProcess;
+pub use execution::LogRecord;
+pub use execution::Metadata;
+pub use execution::Metric;
+pub use execution::OpenFd;
+pub use execution::OutputChunk;
+pub use execution::Report;
+pub use execution::ReportEvent;
 pub use execution::StageReport;
 pub use execution::StageStatus;
 pub use execution::Status;
 pub use execution::TestContext;
+pub use execution::{Attachment, StageUsage};
+pub use execution::{Clock, SystemClock, VirtualClock};
+pub use filter::Filter;
+#[cfg(feature = "fuzz")]
+pub use fuzz::{fuzz_case, Rng};
+pub use libtest::libtest_suite;
+pub use nix::sys::signal::Signal;
+pub use prerequisite::Prerequisite;
+pub use remote::Worker;
+pub use report::ColorWriter;
+pub use sanitizer::SanitizerError;
+/// The colors of [ColorWriter::with_color].
+pub use term::color;
 
 use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
 use std::string::ToString;
 
 type GenericAssertion = Box<dyn FnOnce(TestContext) + 'static>;
@@ -25,15 +90,70 @@ impl TestTree {
     }
 }
 
+/// A predicate deciding whether to skip a test, with the reason to
+/// report, see [skip_if].
+type SkipCondition = (Rc<dyn Fn() -> bool>, String);
+
 #[derive(Clone, Default)]
 struct Options {
     pub(crate) skip_reason: Option<String>,
+    pub(crate) skip_conditions: Vec<SkipCondition>,
+    pub(crate) tags: Vec<String>,
+    pub(crate) exclusive: bool,
+    pub(crate) deny_stderr: bool,
+    pub(crate) expect_failure: bool,
+    pub(crate) focused: bool,
+    pub(crate) metadata: Metadata,
+    pub(crate) dependencies: Vec<String>,
+    pub(crate) pipeline: bool,
+    pub(crate) artifacts_dir: Option<std::path::PathBuf>,
+    pub(crate) io_priority: Option<config::IoPriority>,
 }
 
 impl Options {
-    fn inherit(self, parent: Options) -> Options {
+    fn inherit(self, mut parent: Options) -> Options {
+        for tag in self.tags {
+            if !parent.tags.contains(&tag) {
+                parent.tags.push(tag);
+            }
+        }
+        parent.skip_conditions.extend(self.skip_conditions);
+        parent.dependencies.extend(self.dependencies);
+        for link in self.metadata.links {
+            if !parent.metadata.links.contains(&link) {
+                parent.metadata.links.push(link);
+            }
+        }
         Options {
             skip_reason: self.skip_reason.or(parent.skip_reason),
+            skip_conditions: parent.skip_conditions,
+            tags: parent.tags,
+            exclusive: self.exclusive || parent.exclusive,
+            deny_stderr: self.deny_stderr || parent.deny_stderr,
+            expect_failure: self.expect_failure || parent.expect_failure,
+            focused: self.focused || parent.focused,
+            metadata: Metadata {
+                description: self.metadata.description.or(parent.metadata.description),
+                owner: self.metadata.owner.or(parent.metadata.owner),
+                links: parent.metadata.links,
+            },
+            dependencies: parent.dependencies,
+            pipeline: self.pipeline,
+            artifacts_dir: self.artifacts_dir.or(parent.artifacts_dir),
+            io_priority: self.io_priority.or(parent.io_priority),
+        }
+    }
+
+    /// Turns the first skip condition that holds into the skip
+    /// reason, outermost conditions first.  Conditions are only
+    /// evaluated for tests that aren't skipped unconditionally.
+    fn check_skip_conditions(&mut self) {
+        let conditions = std::mem::take(&mut self.skip_conditions);
+        if self.skip_reason.is_none() {
+            self.skip_reason = conditions
+                .into_iter()
+                .find(|(predicate, _)| predicate())
+                .map(|(_, reason)| reason);
         }
     }
 }
@@ -80,6 +200,23 @@ pub fn test_suite(name: impl ToString, tests: Vec<TestTree>) -> TestTree {
     })
 }
 
+/// Creates a suite whose children are the steps of a pipeline, e.g.
+/// build, deploy and check.  Each step only starts once the previous
+/// one passed and is skipped otherwise, see [depends_on].  The steps
+/// share a directory where they leave artifacts for the next ones,
+/// see [TestContext::artifacts_dir].
+pub fn pipeline_suite(name: impl ToString, steps: Vec<TestTree>) -> TestTree {
+    let mut previous: Option<String> = None;
+    let steps = steps
+        .into_iter()
+        .map(|step| match previous.replace(step.name().to_string()) {
+            Some(previous) => depends_on(previous, step),
+            None => step,
+        })
+        .collect();
+    with_options(test_suite(name, steps), |opts| opts.pipeline = true)
+}
+
 fn with_options(mut test: TestTree, f: impl FnOnce(&mut Options)) -> TestTree {
     match test {
         TestTree(TreeNode::Leaf {
@@ -101,6 +238,159 @@ pub fn skip(reason: impl ToString, test: TestTree) -> TestTree {
     with_options(test, |opts| opts.skip_reason = Some(reason.to_string()))
 }
 
+/// Skips the tests in the tree if `predicate` returns true, e.g. if a
+/// binary they need is missing.  The predicate is evaluated by the
+/// driver when it plans the run, once for each selected test.
+pub fn skip_if(
+    predicate: impl Fn() -> bool + 'static,
+    reason: impl ToString,
+    test: TestTree,
+) -> TestTree {
+    let condition: SkipCondition = (Rc::new(predicate), reason.to_string());
+    with_options(test, |opts| opts.skip_conditions.push(condition))
+}
+
+/// Skips the tests in the tree if the prerequisite is not met, with a
+/// reason naming it.  Checked by the driver like [skip_if].
+pub fn requires(prerequisite: Prerequisite, test: TestTree) -> TestTree {
+    let reason = prerequisite.skip_reason();
+    skip_if(move || !prerequisite.is_met(), reason, test)
+}
+
+/// Marks all the tests in the tree with a tag.  Tags are used to
+/// limit the number of tests running simultaneously, see
+/// [Config::max_concurrency_for_tag].
+pub fn tag(tag: impl ToString, test: TestTree) -> TestTree {
+    let tag = tag.to_string();
+    with_options(test, |opts| {
+        if !opts.tags.contains(&tag) {
+            opts.tags.push(tag)
+        }
+    })
+}
+
+/// Marks all the tests in the tree as exclusive: such a test only
+/// starts once all other running tests complete, and no other test
+/// runs alongside it.  Useful for tests measuring timing or
+/// saturating machine-wide resources.
+pub fn exclusive(test: TestTree) -> TestTree {
+    with_options(test, |opts| opts.exclusive = true)
+}
+
+/// Makes the tests in the tree wait for the test or the suite named
+/// `name` to pass: they are only started once it's done, and skipped
+/// if it failed or was skipped.  The name is looked up next to the
+/// tree first, then in the enclosing suites, e.g. "provision" or
+/// "cluster::provision".  Dependencies that aren't executed, e.g.
+/// because of a filter, are ignored.
+pub fn depends_on(name: impl ToString, test: TestTree) -> TestTree {
+    let name = name.to_string();
+    with_options(test, |opts| opts.dependencies.push(name))
+}
+
+/// Makes all the tests in the tree fail if they pass but write
+/// anything to stderr, to keep warnings out of the code paths they
+/// exercise.  The captured output is shown in the failure report.
+pub fn deny_stderr(test: TestTree) -> TestTree {
+    with_options(test, |opts| opts.deny_stderr = true)
+}
+
+/// Marks the tests in the tree as known to fail, e.g. until a bug is
+/// fixed: a test exiting with an error or killed by a signal ends with
+/// [Status::ExpectedFailure], which doesn't fail the run, and a test
+/// that passes fails so that the mark gets removed.
+pub fn expect_failure(test: TestTree) -> TestTree {
+    with_options(test, |opts| opts.expect_failure = true)
+}
+
+/// Runs the tests of `test` with the I/O `priority` instead of that of
+/// [Config::io_priority], e.g. [config::IoPriority::Idle] for a test
+/// writing gigabytes.
+pub fn with_io_priority(priority: config::IoPriority, test: TestTree) -> TestTree {
+    with_options(test, |opts| opts.io_priority = Some(priority))
+}
+
+/// Runs `setup` before and `teardown` after each test in the tree,
+/// inside the process of the test.  The teardown also runs if the
+/// test panics, the panic is then propagated.  Hooks of enclosing
+/// suites run outside the hooks of nested ones.
+pub fn with_each_hooks(
+    setup: impl Fn() + 'static,
+    teardown: impl Fn() + 'static,
+    test: TestTree,
+) -> TestTree {
+    let setup: Rc<dyn Fn()> = Rc::new(setup);
+    let teardown: Rc<dyn Fn()> = Rc::new(teardown);
+    map_assertions(test, &|assertion| {
+        let (setup, teardown) = (setup.clone(), teardown.clone());
+        Box::new(move |ctx| {
+            setup();
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| assertion(ctx)));
+            teardown();
+            if let Err(payload) = result {
+                std::panic::resume_unwind(payload)
+            }
+        })
+    })
+}
+
+fn map_assertions(test: TestTree, f: &dyn Fn(GenericAssertion) -> GenericAssertion) -> TestTree {
+    match test.0 {
+        TreeNode::Leaf {
+            name,
+            assertion,
+            options,
+        } => TestTree(TreeNode::Leaf {
+            name,
+            assertion: f(assertion),
+            options,
+        }),
+        TreeNode::Fork {
+            name,
+            tests,
+            options,
+        } => TestTree(TreeNode::Fork {
+            name,
+            tests: tests.into_iter().map(|t| map_assertions(t, f)).collect(),
+            options,
+        }),
+    }
+}
+
+/// Focuses on the tests in the tree: if any selected test is focused,
+/// only the focused tests are executed.  Meant for debugging, a
+/// warning is printed whenever the focus is in effect, see also
+/// [Config::forbid_only].
+pub fn only(test: TestTree) -> TestTree {
+    with_options(test, |opts| opts.focused = true)
+}
+
+/// Describes the tests in the tree, the description of a test
+/// overrides the one of its suite.
+pub fn describe(description: impl ToString, test: TestTree) -> TestTree {
+    with_options(test, |opts| {
+        opts.metadata.description = Some(description.to_string())
+    })
+}
+
+/// Names the person or team responsible for the tests in the tree, so
+/// that reports of failures can say whom to contact.
+pub fn owner(owner: impl ToString, test: TestTree) -> TestTree {
+    with_options(test, |opts| opts.metadata.owner = Some(owner.to_string()))
+}
+
+/// Attaches a URL to the tests in the tree, e.g. of the issue
+/// tracking a known failure.  Links accumulate from the enclosing
+/// suites.
+pub fn link(url: impl ToString, test: TestTree) -> TestTree {
+    let url = url.to_string();
+    with_options(test, |opts| {
+        if !opts.metadata.links.contains(&url) {
+            opts.metadata.links.push(url)
+        }
+    })
+}
+
 pub fn should_panic(
     expected_msg: &str,
     f: impl FnOnce() + std::panic::UnwindSafe + 'static,
@@ -144,6 +434,24 @@ pub struct TestResults {
 }
 
 impl TestResults {
+    /// Combines the results of several runs, e.g. of shards of a test
+    /// suite, into one.  Fails with the names of the tests that were
+    /// executed by more than one run.
+    pub fn merge(
+        results: impl IntoIterator<Item = TestResults>,
+    ) -> Result<TestResults, Vec<String>> {
+        let completed_tasks: Vec<_> = results
+            .into_iter()
+            .flat_map(TestResults::into_completed_tasks)
+            .collect();
+        let duplicates = find_duplicates(completed_tasks.iter().map(|t| t.name()));
+        if duplicates.is_empty() {
+            Ok(TestResults { completed_tasks })
+        } else {
+            Err(duplicates)
+        }
+    }
+
     /// "Disarms" this TaskResult and returns the list of completed tasks
     /// for examination.
     pub fn into_completed_tasks(mut self) -> Vec<execution::CompletedTask> {
@@ -159,51 +467,383 @@ impl Drop for TestResults {
     }
 }
 
+/// Generates the `fn main()` of a test target running the tests of
+/// `tree` with [default_main], optionally with a default [Config].
+/// Cargo runs the target instead of libtest once the target is
+/// declared with `harness = false`:
+///
+/// ```toml
+/// [[test]]
+/// name = "integration"
+/// harness = false
+/// ```
+///
+/// and `tests/integration.rs` consists of:
+///
+/// ```no_run
+/// use raclette::{test_case, test_suite};
+///
+/// raclette::main! {
+///     test_suite("integration", vec![test_case("works", || assert_eq!(2 + 2, 4))])
+/// }
+/// ```
+///
+/// The options cargo passes on to the target, e.g. `cargo test --
+/// --test-threads 4 NAME`, work as with libtest.  The process exits
+/// with code 1 if a test failed, see [TestResults].
+#[macro_export]
+macro_rules! main {
+    ($tree:expr $(,)?) => {
+        $crate::main!($tree, $crate::Config::default());
+    };
+    ($tree:expr, $config:expr $(,)?) => {
+        fn main() {
+            let _ = $crate::default_main($config, $tree);
+        }
+    };
+}
+
 /// Runs raclette with a default config but reads the command line arguments
-/// and overrides settings from the default config. If this behavior is undesired
+/// and RACLETTE_* environment variables and overrides settings from the
+/// default config.  Command line arguments take precedence over the
+/// environment. If this behavior is undesired
 /// refer to [default_main_no_config_override] instead.
 ///
-/// Returns a list of [execution::TaskResult] for each test that was ran.
+/// The first argument can be a command, see [config::Command]; tests are
+/// executed by default.
+///
+/// Returns a list of [execution::TaskResult] for each test that was ran or
+/// skipped.
 pub fn default_main(default_config: Config, tree: TestTree) -> TestResults {
-    use config::ConfigParseError as E;
+    use config::{Command, ConfigParseError as E};
+
+    let (command, override_config) = Command::from_args()
+        .and_then(|(command, args_config)| Ok((command, args_config.merge(Config::from_env()?))))
+        .unwrap_or_else(|err| match err {
+            E::HelpRequested => {
+                print!("{}", config::produce_help());
+                std::process::exit(0)
+            }
+            E::OptionError(err) => {
+                println!("{}", err);
+                print!("{}", config::produce_help());
+                std::process::exit(1)
+            }
+            E::UnknownArgs(args) => {
+                println!("Unsupported arguments: {}", args.join(" "));
+                print!("{}", config::produce_help());
+                std::process::exit(1)
+            }
+            E::Unknown(err) => {
+                println!("Failed to parse command line flags: {}", err);
+                std::process::exit(1)
+            }
+        });
 
-    let override_config = Config::from_args().unwrap_or_else(|err| match err {
-        E::HelpRequested => {
-            print!("{}", config::produce_help());
-            std::process::exit(0)
+    let config = override_config.merge(default_config);
+    match command {
+        Command::Run => default_main_no_config_override(config, tree),
+        Command::List => {
+            let plan = make_plan(&config, tree);
+            report::list(
+                config.format,
+                &mut report::ColorWriter::new(config.color),
+                &plan,
+                config.timeout.unwrap_or(execution::DEFAULT_TIMEOUT),
+            );
+            TestResults {
+                completed_tasks: Vec::new(),
+            }
         }
-        E::OptionError(err) => {
-            println!("{}", err);
-            print!("{}", config::produce_help());
-            std::process::exit(1)
+        Command::Report(path) => {
+            let completed_tasks = replay_file(&path, &mut *make_report(&config));
+            TestResults { completed_tasks }
+        }
+        Command::Merge(paths) => {
+            let runs = paths.iter().map(|path| read_events(path)).collect();
+            let events = json::merge_events(runs).unwrap_or_else(|err| {
+                eprintln!("Failed to merge results: {}", err);
+                std::process::exit(1)
+            });
+            let completed_tasks = json::replay(events, &mut *make_report(&config));
+            TestResults { completed_tasks }
         }
-        E::UnknownArgs(args) => {
-            println!("Unsupported arguments: {}", args.join(" "));
-            print!("{}", config::produce_help());
+        Command::Worker(addr) => remote::listen(&config, tree, &addr),
+    }
+}
+
+/// Shows the results recorded in `path` with `--format json` or
+/// `--record` again, exits if it's unreadable.
+fn replay_file(path: &std::path::Path, report: &mut dyn execution::Report) -> Vec<CompletedTask> {
+    let mut input = match std::fs::File::open(path) {
+        Ok(file) => std::io::BufReader::new(file),
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", path.display(), err);
             std::process::exit(1)
         }
-        E::Unknown(err) => {
-            println!("Failed to parse command line flags: {}", err);
+    };
+    if !recording::is_recording(&mut input).unwrap_or(false) {
+        return json::replay(read_events(path), report);
+    }
+    recording::replay(input, report).unwrap_or_else(|err| {
+        eprintln!("Failed to read {}: {}", path.display(), err);
+        std::process::exit(1)
+    })
+}
+
+/// Reads the events of a results file, exits if it's unreadable.
+fn read_events(path: &std::path::Path) -> Vec<json::Event> {
+    std::fs::File::open(path)
+        .map_err(|err| err.to_string())
+        .and_then(|file| json::read_events(std::io::BufReader::new(file)))
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to read {}: {}", path.display(), err);
             std::process::exit(1)
+        })
+}
+
+/// Makes the plan of the tests to execute, exits if there's none.
+pub(crate) fn make_plan(config: &Config, tree: TestTree) -> Vec<execution::Task> {
+    execution::make_plan(config, tree).unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        std::process::exit(1)
+    })
+}
+
+/// Returns the names that occur more than once, in the order of their
+/// second occurrences.
+pub(crate) fn find_duplicates(names: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    let mut duplicates = Vec::new();
+    for name in names {
+        if !seen.insert(name.clone()) && !duplicates.contains(&name) {
+            duplicates.push(name);
         }
-    });
+    }
+    duplicates
+}
 
-    let config = override_config.merge(default_config);
-    default_main_no_config_override(config, tree)
+#[cfg(feature = "history")]
+fn record_history(path: &std::path::Path, completed_tasks: &[CompletedTask]) {
+    let recorded = history::History::open(path)
+        .and_then(|mut db| db.record(std::time::SystemTime::now(), completed_tasks));
+    if let Err(err) = recorded {
+        eprintln!("Failed to record history in {}: {}", path.display(), err);
+    }
 }
 
-/// Runs raclette with a fixed configuration. Does not inspect command line options.
-pub fn default_main_no_config_override(config: Config, tree: TestTree) -> TestResults {
+#[cfg(not(feature = "history"))]
+fn record_history(path: &std::path::Path, _completed_tasks: &[CompletedTask]) {
+    eprintln!(
+        "Not recording history in {}: raclette was built without the history feature",
+        path.display()
+    );
+}
+
+#[cfg(feature = "ui")]
+fn make_ui_report(config: &Config) -> Option<Box<dyn execution::Report>> {
+    use crossterm::tty::IsTty;
+
+    if !std::io::stdout().is_tty() {
+        eprintln!("Not showing the dashboard: stdout is not a terminal");
+        return None;
+    }
+    let timeout = config.timeout.unwrap_or(execution::DEFAULT_TIMEOUT);
+    Some(Box::new(ui::UiReport::new(timeout)))
+}
+
+#[cfg(not(feature = "ui"))]
+fn make_ui_report(_config: &Config) -> Option<Box<dyn execution::Report>> {
+    eprintln!("Not showing the dashboard: raclette was built without the ui feature");
+    None
+}
+
+fn libtest_report(config: &Config, writer: report::ColorWriter) -> report::LibTestReport {
+    let thresholds = config
+        .report_time
+        .then(|| report::TimeThresholds::from_env(&|name| std::env::var(name).ok()));
+    report::LibTestReport::new(writer)
+        .timestamps(config.timestamps)
+        .report_time(thresholds)
+        .aligned(config.aligned)
+        .stage_times(config.stage_times)
+        .ci(config.ci)
+}
+
+fn make_report(config: &Config) -> Box<dyn execution::Report> {
     use config::Format;
+    use report::ColorWriter;
 
-    let writer = report::ColorWriter::new(config.color);
-    let mut report: Box<dyn execution::Report> = match config.format {
-        Format::Auto | Format::LibTest => Box::new(report::LibTestReport::new(writer)),
-        Format::Json => Box::new(report::JsonReport::new(writer)),
-        Format::Tap => Box::new(report::TapReport::new(writer)),
-    };
-    let plan = execution::make_plan(&config, tree);
+    let human = || ColorWriter::for_stream(config.color, config.diagnostics.unwrap_or_default());
+    let machine = || ColorWriter::new(config.color);
+    match config.format {
+        Format::Auto | Format::LibTest if config.ui => match make_ui_report(config) {
+            Some(report) => report,
+            None => Box::new(libtest_report(config, human())),
+        },
+        Format::Auto | Format::LibTest => Box::new(libtest_report(config, human())),
+        Format::Json => {
+            Box::new(report::JsonReport::new(machine()).base64_output(config.base64_output))
+        }
+        Format::Tap => Box::new(report::TapReport::new(machine()).timestamps(config.timestamps)),
+        Format::Trx => {
+            Box::new(trx::TrxReport::new(machine()).compress_output(config.compress_output))
+        }
+        #[cfg(feature = "csv-report")]
+        Format::Csv => Box::new(report::CsvReport::new(machine())),
+        #[cfg(not(feature = "csv-report"))]
+        Format::Csv => {
+            eprintln!("Not writing CSV: raclette was built without the csv-report feature");
+            Box::new(libtest_report(config, human()))
+        }
+    }
+}
 
-    let completed_tasks = execution::execute(&config, plan, &mut *report);
+/// Runs raclette with a fixed configuration. Does not inspect command line options.
+pub fn default_main_no_config_override(config: Config, tree: TestTree) -> TestResults {
+    if let Some(jobs) = config.suggest_split {
+        let plan = make_plan(&config, tree);
+        split::suggest(&config, &plan, jobs);
+        return TestResults {
+            completed_tasks: Vec::new(),
+        };
+    }
+    let baseline: Option<Vec<CompletedTask>> = config
+        .baseline
+        .as_ref()
+        .map(|path| json::completed_tasks(read_events(path)));
+    let mut report = make_report(&config);
+    if let Some(path) = &config.record {
+        report = match recording::RecordingReport::create(path, report) {
+            Ok(recording) => Box::new(recording),
+            Err(err) => {
+                eprintln!("Failed to create {}: {}", path.display(), err);
+                std::process::exit(1)
+            }
+        };
+    }
+    if let Some(path) = &config.logfile {
+        report = match report::LogfileReport::create(path, report) {
+            Ok(logfile) => Box::new(logfile),
+            Err(err) => {
+                eprintln!("Failed to create {}: {}", path.display(), err);
+                std::process::exit(1)
+            }
+        };
+    }
+    if let Some(path) = &config.junit {
+        report = match junit::JUnitReport::create(path, report) {
+            Ok(junit) => Box::new(junit.compress_output(config.compress_output)),
+            Err(err) => {
+                eprintln!("Failed to create {}: {}", path.display(), err);
+                std::process::exit(1)
+            }
+        };
+    }
+    if let Some(resolution) = config.duration_resolution {
+        report = Box::new(report::RoundedDurations::new(resolution, report));
+    }
+    let mut resumed = Vec::new();
+    if let Some(path) = &config.checkpoint {
+        if config.resume {
+            resumed = checkpoint::load(path).unwrap_or_else(|err| {
+                eprintln!("Failed to read {}: {}", path.display(), err);
+                std::process::exit(1)
+            });
+        }
+        report = match checkpoint::CheckpointReport::create(path, report) {
+            Ok(checkpoint) => Box::new(checkpoint),
+            Err(err) => {
+                eprintln!("Failed to create {}: {}", path.display(), err);
+                std::process::exit(1)
+            }
+        };
+    }
+    if let (Some(_), Some(path)) = (config.shard, &config.shard_status_file) {
+        // Tells `bazel test` that the binary executes its shard only.
+        let _ = std::fs::File::create(path);
+    }
+    let completed_tasks = if !config.workers.is_empty() {
+        remote::execute(&config, tree, &mut *report)
+    } else if !config.remotes.is_empty() {
+        remote::execute_on_remotes(&config, tree, &mut *report)
+    } else {
+        let mut plan = make_plan(&config, tree);
+        let planned: std::collections::HashSet<_> =
+            plan.iter().map(|task| task.full_name.clone()).collect();
+        resumed.retain(|done| planned.contains(&done.full_name));
+        let passed: std::collections::HashSet<_> =
+            resumed.iter().map(|done| &done.full_name).collect();
+        plan.retain(|task| !passed.contains(&task.full_name));
+        if !resumed.is_empty() {
+            report = Box::new(checkpoint::ResumedReport::new(resumed.clone(), report));
+        }
+        let mut completed_tasks = resumed;
+        completed_tasks.extend(execution::execute(&config, plan, &mut *report));
+        completed_tasks
+    };
+    if let Some(baseline) = baseline {
+        let comparison = baseline::compare(&baseline, &completed_tasks);
+        report.on_event(&execution::ReportEvent::Compared(&comparison));
+    }
+    if let Some(path) = &config.history_db {
+        record_history(path, &completed_tasks);
+    }
+    if config.notify {
+        notify::send_summary(&completed_tasks);
+    }
     TestResults { completed_tasks }
 }
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    // The generated functions are only compiled, running them would
+    // parse the arguments of the test harness.
+    #[allow(dead_code)]
+    mod default_config {
+        crate::main!(crate::test_case("t", || ()));
+        pub(super) const MAIN: fn() = main;
+    }
+
+    #[allow(dead_code)]
+    mod custom_config {
+        crate::main! {
+            crate::test_suite("s", vec![crate::test_case("t", || ())]),
+            crate::Config::default().jobs(1),
+        }
+        pub(super) const MAIN: fn() = main;
+    }
+
+    #[test]
+    fn main_macro_accepts_a_config() {
+        let _ = (default_config::MAIN, custom_config::MAIN);
+    }
+
+    #[test]
+    fn teardown_hooks_run_after_panics() {
+        use std::io::Write;
+        let log = std::env::temp_dir().join(format!("raclette-hooks-{}", std::process::id()));
+        let append = |path: std::path::PathBuf, line: &'static str| {
+            move || {
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .unwrap();
+                writeln!(file, "{}", line).unwrap();
+            }
+        };
+        let tree = with_each_hooks(
+            append(log.clone(), "setup"),
+            append(log.clone(), "teardown"),
+            test_case("panics", || panic!("broken")),
+        );
+        let tasks = execution::test::run(&Config::default(), tree);
+        let hooks = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_file(&log).unwrap();
+        assert_eq!(tasks[0].status, Status::Failure(prefork::PANIC_EXIT_CODE));
+        assert_eq!(hooks, "setup\nteardown\n");
+    }
+}
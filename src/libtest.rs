@@ -0,0 +1,200 @@
+//! Test binaries built with the libtest harness, e.g. by `cargo test`,
+//! grafted into the tree as suites, see [libtest_suite].
+//!
+//! The tests of the binary are listed when the tree is built.  Each
+//! of them becomes a test running the binary with a filter selecting
+//! it and the JSON format of libtest, whose events give the outcome
+//! and the output of the test.  The JSON format is unstable, so the
+//! binary runs with `RUSTC_BOOTSTRAP=1`.
+
+use crate::{skip, test_case, test_case_ctx, test_suite, TestContext, TestTree};
+use serde::Deserialize;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The events of libtest we care about.
+#[derive(Deserialize)]
+struct LibTestEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    event: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    stdout: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// Returns the names of the tests of the binary, ignored or not.
+fn list(binary: &Path, ignored: bool) -> Result<Vec<String>, String> {
+    let mut command = Command::new(binary);
+    command.args(["--list", "--format", "terse"]);
+    if ignored {
+        command.arg("--ignored");
+    }
+    let output = command
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| format!("failed to run {}: {}", binary.display(), err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed to list its tests: {}",
+            binary.display(),
+            output.status
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.strip_suffix(": test"))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Runs a single test of the binary and forwards its outcome.
+fn run(binary: &Path, name: &str, mut ctx: TestContext) {
+    let mut child = Command::new(binary)
+        .args([name, "--exact", "--include-ignored", "--test-threads", "1"])
+        .args([
+            "-Z",
+            "unstable-options",
+            "--format",
+            "json",
+            "--show-output",
+        ])
+        .env("RUSTC_BOOTSTRAP", "1")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|err| panic!("failed to run {}: {}", binary.display(), err));
+
+    let mut outcome = None;
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    for line in stdout.lines() {
+        let line = line.expect("failed to read the output of the test binary");
+        match serde_json::from_str::<LibTestEvent>(&line) {
+            Ok(event) if event.kind == "test" && event.name == name => {
+                if event.event != "started" {
+                    outcome = Some(event);
+                }
+            }
+            Ok(_) => (),
+            // Not an event, e.g. printed by a test ignoring capture.
+            Err(_) => println!("{}", line),
+        }
+    }
+    let status = child.wait().expect("failed to wait for the test binary");
+
+    let event = outcome.unwrap_or_else(|| {
+        panic!(
+            "{} didn't report the outcome of {} ({})",
+            binary.display(),
+            name,
+            status
+        )
+    });
+    if let Some(stdout) = &event.stdout {
+        print!("{}", stdout);
+        let _ = std::io::stdout().flush();
+    }
+    match event.event.as_str() {
+        "ok" => (),
+        "ignored" => ctx.skip(event.message.as_deref().unwrap_or("ignored")),
+        _ => match event.message {
+            Some(message) => panic!("{}", message),
+            None => panic!("{} failed", name),
+        },
+    }
+}
+
+/// Creates a suite named `name` with a test for each test of the
+/// libtest binary `binary`, e.g. built by `cargo test --no-run`.
+/// The tests ignored by the binary are skipped unless raclette runs
+/// the ignored tests.
+///
+/// The binary is asked to list its tests right away.  If it fails,
+/// the suite consists of a single failing test named "list".
+pub fn libtest_suite(name: impl ToString, binary: impl Into<PathBuf>) -> TestTree {
+    let binary = binary.into();
+    let (all, ignored) = match (list(&binary, false), list(&binary, true)) {
+        (Ok(all), Ok(ignored)) => (all, ignored),
+        (Err(err), _) | (_, Err(err)) => {
+            return test_suite(name, vec![test_case("list", move || panic!("{}", err))])
+        }
+    };
+    let tests = all
+        .into_iter()
+        .map(|test_name| {
+            let is_ignored = ignored.contains(&test_name);
+            let binary = binary.clone();
+            let leaf = test_case_ctx(test_name.clone(), move |ctx| run(&binary, &test_name, ctx));
+            if is_ignored {
+                skip("ignored by the test binary", leaf)
+            } else {
+                leaf
+            }
+        })
+        .collect();
+    test_suite(name, tests)
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Stands in for a test binary with the tests a, b (ignored) and
+    /// c (failing).
+    #[allow(dead_code)]
+    const FAKE_BINARY: &str = r#"#!/bin/sh
+case "$*" in
+    *--list*--ignored*) echo "b: test" ;;
+    *--list*) printf 'a: test\nb: test\nc: test\n' ;;
+    "a "*)
+        echo '{ "type": "test", "event": "started", "name": "a" }'
+        echo '{ "type": "test", "event": "ok", "name": "a" }' ;;
+    "c "*) echo '{ "type": "test", "event": "failed", "name": "c", "message": "boom" }' ;;
+esac
+"#;
+
+    #[test]
+    fn libtest_binaries_become_suites() {
+        use crate::{
+            config::Config,
+            execution::{test::run, Status},
+        };
+
+        // A shell writes the binary, as the processes the other tests
+        // fork meanwhile would inherit a descriptor opened here, and
+        // executing a file open for writing fails.
+        let binary = std::env::temp_dir().join(format!("raclette-libtest-{}", std::process::id()));
+        let written = Command::new("sh")
+            .args(["-c", r#"printf '%s' "$1" > "$0" && chmod +x "$0""#])
+            .arg(&binary)
+            .arg(FAKE_BINARY)
+            .status()
+            .unwrap();
+        assert!(written.success());
+        let tasks = run(&Config::default(), libtest_suite("bin", &binary));
+        let _ = std::fs::remove_file(&binary);
+
+        let outcomes: Vec<_> = tasks
+            .iter()
+            .map(|task| (task.name(), task.status.clone()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("bin::a".to_string(), Status::Success),
+                (
+                    "bin::b".to_string(),
+                    Status::Skipped("ignored by the test binary".to_string())
+                ),
+                ("bin::c".to_string(), Status::Failure(101)),
+            ]
+        );
+
+        let tasks = run(&Config::default(), libtest_suite("bin", &binary));
+        assert_eq!(tasks[0].name(), "bin::list");
+        assert_eq!(tasks[0].status, Status::Failure(101));
+    }
+}
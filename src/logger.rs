@@ -0,0 +1,104 @@
+//! Capture of the records logged by tests with the `log` crate, see
+//! [crate::Config::log_level].
+//!
+//! The logger forwards the records to the driver over the report pipe
+//! of the test.  Libraries using `tracing` are captured too if they
+//! enable its `log` feature.
+
+use crate::execution::{send_report, LogRecord, ReportMessage};
+use log::{Level, Log, Metadata, Record};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// The report pipe of the test running in this process, -1 if no
+/// test is running.  Prefork workers update it for every task.
+static REPORT_FD: AtomicI32 = AtomicI32::new(-1);
+
+struct PipeLogger;
+
+impl Log for PipeLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        REPORT_FD.load(Ordering::Relaxed) >= 0
+    }
+
+    fn log(&self, record: &Record) {
+        let fd = REPORT_FD.load(Ordering::Relaxed);
+        if fd < 0 {
+            return;
+        }
+        let msg = ReportMessage::Log(LogRecord {
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        });
+        // The driver might have stopped listening already.
+        let _ = send_report(fd, &msg);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Makes the records logged at `level` and above in this process
+/// reach the driver over `report_fd`.  Does nothing if the test
+/// binary installed a logger of its own.
+pub(crate) fn capture(report_fd: RawFd, level: Level) {
+    static LOGGER: PipeLogger = PipeLogger;
+
+    REPORT_FD.store(report_fd, Ordering::Relaxed);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level.to_level_filter());
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn records_of_concurrent_threads_stay_whole() {
+        use crate::execution::StreamDecoder;
+        use std::io::Read;
+        use std::os::unix::io::FromRawFd;
+
+        let (read_fd, write_fd) = nix::unistd::pipe().unwrap();
+        capture(write_fd, Level::Info);
+        // Larger than the writes a pipe makes at once.
+        let message = |thread: usize, i: usize| format!("{}-{}-{}", thread, i, "x".repeat(10_000));
+        let reader = std::thread::spawn(move || {
+            let mut data = Vec::new();
+            let mut pipe = unsafe { std::fs::File::from_raw_fd(read_fd) };
+            pipe.read_to_end(&mut data).unwrap();
+            data
+        });
+        let threads: Vec<_> = (0..4)
+            .map(|thread| {
+                std::thread::spawn(move || {
+                    for i in 0..20 {
+                        log::info!("{}", message(thread, i));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        REPORT_FD.store(-1, Ordering::Relaxed);
+        nix::unistd::close(write_fd).unwrap();
+
+        let mut decoder = StreamDecoder::<ReportMessage>::new();
+        decoder.append(&reader.join().unwrap());
+        let mut logged = Vec::new();
+        while let Some(msg) = decoder.try_decode() {
+            match msg {
+                ReportMessage::Log(record) => logged.push(record.message),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        logged.sort();
+        let mut expected: Vec<_> = (0..4)
+            .flat_map(|thread| (0..20).map(move |i| message(thread, i)))
+            .collect();
+        expected.sort();
+        assert_eq!(logged, expected);
+    }
+}
@@ -0,0 +1,69 @@
+//! Desktop notifications of the outcome of a run, see
+//! [crate::Config::notify].
+//!
+//! Notifications are sent with the command line tool of the platform,
+//! `notify-send` on Linux and `osascript` on macOS, so that no
+//! notification library has to be linked into the test binaries.
+
+use crate::execution::CompletedTask;
+use crate::report::TestStats;
+use std::process::{Command, Stdio};
+
+/// The title and the body of the notification summarizing `tasks`.
+fn summary(binary: &str, tasks: &[CompletedTask]) -> (String, String) {
+    let mut stats = TestStats::default();
+    for task in tasks {
+        stats.update(task);
+    }
+    let status = if stats.ok() { "ok" } else { "FAILED" };
+    let title = format!("{}: {}", binary, status);
+    let body = format!(
+        "{} passed; {} failed; {} ignored",
+        stats.ok, stats.failed, stats.ignored
+    );
+    (title, body)
+}
+
+#[cfg(target_os = "macos")]
+fn command(title: &str, body: &str) -> Command {
+    let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut cmd = Command::new("osascript");
+    cmd.arg("-e").arg(format!(
+        "display notification {} with title {}",
+        quote(body),
+        quote(title)
+    ));
+    cmd
+}
+
+#[cfg(not(target_os = "macos"))]
+fn command(title: &str, body: &str) -> Command {
+    let mut cmd = Command::new("notify-send");
+    cmd.arg("--app-name=raclette").arg(title).arg(body);
+    cmd
+}
+
+/// Sends a notification summarizing the outcome of `tasks`.  Failing
+/// to send it only prints a warning.
+pub(crate) fn send_summary(tasks: &[CompletedTask]) {
+    let binary = std::env::args()
+        .next()
+        .and_then(|arg0| {
+            std::path::Path::new(&arg0)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| "raclette".to_string());
+    let (title, body) = summary(&binary, tasks);
+    let mut cmd = command(&title, &body);
+    let sent = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match sent {
+        Ok(status) if status.success() => (),
+        Ok(status) => eprintln!("Failed to send a notification: {:?} {}", cmd, status),
+        Err(err) => eprintln!("Failed to send a notification: {:?}: {}", cmd, err),
+    }
+}
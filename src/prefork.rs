@@ -0,0 +1,152 @@
+//! The worker side of the prefork execution mode.
+//!
+//! In this mode the driver forks a pool of long-lived worker
+//! processes instead of forking a process per test.  The driver sends
+//! a worker indices of tasks to run over a control pipe, and the
+//! worker executes them one by one.  Since all the tests executed by
+//! a worker share its stdout/stderr pipes, the worker prints a marker
+//! to both streams after each test so that the driver can attribute
+//! the output to the right test.
+
+use crate::config::Config;
+use crate::execution::{run_work, write_report, ReportMessage, StreamDecoder, Task};
+use mio::unix::pipe;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+
+/// The marker printed by a worker to stdout and stderr after each
+/// test.
+pub const END_OF_TASK: &[u8] = b"\0\0raclette:end-of-task\0\0";
+
+/// The exit code of a process that panicked.
+pub(crate) const PANIC_EXIT_CODE: i32 = 101;
+
+/// Commands sent by the driver to a worker over the command pipe.
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+pub enum WorkerCommand {
+    /// Execute the task with the given index in the plan.
+    Run(usize),
+    /// Terminate the worker.
+    Exit,
+}
+
+/// Returns the position of the first occurrence of `needle` in
+/// `haystack`.
+pub fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn print_marker(w: &mut dyn Write) {
+    w.flush()
+        .and_then(|_| w.write_all(END_OF_TASK))
+        .and_then(|_| w.flush())
+        .expect("worker: failed to print the end of task marker");
+}
+
+/// Reads commands from the command pipe until the driver asks to
+/// run a task.  Returns None if the driver asks to exit or closes the
+/// pipe.
+pub fn next_task(
+    commands: &mut dyn Read,
+    decoder: &mut StreamDecoder<WorkerCommand>,
+) -> Option<usize> {
+    let mut buf = [0u8; 64];
+    loop {
+        match decoder.try_decode() {
+            Some(WorkerCommand::Run(index)) => return Some(index),
+            Some(WorkerCommand::Exit) => return None,
+            None => (),
+        }
+        match commands.read(&mut buf) {
+            Ok(0) => return None,
+            Ok(n) => decoder.append(&buf[0..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => panic!("failed to read a command: {}", e),
+        }
+    }
+}
+
+/// Discards the messages the driver sent over the control socket
+/// while the previous task was finishing.  The driver sends the
+/// command to run the next task only after these, so they have fully
+/// arrived.
+fn drain(control: &mut UnixStream) {
+    let mut buf = [0u8; 256];
+    control
+        .set_nonblocking(true)
+        .expect("worker: failed to drain the control socket");
+    loop {
+        match control.read(&mut buf) {
+            Ok(n) if n > 0 => (),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            _ => break,
+        }
+    }
+    control
+        .set_nonblocking(false)
+        .expect("worker: failed to drain the control socket");
+}
+
+/// The main loop of a worker process: executes tasks as instructed by
+/// the driver until the command pipe is closed.
+pub fn worker_main(
+    config: &Config,
+    tasks: &mut [Option<Task>],
+    mut commands: pipe::Receiver,
+    mut report: pipe::Sender,
+    mut control: UnixStream,
+) -> ! {
+    let self_pid = nix::unistd::getpid();
+    let mut decoder = StreamDecoder::<WorkerCommand>::new();
+
+    if let Some(init) = config.child_init {
+        init();
+    }
+
+    while let Some(index) = next_task(&mut commands, &mut decoder) {
+        drain(&mut control);
+
+        let task = tasks[index]
+            .take()
+            .expect("worker: the task has already been executed");
+
+        if let Some(ref dir) = config.coverage_dir {
+            let stem = crate::execution::file_stem(&task.full_name, self_pid);
+            std::env::set_var(
+                "LLVM_PROFILE_FILE",
+                dir.join(format!("{}-%p.profraw", stem)),
+            );
+        }
+        if let Some(ref dir) = config.tmp_dir {
+            crate::execution::use_tmp_dir(dir, &task.full_name, self_pid);
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_work(config, task, report.as_raw_fd(), control.as_raw_fd(), None)
+        }));
+        let code = if result.is_ok() { 0 } else { PANIC_EXIT_CODE };
+
+        print_marker(&mut io::stdout());
+        print_marker(&mut io::stderr());
+        write_report(&mut report, &ReportMessage::TaskDone(code))
+            .expect("worker: failed to report task completion");
+    }
+    std::process::exit(0)
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn find_locates_marker() {
+        let mut buf = b"hello".to_vec();
+        buf.extend_from_slice(END_OF_TASK);
+        buf.extend_from_slice(b"next");
+        assert_eq!(find(&buf, END_OF_TASK), Some(5));
+        assert_eq!(find(b"hello", END_OF_TASK), None);
+    }
+}
@@ -0,0 +1,85 @@
+//! External prerequisites of tests, checked by the driver before the
+//! tests are launched, see [crate::requires].
+
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long to wait for a connection to a [Prerequisite::TcpPort].
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Prerequisite {
+    /// An executable with the given name in PATH, or at the given
+    /// path if the name contains a slash.
+    Binary(String),
+    /// An environment variable set to a non-empty value.
+    EnvVar(String),
+    /// A server accepting TCP connections at the given address, e.g.
+    /// "localhost:5432".
+    TcpPort(String),
+    /// An existing file or directory.
+    Path(PathBuf),
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+fn accepts_connections(address: &str) -> bool {
+    match address.to_socket_addrs() {
+        Ok(mut addrs) => {
+            addrs.any(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).is_ok())
+        }
+        Err(_) => false,
+    }
+}
+
+impl Prerequisite {
+    pub fn is_met(&self) -> bool {
+        match self {
+            Prerequisite::Binary(name) if name.contains('/') => is_executable(Path::new(name)),
+            Prerequisite::Binary(name) => std::env::var_os("PATH")
+                .map(|path| std::env::split_paths(&path).any(|dir| is_executable(&dir.join(name))))
+                .unwrap_or(false),
+            Prerequisite::EnvVar(var) => std::env::var_os(var).is_some_and(|v| !v.is_empty()),
+            Prerequisite::TcpPort(address) => accepts_connections(address),
+            Prerequisite::Path(path) => path.exists(),
+        }
+    }
+
+    /// The reason reported for tests skipped because the
+    /// prerequisite is not met.
+    pub fn skip_reason(&self) -> String {
+        match self {
+            Prerequisite::Binary(name) => format!("requires the {} binary", name),
+            Prerequisite::EnvVar(var) => format!("requires {} to be set", var),
+            Prerequisite::TcpPort(address) => format!("requires a server at {}", address),
+            Prerequisite::Path(path) => format!("requires {} to exist", path.display()),
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn prerequisites_are_checked() {
+        use Prerequisite::*;
+        assert!(Binary("sh".to_string()).is_met());
+        assert!(Binary("/bin/sh".to_string()).is_met());
+        assert!(!Binary("raclette-no-such-binary".to_string()).is_met());
+        assert!(EnvVar("PATH".to_string()).is_met());
+        assert!(!EnvVar("RACLETTE_NO_SUCH_VARIABLE".to_string()).is_met());
+        assert!(Path("/".into()).is_met());
+        assert!(!TcpPort("no-such-host.invalid:1".to_string()).is_met());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        assert!(TcpPort(address).is_met());
+    }
+}
@@ -0,0 +1,214 @@
+//! Recordings of the events delivered to a report, see
+//! [crate::Config::record], so that a run can be shown again in any
+//! format with `report --from FILE` without executing the tests.
+//!
+//! A recording is a header line followed by an event per line in
+//! JSON.  Unlike the results printed with `--format json`, it keeps
+//! every detail of the completed tasks, e.g. when their output
+//! arrived, and the events that aren't results, e.g. the heartbeats.
+
+use crate::baseline::Comparison;
+use crate::execution::{CompletedTask, Report, ReportEvent, StageReport};
+use crate::json::RunInfo;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// The first line of a recording.
+const HEADER: &str = "raclette-recording 1";
+
+/// The owned version of a [ReportEvent].
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RecordedEvent {
+    Plan(Vec<String>),
+    SuiteStarted {
+        test_count: usize,
+    },
+    Header(RunInfo),
+    TestStarted {
+        full_name: Vec<String>,
+    },
+    TestFinished(CompletedTask),
+    Retried(CompletedTask),
+    Stage {
+        full_name: Vec<String>,
+        stage: StageReport,
+    },
+    StageInProgress {
+        full_name: Vec<String>,
+        stage: String,
+        elapsed: Duration,
+    },
+    Heartbeat(Vec<(Vec<String>, Duration)>),
+    RunFinished,
+    Compared(Comparison),
+}
+
+impl RecordedEvent {
+    /// Returns None for the events that only matter while the tests
+    /// run.
+    fn new(event: &ReportEvent) -> Option<Self> {
+        let recorded = match *event {
+            ReportEvent::Plan(names) => RecordedEvent::Plan(names.to_vec()),
+            ReportEvent::SuiteStarted { test_count } => RecordedEvent::SuiteStarted { test_count },
+            ReportEvent::Header(info) => RecordedEvent::Header(info.clone()),
+            ReportEvent::TestStarted { full_name } => RecordedEvent::TestStarted {
+                full_name: full_name.to_vec(),
+            },
+            ReportEvent::TestFinished(task) => RecordedEvent::TestFinished(task.clone()),
+            ReportEvent::Retried(task) => RecordedEvent::Retried(task.clone()),
+            ReportEvent::Stage { full_name, stage } => RecordedEvent::Stage {
+                full_name: full_name.to_vec(),
+                stage: stage.clone(),
+            },
+            ReportEvent::StageInProgress {
+                full_name,
+                stage,
+                elapsed,
+            } => RecordedEvent::StageInProgress {
+                full_name: full_name.to_vec(),
+                stage: stage.to_string(),
+                elapsed,
+            },
+            ReportEvent::Heartbeat(running) => RecordedEvent::Heartbeat(running.to_vec()),
+            ReportEvent::Tick => return None,
+            ReportEvent::RunFinished => RecordedEvent::RunFinished,
+            ReportEvent::Compared(comparison) => RecordedEvent::Compared(comparison.clone()),
+        };
+        Some(recorded)
+    }
+
+    fn as_event(&self) -> ReportEvent<'_> {
+        match self {
+            RecordedEvent::Plan(names) => ReportEvent::Plan(names),
+            RecordedEvent::SuiteStarted { test_count } => ReportEvent::SuiteStarted {
+                test_count: *test_count,
+            },
+            RecordedEvent::Header(info) => ReportEvent::Header(info),
+            RecordedEvent::TestStarted { full_name } => ReportEvent::TestStarted { full_name },
+            RecordedEvent::TestFinished(task) => ReportEvent::TestFinished(task),
+            RecordedEvent::Retried(task) => ReportEvent::Retried(task),
+            RecordedEvent::Stage { full_name, stage } => ReportEvent::Stage { full_name, stage },
+            RecordedEvent::StageInProgress {
+                full_name,
+                stage,
+                elapsed,
+            } => ReportEvent::StageInProgress {
+                full_name,
+                stage,
+                elapsed: *elapsed,
+            },
+            RecordedEvent::Heartbeat(running) => ReportEvent::Heartbeat(running),
+            RecordedEvent::RunFinished => ReportEvent::RunFinished,
+            RecordedEvent::Compared(comparison) => ReportEvent::Compared(comparison),
+        }
+    }
+}
+
+/// A report writing the events to a file before passing them on to
+/// another report.
+pub struct RecordingReport {
+    inner: Box<dyn Report>,
+    // None once writing failed, the run goes on without recording.
+    writer: Option<BufWriter<File>>,
+}
+
+impl RecordingReport {
+    /// Creates the recording in `path`, replacing any existing file.
+    pub fn create(path: &Path, inner: Box<dyn Report>) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", HEADER)?;
+        writer.flush()?;
+        Ok(Self {
+            inner,
+            writer: Some(writer),
+        })
+    }
+
+    fn record(&mut self, event: &ReportEvent) -> io::Result<()> {
+        if let (Some(writer), Some(recorded)) = (self.writer.as_mut(), RecordedEvent::new(event)) {
+            serde_json::to_writer(&mut *writer, &recorded)?;
+            writeln!(writer)?;
+            // Nothing may stay buffered when the driver forks a test
+            // process, which could write it again when it exits.
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Report for RecordingReport {
+    fn on_event(&mut self, event: &ReportEvent) {
+        if let Err(err) = self.record(event) {
+            eprintln!("Failed to record the events of the run: {}", err);
+            self.writer = None;
+        }
+        self.inner.on_event(event);
+    }
+}
+
+/// Returns whether `input` starts with the header of a recording,
+/// without consuming it.
+pub fn is_recording(input: &mut impl BufRead) -> io::Result<bool> {
+    Ok(input.fill_buf()?.starts_with(HEADER.as_bytes()))
+}
+
+/// Delivers the events of a recording to `report` and returns the
+/// tasks that finished in the recorded run.
+pub fn replay(input: impl BufRead, report: &mut dyn Report) -> Result<Vec<CompletedTask>, String> {
+    let mut lines = input.lines();
+    match lines.next() {
+        Some(Ok(header)) if header == HEADER => (),
+        Some(Err(err)) => return Err(format!("failed to read the recording: {}", err)),
+        _ => return Err("not a recording".to_string()),
+    }
+    let mut completed = Vec::new();
+    for (n, line) in lines.enumerate() {
+        let line = line.map_err(|e| format!("failed to read the recording: {}", e))?;
+        let recorded: RecordedEvent =
+            serde_json::from_str(&line).map_err(|e| format!("line {}: {}", n + 2, e))?;
+        report.on_event(&recorded.as_event());
+        if let RecordedEvent::TestFinished(task) = recorded {
+            completed.push(task);
+        }
+    }
+    Ok(completed)
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn recordings_round_trip() {
+        struct Names(Vec<String>);
+
+        impl Report for Names {
+            fn start(&mut self, full_name: &[String]) {
+                self.0.push(full_name.join("/"));
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("raclette-recording-{}", std::process::id()));
+        let mut recording = RecordingReport::create(&path, Box::new(Names(Vec::new()))).unwrap();
+        let full_name = vec!["s".to_string(), "a::b".to_string()];
+        recording.on_event(&ReportEvent::SuiteStarted { test_count: 1 });
+        recording.on_event(&ReportEvent::TestStarted {
+            full_name: &full_name,
+        });
+        recording.on_event(&ReportEvent::Tick);
+        recording.on_event(&ReportEvent::RunFinished);
+        drop(recording);
+
+        let mut input = io::BufReader::new(File::open(&path).unwrap());
+        assert!(is_recording(&mut input).unwrap());
+        let mut names = Names(Vec::new());
+        assert_eq!(replay(input, &mut names).unwrap().len(), 0);
+        assert_eq!(names.0, vec!["s/a::b"]);
+        assert!(replay(&b"{}\n"[..], &mut names).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,681 @@
+//! The experimental distributed modes, see [crate::Config::worker]
+//! and [crate::Config::remote].
+//!
+//! Over SSH, each worker host executes a shard of the plan with the
+//! same test binary and the same options, printing JSON events that
+//! come back over SSH.  Once all the workers are done, their events
+//! are merged and shown as if the run had happened locally, the tests
+//! of the workers that failed ending with a driver error.
+//!
+//! Over TCP, the workers are persistent processes started with
+//! `worker --listen ADDR`.  A driver connects to them and sends the
+//! names of the tests to execute, each worker replies with the
+//! results as the tests finish.  The messages are framed like those
+//! of the test processes, see [crate::execution::StreamDecoder].
+//! Workers execute the tests of any driver connecting to them without
+//! authentication, so their port must only be reachable from trusted
+//! hosts, never exposed to the internet.
+
+use crate::config::{self, Config, Scope};
+use crate::execution::{
+    self, serialize_and_write, CompletedTask, Report, ReportEvent, Status, StreamDecoder, Task,
+};
+use crate::json::{self, Event, RunInfo, SuiteEvent, SuiteSummary, TestEvent, SCHEMA_VERSION};
+use crate::report::shell_quote;
+use crate::TestTree;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// How long a driver and a worker wait for each other, to connect or
+/// for the next message, before giving up on the other.
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a worker executing tests tells the driver it's alive if
+/// it has nothing else to send.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A host executing tests, given as `ssh://[USER@]HOST[:PORT][/PATH]`
+/// where PATH is the test binary on the host.  Without a PATH, the
+/// binary of the driver is copied to the host for the run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Worker {
+    destination: String,
+    port: Option<u16>,
+    binary: Option<PathBuf>,
+}
+
+impl FromStr for Worker {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, String> {
+        let rest = url
+            .strip_prefix("ssh://")
+            .ok_or_else(|| format!("expected ssh://[USER@]HOST[:PORT][/PATH], got: {}", url))?;
+        let (authority, binary) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], Some(PathBuf::from(&rest[pos..]))),
+            None => (rest, None),
+        };
+        let (destination, port) = match authority.rsplit_once(':') {
+            Some((destination, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("invalid port in {}", url))?;
+                (destination, Some(port))
+            }
+            None => (authority, None),
+        };
+        if destination.is_empty() {
+            return Err(format!("missing host in {}", url));
+        }
+        Ok(Worker {
+            destination: destination.to_string(),
+            port,
+            binary,
+        })
+    }
+}
+
+/// Returns the arguments of the driver without the options that only
+/// apply to the driver, see [config::OPTIONS].
+fn forwarded_args(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut forwarded = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, _)) if name.starts_with('-') => (name, true),
+            _ => (arg.as_str(), false),
+        };
+        let option = config::OPTIONS
+            .iter()
+            .find(|(names, _, _)| names.contains(&name))
+            .map(|&(_, takes_value, scope)| (takes_value && !inline_value, scope));
+        match option {
+            Some((takes_value, scope)) => {
+                let value = if takes_value { args.next() } else { None };
+                if scope == Scope::Tests {
+                    forwarded.push(arg);
+                    forwarded.extend(value);
+                }
+            }
+            None => forwarded.push(arg),
+        }
+    }
+    forwarded
+}
+
+fn ssh(worker: &Worker) -> Command {
+    let mut command = Command::new("ssh");
+    command.args(["-o", "BatchMode=yes"]);
+    if let Some(port) = worker.port {
+        command.args(["-p", &port.to_string()]);
+    }
+    command.arg(&worker.destination);
+    command
+}
+
+/// Creates a private directory on the worker with mktemp(1) and
+/// returns its path.
+fn remote_temp_dir(worker: &Worker) -> Result<String, String> {
+    let output = ssh(worker)
+        .arg("mktemp -d")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| format!("failed to run ssh: {}", err))?;
+    let dir = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || dir.is_empty() {
+        return Err(format!(
+            "failed to create a directory: ssh {}",
+            output.status
+        ));
+    }
+    Ok(dir)
+}
+
+/// Executes the shard `index` out of `total` on the worker and
+/// returns the events it printed.  The test binary is copied to a
+/// directory of its own on the worker unless the worker names one.
+fn run_shard(
+    worker: &Worker,
+    args: &[String],
+    index: usize,
+    total: usize,
+) -> Result<Vec<Event>, String> {
+    let (binary, copied_to) = match &worker.binary {
+        Some(binary) => (binary.display().to_string(), None),
+        None => {
+            let exe = std::env::current_exe().map_err(|err| err.to_string())?;
+            let dir = remote_temp_dir(worker)?;
+            let name = exe
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "raclette".to_string());
+            let binary = format!("{}/{}", dir, name);
+            let mut scp = Command::new("scp");
+            scp.args(["-q", "-o", "BatchMode=yes"]);
+            if let Some(port) = worker.port {
+                scp.args(["-P", &port.to_string()]);
+            }
+            let status = scp
+                .arg(&exe)
+                .arg(format!("{}:{}", worker.destination, binary))
+                .status()
+                .map_err(|err| format!("failed to run scp: {}", err))?;
+            if !status.success() {
+                let _ = ssh(worker)
+                    .arg(format!("rm -rf {}", shell_quote(&dir)))
+                    .stdin(Stdio::null())
+                    .status();
+                return Err(format!("failed to copy the test binary: scp {}", status));
+            }
+            (binary, Some(dir))
+        }
+    };
+
+    let mut words = vec![binary.clone()];
+    words.extend(args.iter().cloned());
+    words.extend([
+        "--shard".to_string(),
+        format!("{}/{}", index, total),
+        "--format".to_string(),
+        "json".to_string(),
+    ]);
+    let mut remote_command: Vec<String> = words.iter().map(|w| shell_quote(w)).collect();
+    if let Some(dir) = copied_to {
+        remote_command.push(format!("; s=$?; rm -rf {}; exit $s", shell_quote(&dir)));
+    }
+
+    let mut child = ssh(worker)
+        .arg(remote_command.join(" "))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("failed to run ssh: {}", err))?;
+    let events = json::read_events(BufReader::new(child.stdout.take().unwrap()));
+    let status = child.wait().map_err(|err| err.to_string())?;
+    match events {
+        // The exit code only says whether tests failed.
+        Ok(events) if !events.is_empty() => Ok(events),
+        Ok(_) => Err(format!("no results, ssh {}", status)),
+        Err(err) => Err(err),
+    }
+}
+
+/// The events of the shard `index` out of `total` of the plan whose
+/// worker failed: its tests end with a driver error, like those of the
+/// remotes in [execute_on_remotes].
+fn failed_shard(
+    config: &Config,
+    plan: &mut [Option<Task>],
+    index: usize,
+    total: usize,
+    reason: &str,
+) -> Vec<Event> {
+    let mut results = Vec::new();
+    for i in (index..plan.len()).step_by(total) {
+        if let Some(task) = plan[i].take() {
+            let status = Status::DriverError(reason.to_string());
+            let task = execution::unexecuted_task(config, i, task, status);
+            let task = execution::classify(config, task);
+            results.push(Event::Test(TestEvent::new(&task, config.base64_output)));
+        }
+    }
+    let summary = SuiteSummary {
+        failed: results.len(),
+        ..SuiteSummary::default()
+    };
+    let mut events = vec![Event::Suite(SuiteEvent::Started {
+        schema_version: SCHEMA_VERSION,
+        test_count: results.len(),
+    })];
+    events.append(&mut results);
+    events.push(Event::Suite(if summary.failed == 0 {
+        SuiteEvent::Ok(summary)
+    } else {
+        SuiteEvent::Failed(summary)
+    }));
+    events
+}
+
+/// Executes the plan on the workers of the configuration and shows the
+/// merged results in `report`.  The tests of a worker failing to
+/// report its results end with a driver error.
+pub(crate) fn execute(
+    config: &Config,
+    tree: TestTree,
+    report: &mut dyn Report,
+) -> Vec<CompletedTask> {
+    let workers = &config.workers;
+    let mut plan: Vec<_> = crate::make_plan(config, tree)
+        .into_iter()
+        .map(Some)
+        .collect();
+    let args = forwarded_args(std::env::args().skip(1));
+    let runs = std::thread::scope(|scope| {
+        let shards: Vec<_> = workers
+            .iter()
+            .enumerate()
+            .map(|(index, worker)| {
+                let args = &args;
+                scope.spawn(move || run_shard(worker, args, index, workers.len()))
+            })
+            .collect();
+        shards
+            .into_iter()
+            .zip(workers)
+            .enumerate()
+            .map(|(index, (shard, worker))| {
+                shard.join().unwrap().unwrap_or_else(|err| {
+                    let reason = format!("the worker at {} failed: {}", worker.destination, err);
+                    eprintln!("{}", reason);
+                    failed_shard(config, &mut plan, index, workers.len(), &reason)
+                })
+            })
+            .collect()
+    });
+    let events = json::merge_events(runs).unwrap_or_else(|err| {
+        eprintln!("Failed to merge the results of the workers: {}", err);
+        std::process::exit(1)
+    });
+    json::replay(events, report)
+}
+
+/// A request of a driver to a worker listening with `worker --listen`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Request {
+    /// Executes the tests with these full names.
+    Run(Vec<Vec<String>>),
+}
+
+/// The messages of a worker to a driver, for each request.
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Deserialize)]
+enum Reply {
+    Started(Vec<String>),
+    Finished(CompletedTask),
+    /// The worker has no such test to execute, e.g. its filter
+    /// excludes it.
+    Unknown(Vec<String>),
+    /// All the tests of the request were executed.
+    Done,
+    /// The worker is still executing tests, sent when it had nothing
+    /// else to send for [KEEPALIVE_INTERVAL].
+    Alive,
+}
+
+fn send<A: Serialize>(stream: &mut impl Write, message: &A) -> io::Result<()> {
+    // A socket can take a part of a write, so the message is framed
+    // before writing it all.
+    let mut frame = Vec::new();
+    serialize_and_write(&mut frame, message)?;
+    stream.write_all(&frame)
+}
+
+/// Returns the next message of the stream, or None once it's closed.
+fn receive<T: DeserializeOwned>(
+    stream: &mut impl Read,
+    decoder: &mut StreamDecoder<T>,
+) -> io::Result<Option<T>> {
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some(message) = decoder.try_decode() {
+            return Ok(Some(message));
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        decoder.append(&buf[..n]);
+    }
+}
+
+/// A report replying to the driver as the tests start and finish.
+struct ReplyReport<'a> {
+    stream: &'a mut TcpStream,
+    error: Option<io::Error>,
+    sent_at: Instant,
+}
+
+impl Report for ReplyReport<'_> {
+    fn on_event(&mut self, event: &ReportEvent) {
+        let reply = match event {
+            ReportEvent::TestStarted { full_name } => Reply::Started(full_name.to_vec()),
+            ReportEvent::TestFinished(task) => Reply::Finished((*task).clone()),
+            ReportEvent::Tick if self.sent_at.elapsed() >= KEEPALIVE_INTERVAL => Reply::Alive,
+            _ => return,
+        };
+        if self.error.is_none() {
+            self.error = send(self.stream, &reply).err();
+            self.sent_at = Instant::now();
+        }
+    }
+}
+
+/// Serves the requests of a driver until it disconnects.
+fn serve(
+    config: &Config,
+    stream: &mut TcpStream,
+    tasks: &mut [Option<Task>],
+    index: &HashMap<Vec<String>, usize>,
+) -> io::Result<()> {
+    let mut decoder = StreamDecoder::<Request>::new();
+    while let Some(Request::Run(names)) = receive(stream, &mut decoder)? {
+        let mut selected = Vec::new();
+        for name in names {
+            // Skipped tasks are taken out of the plan when reported.
+            match index.get(&name).filter(|i| tasks[**i].is_some()) {
+                Some(i) => selected.push(*i),
+                None => send(stream, &Reply::Unknown(name))?,
+            }
+        }
+        let mut report = ReplyReport {
+            stream,
+            error: None,
+            sent_at: Instant::now(),
+        };
+        execution::execute_selected(config, tasks, &selected, &mut report);
+        if let Some(err) = report.error {
+            return Err(err);
+        }
+        send(stream, &Reply::Done)?;
+    }
+    Ok(())
+}
+
+/// Listens on `addr` and executes the tests requested by the drivers
+/// connecting to it, one driver at a time, until killed.
+pub(crate) fn listen(config: &Config, tree: TestTree, addr: &str) -> ! {
+    let plan = crate::make_plan(config, tree);
+    let index: HashMap<_, _> = plan
+        .iter()
+        .enumerate()
+        .map(|(i, task)| (task.full_name.clone(), i))
+        .collect();
+    let mut tasks: Vec<Option<Task>> = plan.into_iter().map(Some).collect();
+    let listener = TcpListener::bind(addr).unwrap_or_else(|err| {
+        eprintln!("Failed to listen on {}: {}", addr, err);
+        std::process::exit(1)
+    });
+    if let Ok(local) = listener.local_addr() {
+        eprintln!("Listening on {}", local);
+    }
+    for stream in listener.incoming() {
+        let result = stream.and_then(|mut stream| {
+            let peer = stream.peer_addr()?;
+            stream.set_read_timeout(Some(REMOTE_TIMEOUT))?;
+            eprintln!("Executing tests for {}", peer);
+            serve(config, &mut stream, &mut tasks, &index)
+        });
+        if let Err(err) = result {
+            eprintln!("Lost the driver: {}", err);
+        }
+    }
+    unreachable!("the listener stopped accepting connections")
+}
+
+/// Connects to the first address of `addr` accepting the connection
+/// within `timeout`.
+fn connect(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut error = io::Error::new(io::ErrorKind::NotFound, "the address resolves to nothing");
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => error = err,
+        }
+    }
+    Err(error)
+}
+
+/// Sends the tests to the worker at `addr` and passes its replies on
+/// to `replies` until it's done.  Fails if the worker stays silent for
+/// `timeout`.
+fn request(
+    addr: &str,
+    names: Vec<Vec<String>>,
+    replies: &std::sync::mpsc::Sender<Reply>,
+    timeout: Duration,
+) -> io::Result<()> {
+    let mut stream = connect(addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    send(&mut stream, &Request::Run(names))?;
+    let mut decoder = StreamDecoder::<Reply>::new();
+    loop {
+        let reply = receive(&mut stream, &mut decoder).map_err(|err| match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no reply for {:?}", timeout),
+            ),
+            _ => err,
+        })?;
+        match reply {
+            Some(Reply::Done) => return Ok(()),
+            Some(Reply::Alive) => (),
+            Some(reply) => {
+                let _ = replies.send(reply);
+            }
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+/// Executes the plan on the remotes of the configuration, splitting
+/// it round-robin, and shows the results in `report` as they come.
+/// The tests a remote failed to execute end with a driver error.
+pub(crate) fn execute_on_remotes(
+    config: &Config,
+    tree: TestTree,
+    report: &mut dyn Report,
+) -> Vec<CompletedTask> {
+    let plan = crate::make_plan(config, tree);
+    let names: Vec<String> = plan.iter().map(Task::name).collect();
+    report.on_event(&ReportEvent::Plan(&names));
+    report.on_event(&ReportEvent::SuiteStarted {
+        test_count: plan.len(),
+    });
+    report.on_event(&ReportEvent::Header(&RunInfo::collect(config)));
+
+    let remotes = &config.remotes;
+    let mut parts = vec![Vec::new(); remotes.len()];
+    let mut order = Vec::new();
+    let mut unfinished = HashMap::new();
+    for (i, task) in plan.into_iter().enumerate() {
+        let addr = remotes[i % remotes.len()].as_str();
+        parts[i % remotes.len()].push(task.full_name.clone());
+        order.push(task.full_name.clone());
+        unfinished.insert(task.full_name.clone(), (i, task, addr));
+    }
+
+    let mut completed_tasks = Vec::new();
+    let mut finish = |task: CompletedTask, report: &mut dyn Report| {
+        report.on_event(&ReportEvent::TestFinished(&task));
+        completed_tasks.push(task);
+    };
+    let unexecuted = |index: usize, task: Task, reason: String| {
+        let status = Status::DriverError(reason);
+        execution::classify(
+            config,
+            execution::unexecuted_task(config, index, task, status),
+        )
+    };
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let errors = std::thread::scope(|scope| {
+        let requests: Vec<_> = parts
+            .into_iter()
+            .zip(remotes)
+            .map(|(names, addr)| {
+                let sender = sender.clone();
+                scope.spawn(move || request(addr, names, &sender, REMOTE_TIMEOUT))
+            })
+            .collect();
+        drop(sender);
+        for reply in receiver {
+            match reply {
+                Reply::Started(full_name) => report.on_event(&ReportEvent::TestStarted {
+                    full_name: &full_name,
+                }),
+                Reply::Finished(mut task) => {
+                    if let Some((index, _, _)) = unfinished.remove(&task.full_name) {
+                        // The workers run with the clock of the system.
+                        if config.clock.is_some() {
+                            task.start_time = execution::task_start(config, index);
+                        }
+                    }
+                    finish(task, report);
+                }
+                Reply::Unknown(full_name) => {
+                    if let Some((index, task, addr)) = unfinished.remove(&full_name) {
+                        let reason = format!("unknown to the worker at {}", addr);
+                        finish(unexecuted(index, task, reason), report);
+                    }
+                }
+                Reply::Done | Reply::Alive => (),
+            }
+        }
+        requests
+            .into_iter()
+            .zip(remotes)
+            .filter_map(|(request, addr)| {
+                let result = request.join().unwrap();
+                result.err().map(|err| (addr.as_str(), err.to_string()))
+            })
+            .collect::<HashMap<_, _>>()
+    });
+
+    for full_name in order {
+        if let Some((index, task, addr)) = unfinished.remove(&full_name) {
+            let reason = match errors.get(addr) {
+                Some(err) => format!("the worker at {} failed: {}", addr, err),
+                None => format!("the worker at {} didn't execute it", addr),
+            };
+            finish(unexecuted(index, task, reason), report);
+        }
+    }
+    report.on_event(&ReportEvent::RunFinished);
+    completed_tasks
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn workers_parse_and_local_options_stay_local() {
+        let worker: Worker = "ssh://ci@build-1:2222/opt/tests".parse().unwrap();
+        assert_eq!(worker.destination, "ci@build-1");
+        assert_eq!(worker.port, Some(2222));
+        assert_eq!(worker.binary, Some(PathBuf::from("/opt/tests")));
+        assert!("build-1".parse::<Worker>().is_err());
+
+        let args = [
+            "-j",
+            "4",
+            "--worker",
+            "ssh://a",
+            "--format=tap",
+            "--ui",
+            "--skip",
+            "--ui",
+            "--checkpoint",
+            "run.json",
+            "--resume",
+            "--ci=github",
+            "--duration-resolution",
+            "10",
+            "--virtual-time",
+            "--nocapture",
+            "db",
+        ];
+        assert_eq!(
+            forwarded_args(args.iter().map(|a| a.to_string())),
+            vec!["-j", "4", "--skip", "--ui", "db"]
+        );
+    }
+
+    #[test]
+    fn dead_workers_fail_their_tests() {
+        use crate::{test_case, test_suite};
+        // Connections to a listener that never accepts them are
+        // established, but nothing ever comes back.
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap().to_string();
+        let (sender, _receiver) = std::sync::mpsc::channel();
+        let timeout = Duration::from_millis(100);
+        let err = request(&addr, Vec::new(), &sender, timeout).unwrap_err();
+        assert_eq!(err.to_string(), "no reply for 100ms");
+
+        let gone = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = gone.local_addr().unwrap().to_string();
+        drop(gone);
+        struct Silent;
+        impl Report for Silent {}
+        let config = Config::default().remote(&addr);
+        let tree = test_suite("s", vec![test_case("a", || ()), test_case("b", || ())]);
+        let tasks = execute_on_remotes(&config, tree, &mut Silent);
+        assert_eq!(tasks.len(), 2);
+        for task in tasks {
+            match task.status {
+                Status::DriverError(reason) => assert!(
+                    reason.starts_with(&format!("the worker at {} failed: ", addr)),
+                    "{}",
+                    reason
+                ),
+                status => panic!("unexpected status {:?}", status),
+            }
+        }
+    }
+
+    #[test]
+    fn the_tests_of_failed_ssh_workers_fail() {
+        use crate::{test_case, test_suite};
+        let gone = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = gone.local_addr().unwrap().port();
+        drop(gone);
+        struct Silent;
+        impl Report for Silent {}
+        let worker: Worker = format!("ssh://127.0.0.1:{}/bin/true", port)
+            .parse()
+            .unwrap();
+        let config = Config::default().worker(worker.clone()).worker(worker);
+        let tree = test_suite(
+            "s",
+            vec![
+                test_case("a", || ()),
+                test_case("b", || ()),
+                test_case("c", || ()),
+            ],
+        );
+        let tasks = execute(&config, tree, &mut Silent);
+        let names: Vec<_> = tasks.iter().map(CompletedTask::name).collect();
+        assert_eq!(names, vec!["s::a", "s::c", "s::b"]);
+        for task in tasks {
+            match task.status {
+                Status::DriverError(reason) => assert!(
+                    reason.starts_with("the worker at 127.0.0.1 failed: "),
+                    "{}",
+                    reason
+                ),
+                status => panic!("unexpected status {:?}", status),
+            }
+        }
+    }
+
+    #[test]
+    fn requests_survive_the_framing() {
+        let request = Request::Run(vec![vec!["s".to_string(), "t".to_string()]]);
+        let mut stream = Vec::new();
+        send(&mut stream, &request).unwrap();
+        send(&mut stream, &request).unwrap();
+        let mut stream = io::Cursor::new(stream);
+        let mut decoder = StreamDecoder::new();
+        assert_eq!(receive(&mut stream, &mut decoder).unwrap(), Some(request));
+        assert!(receive(&mut stream, &mut decoder).unwrap().is_some());
+        assert_eq!(receive(&mut stream, &mut decoder).unwrap(), None);
+    }
+}
@@ -1,11 +1,25 @@
 use crate::{
-    config::When,
-    execution::{CompletedTask, Report, Status, Task},
+    baseline::Comparison,
+    config::{Ci, Format, Stream, When},
+    diff::{self, Change},
+    execution::{
+        heartbeat_message, Attachment, CompletedTask, LogRecord, Metadata, Metric, OutputChunk,
+        Report, ReportEvent, StageReport, StageStatus, Status, Task,
+    },
+    fuzz::SEED_VAR,
+    json::{self, Event, RunInfo, StageEvent, SuiteEvent, SuiteSummary, TestEvent, SCHEMA_VERSION},
 };
-use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
 use std::io::{self, Write};
+use std::mem::discriminant;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::rc::Rc;
 use std::time::Duration;
-use term::color::{Color, BRIGHT_GREEN, BRIGHT_RED, BRIGHT_YELLOW};
+use term::color::{Color, BRIGHT_GREEN, BRIGHT_RED, BRIGHT_YELLOW, YELLOW};
+use unicode_width::UnicodeWidthStr;
 
 #[derive(Default)]
 pub struct TestStats {
@@ -13,16 +27,31 @@ pub struct TestStats {
     pub ok: usize,
     pub failed: usize,
     pub ignored: usize,
+    /// Tests that passed only after being retried, also counted in
+    /// `ok`.
+    pub flaky: usize,
 }
 
 impl TestStats {
     pub fn update(&mut self, task: &CompletedTask) {
         self.total += 1;
         match task.status {
-            Status::Success => {
+            Status::Success | Status::ExpectedFailure => {
+                self.ok += 1;
+            }
+            Status::Flaky { .. } => {
                 self.ok += 1;
+                self.flaky += 1;
             }
-            Status::Failure(_) | Status::Signaled(_) | Status::Timeout => {
+            Status::Failure(_)
+            | Status::Signaled(_)
+            | Status::Timeout
+            | Status::LeakedFds(_)
+            | Status::SanitizerError(_)
+            | Status::DriverError(_)
+            | Status::MemoryExceeded
+            | Status::OutputExceeded
+            | Status::WroteStderr(_) => {
                 self.failed += 1;
             }
             Status::Skipped(_) => {
@@ -36,22 +65,98 @@ impl TestStats {
     }
 }
 
+impl Stream {
+    fn fd(self) -> RawFd {
+        match self {
+            Stream::Stdout => io::stdout().as_raw_fd(),
+            Stream::Stderr => io::stderr().as_raw_fd(),
+        }
+    }
+}
+
+/// Decides whether output to `stream` should be colored.  `var`
+/// looks up environment variables.
+///
+/// In order of precedence: the --color flag, CARGO_TERM_COLOR,
+/// NO_COLOR (any non-empty value disables colors), CLICOLOR_FORCE
+/// (any non-empty value but 0 enables colors) and finally whether the
+/// stream is a terminal other than a dumb one.
+fn use_color(color: When, stream: Stream, var: &dyn Fn(&str) -> Option<String>) -> bool {
+    let is_set = |name| var(name).is_some_and(|v| !v.is_empty());
+    let color = match color {
+        When::Auto => match var("CARGO_TERM_COLOR").as_deref() {
+            Some("always") => When::Always,
+            Some("never") => When::Never,
+            _ => When::Auto,
+        },
+        color => color,
+    };
+    match color {
+        When::Always => true,
+        When::Never => false,
+        When::Auto if is_set("NO_COLOR") => false,
+        When::Auto if is_set("CLICOLOR_FORCE") && var("CLICOLOR_FORCE").as_deref() != Some("0") => {
+            true
+        }
+        When::Auto => {
+            let dumb = var("TERM").is_none_or(|term| term.is_empty() || term == "dumb");
+            !dumb && nix::unistd::isatty(stream.fd()).unwrap_or(false)
+        }
+    }
+}
+
+/// Writes to stdout or stderr, coloring the output as decided by
+/// [crate::Config::color] and the environment.  Custom reports can
+/// write through it to be colored like the built-in ones.
 pub struct ColorWriter {
-    out: Option<Box<term::StdoutTerminal>>,
+    stream: Stream,
     use_color: bool,
+    // The buffer collecting the output instead of the stream, so that
+    // tests can check it.
+    captured: Option<Rc<RefCell<Vec<u8>>>>,
 }
 
 impl ColorWriter {
     pub fn new(color: When) -> Self {
-        let out = term::stdout();
-        let use_color = match color {
-            When::Never => false,
-            When::Always | When::Auto => match out {
-                Some(ref t) => t.supports_color() && t.supports_reset(),
-                None => false,
-            },
+        Self::for_stream(color, Stream::Stdout)
+    }
+
+    pub fn for_stream(color: When, stream: Stream) -> Self {
+        let use_color = use_color(color, stream, &|name| std::env::var(name).ok());
+        Self {
+            stream,
+            use_color,
+            captured: None,
+        }
+    }
+
+    /// Whether [ColorWriter::with_color] colors the output.
+    pub fn colored(&self) -> bool {
+        self.use_color
+    }
+
+    /// Whether the stream is a terminal.
+    pub fn is_tty(&self) -> bool {
+        nix::unistd::isatty(self.stream.fd()).unwrap_or(false)
+    }
+
+    /// The number of columns of the terminal, or the value of
+    /// COLUMNS if the stream isn't a terminal.  None if neither is
+    /// known.
+    pub fn width(&self) -> Option<usize> {
+        nix::ioctl_read_bad!(window_size, nix::libc::TIOCGWINSZ, nix::pty::Winsize);
+
+        let mut size = nix::pty::Winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
         };
-        Self { out, use_color }
+        // SAFETY: TIOCGWINSZ only writes a Winsize.
+        match unsafe { window_size(self.stream.fd(), &mut size) } {
+            Ok(_) if size.ws_col > 0 => Some(size.ws_col as usize),
+            _ => std::env::var("COLUMNS").ok()?.parse().ok(),
+        }
     }
 
     pub fn newline(&mut self) {
@@ -59,35 +164,506 @@ impl ColorWriter {
     }
 
     pub fn with_color(&mut self, color: Color, f: impl FnOnce(&mut dyn Write)) {
-        match self.out {
-            Some(ref mut t) => {
-                if self.use_color {
-                    t.fg(color).unwrap();
-                    f(t.get_mut());
-                    t.reset().unwrap();
-                } else {
-                    f(t.get_mut());
-                }
-            }
-            None => f(&mut io::stdout()),
+        if self.use_color {
+            // Colors 8-15 are the bright versions of colors 0-7.
+            let code = if color < 8 {
+                30 + color
+            } else {
+                90 + color - 8
+            };
+            write!(self, "\x1b[{}m", code).unwrap();
+            f(self);
+            write!(self, "\x1b[0m").unwrap();
+        } else {
+            f(self);
         }
     }
 }
 
 impl Write for ColorWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.out {
-            Some(ref mut t) => t.write(buf),
-            None => io::stdout().write(buf),
+        if let Some(ref captured) = self.captured {
+            return captured.borrow_mut().write(buf);
+        }
+        match self.stream {
+            Stream::Stdout => io::stdout().write(buf),
+            Stream::Stderr => io::stderr().write(buf),
         }
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        match self.out {
-            Some(ref mut t) => t.flush(),
-            None => io::stdout().flush(),
+        if self.captured.is_some() {
+            return Ok(());
+        }
+        match self.stream {
+            Stream::Stdout => io::stdout().flush(),
+            Stream::Stderr => io::stderr().flush(),
+        }
+    }
+}
+
+/// Prints the names of the tasks in the plan.  The JSON format gets
+/// a "discovered" event per task, describing how the task would run
+/// with the given timeout, other formats get the lines libtest prints
+/// with --list.
+pub fn list(format: Format, writer: &mut ColorWriter, plan: &[Task], timeout: Duration) {
+    for task in plan {
+        if format == Format::Json {
+            let event = Event::Test(TestEvent::Discovered {
+                name: task.name(),
+                name_parts: task.full_name.clone(),
+                tags: task.tags().to_vec(),
+                timeout: Some(timeout),
+                skip_reason: task.skip_reason().map(str::to_string),
+            });
+            serde_json::to_writer(&mut *writer, &event).unwrap();
+            writeln!(writer).unwrap();
+        } else {
+            writeln!(writer, "{}: test", task.name()).unwrap();
+        }
+    }
+    if format != Format::Json {
+        let n = plan.len();
+        writeln!(writer, "\n{} test{}", n, if n == 1 { "" } else { "s" }).unwrap();
+    }
+}
+
+/// Writes a diff of two labeled values: a word diff if both fit on a
+/// line, a diff of their lines otherwise.  Without colors, changed
+/// words are marked with `[-...-]` and `{+...+}`.
+fn write_diff(writer: &mut ColorWriter, old: (&str, &str), new: (&str, &str)) {
+    let (old_label, old) = old;
+    let (new_label, new) = new;
+    writer.with_color(BRIGHT_RED, |out| writeln!(out, "- {}", old_label).unwrap());
+    writer.with_color(BRIGHT_GREEN, |out| {
+        writeln!(out, "+ {}", new_label).unwrap()
+    });
+
+    if old.contains('\n') || new.contains('\n') {
+        let old: Vec<&str> = old.lines().collect();
+        let new: Vec<&str> = new.lines().collect();
+        for change in diff::diff(&old, &new) {
+            match change {
+                Change::Same(line) => writeln!(writer, " {}", line).unwrap(),
+                Change::Removed(line) => {
+                    writer.with_color(BRIGHT_RED, |out| writeln!(out, "-{}", line).unwrap())
+                }
+                Change::Added(line) => {
+                    writer.with_color(BRIGHT_GREEN, |out| writeln!(out, "+{}", line).unwrap())
+                }
+            }
+        }
+        return;
+    }
+
+    // Adjacent changes of the same kind are written together.
+    let mut runs: Vec<(Change, String)> = Vec::new();
+    for change in diff::diff(&diff::words(old), &diff::words(new)) {
+        let word = match change {
+            Change::Same(w) | Change::Removed(w) | Change::Added(w) => w,
+        };
+        match runs.last_mut() {
+            Some((last, text)) if discriminant(last) == discriminant(&change) => {
+                text.push_str(word)
+            }
+            _ => runs.push((change, word.to_string())),
+        }
+    }
+    let use_color = writer.use_color;
+    for (kind, text) in runs {
+        match kind {
+            Change::Same(_) => write!(writer, "{}", text).unwrap(),
+            Change::Removed(_) if use_color => {
+                writer.with_color(BRIGHT_RED, |out| write!(out, "{}", text).unwrap())
+            }
+            Change::Added(_) if use_color => {
+                writer.with_color(BRIGHT_GREEN, |out| write!(out, "{}", text).unwrap())
+            }
+            Change::Removed(_) => write!(writer, "[-{}-]", text).unwrap(),
+            Change::Added(_) => write!(writer, "{{+{}+}}", text).unwrap(),
+        }
+    }
+    writer.newline();
+}
+
+fn format_metric(metric: &Metric) -> String {
+    format!("{}: {:.2}", metric.name, metric.value)
+}
+
+/// Formats the metadata of a failed test.
+fn metadata_lines(metadata: &Metadata) -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Some(ref description) = metadata.description {
+        lines.push(format!("description: {}", description));
+    }
+    if let Some(ref owner) = metadata.owner {
+        lines.push(format!("owner: {}", owner));
+    }
+    for link in metadata.links.iter() {
+        lines.push(format!("link: {}", link));
+    }
+    lines
+}
+
+/// Escapes text for XML, replacing the characters XML doesn't allow,
+/// e.g. the escape sequences of colored output.
+pub(crate) fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' => escaped.push('\u{fffd}'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time, e.g.
+/// "2024-03-01T12:30:00Z".
+pub(crate) fn format_utc(secs: f64) -> String {
+    let secs = secs.max(0.0) as i64;
+    let (days, time) = (secs / 86400, secs % 86400);
+    // Converts days since the epoch to a date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Formats the description of a run.
+fn header_lines(info: &RunInfo) -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Some(ref hostname) = info.hostname {
+        lines.push(format!("host: {}", hostname));
+    }
+    lines.push(format!("started: {}", format_utc(info.start_time)));
+    lines.push(format!("raclette: {}", info.version));
+    if let Some(ref sha) = info.git_sha {
+        lines.push(format!("git: {}", sha));
+    }
+    let mut config = format!("jobs: {}, timeout: {:?}", info.jobs, info.timeout);
+    if !info.filters.is_empty() {
+        let filters: Vec<_> = info.filters.iter().map(|f| shell_quote(f)).collect();
+        config.push_str(&format!(", filter: {}", filters.join(" ")));
+    } else if let Some(ref filter) = info.filter {
+        config.push_str(&format!(", filter: {}", filter));
+    }
+    if !info.skip_filters.is_empty() {
+        config.push_str(&format!(", skip: {}", info.skip_filters.join(" ")));
+    }
+    if !info.tag_filters.is_empty() {
+        config.push_str(&format!(", tags: {}", info.tag_filters.join(" ")));
+    }
+    if let Some(seed) = info.seed {
+        config.push_str(&format!(", seed: {}", seed));
+    }
+    lines.push(config);
+    lines
+}
+
+/// Starts the collapsed section of the log of a CI system holding the
+/// details of the failed `task`, the `index`th failure of the run.
+fn begin_section(writer: &mut ColorWriter, ci: Ci, index: usize, task: &CompletedTask) {
+    let title = format!("test {} failed", task.name());
+    match ci {
+        Ci::Buildkite => writeln!(writer, "--- {}", title).unwrap(),
+        Ci::GitLab => writeln!(
+            writer,
+            "\x1b[0Ksection_start:{}:failure_{}[collapsed=true]\r\x1b[0K{}",
+            unix_time(),
+            index,
+            title
+        )
+        .unwrap(),
+        Ci::GitHub => writeln!(writer, "::group::{}", title).unwrap(),
+    }
+}
+
+/// Ends the section started by [begin_section].  On GitHub, also
+/// annotates the job with the failure.
+fn end_section(writer: &mut ColorWriter, ci: Ci, index: usize, task: &CompletedTask) {
+    match ci {
+        Ci::Buildkite => (),
+        Ci::GitLab => writeln!(
+            writer,
+            "\x1b[0Ksection_end:{}:failure_{}\r\x1b[0K",
+            unix_time(),
+            index
+        )
+        .unwrap(),
+        Ci::GitHub => {
+            writeln!(writer, "::endgroup::").unwrap();
+            let reason = json::outcome(&task.status).1;
+            writeln!(
+                writer,
+                "::error title={}::{}",
+                github_escape(&format!("test {} failed", task.name()), true),
+                github_escape(reason.as_deref().unwrap_or("failed"), false)
+            )
+            .unwrap();
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Escapes the message of a GitHub workflow command, or one of its
+/// properties, which also can't contain ':' and ','.
+fn github_escape(s: &str, property: bool) -> String {
+    let s = s
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A");
+    if property {
+        s.replace(':', "%3A").replace(',', "%2C")
+    } else {
+        s
+    }
+}
+
+/// Describes the stage a running test is in, e.g. "currently in stage
+/// deploy (2m15s)".
+pub(crate) fn current_stage(stage: &str, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    let elapsed = if secs < 60 {
+        format!("{:.1}s", elapsed.as_secs_f64())
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    };
+    format!("currently in stage {} ({})", stage, elapsed)
+}
+
+/// The number of stages listed at the end of the libtest report with
+/// [crate::Config::stage_times].
+const SLOWEST_STAGES: usize = 10;
+
+/// Formats the growth of the peak resident set size of a stage, e.g.
+/// "+12.5MiB".
+fn format_rss_growth(kb: u64) -> String {
+    match kb {
+        0 => "0".to_string(),
+        kb if kb < 1024 => format!("+{}KiB", kb),
+        kb if kb < 1024 * 1024 => format!("+{:.1}MiB", kb as f64 / 1024.0),
+        kb => format!("+{:.1}GiB", kb as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// Formats the stages of a test as a table of their names, outcomes,
+/// durations and resource usage, empty if the test reported no stages.
+fn stage_lines(stages: &[StageReport]) -> Vec<String> {
+    if stages.is_empty() {
+        return Vec::new();
+    }
+    let width = stages
+        .iter()
+        .map(|stage| stage.stage_name.width())
+        .chain(std::iter::once("stage".len()))
+        .max()
+        .unwrap_or(0);
+    let with_usage = stages.iter().any(|stage| stage.usage.is_some());
+    let mut header = format!("{:<width$}  {:<7}  {:>9}", "stage", "status", "time");
+    if with_usage {
+        header.push_str(&format!("  {:>9}  {:>8}", "cpu", "rss"));
+    }
+    let mut lines = vec![header];
+    for stage in stages {
+        let status = match stage.status {
+            StageStatus::Success => "ok",
+            StageStatus::Failure(_) => "FAILED",
+            StageStatus::Skipped(_) => "ignored",
+            StageStatus::Timeout => "TIMEOUT",
+        };
+        let padding = width - stage.stage_name.width();
+        let mut line = format!(
+            "{}{}  {:<7}  {:>8.3}s",
+            stage.stage_name,
+            " ".repeat(padding),
+            status,
+            stage.duration.as_secs_f64()
+        );
+        match stage.usage {
+            Some(usage) => line.push_str(&format!(
+                "  {:>8.3}s  {:>8}",
+                usage.cpu_time.as_secs_f64(),
+                format_rss_growth(usage.max_rss_growth_kb)
+            )),
+            None if with_usage => line.push_str(&format!("  {:>9}  {:>8}", "-", "-")),
+            None => (),
+        }
+        if let Some(message) = stage.message.as_deref().and_then(|m| m.lines().next()) {
+            line.push_str(&format!("  {}", message));
+        }
+        lines.push(line);
+        for attachment in stage.attachments.iter() {
+            match attachment {
+                Attachment::Text { name, text } => {
+                    lines.push(format!("  {}:", name));
+                    lines.extend(text.lines().map(|l| format!("    {}", l)));
+                }
+                Attachment::File(path) => lines.push(format!("  file: {}", path.display())),
+            }
+        }
+    }
+    lines
+}
+
+/// Quotes a word for a POSIX shell if necessary.
+pub(crate) fn shell_quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// Returns a shell command rerunning only the test `name` with the
+/// recorded environment, None if the command line wasn't recorded.
+fn reproduce_command(info: &RunInfo, name: &str) -> Option<String> {
+    let program = info.command_line.first()?;
+    let mut words: Vec<String> = info
+        .env
+        .iter()
+        .map(|(var, value)| format!("{}={}", var, shell_quote(value)))
+        .collect();
+    if let Some(seed) = info.seed.filter(|_| !info.env.contains_key(SEED_VAR)) {
+        words.push(format!("{}={}", SEED_VAR, seed));
+    }
+    words.push(shell_quote(program));
+    words.push("--exact".to_string());
+    words.push(shell_quote(name));
+    Some(words.join(" "))
+}
+
+/// Formats a record logged by a test.
+fn format_log(record: &LogRecord) -> String {
+    format!("{:<5} {}: {}", record.level, record.target, record.message)
+}
+
+/// Splits the output of a test recorded as a transcript into lines,
+/// ordered by the time they started arriving, see
+/// [CompletedTask::output_chunks].
+fn transcript(
+    stdout: &[u8],
+    stderr: &[u8],
+    chunks: &[OutputChunk],
+) -> Vec<(Duration, Stream, String)> {
+    let mut lines: Vec<(Duration, Stream, Vec<u8>)> = Vec::new();
+    // Per stream: the offset of the next chunk and the line still
+    // waiting for its end, if any.
+    let mut offsets = [0, 0];
+    let mut pending: [Option<usize>; 2] = [None, None];
+    let last = chunks.last().map(|c| c.at).unwrap_or_default();
+    let rest = [
+        (Stream::Stdout, stdout.len()),
+        (Stream::Stderr, stderr.len()),
+    ];
+    let chunks = chunks.iter().map(|c| (c.stream, c.at, c.end));
+    // Output that arrived after the last chunk, e.g. with prefork,
+    // is attributed to the time of the last chunk.
+    for (stream, at, end) in chunks.chain(rest.iter().map(|&(s, end)| (s, last, end))) {
+        let (i, output) = match stream {
+            Stream::Stdout => (0, stdout),
+            Stream::Stderr => (1, stderr),
+        };
+        let end = end.min(output.len());
+        if end <= offsets[i] {
+            continue;
         }
+        for piece in output[offsets[i]..end].split_inclusive(|&b| b == b'\n') {
+            let line = *pending[i].get_or_insert_with(|| {
+                lines.push((at, stream, Vec::new()));
+                lines.len() - 1
+            });
+            lines[line].2.extend_from_slice(piece);
+            if piece.ends_with(b"\n") {
+                lines[line].2.pop();
+                pending[i] = None;
+            }
+        }
+        offsets[i] = end;
+    }
+    lines
+        .into_iter()
+        .map(|(at, stream, line)| (at, stream, String::from_utf8_lossy(&line).into_owned()))
+        .collect()
+}
+
+/// Formats a line of the transcript of a test.
+fn format_transcript_line(
+    (at, stream, line): &(Duration, Stream, String),
+    timestamps: bool,
+) -> String {
+    let stream = match stream {
+        Stream::Stdout => "stdout",
+        Stream::Stderr => "stderr",
+    };
+    if timestamps {
+        format!("[{:>9.3}s {}] {}", at.as_secs_f64(), stream, line)
+    } else {
+        format!("[{}] {}", stream, line)
+    }
+}
+
+/// Formats the summary of differences from the baseline.
+pub(crate) fn comparison_lines(comparison: &Comparison) -> Vec<String> {
+    if comparison.is_empty() {
+        return vec!["no differences from the baseline".to_string()];
     }
+    let mut lines = vec!["differences from the baseline:".to_string()];
+    let mut section = |title: &str, names: Vec<String>| {
+        if !names.is_empty() {
+            lines.push(format!("  {} ({}):", title, names.len()));
+            lines.extend(names.into_iter().map(|name| format!("    {}", name)));
+        }
+    };
+    section("newly failing", comparison.newly_failing.clone());
+    section("newly passing", comparison.newly_passing.clone());
+    section("newly skipped", comparison.newly_skipped.clone());
+    section("added", comparison.added.clone());
+    section("removed", comparison.removed.clone());
+    section(
+        "slower",
+        comparison
+            .slower
+            .iter()
+            .map(|(name, before, after)| {
+                format!(
+                    "{} ({:.3}s -> {:.3}s)",
+                    name,
+                    before.as_secs_f64(),
+                    after.as_secs_f64()
+                )
+            })
+            .collect(),
+    );
+    lines
 }
 
 /// This reporter displays results in http://testanything.org/ format.
@@ -97,6 +673,8 @@ pub struct TapReport {
     writer: ColorWriter,
     count: usize,
     total: usize,
+    timestamps: bool,
+    info: Option<RunInfo>,
 }
 
 impl TapReport {
@@ -105,24 +683,42 @@ impl TapReport {
             writer,
             total: 0,
             count: 0,
+            timestamps: false,
+            info: None,
         }
     }
+
+    /// Shows the arrival times in the transcripts of failed tests.
+    pub fn timestamps(mut self, enabled: bool) -> Self {
+        self.timestamps = enabled;
+        self
+    }
 }
 
 impl Report for TapReport {
-    fn init(&mut self, plan: &[Task]) {
+    fn init(&mut self, test_count: usize) {
         writeln!(self.writer, "TAP version 13").unwrap();
-        writeln!(self.writer, "1..{}", plan.len()).unwrap();
-        self.total = plan.len();
+        writeln!(self.writer, "1..{}", test_count).unwrap();
+        self.total = test_count;
     }
 
-    fn start(&mut self, _name: String) {}
+    fn header(&mut self, info: &RunInfo) {
+        for line in header_lines(info) {
+            writeln!(self.writer, "# {}", line).unwrap();
+        }
+        self.info = Some(info.clone());
+    }
+
+    fn start(&mut self, _full_name: &[String]) {}
 
     fn report(&mut self, task: &CompletedTask) {
         self.count += 1;
         let (ok, suffix) = match &task.status {
-            Status::Success => (true, None),
+            Status::Success | Status::Flaky { .. } => (true, None),
             Status::Skipped(reason) => (true, Some(format!(" # SKIP {}", reason))),
+            // TAP reports expected failures as "not ok" tests with a
+            // TODO directive.
+            Status::ExpectedFailure => (false, Some(" # TODO expected failure".to_string())),
             _ => (false, None),
         };
 
@@ -169,9 +765,150 @@ impl Report for TapReport {
                 writeln!(self.writer, "# timed out after {:?}", task.duration).unwrap();
             }
             Status::Skipped(_) => (),
+            Status::LeakedFds(n) => {
+                writeln!(
+                    self.writer,
+                    "# leaked {} file descriptor(s) after {:?}",
+                    n, task.duration
+                )
+                .unwrap();
+            }
+            Status::SanitizerError(ref err) => {
+                writeln!(self.writer, "# {} after {:?}", err, task.duration).unwrap();
+            }
+            Status::Flaky { attempts } => {
+                writeln!(
+                    self.writer,
+                    "# passed after {} attempts in {:?}",
+                    attempts, task.duration
+                )
+                .unwrap();
+            }
+            Status::ExpectedFailure => {
+                writeln!(
+                    self.writer,
+                    "# failed as expected after {:?}",
+                    task.duration
+                )
+                .unwrap();
+            }
+            Status::DriverError(ref err) => {
+                writeln!(self.writer, "# driver error: {}", err).unwrap();
+            }
+            Status::MemoryExceeded => {
+                writeln!(
+                    self.writer,
+                    "# exceeded the memory limit after {:?}",
+                    task.duration
+                )
+                .unwrap();
+            }
+            Status::OutputExceeded => {
+                writeln!(
+                    self.writer,
+                    "# exceeded the output limit after {:?}",
+                    task.duration
+                )
+                .unwrap();
+            }
+            Status::WroteStderr(n) => {
+                writeln!(
+                    self.writer,
+                    "# wrote {} byte(s) to stderr after {:?}",
+                    n, task.duration
+                )
+                .unwrap();
+            }
         }
 
+        for fd in task.leaked_fds.iter() {
+            writeln!(self.writer, "# leaked fd {}", fd).unwrap();
+        }
+        for process in task.leaked_processes.iter() {
+            writeln!(self.writer, "# leaked process {}", process).unwrap();
+        }
+        if let Some(ref category) = task.category {
+            writeln!(self.writer, "# category: {}", category).unwrap();
+        }
+        for metric in task.metrics.iter() {
+            writeln!(self.writer, "# metric {}", format_metric(metric)).unwrap();
+        }
+        if let Some(ref failure) = task.assertion {
+            writeln!(self.writer, "# {}", failure.message).unwrap();
+            for (label, value) in [("expected", &failure.expected), ("actual", &failure.actual)] {
+                writeln!(self.writer, "# --- {} ---", label).unwrap();
+                for line in value.lines() {
+                    writeln!(self.writer, "# {}", line).unwrap();
+                }
+            }
+        }
         if !ok {
+            for line in metadata_lines(&task.metadata) {
+                writeln!(self.writer, "# {}", line).unwrap();
+            }
+        }
+        if let Some(command) = self
+            .info
+            .as_ref()
+            .filter(|_| !task.status.is_ok())
+            .and_then(|info| reproduce_command(info, &task.name()))
+        {
+            writeln!(self.writer, "# reproduce with: {}", command).unwrap();
+        }
+
+        if let Some(ref core) = task.core_dump {
+            writeln!(self.writer, "# core dumped to {}", core.display()).unwrap();
+        }
+        if let Some(ref bt) = task.backtrace {
+            writeln!(self.writer, "# --- backtrace ---").unwrap();
+            for line in bt.lines() {
+                writeln!(self.writer, "# {}", line).unwrap();
+            }
+        }
+
+        if !ok {
+            self.write_output(task);
+        }
+    }
+
+    fn retry(&mut self, attempt: &CompletedTask) {
+        let (_, reason) = json::outcome(&attempt.status);
+        writeln!(
+            self.writer,
+            "# attempt {} of {} failed after {:?}: {}",
+            attempt.attempt,
+            attempt.name(),
+            attempt.duration,
+            reason.unwrap_or_default()
+        )
+        .unwrap();
+        self.write_output(attempt);
+    }
+
+    fn done(&mut self) {}
+
+    fn compare(&mut self, comparison: &Comparison) {
+        for line in comparison_lines(comparison) {
+            writeln!(self.writer, "# {}", line).unwrap();
+        }
+    }
+}
+
+impl TapReport {
+    /// Writes the captured output and logs of a failed task as
+    /// comments.
+    fn write_output(&mut self, task: &CompletedTask) {
+        if !task.output_chunks.is_empty() {
+            writeln!(self.writer, "# --- output ---").unwrap();
+            for line in transcript(&task.stdout, &task.stderr, &task.output_chunks).iter() {
+                writeln!(
+                    self.writer,
+                    "# {}",
+                    format_transcript_line(line, self.timestamps)
+                )
+                .unwrap();
+            }
+        } else {
             if !task.stdout.is_empty() {
                 writeln!(self.writer, "# --- stdout ---").unwrap();
                 for line in task.stdout_as_string().lines() {
@@ -184,10 +921,60 @@ impl Report for TapReport {
                     writeln!(self.writer, "# {}", line).unwrap();
                 }
             }
+            if !task.logs.is_empty() {
+                writeln!(self.writer, "# --- logs ---").unwrap();
+                for record in task.logs.iter() {
+                    for line in format_log(record).lines() {
+                        writeln!(self.writer, "# {}", line).unwrap();
+                    }
+                }
+            }
         }
     }
+}
 
-    fn done(&mut self) {}
+/// The execution times over which the libtest report colors the time
+/// of a test, see [crate::Config::report_time].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeThresholds {
+    pub warn: Duration,
+    pub critical: Duration,
+}
+
+impl Default for TimeThresholds {
+    fn default() -> Self {
+        Self {
+            warn: Duration::from_millis(500),
+            critical: Duration::from_millis(1000),
+        }
+    }
+}
+
+impl TimeThresholds {
+    /// Reads the thresholds from `RUST_TEST_TIME_INTEGRATION`, like
+    /// libtest does for integration tests, falling back to the
+    /// defaults if it's not set or invalid.
+    pub fn from_env(lookup: &dyn Fn(&str) -> Option<String>) -> Self {
+        lookup("RUST_TEST_TIME_INTEGRATION")
+            .and_then(|value| {
+                let (warn, critical) = value.split_once(',')?;
+                Some(Self {
+                    warn: Duration::from_millis(warn.trim().parse().ok()?),
+                    critical: Duration::from_millis(critical.trim().parse().ok()?),
+                })
+            })
+            .unwrap_or_default()
+    }
+
+    fn color(&self, duration: Duration) -> Option<Color> {
+        if duration >= self.critical {
+            Some(BRIGHT_RED)
+        } else if duration >= self.warn {
+            Some(BRIGHT_YELLOW)
+        } else {
+            None
+        }
+    }
 }
 
 /// This reporter tries to imitate the format used by
@@ -200,6 +987,23 @@ pub struct LibTestReport {
     passed: usize,
     failed: Vec<CompletedTask>,
     ignored: usize,
+    // The names of the skipped tests and the reasons.
+    skipped: Vec<(String, String)>,
+    timestamps: bool,
+    report_time: Option<TimeThresholds>,
+    aligned: bool,
+    // The display width of the longest name in the plan, only
+    // computed if aligned.
+    name_width: usize,
+    info: Option<RunInfo>,
+    stage_times: bool,
+    // The full names of the stages of all tests and their durations,
+    // only collected with stage_times.
+    stages: Vec<(String, Duration)>,
+    // The stage each running test reported as in progress, and how
+    // long it had been running at the last report.
+    current_stages: HashMap<String, (String, Duration)>,
+    ci: Option<Ci>,
 }
 
 impl LibTestReport {
@@ -209,13 +1013,68 @@ impl LibTestReport {
             passed: 0,
             failed: vec![],
             ignored: 0,
+            skipped: vec![],
+            timestamps: false,
+            report_time: None,
+            aligned: false,
+            name_width: 0,
+            info: None,
+            stage_times: false,
+            stages: vec![],
+            current_stages: HashMap::new(),
+            ci: None,
         }
     }
+
+    /// Shows the arrival times in the transcripts of failed tests.
+    pub fn timestamps(mut self, enabled: bool) -> Self {
+        self.timestamps = enabled;
+        self
+    }
+
+    /// Appends the execution time to the line of each test that ran,
+    /// colored if it exceeds the thresholds.
+    pub fn report_time(mut self, thresholds: Option<TimeThresholds>) -> Self {
+        self.report_time = thresholds;
+        self
+    }
+
+    /// Pads the test names to the longest name in the plan, so that
+    /// the statuses line up in a column.
+    pub fn aligned(mut self, enabled: bool) -> Self {
+        self.aligned = enabled;
+        self
+    }
+
+    /// Prints the durations of the stages below the line of each
+    /// test, and the slowest stages of all tests at the end.
+    pub fn stage_times(mut self, enabled: bool) -> Self {
+        self.stage_times = enabled;
+        self
+    }
+
+    /// Wraps the details of each failed test in the markers of a
+    /// collapsible section of the log of `ci`.
+    pub fn ci(mut self, ci: Option<Ci>) -> Self {
+        self.ci = ci;
+        self
+    }
+
+    fn write_name(&mut self, name: &str) {
+        let padding = self.name_width.saturating_sub(name.width());
+        write!(self.writer, "test {}{} ... ", name, " ".repeat(padding)).unwrap();
+    }
 }
 
 impl Report for LibTestReport {
-    fn init(&mut self, plan: &[Task]) {
-        let n = plan.len();
+    fn plan(&mut self, names: &[String]) {
+        if self.aligned {
+            self.name_width = names.iter().map(|name| name.width()).max().unwrap_or(0);
+        }
+    }
+
+    fn init(&mut self, test_count: usize) {
+        let n = test_count;
         writeln!(
             self.writer,
             "running {} test{}",
@@ -225,32 +1084,107 @@ impl Report for LibTestReport {
         .unwrap();
     }
 
-    fn start(&mut self, _name: String) {}
+    fn header(&mut self, info: &RunInfo) {
+        for line in header_lines(info) {
+            writeln!(self.writer, "{}", line).unwrap();
+        }
+        self.writer.newline();
+        self.info = Some(info.clone());
+    }
+
+    fn start(&mut self, _full_name: &[String]) {}
+
+    fn stage_in_progress(&mut self, full_name: &[String], stage: &str, elapsed: Duration) {
+        self.current_stages
+            .insert(full_name.join("::"), (stage.to_string(), elapsed));
+    }
+
+    fn heartbeat(&mut self, running: &[(Vec<String>, Duration)]) {
+        let msg = heartbeat_message(running, |name| {
+            let (stage, elapsed) = self.current_stages.get(name)?;
+            Some(current_stage(stage, *elapsed))
+        });
+        eprint!("{}", msg);
+    }
 
     fn report(&mut self, task: &CompletedTask) {
+        self.current_stages.remove(&task.name());
         enum S {
             Ok,
             Ignored,
             Failed,
-        };
+        }
 
         let (ok, status, color) = match task.status {
             Status::Success => (S::Ok, "ok", BRIGHT_GREEN),
+            Status::Flaky { .. } => (S::Ok, "ok (flaky)", BRIGHT_YELLOW),
+            Status::ExpectedFailure => (S::Ok, "ok (expected failure)", BRIGHT_GREEN),
             Status::Skipped(_) => (S::Ignored, "ignored", BRIGHT_YELLOW),
             _ => (S::Failed, "FAILED", BRIGHT_RED),
         };
 
-        write!(self.writer, "test {} ... ", task.name()).unwrap();
+        self.write_name(&task.name());
         self.writer.with_color(color, |out| {
-            writeln!(out, "{}", status).unwrap();
+            write!(out, "{}", status).unwrap();
         });
+        if let Some(thresholds) = self.report_time.filter(|_| !matches!(ok, S::Ignored)) {
+            let time = format!(" <{:.3}s>", task.duration.as_secs_f64());
+            match thresholds.color(task.duration) {
+                Some(color) => self.writer.with_color(color, |out| {
+                    write!(out, "{}", time).unwrap();
+                }),
+                None => write!(self.writer, "{}", time).unwrap(),
+            }
+        }
+        if let Some(ref category) = task.category {
+            write!(self.writer, " ({})", category).unwrap();
+        }
+        if !task.metrics.is_empty() {
+            let metrics: Vec<String> = task.metrics.iter().map(format_metric).collect();
+            write!(self.writer, " [{}]", metrics.join(", ")).unwrap();
+        }
+        self.writer.newline();
+        if self.stage_times {
+            for line in stage_lines(&task.stages) {
+                writeln!(self.writer, "    {}", line).unwrap();
+            }
+            for stage in task.stages.iter() {
+                let name = format!("{}::{}", task.name(), stage.stage_name);
+                self.stages.push((name, stage.duration));
+            }
+        }
 
         match ok {
             S::Ok => {
+                if !task.leaked_fds.is_empty() {
+                    self.writer.with_color(BRIGHT_YELLOW, |out| {
+                        writeln!(
+                            out,
+                            "warning: test {} leaked {} file descriptor(s)",
+                            task.name(),
+                            task.leaked_fds.len()
+                        )
+                        .unwrap();
+                    });
+                }
+                if !task.leaked_processes.is_empty() {
+                    self.writer.with_color(BRIGHT_YELLOW, |out| {
+                        writeln!(
+                            out,
+                            "warning: test {} left {} process(es) running",
+                            task.name(),
+                            task.leaked_processes.len()
+                        )
+                        .unwrap();
+                    });
+                }
                 self.passed += 1;
             }
             S::Ignored => {
                 self.ignored += 1;
+                if let Status::Skipped(ref reason) = task.status {
+                    self.skipped.push((task.name(), reason.clone()));
+                }
             }
             S::Failed => {
                 self.failed.push(task.clone());
@@ -258,12 +1192,35 @@ impl Report for LibTestReport {
         }
     }
 
+    fn retry(&mut self, attempt: &CompletedTask) {
+        self.current_stages.remove(&attempt.name());
+        self.write_name(&attempt.name());
+        self.writer.with_color(BRIGHT_YELLOW, |out| {
+            write!(out, "FAILED (attempt {}), retrying", attempt.attempt).unwrap();
+        });
+        self.writer.newline();
+    }
+
     fn done(&mut self) {
         if !self.failed.is_empty() {
             writeln!(self.writer, "\nfailures:\n").unwrap();
 
-            for task in self.failed.iter() {
-                if !task.stdout.is_empty() {
+            for (index, task) in self.failed.iter().enumerate() {
+                if let Some(ci) = self.ci {
+                    begin_section(&mut self.writer, ci, index, task);
+                }
+                if !task.output_chunks.is_empty() {
+                    writeln!(self.writer, "---- test {} output ----", task.name()).unwrap();
+                    for line in transcript(&task.stdout, &task.stderr, &task.output_chunks).iter() {
+                        writeln!(
+                            self.writer,
+                            "{}",
+                            format_transcript_line(line, self.timestamps)
+                        )
+                        .unwrap();
+                    }
+                    self.writer.newline();
+                } else if !task.stdout.is_empty() {
                     let out = task.stdout_as_string();
                     writeln!(
                         self.writer,
@@ -276,19 +1233,154 @@ impl Report for LibTestReport {
                         self.writer.newline();
                     }
                 }
-                if !task.stderr.is_empty() {
+                if task.output_chunks.is_empty() && !task.stderr.is_empty() {
                     let err = task.stderr_as_string();
+                    let name = task.name();
+                    // Tell the two streams apart at a glance.
+                    self.writer.with_color(YELLOW, |out| {
+                        write!(out, "---- test {} stderr ----\n{}", name, err).unwrap();
+                    });
+                    self.writer.newline();
+                    if !err.ends_with('\n') {
+                        self.writer.newline();
+                    }
+                }
+                if !task.logs.is_empty() {
+                    writeln!(self.writer, "---- test {} logs ----", task.name()).unwrap();
+                    for record in task.logs.iter() {
+                        writeln!(self.writer, "{}", format_log(record)).unwrap();
+                    }
+                    self.writer.newline();
+                }
+                match task.status {
+                    Status::SanitizerError(ref err) => {
+                        writeln!(
+                            self.writer,
+                            "---- test {} sanitizer report ----\n{}\n",
+                            task.name(),
+                            err
+                        )
+                        .unwrap();
+                    }
+                    Status::DriverError(ref err) => {
+                        writeln!(
+                            self.writer,
+                            "---- test {} driver error ----\n{}\n",
+                            task.name(),
+                            err
+                        )
+                        .unwrap();
+                    }
+                    Status::MemoryExceeded => {
+                        writeln!(
+                            self.writer,
+                            "---- test {} exceeded the memory limit ----\n",
+                            task.name()
+                        )
+                        .unwrap();
+                    }
+                    Status::OutputExceeded => {
+                        writeln!(
+                            self.writer,
+                            "---- test {} exceeded the output limit ----\n",
+                            task.name()
+                        )
+                        .unwrap();
+                    }
+                    Status::Timeout => {
+                        let stage = task
+                            .stages
+                            .iter()
+                            .rfind(|stage| stage.status == StageStatus::Timeout);
+                        if let Some(stage) = stage {
+                            writeln!(
+                                self.writer,
+                                "---- test {} timed out in stage {} ----\n",
+                                task.name(),
+                                stage.stage_name
+                            )
+                            .unwrap();
+                        }
+                    }
+                    _ => (),
+                }
+                if let Some(ref core) = task.core_dump {
                     writeln!(
                         self.writer,
-                        "---- test {} stderr ----\n{}",
+                        "---- test {} core dump ----\n{}",
                         task.name(),
-                        err,
+                        core.display()
                     )
                     .unwrap();
-                    if !err.ends_with('\n') {
-                        self.writer.newline();
+                    if let Some(ref bt) = task.backtrace {
+                        write!(self.writer, "{}", bt).unwrap();
                     }
+                    self.writer.newline();
                 }
+                if !task.leaked_fds.is_empty() {
+                    writeln!(
+                        self.writer,
+                        "---- test {} leaked file descriptors ----",
+                        task.name()
+                    )
+                    .unwrap();
+                    for fd in task.leaked_fds.iter() {
+                        writeln!(self.writer, "{}", fd).unwrap();
+                    }
+                    self.writer.newline();
+                }
+                if !task.leaked_processes.is_empty() {
+                    writeln!(
+                        self.writer,
+                        "---- test {} leaked processes ----",
+                        task.name()
+                    )
+                    .unwrap();
+                    for process in task.leaked_processes.iter() {
+                        writeln!(self.writer, "{}", process).unwrap();
+                    }
+                    self.writer.newline();
+                }
+                if let Some(ref failure) = task.assertion {
+                    writeln!(
+                        self.writer,
+                        "---- test {} assertion ----\n{}",
+                        task.name(),
+                        failure.message
+                    )
+                    .unwrap();
+                    write_diff(
+                        &mut self.writer,
+                        ("expected", &failure.expected),
+                        ("actual", &failure.actual),
+                    );
+                    self.writer.newline();
+                } else if let Some((left, right)) = diff::left_right(&task.stderr_as_string()) {
+                    writeln!(self.writer, "---- test {} diff ----", task.name()).unwrap();
+                    write_diff(&mut self.writer, ("left", left), ("right", right));
+                    self.writer.newline();
+                }
+                if !task.metadata.is_empty() {
+                    writeln!(self.writer, "---- test {} metadata ----", task.name()).unwrap();
+                    for line in metadata_lines(&task.metadata) {
+                        writeln!(self.writer, "{}", line).unwrap();
+                    }
+                    self.writer.newline();
+                }
+                if let Some(command) = self
+                    .info
+                    .as_ref()
+                    .and_then(|info| reproduce_command(info, &task.name()))
+                {
+                    writeln!(self.writer, "reproduce with: {}\n", command).unwrap();
+                }
+                if let Some(ci) = self.ci {
+                    end_section(&mut self.writer, ci, index, task);
+                }
+            }
+            if self.ci == Some(Ci::Buildkite) {
+                // Sections only end where the next one starts.
+                writeln!(self.writer, "+++ failures").unwrap();
             }
 
             writeln!(self.writer, "\nfailures:").unwrap();
@@ -296,6 +1388,47 @@ impl Report for LibTestReport {
             for task in self.failed.iter() {
                 writeln!(self.writer, "    {}", task.name()).unwrap();
             }
+
+            if self.failed.iter().any(|task| task.category.is_some()) {
+                let mut categories: Vec<(&str, usize)> = Vec::new();
+                for task in self.failed.iter() {
+                    let category = task.category.as_deref().unwrap_or("uncategorized");
+                    match categories.iter_mut().find(|(c, _)| *c == category) {
+                        Some((_, count)) => *count += 1,
+                        None => categories.push((category, 1)),
+                    }
+                }
+                writeln!(self.writer, "\nfailures by category:").unwrap();
+                for (category, count) in categories {
+                    writeln!(self.writer, "    {}: {}", category, count).unwrap();
+                }
+            }
+        }
+
+        if !self.skipped.is_empty() {
+            let mut reasons: Vec<(&str, Vec<&str>)> = Vec::new();
+            for (name, reason) in self.skipped.iter() {
+                match reasons.iter_mut().find(|(r, _)| r == reason) {
+                    Some((_, names)) => names.push(name),
+                    None => reasons.push((reason, vec![name])),
+                }
+            }
+            writeln!(self.writer, "\nskipped:").unwrap();
+            for (reason, names) in reasons {
+                writeln!(self.writer, "    {} ({}):", reason, names.len()).unwrap();
+                for name in names {
+                    writeln!(self.writer, "        {}", name).unwrap();
+                }
+            }
+        }
+
+        if !self.stages.is_empty() {
+            self.stages
+                .sort_by_key(|(_, duration)| std::cmp::Reverse(*duration));
+            writeln!(self.writer, "\nslowest stages:").unwrap();
+            for (name, duration) in self.stages.iter().take(SLOWEST_STAGES) {
+                writeln!(self.writer, "    {:>9.3}s {}", duration.as_secs_f64(), name).unwrap();
+            }
         }
 
         self.writer.newline();
@@ -318,11 +1451,29 @@ impl Report for LibTestReport {
         )
         .unwrap();
     }
+
+    fn compare(&mut self, comparison: &Comparison) {
+        let color = if comparison.newly_failing.is_empty() {
+            BRIGHT_GREEN
+        } else {
+            BRIGHT_RED
+        };
+        let mut lines = comparison_lines(comparison).into_iter();
+        if let Some(header) = lines.next() {
+            self.writer
+                .with_color(color, |out| writeln!(out, "{}", header).unwrap());
+        }
+        for line in lines {
+            writeln!(self.writer, "{}", line).unwrap();
+        }
+        self.writer.newline();
+    }
 }
 
 pub struct JsonReport {
     writer: ColorWriter,
     stats: TestStats,
+    base64_output: bool,
 }
 
 impl JsonReport {
@@ -330,204 +1481,844 @@ impl JsonReport {
         Self {
             writer,
             stats: Default::default(),
+            base64_output: false,
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn write_event(
-        &mut self,
-        ty: &str,
-        name: &str,
-        evt: &str,
-        exec_time: Duration,
-        stdout: Cow<'_, str>,
-        stderr: Cow<'_, str>,
-        extra: Option<&str>,
-    ) {
-        // A doc test's name includes a filename which must be escaped for correct json.
-        write!(
-            self.writer,
-            r#"{{ "type": "{}", "name": "{}", "event": "{}", "exec_time": "{:.4}s""#,
-            ty,
-            EscapedString(name),
-            evt,
-            exec_time.as_secs_f64(),
-        )
-        .unwrap();
+    /// Encodes the output of tests that is not valid UTF-8 in base64.
+    pub fn base64_output(mut self, enabled: bool) -> Self {
+        self.base64_output = enabled;
+        self
+    }
 
-        if !stdout.is_empty() {
-            write!(self.writer, r#", "stdout": "{}""#, EscapedString(stdout)).unwrap();
+    fn write_event(&mut self, event: Event) {
+        serde_json::to_writer(&mut self.writer, &event).unwrap();
+        writeln!(self.writer).unwrap();
+    }
+}
+
+impl Report for JsonReport {
+    fn init(&mut self, test_count: usize) {
+        self.write_event(Event::Suite(SuiteEvent::Started {
+            schema_version: SCHEMA_VERSION,
+            test_count,
+        }));
+    }
+
+    fn header(&mut self, info: &RunInfo) {
+        self.write_event(Event::Suite(SuiteEvent::Info(Box::new(info.clone()))));
+    }
+
+    fn start(&mut self, full_name: &[String]) {
+        self.write_event(Event::Test(TestEvent::Started {
+            name: full_name.join("::"),
+            name_parts: full_name.to_vec(),
+        }));
+    }
+
+    fn report(&mut self, task: &CompletedTask) {
+        self.stats.update(task);
+        self.write_event(Event::Test(TestEvent::new(task, self.base64_output)));
+    }
+
+    fn retry(&mut self, attempt: &CompletedTask) {
+        self.write_event(Event::Test(TestEvent::retried(attempt, self.base64_output)));
+    }
+
+    fn stage(&mut self, full_name: &[String], stage_rep: StageReport) {
+        self.write_event(Event::Stage(StageEvent::new(full_name, stage_rep)));
+    }
+
+    fn stage_in_progress(&mut self, full_name: &[String], stage: &str, elapsed: Duration) {
+        self.write_event(Event::Stage(StageEvent::running(full_name, stage, elapsed)));
+    }
+
+    fn heartbeat(&mut self, running: &[(Vec<String>, Duration)]) {
+        for (full_name, elapsed) in running {
+            self.write_event(Event::Test(TestEvent::Running {
+                name: full_name.join("::"),
+                name_parts: full_name.clone(),
+                elapsed: *elapsed,
+            }));
         }
-        if !stderr.is_empty() {
-            write!(self.writer, r#", "stderr": "{}""#, EscapedString(stderr)).unwrap();
+    }
+
+    fn compare(&mut self, comparison: &Comparison) {
+        self.write_event(Event::Suite(SuiteEvent::Compared(comparison.into())));
+    }
+
+    fn done(&mut self) {
+        let summary = SuiteSummary {
+            passed: self.stats.ok,
+            failed: self.stats.failed,
+            ignored: self.stats.ignored,
+            flaky: self.stats.flaky,
+        };
+        self.write_event(Event::Suite(if self.stats.ok() {
+            SuiteEvent::Ok(summary)
+        } else {
+            SuiteEvent::Failed(summary)
+        }));
+    }
+}
+
+/// This reporter writes a line of comma-separated values per test,
+/// after a line naming the columns.
+///
+/// This reporter can be enabled by `--format=csv` option.
+#[cfg(feature = "csv-report")]
+pub struct CsvReport {
+    writer: csv::Writer<ColorWriter>,
+}
+
+#[cfg(feature = "csv-report")]
+impl CsvReport {
+    pub fn new(writer: ColorWriter) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(writer),
         }
-        if let Some(extra) = extra {
-            write!(self.writer, r#", {}"#, extra).unwrap();
+    }
+
+    fn write_record<I>(&mut self, record: I)
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        self.writer.write_record(record).unwrap();
+        // Nothing may stay buffered when the driver forks a test
+        // process, which could write it again when it exits.
+        self.writer.flush().unwrap();
+    }
+}
+
+/// A report writing a line per test to a file, as libtest does with
+/// `--logfile`, before passing the events on to another report.
+pub struct LogfileReport {
+    inner: Box<dyn Report>,
+    // None once writing failed.
+    file: Option<File>,
+}
+
+impl LogfileReport {
+    /// Creates the log in `path`, replacing any existing file.
+    pub fn create(path: &Path, inner: Box<dyn Report>) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            file: Some(File::create(path)?),
+        })
+    }
+}
+
+impl Report for LogfileReport {
+    fn on_event(&mut self, event: &ReportEvent) {
+        if let (ReportEvent::TestFinished(task), Some(file)) = (event, self.file.as_mut()) {
+            let (status, _) = json::outcome(&task.status);
+            // The file isn't buffered, so that a forked test process
+            // can't write the same lines again.
+            let line = format!(
+                "{} {} {:.3}s\n",
+                status,
+                task.name(),
+                task.duration.as_secs_f64()
+            );
+            if let Err(err) = file.write_all(line.as_bytes()) {
+                eprintln!("Failed to write the log file: {}", err);
+                self.file = None;
+            }
         }
-        writeln!(self.writer, "}}").unwrap();
+        self.inner.on_event(event);
     }
 }
 
-impl Report for JsonReport {
-    fn init(&mut self, plan: &[Task]) {
-        write!(
-            self.writer,
-            r#"{{ "type": "suite", "event": "started", "test_count": {} }}"#,
-            plan.len()
-        )
-        .unwrap();
-        writeln!(self.writer).unwrap();
+/// Forwards the events to another report with their durations
+/// rounded down to a multiple of a resolution, see
+/// [crate::Config::duration_resolution].
+pub struct RoundedDurations {
+    inner: Box<dyn Report>,
+    resolution: Duration,
+}
+
+impl RoundedDurations {
+    pub fn new(resolution: Duration, inner: Box<dyn Report>) -> Self {
+        Self { inner, resolution }
     }
+}
 
-    fn start(&mut self, name: String) {
-        write!(
-            self.writer,
-            r#"{{ "type": "test", "event": "started", "name": "{}" }}"#,
-            EscapedString(name),
-        )
-        .unwrap();
-        writeln!(self.writer).unwrap();
+fn round_down(duration: Duration, resolution: Duration) -> Duration {
+    if resolution == Duration::default() {
+        return duration;
     }
+    let nanos = duration.as_nanos() / resolution.as_nanos() * resolution.as_nanos();
+    Duration::from_nanos(nanos as u64)
+}
 
-    fn report(&mut self, task: &CompletedTask) {
-        self.stats.update(&task);
-        match task.status {
-            Status::Success => {
-                self.write_event(
-                    "test",
-                    task.name().as_str(),
-                    "ok",
-                    task.duration,
-                    task.stdout_as_string(),
-                    task.stderr_as_string(),
-                    None,
-                );
-            }
-            Status::Failure(ref code) => {
-                self.write_event(
-                    "test",
-                    task.name().as_str(),
-                    "failed",
-                    task.duration,
-                    task.stdout_as_string(),
-                    task.stderr_as_string(),
-                    Some(&format!(
-                        r#""reason": "test process exited with code {}""#,
-                        code
-                    )),
-                );
-            }
-            Status::Signaled(ref signame) => {
-                self.write_event(
-                    "test",
-                    task.name().as_str(),
-                    "failed",
-                    task.duration,
-                    task.stdout_as_string(),
-                    task.stderr_as_string(),
-                    Some(&format!(r#""reason": "killed by signal {}""#, signame)),
-                );
+fn round_stage(mut stage: StageReport, resolution: Duration) -> StageReport {
+    stage.duration = round_down(stage.duration, resolution);
+    if let Some(ref mut usage) = stage.usage {
+        usage.cpu_time = round_down(usage.cpu_time, resolution);
+    }
+    stage
+}
+
+fn round_task(task: &CompletedTask, resolution: Duration) -> CompletedTask {
+    let mut task = task.clone();
+    task.duration = round_down(task.duration, resolution);
+    for chunk in task.output_chunks.iter_mut() {
+        chunk.at = round_down(chunk.at, resolution);
+    }
+    task.stages = task
+        .stages
+        .into_iter()
+        .map(|stage| round_stage(stage, resolution))
+        .collect();
+    task.previous_attempts = task
+        .previous_attempts
+        .iter()
+        .map(|attempt| round_task(attempt, resolution))
+        .collect();
+    task
+}
+
+impl Report for RoundedDurations {
+    fn on_event(&mut self, event: &ReportEvent) {
+        let resolution = self.resolution;
+        match *event {
+            ReportEvent::TestFinished(task) => {
+                let task = round_task(task, resolution);
+                self.inner.on_event(&ReportEvent::TestFinished(&task));
             }
-            Status::Timeout => {
-                self.write_event(
-                    "test",
-                    task.name().as_str(),
-                    "failed",
-                    task.duration,
-                    task.stdout_as_string(),
-                    task.stderr_as_string(),
-                    Some(r#""reason": "time limit exceeded""#),
-                );
-            }
-            Status::Skipped(ref reason) => {
-                self.write_event(
-                    "test",
-                    task.name().as_str(),
-                    "ignored",
-                    task.duration,
-                    task.stdout_as_string(),
-                    task.stderr_as_string(),
-                    Some(&format!(r#""reason": "{}""#, EscapedString(reason),)),
-                );
+            ReportEvent::Retried(task) => {
+                let task = round_task(task, resolution);
+                self.inner.on_event(&ReportEvent::Retried(&task));
+            }
+            ReportEvent::Stage { full_name, stage } => {
+                let stage = round_stage(stage.clone(), resolution);
+                self.inner.on_event(&ReportEvent::Stage {
+                    full_name,
+                    stage: &stage,
+                });
+            }
+            ReportEvent::StageInProgress {
+                full_name,
+                stage,
+                elapsed,
+            } => self.inner.on_event(&ReportEvent::StageInProgress {
+                full_name,
+                stage,
+                elapsed: round_down(elapsed, resolution),
+            }),
+            ReportEvent::Heartbeat(running) => {
+                let running: Vec<_> = running
+                    .iter()
+                    .map(|(name, elapsed)| (name.clone(), round_down(*elapsed, resolution)))
+                    .collect();
+                self.inner.on_event(&ReportEvent::Heartbeat(&running));
             }
+            ReportEvent::Compared(comparison) => {
+                let mut comparison = comparison.clone();
+                for (_, before, after) in comparison.slower.iter_mut() {
+                    *before = round_down(*before, resolution);
+                    *after = round_down(*after, resolution);
+                }
+                self.inner.on_event(&ReportEvent::Compared(&comparison));
+            }
+            _ => self.inner.on_event(event),
         }
     }
+}
 
-    fn done(&mut self) {
-        write!(
-            self.writer,
-            r#"{{ "type": "suite", "event": "{}", "passed": {}, "failed": {}, "ignored": {} }}"#,
-            if self.stats.ok() { "ok" } else { "failed" },
-            self.stats.ok,
-            self.stats.failed,
-            self.stats.ignored,
-        )
-        .unwrap();
-        writeln!(self.writer).unwrap();
+/// The value of the status column of a CSV report.
+#[cfg(feature = "csv-report")]
+fn csv_status(status: &Status) -> &'static str {
+    match status {
+        Status::Flaky { .. } => "flaky",
+        Status::Skipped(_) => "skipped",
+        Status::Timeout => "timeout",
+        status if status.is_ok() => "passed",
+        _ => "failed",
     }
 }
 
-struct EscapedString<S: AsRef<str>>(S);
-
-impl<S: AsRef<str>> std::fmt::Display for EscapedString<S> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> ::std::fmt::Result {
-        let mut start = 0;
-
-        for (i, byte) in self.0.as_ref().bytes().enumerate() {
-            let escaped = match byte {
-                b'"' => "\\\"",
-                b'\\' => "\\\\",
-                b'\x00' => "\\u0000",
-                b'\x01' => "\\u0001",
-                b'\x02' => "\\u0002",
-                b'\x03' => "\\u0003",
-                b'\x04' => "\\u0004",
-                b'\x05' => "\\u0005",
-                b'\x06' => "\\u0006",
-                b'\x07' => "\\u0007",
-                b'\x08' => "\\b",
-                b'\t' => "\\t",
-                b'\n' => "\\n",
-                b'\x0b' => "\\u000b",
-                b'\x0c' => "\\f",
-                b'\r' => "\\r",
-                b'\x0e' => "\\u000e",
-                b'\x0f' => "\\u000f",
-                b'\x10' => "\\u0010",
-                b'\x11' => "\\u0011",
-                b'\x12' => "\\u0012",
-                b'\x13' => "\\u0013",
-                b'\x14' => "\\u0014",
-                b'\x15' => "\\u0015",
-                b'\x16' => "\\u0016",
-                b'\x17' => "\\u0017",
-                b'\x18' => "\\u0018",
-                b'\x19' => "\\u0019",
-                b'\x1a' => "\\u001a",
-                b'\x1b' => "\\u001b",
-                b'\x1c' => "\\u001c",
-                b'\x1d' => "\\u001d",
-                b'\x1e' => "\\u001e",
-                b'\x1f' => "\\u001f",
-                b'\x7f' => "\\u007f",
-                _ => {
-                    continue;
-                }
-            };
+#[cfg(feature = "csv-report")]
+impl Report for CsvReport {
+    fn init(&mut self, _test_count: usize) {
+        self.write_record([
+            "name",
+            "suite",
+            "status",
+            "duration_ms",
+            "attempts",
+            "reason",
+        ]);
+    }
 
-            if start < i {
-                f.write_str(&self.0.as_ref()[start..i])?;
-            }
+    fn report(&mut self, task: &CompletedTask) {
+        let suite = match task.full_name.split_last() {
+            Some((_, suites)) => suites.join("::"),
+            None => String::new(),
+        };
+        let (_, reason) = json::outcome(&task.status);
+        self.write_record([
+            task.name(),
+            suite,
+            csv_status(&task.status).to_string(),
+            task.duration.as_millis().to_string(),
+            task.attempt.to_string(),
+            reason.unwrap_or_default(),
+        ]);
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// A writer capturing the output, and the output so far.
+    #[allow(dead_code)]
+    fn captured(use_color: bool) -> (ColorWriter, impl Fn() -> String) {
+        let captured = Rc::new(RefCell::new(Vec::new()));
+        let writer = ColorWriter {
+            stream: Stream::Stdout,
+            use_color,
+            captured: Some(captured.clone()),
+        };
+        (writer, move || {
+            String::from_utf8(captured.borrow().clone()).unwrap()
+        })
+    }
+
+    #[allow(dead_code)]
+    fn task(name: &str, status: Status, duration_ms: u64) -> CompletedTask {
+        CompletedTask {
+            duration: Duration::from_millis(duration_ms),
+            ..CompletedTask::new(
+                name.split("::").map(String::from).collect(),
+                status,
+                std::time::UNIX_EPOCH,
+            )
+        }
+    }
+
+    #[test]
+    fn aligned_statuses_line_up_by_display_width() {
+        let (writer, output) = captured(false);
+        let mut report = LibTestReport::new(writer).aligned(true);
+        report.plan(&["s::数据".to_string(), "s::long_name".to_string()]);
+        report.init(2);
+        report.report(&task("s::数据", Status::Success, 0));
+        report.report(&task("s::long_name", Status::Success, 0));
+        assert_eq!(
+            output(),
+            "running 2 tests\ntest s::数据      ... ok\ntest s::long_name ... ok\n"
+        );
+    }
 
-            f.write_str(escaped)?;
+    #[test]
+    fn skipped_tests_are_grouped_by_reason() {
+        let (writer, output) = captured(false);
+        let mut report = LibTestReport::new(writer);
+        let skipped = |name, reason: &str| task(name, Status::Skipped(reason.to_string()), 0);
+        report.report(&skipped("a", "no docker"));
+        report.report(&task("b", Status::Success, 0));
+        report.report(&skipped("c", "flaky"));
+        report.report(&skipped("d", "no docker"));
+        report.done();
+        let summary = output();
+        let summary = &summary[summary.find("\nskipped:").unwrap()..];
+        assert_eq!(
+            summary,
+            "\nskipped:
+    no docker (2):
+        a
+        d
+    flaky (1):
+        c
 
-            start = i + 1;
+test result: ok. 1 passed; 0 failed; 3 ignored;
+
+"
+        );
+    }
+
+    #[test]
+    fn captured_stderr_is_colored() {
+        let mut test = task("s::t", Status::Failure(1), 0);
+        test.stdout = b"to stdout\n".to_vec();
+        test.stderr = b"to stderr\n".to_vec();
+
+        let (writer, output) = captured(true);
+        let mut report = LibTestReport::new(writer);
+        report.failed.push(test);
+        report.done();
+        let output = output();
+        assert!(
+            output.contains("---- test s::t stdout ----\nto stdout\n\n"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("\x1b[33m---- test s::t stderr ----\nto stderr\n\x1b[0m\n\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn stage_times_are_tabled_and_summarized() {
+        let stage = |name: &str, status, ms| StageReport {
+            stage_name: name.to_string(),
+            status,
+            duration: Duration::from_millis(ms),
+            message: None,
+            attachments: Vec::new(),
+            usage: None,
+        };
+        let mut test = task("s::t", Status::Failure(1), 3500);
+        test.stages = vec![
+            stage("setup", StageStatus::Success, 500),
+            StageReport {
+                message: Some("connection refused\nretried 3 times".to_string()),
+                ..stage("deploy", StageStatus::Failure(1), 3000)
+            },
+        ];
+
+        let (writer, output) = captured(false);
+        let mut report = LibTestReport::new(writer).stage_times(true);
+        report.report(&test);
+        report.done();
+        let output = output();
+        assert!(output.starts_with(
+            "test s::t ... FAILED
+    stage   status        time
+    setup   ok          0.500s
+    deploy  FAILED      3.000s  connection refused
+"
+        ));
+        assert!(output.contains(
+            "\nslowest stages:
+        3.000s s::t::deploy
+        0.500s s::t::setup
+"
+        ));
+
+        let (writer, output) = captured(false);
+        JsonReport::new(writer).report(&test);
+        assert!(output().contains(r#""stages":[{"name":"s::t::setup","#));
+    }
+
+    #[test]
+    fn attachments_are_shown_under_their_stage() {
+        let mut test = task("s::t", Status::Failure(1), 1000);
+        test.stages = vec![StageReport {
+            stage_name: "deploy".to_string(),
+            status: StageStatus::Failure(1),
+            duration: Duration::from_millis(1000),
+            message: None,
+            attachments: vec![
+                Attachment::Text {
+                    name: "log".to_string(),
+                    text: "pushing\nrefused".to_string(),
+                },
+                Attachment::File("/tmp/core".into()),
+            ],
+            usage: None,
+        }];
+
+        let (writer, output) = captured(false);
+        LibTestReport::new(writer).stage_times(true).report(&test);
+        assert!(output().starts_with(
+            "test s::t ... FAILED
+    stage   status        time
+    deploy  FAILED      1.000s
+      log:
+        pushing
+        refused
+      file: /tmp/core
+"
+        ));
+
+        let (writer, output) = captured(false);
+        JsonReport::new(writer).report(&test);
+        assert!(output().contains(
+            r#""attachments":[{"Text":{"name":"log","text":"pushing\nrefused"}},{"File":"/tmp/core"}]"#
+        ));
+    }
+
+    #[test]
+    fn listed_tests_are_described_in_json() {
+        use crate::{config::Config, execution::make_plan, skip, tag, test_case, test_suite};
+        let tree = test_suite(
+            "s",
+            vec![
+                tag("db", test_case("Vec::new", || ())),
+                skip("slow", test_case("b", || ())),
+            ],
+        );
+        let plan = make_plan(&Config::default(), tree).unwrap();
+        let (mut writer, output) = captured(false);
+        list(Format::Json, &mut writer, &plan, Duration::from_secs(10));
+        assert_eq!(
+            output(),
+            r#"{"type":"test","event":"discovered","name":"s::Vec::new","name_parts":["s","Vec::new"],"tags":["db"],"timeout":"10.0000s"}
+{"type":"test","event":"discovered","name":"s::b","name_parts":["s","b"],"timeout":"10.0000s","skip_reason":"slow"}
+"#
+        );
+    }
+
+    #[test]
+    fn color_writer_colors_only_if_enabled() {
+        for (use_color, expected) in [
+            (true, "\x1b[91mFAILED\x1b[0m \x1b[33mslow\x1b[0m\n"),
+            (false, "FAILED slow\n"),
+        ] {
+            let (mut writer, output) = captured(use_color);
+            assert_eq!(writer.colored(), use_color);
+            writer.with_color(BRIGHT_RED, |out| write!(out, "FAILED").unwrap());
+            write!(writer, " ").unwrap();
+            writer.with_color(term::color::YELLOW, |out| write!(out, "slow").unwrap());
+            writer.newline();
+            assert_eq!(output(), expected);
         }
+    }
+
+    #[test]
+    fn ci_sections_wrap_the_details_of_failures() {
+        let failures = |ci| {
+            let (writer, output) = captured(false);
+            let mut report = LibTestReport::new(writer).ci(Some(ci));
+            let mut failed = task("s::t", Status::Failure(1), 0);
+            failed.stderr = b"boom\n".to_vec();
+            report.report(&failed);
+            report.done();
+            let output = output();
+            let start = output.find("failures:\n\n").unwrap() + "failures:\n\n".len();
+            let end = output.rfind("\nfailures:\n").unwrap();
+            output[start..end].to_string()
+        };
+        assert_eq!(
+            failures(Ci::GitHub),
+            "::group::test s::t failed
+---- test s::t stderr ----
+boom
+
+::endgroup::
+::error title=test s%3A%3At failed::test process exited with code 1
+"
+        );
+        assert_eq!(
+            failures(Ci::Buildkite),
+            "--- test s::t failed
+---- test s::t stderr ----
+boom
+
++++ failures
+"
+        );
+        let gitlab = failures(Ci::GitLab);
+        assert!(gitlab.starts_with("\x1b[0Ksection_start:"));
+        assert!(gitlab.contains(":failure_0[collapsed=true]\r\x1b[0Ktest s::t failed\n"));
+        assert!(gitlab.ends_with(":failure_0\r\x1b[0K\n"));
+    }
 
-        if start != self.0.as_ref().len() {
-            f.write_str(&self.0.as_ref()[start..])?;
+    #[cfg(feature = "csv-report")]
+    #[test]
+    fn csv_report_writes_a_line_per_test() {
+        let (writer, output) = captured(false);
+        let mut report = CsvReport::new(writer);
+        report.init(2);
+        report.report(&CompletedTask {
+            attempt: 2,
+            ..task("s::a, b", Status::Flaky { attempts: 2 }, 1250)
+        });
+        report.report(&task("t", Status::Timeout, 10_000));
+        assert_eq!(
+            output(),
+            "name,suite,status,duration_ms,attempts,reason
+\"s::a, b\",s,flaky,1250,2,passed after 2 attempts
+t,,timeout,10000,1,time limit exceeded
+"
+        );
+    }
+
+    #[test]
+    fn logfile_has_a_line_per_test_whatever_the_format() {
+        let (writer, output) = captured(false);
+        let path = std::env::temp_dir().join(format!("raclette-logfile-{}", std::process::id()));
+        let mut report =
+            LogfileReport::create(&path, Box::new(LibTestReport::new(writer))).unwrap();
+        report.on_event(&ReportEvent::TestFinished(&task(
+            "s::a",
+            Status::Success,
+            1500,
+        )));
+        report.on_event(&ReportEvent::TestFinished(&task(
+            "s::b",
+            Status::Skipped("slow".to_string()),
+            0,
+        )));
+        drop(report);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "ok s::a 1.500s\nignored s::b 0.000s\n"
+        );
+        assert_eq!(output(), "test s::a ... ok\ntest s::b ... ignored\n");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn use_color_honors_environment() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        let out = Stream::Stdout;
+        assert!(use_color(When::Always, out, &env(&[("TERM", "dumb")])));
+        assert!(!use_color(
+            When::Never,
+            out,
+            &env(&[("CLICOLOR_FORCE", "1")])
+        ));
+        assert!(use_color(
+            When::Auto,
+            out,
+            &env(&[("CARGO_TERM_COLOR", "always"), ("NO_COLOR", "1")])
+        ));
+        assert!(!use_color(
+            When::Auto,
+            out,
+            &env(&[("NO_COLOR", "1"), ("CLICOLOR_FORCE", "1")])
+        ));
+        assert!(use_color(
+            When::Auto,
+            out,
+            &env(&[("NO_COLOR", ""), ("CLICOLOR_FORCE", "1")])
+        ));
+        assert!(!use_color(
+            When::Auto,
+            out,
+            &env(&[("CLICOLOR_FORCE", "0"), ("TERM", "dumb")])
+        ));
+    }
+
+    #[test]
+    fn transcript_interleaves_lines_by_arrival() {
+        let chunk = |stream, ms, end| OutputChunk {
+            stream,
+            at: Duration::from_millis(ms),
+            end,
+        };
+        let chunks = [
+            chunk(Stream::Stdout, 1, 8),
+            chunk(Stream::Stderr, 2, 8),
+            chunk(Stream::Stdout, 3, 20),
+        ];
+        let lines: Vec<String> = transcript(b"one\ntwo, continued\nrest", b"warning\n", &chunks)
+            .iter()
+            .map(|line| format_transcript_line(line, true))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "[    0.001s stdout] one",
+                "[    0.001s stdout] two, continued",
+                "[    0.002s stderr] warning",
+                "[    0.003s stdout] rest",
+            ]
+        );
+        assert_eq!(
+            format_transcript_line(&transcript(b"", b"x\n", &chunks)[0], false),
+            "[stderr] x"
+        );
+    }
+
+    #[test]
+    fn time_thresholds_follow_libtest_variable() {
+        let env = |value: &'static str| move |_: &str| Some(value.to_string());
+        let ms = Duration::from_millis;
+        let thresholds = TimeThresholds::from_env(&env("100, 200"));
+        assert_eq!((thresholds.warn, thresholds.critical), (ms(100), ms(200)));
+        assert_eq!(thresholds.color(ms(99)), None);
+        assert_eq!(thresholds.color(ms(150)), Some(BRIGHT_YELLOW));
+        assert_eq!(thresholds.color(ms(200)), Some(BRIGHT_RED));
+        assert_eq!(
+            TimeThresholds::from_env(&env("fast")),
+            TimeThresholds::default()
+        );
+    }
+
+    #[test]
+    fn format_utc_converts_to_calendar_dates() {
+        assert_eq!(format_utc(0.0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(951_827_696.5), "2000-02-29T12:34:56Z");
+        assert_eq!(format_utc(1_735_689_599.0), "2024-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn reproduce_command_quotes_words() {
+        let mut info = RunInfo {
+            hostname: None,
+            start_time: 0.0,
+            version: String::new(),
+            jobs: 1,
+            timeout: Duration::from_secs(1),
+            filter: None,
+            filters: Vec::new(),
+            skip_filters: Vec::new(),
+            tag_filters: Vec::new(),
+            seed: Some(7),
+            git_sha: None,
+            command_line: Vec::new(),
+            env: Default::default(),
+        };
+        assert_eq!(reproduce_command(&info, "a::b"), None);
+        info.command_line = vec!["target/debug/tests".to_string(), "a".to_string()];
+        info.env
+            .insert("RUST_LOG".to_string(), "it's debug".to_string());
+        assert_eq!(
+            reproduce_command(&info, "a::b c").unwrap(),
+            "RUST_LOG='it'\\''s debug' RACLETTE_FUZZ_SEED=7 target/debug/tests --exact 'a::b c'"
+        );
+    }
+
+    #[test]
+    fn filters_with_spaces_are_described_whole() {
+        // The start time of the system clock may not survive JSON.
+        let config = crate::Config::default()
+            .clock(crate::VirtualClock)
+            .filter("a b".to_string())
+            .add_filter("c");
+        let info = RunInfo::collect(&config);
+        assert_eq!(info.filters, vec!["a b", "c"]);
+        assert_eq!(info.filter.as_deref(), Some("a b c"));
+        assert!(header_lines(&info)
+            .iter()
+            .any(|line| line.contains(", filter: 'a b' c")));
+
+        let (writer, output) = captured(false);
+        JsonReport::new(writer).header(&info);
+        let event: Event = serde_json::from_str(&output()).unwrap();
+        assert_eq!(event, Event::Suite(SuiteEvent::Info(Box::new(info))));
+    }
+
+    #[test]
+    fn current_stage_shows_minutes_once_long() {
+        assert_eq!(
+            current_stage("build", Duration::from_millis(2500)),
+            "currently in stage build (2.5s)"
+        );
+        assert_eq!(
+            current_stage("deploy", Duration::from_secs(135)),
+            "currently in stage deploy (2m15s)"
+        );
+    }
+
+    #[test]
+    fn durations_are_rounded_down() {
+        let resolution = Duration::from_millis(100);
+        assert_eq!(
+            round_down(Duration::from_millis(1299), resolution),
+            Duration::from_millis(1200)
+        );
+        assert_eq!(
+            round_down(Duration::from_millis(99), resolution),
+            Duration::default()
+        );
+        assert_eq!(
+            round_down(Duration::from_millis(99), Duration::default()),
+            Duration::from_millis(99)
+        );
+    }
+
+    #[test]
+    fn rounded_reports_are_the_same_from_one_run_to_the_next() {
+        let resolution = Duration::from_millis(100);
+        let run = |build_ms: u64, test_ms: u64| {
+            let mut test = task("s::t", Status::Failure(1), build_ms + test_ms + 7);
+            test.stages = vec![
+                StageReport {
+                    stage_name: "build".to_string(),
+                    status: StageStatus::Success,
+                    duration: Duration::from_millis(build_ms),
+                    message: None,
+                    attachments: Vec::new(),
+                    usage: None,
+                },
+                StageReport {
+                    stage_name: "test".to_string(),
+                    status: StageStatus::Failure(1),
+                    duration: Duration::from_millis(test_ms),
+                    message: None,
+                    attachments: Vec::new(),
+                    usage: None,
+                },
+            ];
+            let (writer, libtest) = captured(false);
+            let mut report = RoundedDurations::new(
+                resolution,
+                Box::new(LibTestReport::new(writer).stage_times(true)),
+            );
+            report.on_event(&ReportEvent::SuiteStarted { test_count: 1 });
+            report.on_event(&ReportEvent::TestFinished(&test));
+            report.on_event(&ReportEvent::RunFinished);
+            let (writer, json) = captured(false);
+            let mut report = RoundedDurations::new(resolution, Box::new(JsonReport::new(writer)));
+            report.on_event(&ReportEvent::TestFinished(&test));
+            (libtest(), json())
+        };
+        let (libtest, json) = run(1234, 567);
+        assert_eq!(
+            libtest,
+            "running 1 test
+test s::t ... FAILED
+    stage  status        time
+    build  ok          1.200s
+    test   FAILED      0.500s
+
+failures:
+
+
+failures:
+    s::t
+
+slowest stages:
+        1.200s s::t::build
+        0.500s s::t::test
+
+test result: FAILED. 0 passed; 1 failed; 0 ignored;
+
+"
+        );
+        assert_eq!(
+            json,
+            r#"{"type":"test","event":"failed","name":"s::t","name_parts":["s","t"],"status":{"failure":1},"exec_time":"1.8000s","reason":"test process exited with code 1","start_time":0.0,"attempt":1,"stages":[{"name":"s::t::build","name_parts":["s","t","build"],"status":"Success","exec_time":"1.2000s"},{"name":"s::t::test","name_parts":["s","t","test"],"status":{"Failure":1},"exec_time":"0.5000s","reason":"test process exited with code 1"}]}
+"#
+        );
+        assert_eq!(run(1299, 501), (libtest, json));
+
+        struct Elapsed(Rc<RefCell<Vec<Duration>>>);
+        impl Report for Elapsed {
+            fn stage_in_progress(&mut self, _: &[String], _: &str, elapsed: Duration) {
+                self.0.borrow_mut().push(elapsed);
+            }
         }
+        let elapsed = Rc::new(RefCell::new(Vec::new()));
+        let mut report = RoundedDurations::new(resolution, Box::new(Elapsed(elapsed.clone())));
+        report.on_event(&ReportEvent::StageInProgress {
+            full_name: &["s".to_string(), "t".to_string()],
+            stage: "build",
+            elapsed: Duration::from_millis(1234),
+        });
+        assert_eq!(*elapsed.borrow(), vec![Duration::from_millis(1200)]);
+    }
 
-        Ok(())
+    #[test]
+    fn rss_growth_uses_largest_unit() {
+        assert_eq!(format_rss_growth(0), "0");
+        assert_eq!(format_rss_growth(512), "+512KiB");
+        assert_eq!(format_rss_growth(12800), "+12.5MiB");
+        assert_eq!(format_rss_growth(3 * 1024 * 1024), "+3.0GiB");
     }
 }
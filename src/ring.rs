@@ -0,0 +1,288 @@
+//! A ring buffer in shared memory carrying the stages and metrics of a
+//! test process to the driver, see [crate::Config::shm_reports].
+//!
+//! Going through the report pipe, each metric costs a write(2) in the
+//! test and a wakeup of the driver.  Instead, the driver maps a memfd
+//! before forking the test process, which writes its messages into
+//! it, and the test only sends [ReportMessage::Drain] over the report
+//! pipe when the driver has read all the previous messages.  The
+//! driver then reads the ring until it is empty.  A message too large
+//! for the ring goes through the report pipe once the ring is empty,
+//! so the driver still gets the messages in order.
+//!
+//! Only Linux has memfds, the messages go through the report pipe on
+//! the other platforms.
+
+use crate::execution::ReportMessage;
+use nix::sys::mman::munmap;
+use std::io;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use {
+    nix::sys::memfd::{memfd_create, MemFdCreateFlag},
+    nix::sys::mman::{mmap, MapFlags, ProtFlags},
+    nix::unistd,
+    std::ffi::CString,
+};
+
+/// The number of bytes of messages a ring holds.
+pub(crate) const RING_SIZE: usize = 1 << 20;
+
+/// How long the test waits for the driver to make room in a full
+/// ring before checking again.
+const FULL_RING_DELAY: Duration = Duration::from_micros(100);
+
+/// The positions in the ring, in front of the messages.  They only
+/// grow, the offset of a position in the ring is modulo its capacity.
+#[repr(C)]
+struct Header {
+    /// Where the test writes the next message.
+    head: AtomicUsize,
+    /// Where the driver reads the next message.
+    tail: AtomicUsize,
+    /// Set when the driver read all the messages, the next one must
+    /// come with a [ReportMessage::Drain].
+    idle: AtomicBool,
+}
+
+/// Where the messages start in the mapping, a cache line after the
+/// header.
+const DATA_OFFSET: usize = 64;
+
+#[cfg(target_os = "linux")]
+fn io_error(err: nix::Error) -> io::Error {
+    match err.as_errno() {
+        Some(errno) => io::Error::from(errno),
+        None => io::Error::other(err),
+    }
+}
+
+/// Maps `len` bytes of a new memfd, shared with the processes forked
+/// afterwards.
+#[cfg(target_os = "linux")]
+fn map_shared(len: usize) -> io::Result<*mut u8> {
+    let name = CString::new("raclette-reports").unwrap();
+    let fd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC).map_err(io_error)?;
+    let mapped = unistd::ftruncate(fd, len as i64).and_then(|()| {
+        // SAFETY: a new mapping of a file nothing else uses.
+        unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                fd,
+                0,
+            )
+        }
+    });
+    // The mapping keeps the memory.
+    let _ = unistd::close(fd);
+    Ok(mapped.map_err(io_error)? as *mut u8)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn map_shared(_len: usize) -> io::Result<*mut u8> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unsupported on this platform",
+    ))
+}
+
+/// The ring of a test process, mapped in both the driver and the test
+/// process.  Each message is its size as 4 bytes followed by the
+/// message serialized with bincode.
+pub(crate) struct ReportRing {
+    base: *mut u8,
+    capacity: usize,
+    // Set once the driver found something else than messages in the
+    // ring, the rest is ignored.
+    corrupt: bool,
+}
+
+// SAFETY: the mapping is not tied to a thread, and the test and the
+// driver each access it from a single thread at a time, through the
+// atomic positions.
+unsafe impl Send for ReportRing {}
+
+impl ReportRing {
+    /// Maps a ring of `capacity` bytes, to be inherited by a test
+    /// process.
+    pub(crate) fn new(capacity: usize) -> io::Result<Self> {
+        let ring = Self {
+            base: map_shared(DATA_OFFSET + capacity)?,
+            capacity,
+            corrupt: false,
+        };
+        // The memory is zeroed, both positions are 0.
+        ring.header().idle.store(true, Ordering::SeqCst);
+        Ok(ring)
+    }
+
+    fn header(&self) -> &Header {
+        // SAFETY: the mapping starts with the header and lives as
+        // long as the ring.
+        unsafe { &*(self.base as *const Header) }
+    }
+
+    /// Copies `bytes` to the position `pos`, wrapping around the end.
+    fn write_at(&self, pos: usize, bytes: &[u8]) {
+        let offset = pos % self.capacity;
+        let first = bytes.len().min(self.capacity - offset);
+        // SAFETY: both parts are within the data of the mapping, which
+        // only the test writes to between the head and the tail.
+        unsafe {
+            let data = self.base.add(DATA_OFFSET);
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), data.add(offset), first);
+            std::ptr::copy_nonoverlapping(bytes[first..].as_ptr(), data, bytes.len() - first);
+        }
+    }
+
+    /// Copies the bytes at the position `pos` to `out`, wrapping
+    /// around the end.
+    fn read_at(&self, pos: usize, out: &mut [u8]) {
+        let offset = pos % self.capacity;
+        let first = out.len().min(self.capacity - offset);
+        // SAFETY: same as above.
+        unsafe {
+            let data = self.base.add(DATA_OFFSET);
+            std::ptr::copy_nonoverlapping(data.add(offset), out.as_mut_ptr(), first);
+            std::ptr::copy_nonoverlapping(data, out[first..].as_mut_ptr(), out.len() - first);
+        }
+    }
+
+    /// Writes `message` to the ring, in the test process, waiting for
+    /// the driver to make room if needed.  `wake` is called to send a
+    /// [ReportMessage::Drain] when the driver has to read it.  Returns
+    /// false if the message doesn't fit in the ring, once the driver
+    /// has read all the others, so that it can be sent over the pipe.
+    pub(crate) fn push(&mut self, message: &ReportMessage, mut wake: impl FnMut()) -> bool {
+        let payload = bincode::serialize(message).unwrap();
+        let needed = size_of::<u32>() + payload.len();
+        let header = self.header();
+        let head = header.head.load(Ordering::Relaxed);
+        if needed > self.capacity {
+            while header.tail.load(Ordering::SeqCst) != head {
+                std::thread::sleep(FULL_RING_DELAY);
+            }
+            return false;
+        }
+        // The driver has a Drain to read as long as the ring isn't
+        // empty, see drain().
+        while self.capacity - head.wrapping_sub(header.tail.load(Ordering::SeqCst)) < needed {
+            std::thread::sleep(FULL_RING_DELAY);
+        }
+        self.write_at(head, &(payload.len() as u32).to_le_bytes());
+        self.write_at(head.wrapping_add(size_of::<u32>()), &payload);
+        header
+            .head
+            .store(head.wrapping_add(needed), Ordering::SeqCst);
+        if header.idle.swap(false, Ordering::SeqCst) {
+            wake();
+        }
+        true
+    }
+
+    fn give_up<T>(&mut self, reason: &str) -> Option<T> {
+        eprintln!(
+            "Failed to read the report ring: {}; ignoring the rest of it",
+            reason
+        );
+        self.corrupt = true;
+        None
+    }
+
+    /// Reads the message at `tail`, out of the `available` bytes, and
+    /// returns it with the number of bytes it took.
+    fn read_message(&mut self, tail: usize, available: usize) -> Option<(ReportMessage, usize)> {
+        if available < size_of::<u32>() || available > self.capacity {
+            return self.give_up(&format!("{} bytes are available", available));
+        }
+        let mut size = [0; size_of::<u32>()];
+        self.read_at(tail, &mut size);
+        let size = u32::from_le_bytes(size) as usize;
+        if size > available - size_of::<u32>() {
+            return self.give_up(&format!("the size {} is too large", size));
+        }
+        let mut payload = vec![0; size];
+        self.read_at(tail.wrapping_add(size_of::<u32>()), &mut payload);
+        match bincode::deserialize(&payload) {
+            Ok(message) => Some((message, size_of::<u32>() + size)),
+            Err(err) => self.give_up(&err.to_string()),
+        }
+    }
+
+    /// Reads all the messages in the ring, in the driver, after a
+    /// [ReportMessage::Drain].
+    pub(crate) fn drain(&mut self) -> Vec<ReportMessage> {
+        let mut messages = Vec::new();
+        while !self.corrupt {
+            let head = self.header().head.load(Ordering::SeqCst);
+            let mut tail = self.header().tail.load(Ordering::Relaxed);
+            while tail != head {
+                match self.read_message(tail, head.wrapping_sub(tail)) {
+                    Some((message, len)) => {
+                        messages.push(message);
+                        tail = tail.wrapping_add(len);
+                    }
+                    None => return messages,
+                }
+            }
+            let header = self.header();
+            header.tail.store(tail, Ordering::SeqCst);
+            // A message written before the test sees the flag is read
+            // by the check below, the others come with a Drain.
+            header.idle.store(true, Ordering::SeqCst);
+            if header.head.load(Ordering::SeqCst) == tail {
+                break;
+            }
+            header.idle.store(false, Ordering::SeqCst);
+        }
+        messages
+    }
+}
+
+impl Drop for ReportRing {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is not used anymore.
+        let _ = unsafe { munmap(self.base as *mut _, DATA_OFFSET + self.capacity) };
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::execution::Metric;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn ring_wakes_the_driver_once_per_drain() {
+        let metric = |value: f64| {
+            ReportMessage::Metric(Metric {
+                name: "m".to_string(),
+                value,
+            })
+        };
+        // Room for a few messages, so that they wrap around.
+        let mut ring = ReportRing::new(100).unwrap();
+        let mut wakes = 0;
+        for round in 0..10 {
+            for i in 0..3 {
+                assert!(ring.push(&metric((round * 3 + i) as f64), || wakes += 1));
+            }
+            assert_eq!(wakes, round + 1);
+            assert_eq!(
+                ring.drain(),
+                (0..3)
+                    .map(|i| metric((round * 3 + i) as f64))
+                    .collect::<Vec<_>>()
+            );
+        }
+        let large = ReportMessage::Skipped("x".repeat(100));
+        assert!(!ring.push(&large, || wakes += 1));
+        assert_eq!(wakes, 10);
+    }
+}
@@ -0,0 +1,109 @@
+//! Recognition of reports produced by LLVM sanitizers (ASAN, TSAN,
+//! MSAN, LSAN) in the captured stderr of failed tests.
+
+use serde::{Deserialize, Serialize};
+
+/// The essential part of a sanitizer report.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SanitizerError {
+    /// Name of the sanitizer, e.g. "AddressSanitizer".
+    pub sanitizer: String,
+    /// Kind of the problem, e.g. "heap-use-after-free" or "data race".
+    pub kind: String,
+    /// The one-line SUMMARY of the report, if there is one.
+    pub summary: Option<String>,
+}
+
+impl std::fmt::Display for SanitizerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.summary {
+            Some(ref summary) => write!(f, "{}", summary),
+            None => write!(f, "{}: {}", self.sanitizer, self.kind),
+        }
+    }
+}
+
+/// Parses the header of a report, e.g.
+/// `==42==ERROR: AddressSanitizer: heap-use-after-free on address 0x...`
+/// or `WARNING: ThreadSanitizer: data race (pid=42)`.
+fn parse_header(line: &str) -> Option<(String, String)> {
+    let line = line
+        .find("ERROR: ")
+        .or_else(|| line.find("WARNING: "))
+        .map(|pos| &line[pos..])?;
+    let (_, rest) = line.split_at(line.find(' ')? + 1);
+    let sep = rest.find(": ")?;
+    let sanitizer = &rest[..sep];
+    if !sanitizer.ends_with("Sanitizer") || sanitizer.contains(' ') {
+        return None;
+    }
+    let kind = &rest[sep + 2..];
+    let kind = [" on ", " ("]
+        .iter()
+        .filter_map(|delim| kind.find(delim))
+        .min()
+        .map(|end| &kind[..end])
+        .unwrap_or(kind)
+        .trim();
+    Some((sanitizer.to_string(), kind.to_string()))
+}
+
+/// Looks for the first sanitizer report in the output of a test.
+pub fn parse(stderr: &str) -> Option<SanitizerError> {
+    let (sanitizer, kind) = stderr.lines().find_map(parse_header)?;
+    let summary = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("SUMMARY: "))
+        .map(|s| s.trim().to_string());
+    Some(SanitizerError {
+        sanitizer,
+        kind,
+        summary,
+    })
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn parses_asan_report() {
+        let stderr = "some output\n\
+             =================================================================\n\
+             ==3453==ERROR: AddressSanitizer: heap-use-after-free on address 0x6020 at pc 0x55\n\
+             READ of size 4 at 0x6020 thread T0\n\
+             \x20   #0 0x55 in main src/main.rs:4\n\
+             SUMMARY: AddressSanitizer: heap-use-after-free src/main.rs:4 in main\n";
+        assert_eq!(
+            parse(stderr),
+            Some(SanitizerError {
+                sanitizer: "AddressSanitizer".to_string(),
+                kind: "heap-use-after-free".to_string(),
+                summary: Some(
+                    "AddressSanitizer: heap-use-after-free src/main.rs:4 in main".to_string()
+                ),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_tsan_report() {
+        let stderr = "==================\n\
+             WARNING: ThreadSanitizer: data race (pid=1234)\n\
+             \x20 Write of size 8 at 0x7b04 by thread T1:\n";
+        assert_eq!(
+            parse(stderr),
+            Some(SanitizerError {
+                sanitizer: "ThreadSanitizer".to_string(),
+                kind: "data race".to_string(),
+                summary: None,
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_regular_output() {
+        assert_eq!(parse("ERROR: something went wrong: badly\n"), None);
+        assert_eq!(parse("thread 'main' panicked at 'oops'\n"), None);
+    }
+}
@@ -0,0 +1,218 @@
+//! The scheduling of the test processes by the kernel, see
+//! [crate::Config::nice], [crate::Config::cpus] and
+//! [crate::Config::io_priority].  Only Linux supports the CPU
+//! affinity and the I/O priority, the other platforms report them as
+//! unsupported.
+
+use crate::config::{Config, IoPriority};
+use nix::libc;
+#[cfg(target_os = "linux")]
+use nix::sched::{sched_getaffinity, sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "unsupported on this platform",
+    )
+}
+
+/// Restricts the thread `pid`, 0 for the current one, to `cpus`.
+#[cfg(target_os = "linux")]
+fn set_affinity(pid: Pid, cpus: &[usize]) -> std::io::Result<()> {
+    let mut set = CpuSet::new();
+    for cpu in cpus {
+        // CPUs beyond the capacity of the set don't exist.
+        let _ = set.set(*cpu);
+    }
+    sched_setaffinity(pid, &set).map_err(|_| std::io::Error::last_os_error())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_pid: Pid, _cpus: &[usize]) -> std::io::Result<()> {
+    Err(unsupported())
+}
+
+/// Sets the I/O priority of the current thread, the threads it starts
+/// inherit it.
+#[cfg(target_os = "linux")]
+fn set_io_priority(priority: IoPriority) -> std::io::Result<()> {
+    // See include/uapi/linux/ioprio.h, libc has no wrapper.
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_SHIFT: u32 = 13;
+    let (class, level) = match priority {
+        IoPriority::RealTime(level) => (1, level),
+        IoPriority::BestEffort(level) => (2, level),
+        IoPriority::Idle => (3, 0),
+    };
+    let ioprio = (class << IOPRIO_CLASS_SHIFT) | libc::c_long::from(level);
+    // SAFETY: ioprio_set has no memory safety requirements.
+    match unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_io_priority(_priority: IoPriority) -> std::io::Result<()> {
+    Err(unsupported())
+}
+
+/// Applies the scheduling options to the current test process, before
+/// the test body runs.  `io_priority` is that of the test, if any, or
+/// that of the configuration.
+pub(crate) fn apply_in_child(config: &Config, io_priority: Option<IoPriority>) {
+    if let Some(priority) = io_priority {
+        if let Err(err) = set_io_priority(priority) {
+            eprintln!("Failed to set the I/O priority to {:?}: {}", priority, err);
+        }
+    }
+    if let Some(nice) = config.nice {
+        // SAFETY: setpriority has no memory safety requirements.
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            eprintln!(
+                "Failed to set the nice value to {}: {}",
+                nice,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    // With --pin-jobs, the driver sets the affinity.
+    if let (Some(cpus), false) = (&config.cpus, config.pin_jobs) {
+        if let Err(err) = set_affinity(Pid::from_raw(0), cpus) {
+            eprintln!("Failed to set the CPU affinity: {}", err);
+        }
+    }
+}
+
+/// The CPUs available to the tests: those given in the configuration,
+/// or those the driver may run on.
+fn available_cpus(config: &Config) -> Vec<usize> {
+    if let Some(cpus) = &config.cpus {
+        return cpus.clone();
+    }
+    #[cfg(target_os = "linux")]
+    if let Ok(set) = sched_getaffinity(Pid::from_raw(0)) {
+        return (0..CpuSet::count())
+            .filter(|cpu| set.is_set(*cpu).unwrap_or(false))
+            .collect();
+    }
+    (0..num_cpus::get()).collect()
+}
+
+/// Splits `cpus` into `jobs` slots of consecutive CPUs, as even as
+/// possible.  With fewer CPUs than jobs, the slots share them.
+pub fn partition(cpus: &[usize], jobs: usize) -> Vec<Vec<usize>> {
+    (0..jobs)
+        .map(|slot| {
+            if cpus.len() < jobs {
+                vec![cpus[slot % cpus.len()]]
+            } else {
+                cpus[slot * cpus.len() / jobs..(slot + 1) * cpus.len() / jobs].to_vec()
+            }
+        })
+        .collect()
+}
+
+/// The CPUs of each of the parallel jobs with [Config::pin_jobs], and
+/// the slot of each running test process.
+pub(crate) struct CpuSlots {
+    slots: Vec<Vec<usize>>,
+    free: Vec<usize>,
+    used: HashMap<Pid, usize>,
+}
+
+impl CpuSlots {
+    pub(crate) fn new(config: &Config, jobs: usize) -> Option<Self> {
+        if !config.pin_jobs {
+            return None;
+        }
+        if cfg!(not(target_os = "linux")) {
+            eprintln!("Failed to pin the jobs to CPUs: unsupported on this platform");
+            return None;
+        }
+        let cpus = available_cpus(config);
+        if cpus.is_empty() || jobs == 0 {
+            return None;
+        }
+        Some(Self {
+            slots: partition(&cpus, jobs),
+            free: (0..jobs).rev().collect(),
+            used: HashMap::new(),
+        })
+    }
+
+    /// Restricts the test process `pid` to the CPUs of a free slot.
+    /// The affinity is set for each thread of the process, since the
+    /// test may have started some already.
+    pub(crate) fn assign(&mut self, pid: Pid) {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => return,
+        };
+        self.used.insert(pid, slot);
+        let threads: Vec<Pid> = std::fs::read_dir(format!("/proc/{}/task", pid))
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                    .map(Pid::from_raw)
+                    .collect()
+            })
+            .unwrap_or_else(|_| vec![pid]);
+        for thread in threads {
+            // The thread may have exited since.
+            let _ = set_affinity(thread, &self.slots[slot]);
+        }
+    }
+
+    /// Frees the slot of a test process that is done.
+    pub(crate) fn release(&mut self, pid: Pid) {
+        if let Some(slot) = self.used.remove(&pid) {
+            self.free.push(slot);
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn partition_splits_cpus_between_jobs() {
+        assert_eq!(
+            partition(&[0, 1, 2, 3, 4], 2),
+            vec![vec![0, 1], vec![2, 3, 4]]
+        );
+        assert_eq!(partition(&[4, 5], 3), vec![vec![4], vec![5], vec![4]]);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn tests_run_with_their_io_priority() {
+        use crate::{test_case, test_suite, with_io_priority, Status};
+        // Checks the class and level of the I/O priority of the test.
+        fn assert_io_priority(class: libc::c_long, level: libc::c_long) {
+            // SAFETY: ioprio_get has no memory safety requirements.
+            let ioprio = unsafe { libc::syscall(libc::SYS_ioprio_get, 1, 0) };
+            assert_eq!(ioprio, (class << 13) | level);
+        }
+        let tree = test_suite(
+            "s",
+            vec![
+                test_case("a", || assert_io_priority(2, 7)),
+                with_io_priority(
+                    IoPriority::Idle,
+                    test_case("b", || assert_io_priority(3, 0)),
+                ),
+            ],
+        );
+        let config = Config::default().io_priority(IoPriority::BestEffort(7));
+        let tasks = crate::execution::test::run(&config, tree);
+        assert_eq!(tasks.len(), 2);
+        for task in tasks {
+            assert_eq!(task.status, Status::Success, "{:?}", task.full_name);
+        }
+    }
+}
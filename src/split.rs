@@ -0,0 +1,167 @@
+//! Suggestions of how to split the tests between CI jobs, see
+//! [crate::Config::suggest_split].
+//!
+//! The tests are spread over the jobs with the longest processing time
+//! first heuristic: from the slowest test to the fastest, each test
+//! goes to the job with the least work so far.  The durations come
+//! from the history database or the baseline results.
+
+use crate::config::Config;
+use crate::execution::Task;
+use crate::report::shell_quote;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The tests suggested for a job and how long they take together.
+#[derive(Debug, PartialEq)]
+pub struct Bucket {
+    pub names: Vec<String>,
+    pub duration: Duration,
+}
+
+/// Splits the tests into `n` buckets of similar durations.  The tests
+/// keep their order within a bucket.
+pub fn partition(tests: &[(String, Duration)], n: usize) -> Vec<Bucket> {
+    let mut order: Vec<usize> = (0..tests.len()).collect();
+    order.sort_by(|a, b| tests[*b].1.cmp(&tests[*a].1));
+    let mut assigned = vec![Vec::new(); n];
+    let mut totals = vec![Duration::ZERO; n];
+    for i in order {
+        let lightest = (0..n).min_by_key(|b| totals[*b]).unwrap();
+        assigned[lightest].push(i);
+        totals[lightest] += tests[i].1;
+    }
+    assigned
+        .into_iter()
+        .zip(totals)
+        .map(|(mut indices, duration)| {
+            indices.sort_unstable();
+            Bucket {
+                names: indices.into_iter().map(|i| tests[i].0.clone()).collect(),
+                duration,
+            }
+        })
+        .collect()
+}
+
+#[cfg(feature = "history")]
+fn history_durations(path: &std::path::Path) -> HashMap<String, Duration> {
+    let tests = crate::history::History::open(path)
+        .and_then(|history| history.tests(std::time::UNIX_EPOCH))
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to read the history in {}: {}", path.display(), err);
+            std::process::exit(1)
+        });
+    tests
+        .into_iter()
+        .map(|test| (test.name, test.mean_duration))
+        .collect()
+}
+
+#[cfg(not(feature = "history"))]
+fn history_durations(path: &std::path::Path) -> HashMap<String, Duration> {
+    eprintln!(
+        "Failed to read the history in {}: raclette was built without the history feature",
+        path.display()
+    );
+    std::process::exit(1)
+}
+
+/// Returns the known durations of the tests, by name.
+fn durations(config: &Config) -> HashMap<String, Duration> {
+    if let Some(path) = &config.history_db {
+        history_durations(path)
+    } else if let Some(path) = &config.baseline {
+        crate::json::completed_tasks(crate::read_events(path))
+            .into_iter()
+            .map(|task| (task.name(), task.duration))
+            .collect()
+    } else {
+        eprintln!("Splitting the tests requires durations from --history-db or --baseline");
+        std::process::exit(1)
+    }
+}
+
+/// Prints the options selecting the tests of each of `n` buckets.
+/// The tests without a known duration are assumed to take the mean
+/// duration of the others.
+pub(crate) fn suggest(config: &Config, plan: &[Task], n: usize) {
+    if n == 0 {
+        eprintln!("The tests can't be split between 0 jobs");
+        std::process::exit(1)
+    }
+    let known = durations(config);
+    let names: Vec<String> = plan.iter().map(Task::name).collect();
+    let found: Vec<Duration> = names.iter().filter_map(|n| known.get(n).copied()).collect();
+    let mean = found
+        .iter()
+        .sum::<Duration>()
+        .checked_div(found.len() as u32)
+        .unwrap_or_default();
+    let tests: Vec<(String, Duration)> = names
+        .iter()
+        .map(|name| (name.clone(), known.get(name).copied().unwrap_or(mean)))
+        .collect();
+    if found.len() < tests.len() {
+        println!(
+            "# {} of {} tests have no known duration, assuming {:.3}s",
+            tests.len() - found.len(),
+            tests.len(),
+            mean.as_secs_f64()
+        );
+    }
+
+    let buckets = partition(&tests, n);
+    for (i, bucket) in buckets.iter().enumerate() {
+        println!(
+            "# Job {} of {}: {} tests, {:.3}s",
+            i + 1,
+            n,
+            bucket.names.len(),
+            bucket.duration.as_secs_f64()
+        );
+        // With --exact, the skipped names are full names, so each job
+        // skips the tests of the other jobs.
+        let mut options = vec!["--exact".to_string()];
+        for (j, other) in buckets.iter().enumerate() {
+            if j != i {
+                for name in &other.names {
+                    options.push("--skip".to_string());
+                    options.push(shell_quote(name));
+                }
+            }
+        }
+        println!("{}", options.join(" "));
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn partition_balances_durations() {
+        let secs = |name: &str, s: u64| (name.to_string(), Duration::from_secs(s));
+        let tests = [
+            secs("a", 1),
+            secs("b", 7),
+            secs("c", 3),
+            secs("d", 4),
+            secs("e", 5),
+        ];
+        assert_eq!(
+            partition(&tests, 2),
+            vec![
+                Bucket {
+                    names: vec!["b".to_string(), "c".to_string()],
+                    duration: Duration::from_secs(10),
+                },
+                Bucket {
+                    names: vec!["a".to_string(), "d".to_string(), "e".to_string()],
+                    duration: Duration::from_secs(10),
+                },
+            ]
+        );
+        assert_eq!(partition(&tests, 3)[0].names, vec!["b"]);
+    }
+}
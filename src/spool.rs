@@ -0,0 +1,131 @@
+//! Capture of the output of the tests in memory files, see
+//! [crate::Config::splice_output].
+//!
+//! The driver moves the output from the pipes of a test to a memfd
+//! with splice(2), so the bytes don't go through the memory of the
+//! driver until the test is done, and the buffers don't grow by
+//! copying as the output arrives.  Both are specific to Linux, the
+//! driver reads the pipes on the other platforms.
+
+use std::io;
+use std::os::unix::io::RawFd;
+#[cfg(target_os = "linux")]
+use {
+    nix::errno::Errno,
+    nix::fcntl::{splice, SpliceFFlags},
+    nix::sys::memfd::{memfd_create, MemFdCreateFlag},
+    std::ffi::CString,
+    std::fs::File,
+    std::io::{Read, Seek, SeekFrom},
+    std::os::unix::io::{AsRawFd, FromRawFd},
+};
+
+/// How many bytes a single splice moves at most.
+#[cfg(target_os = "linux")]
+const SPLICE_LEN: usize = 1 << 20;
+
+/// Returns whether the output can be spooled on this system.
+pub(crate) fn is_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+#[cfg(target_os = "linux")]
+fn io_error(err: nix::Error) -> io::Error {
+    match err.as_errno() {
+        Some(errno) => io::Error::from(errno),
+        None => io::Error::other(err),
+    }
+}
+
+/// The output of a stream of a test captured so far.
+#[cfg(target_os = "linux")]
+pub(crate) struct Spool {
+    file: File,
+    len: i64,
+}
+
+/// Never created, see [is_supported].
+#[cfg(not(target_os = "linux"))]
+pub(crate) struct Spool(std::convert::Infallible);
+
+#[cfg(not(target_os = "linux"))]
+impl Spool {
+    pub(crate) fn new() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unsupported on this platform",
+        ))
+    }
+
+    pub(crate) fn splice_from(&mut self, _pipe: RawFd) -> io::Result<usize> {
+        match self.0 {}
+    }
+
+    pub(crate) fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self.0 {}
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Spool {
+    pub(crate) fn new() -> io::Result<Self> {
+        let name = CString::new("raclette-output").unwrap();
+        let fd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC).map_err(io_error)?;
+        Ok(Self {
+            // SAFETY: the descriptor was just created and nothing else
+            // owns it.
+            file: unsafe { File::from_raw_fd(fd) },
+            len: 0,
+        })
+    }
+
+    /// Moves everything available in the non-blocking `pipe` to the
+    /// spool and returns the number of bytes moved.
+    pub(crate) fn splice_from(&mut self, pipe: RawFd) -> io::Result<usize> {
+        let mut moved = 0;
+        loop {
+            match splice(
+                pipe,
+                None,
+                self.file.as_raw_fd(),
+                Some(&mut self.len),
+                SPLICE_LEN,
+                SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_NONBLOCK,
+            ) {
+                Ok(0) => return Ok(moved),
+                Ok(n) => moved += n,
+                Err(nix::Error::Sys(Errno::EAGAIN)) => return Ok(moved),
+                Err(nix::Error::Sys(Errno::EINTR)) => (),
+                Err(err) => return Err(io_error(err)),
+            }
+        }
+    }
+
+    /// Returns the captured output.
+    pub(crate) fn into_bytes(mut self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.len as usize);
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn spool_keeps_everything_written_to_the_pipe() {
+        let (read, write) = nix::unistd::pipe2(nix::fcntl::OFlag::O_NONBLOCK).unwrap();
+        let mut spool = Spool::new().unwrap();
+        nix::unistd::write(write, b"hello ").unwrap();
+        assert_eq!(spool.splice_from(read).unwrap(), 6);
+        assert_eq!(spool.splice_from(read).unwrap(), 0);
+        nix::unistd::write(write, b"world").unwrap();
+        nix::unistd::close(write).unwrap();
+        assert_eq!(spool.splice_from(read).unwrap(), 5);
+        nix::unistd::close(read).unwrap();
+        assert_eq!(spool.into_bytes().unwrap(), b"hello world");
+    }
+}
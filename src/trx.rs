@@ -0,0 +1,290 @@
+//! The Visual Studio test results (TRX) format, see
+//! [crate::config::Format::Trx], which the PublishTestResults task of
+//! Azure DevOps understands.
+//!
+//! The results are written when the run is done, as a single XML
+//! document.  The identifiers TRX requires are derived from the
+//! names of the tests, so that the same test keeps its identifier
+//! across runs.
+
+use crate::compress::RetainedTasks;
+use crate::execution::{CompletedTask, Report, Status};
+use crate::json::{self, RunInfo};
+use crate::report::{format_utc, xml_escape, ColorWriter};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The type of the unit tests, the same for all TRX files.
+const UNIT_TEST_TYPE: &str = "13cdc9d9-ddb5-4fa4-a97d-d965ccfc6d4b";
+/// The list of the results that don't belong to a list, the same for
+/// all TRX files.
+const DEFAULT_LIST_ID: &str = "8c84fa94-04c1-424b-9868-57a2d4851a1d";
+const ALL_LOADED_LIST_ID: &str = "19431567-8539-422a-85d7-44ee4e166bda";
+
+pub struct TrxReport {
+    writer: ColorWriter,
+    info: Option<RunInfo>,
+    tasks: RetainedTasks,
+}
+
+impl TrxReport {
+    pub fn new(writer: ColorWriter) -> Self {
+        Self {
+            writer,
+            info: None,
+            tasks: RetainedTasks::new(false),
+        }
+    }
+
+    /// Compresses the output of the tests until the report is written,
+    /// see [crate::Config::compress_output].
+    pub fn compress_output(mut self, enabled: bool) -> Self {
+        self.tasks = RetainedTasks::new(enabled);
+        self
+    }
+}
+
+/// Formats a GUID derived from `parts`.
+fn guid(parts: &[&str]) -> String {
+    let half = |seed: u64| {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        parts.hash(&mut hasher);
+        hasher.finish()
+    };
+    let n = (u128::from(half(0)) << 64) | u128::from(half(1));
+    let hex = format!("{:032x}", n);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Formats a duration as TRX does, e.g. "00:01:02.5000000".
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}.{:07}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        d.subsec_nanos() / 100
+    )
+}
+
+fn format_time(t: SystemTime) -> String {
+    format_utc(
+        t.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0),
+    )
+}
+
+/// The TRX outcome of a status.
+fn outcome(status: &Status) -> &'static str {
+    match status {
+        Status::Skipped(_) => "NotExecuted",
+        Status::Timeout => "Timeout",
+        status if status.is_ok() => "Passed",
+        _ => "Failed",
+    }
+}
+
+impl TrxReport {
+    fn write_result(&mut self, task: &CompletedTask, computer: &str) {
+        let name = task.name();
+        let attempt = task.attempt.to_string();
+        writeln!(
+            self.writer,
+            r#"    <UnitTestResult executionId="{}" testId="{}" testName="{}" computerName="{}" duration="{}" startTime="{}" endTime="{}" testType="{}" outcome="{}" testListId="{}">"#,
+            guid(&[&name, &attempt]),
+            guid(&[&name]),
+            xml_escape(&name),
+            xml_escape(computer),
+            format_duration(task.duration),
+            format_time(task.start_time),
+            format_time(task.start_time + task.duration),
+            UNIT_TEST_TYPE,
+            outcome(&task.status),
+            DEFAULT_LIST_ID,
+        )
+        .unwrap();
+        writeln!(self.writer, "      <Output>").unwrap();
+        if !task.stdout.is_empty() {
+            let stdout = xml_escape(&task.stdout_as_string());
+            writeln!(self.writer, "        <StdOut>{}</StdOut>", stdout).unwrap();
+        }
+        if !task.stderr.is_empty() {
+            let stderr = xml_escape(&task.stderr_as_string());
+            writeln!(self.writer, "        <StdErr>{}</StdErr>", stderr).unwrap();
+        }
+        let (_, reason) = json::outcome(&task.status);
+        if let Some(reason) = reason.filter(|_| !task.status.is_ok()) {
+            writeln!(
+                self.writer,
+                "        <ErrorInfo><Message>{}</Message></ErrorInfo>",
+                xml_escape(&reason)
+            )
+            .unwrap();
+        }
+        writeln!(self.writer, "      </Output>").unwrap();
+        writeln!(self.writer, "    </UnitTestResult>").unwrap();
+    }
+}
+
+impl Report for TrxReport {
+    fn header(&mut self, info: &RunInfo) {
+        self.info = Some(info.clone());
+    }
+
+    fn report(&mut self, task: &CompletedTask) {
+        self.tasks.push(task.clone());
+    }
+
+    fn done(&mut self) {
+        let finish = SystemTime::now();
+        let start = self
+            .info
+            .as_ref()
+            .map(|info| UNIX_EPOCH + Duration::from_secs_f64(info.start_time))
+            .unwrap_or(finish);
+        let computer = self
+            .info
+            .as_ref()
+            .and_then(|info| info.hostname.clone())
+            .unwrap_or_default();
+        let binary = std::env::args().next().unwrap_or_default();
+
+        writeln!(self.writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+        writeln!(
+            self.writer,
+            r#"<TestRun id="{}" name="raclette {}" xmlns="http://microsoft.com/schemas/VisualStudio/TeamTest/2010">"#,
+            guid(&[&binary, &format_time(start), &std::process::id().to_string()]),
+            xml_escape(&format_time(start)),
+        )
+        .unwrap();
+        writeln!(
+            self.writer,
+            r#"  <Times creation="{0}" queuing="{0}" start="{0}" finish="{1}"/>"#,
+            format_time(start),
+            format_time(finish)
+        )
+        .unwrap();
+
+        writeln!(self.writer, "  <Results>").unwrap();
+        let tasks = std::mem::replace(&mut self.tasks, RetainedTasks::new(false)).into_vec();
+        for task in tasks.iter() {
+            self.write_result(task, &computer);
+        }
+        writeln!(self.writer, "  </Results>").unwrap();
+
+        writeln!(self.writer, "  <TestDefinitions>").unwrap();
+        for task in tasks.iter() {
+            let name = task.name();
+            let (class, method) = match task.full_name.split_last() {
+                Some((method, suites)) => (suites.join("::"), method.clone()),
+                None => (String::new(), name.clone()),
+            };
+            writeln!(
+                self.writer,
+                r#"    <UnitTest name="{0}" storage="{1}" id="{2}"><Execution id="{3}"/><TestMethod codeBase="{1}" adapterTypeName="executor://raclette" className="{4}" name="{5}"/></UnitTest>"#,
+                xml_escape(&name),
+                xml_escape(&binary),
+                guid(&[&name]),
+                guid(&[&name, &task.attempt.to_string()]),
+                xml_escape(&class),
+                xml_escape(&method),
+            )
+            .unwrap();
+        }
+        writeln!(self.writer, "  </TestDefinitions>").unwrap();
+
+        writeln!(self.writer, "  <TestEntries>").unwrap();
+        for task in tasks.iter() {
+            let name = task.name();
+            writeln!(
+                self.writer,
+                r#"    <TestEntry testId="{}" executionId="{}" testListId="{}"/>"#,
+                guid(&[&name]),
+                guid(&[&name, &task.attempt.to_string()]),
+                DEFAULT_LIST_ID
+            )
+            .unwrap();
+        }
+        writeln!(self.writer, "  </TestEntries>").unwrap();
+
+        writeln!(self.writer, "  <TestLists>").unwrap();
+        writeln!(
+            self.writer,
+            r#"    <TestList name="Results Not in a List" id="{}"/>"#,
+            DEFAULT_LIST_ID
+        )
+        .unwrap();
+        writeln!(
+            self.writer,
+            r#"    <TestList name="All Loaded Results" id="{}"/>"#,
+            ALL_LOADED_LIST_ID
+        )
+        .unwrap();
+        writeln!(self.writer, "  </TestLists>").unwrap();
+
+        let count = |outcome_name: &str| {
+            tasks
+                .iter()
+                .filter(|task| outcome(&task.status) == outcome_name)
+                .count()
+        };
+        let (passed, failed, timeout) = (count("Passed"), count("Failed"), count("Timeout"));
+        let not_executed = count("NotExecuted");
+        writeln!(
+            self.writer,
+            r#"  <ResultSummary outcome="{}">"#,
+            if failed + timeout == 0 {
+                "Completed"
+            } else {
+                "Failed"
+            }
+        )
+        .unwrap();
+        writeln!(
+            self.writer,
+            r#"    <Counters total="{}" executed="{}" passed="{}" failed="{}" error="0" timeout="{}" aborted="0" inconclusive="0" passedButRunAborted="0" notRunnable="0" notExecuted="{}" disconnected="0" warning="0" completed="0" inProgress="0" pending="0"/>"#,
+            tasks.len(),
+            tasks.len() - not_executed,
+            passed,
+            failed,
+            timeout,
+            not_executed
+        )
+        .unwrap();
+        writeln!(self.writer, "  </ResultSummary>").unwrap();
+        writeln!(self.writer, "</TestRun>").unwrap();
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn trx_values_are_well_formed() {
+        assert_eq!(
+            format_duration(Duration::from_millis(62_500)),
+            "00:01:02.5000000"
+        );
+        assert_eq!(
+            xml_escape("<a & \"b\">\u{1b}[0m"),
+            "&lt;a &amp; &quot;b&quot;&gt;\u{fffd}[0m"
+        );
+        let id = guid(&["s::t"]);
+        assert_eq!(id.len(), 36);
+        assert_eq!(id, guid(&["s::t"]));
+        assert_ne!(id, guid(&["s::t", "1"]));
+    }
+}
@@ -0,0 +1,417 @@
+//! A full-screen dashboard of the running tests, enabled by `--ui`.
+//!
+//! While the tests run, the dashboard shows the running tests with
+//! bars of their elapsed time against the timeout, and a log of the
+//! latest completions.  Once all tests completed, failed tests can be
+//! browsed before the summary is printed.  Only available with the
+//! `ui` feature.
+
+use crate::{
+    baseline::Comparison,
+    execution::{CompletedTask, Report, Status},
+    json,
+    report::{comparison_lines, current_stage, TestStats},
+};
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    queue,
+    style::{Color, Print, ResetColor, SetForegroundColor},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+    tty::IsTty,
+};
+use std::collections::VecDeque;
+use std::io::{self, Stdout, Write};
+use std::time::{Duration, Instant};
+
+/// The minimal interval between two redraws of the dashboard.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The width of the elapsed time bars, without the brackets.
+const BAR_WIDTH: usize = 20;
+
+/// The number of completions kept in the log.
+const LOG_SIZE: usize = 1000;
+
+type Line = (Color, String);
+
+/// The stage a running test reported as in progress, and when it
+/// started.
+type CurrentStage = (String, Instant);
+
+pub struct UiReport {
+    out: Stdout,
+    timeout: Duration,
+    total: usize,
+    stats: TestStats,
+    // The running tests, in the order they started.
+    running: Vec<(String, Instant, Option<CurrentStage>)>,
+    // The latest completions, oldest first.
+    log: VecDeque<Line>,
+    failed: Vec<CompletedTask>,
+    started_at: Instant,
+    drawn_at: Option<Instant>,
+    // Whether the dashboard took over the terminal and has yet to
+    // give it back.
+    on_screen: bool,
+}
+
+/// Renders the time a test has been running as a bar filling up as
+/// the test approaches its timeout, colored by how close it is.
+fn elapsed_bar(elapsed: Duration, timeout: Duration) -> Line {
+    let fraction = if timeout.is_zero() {
+        1.0
+    } else {
+        (elapsed.as_secs_f64() / timeout.as_secs_f64()).min(1.0)
+    };
+    let filled = (fraction * BAR_WIDTH as f64).round() as usize;
+    let color = if fraction < 0.5 {
+        Color::Green
+    } else if fraction < 0.9 {
+        Color::Yellow
+    } else {
+        Color::Red
+    };
+    let bar = format!("[{}{}]", "#".repeat(filled), " ".repeat(BAR_WIDTH - filled));
+    (color, bar)
+}
+
+fn outcome(status: &Status) -> Line {
+    match status {
+        Status::Skipped(_) => (Color::Yellow, "ignored".to_string()),
+        status if status.is_ok() => (Color::Green, "ok".to_string()),
+        _ => (Color::Red, "FAILED".to_string()),
+    }
+}
+
+/// The lines describing a failed test in the failure browser.
+fn details(task: &CompletedTask) -> Vec<Line> {
+    let mut lines = vec![(Color::Red, task.name())];
+    if let (_, Some(reason)) = json::outcome(&task.status) {
+        lines.push((Color::Reset, reason));
+    }
+    let mut section = |title: &str, text: &str| {
+        if !text.is_empty() {
+            lines.push((Color::Reset, String::new()));
+            lines.push((Color::Cyan, format!("---- {} ----", title)));
+            lines.extend(text.lines().map(|l| (Color::Reset, l.to_string())));
+        }
+    };
+    if let Some(ref failure) = task.assertion {
+        section("assertion", &failure.message);
+        section("expected", &failure.expected);
+        section("actual", &failure.actual);
+    }
+    section("stdout", &task.stdout_as_string());
+    section("stderr", &task.stderr_as_string());
+    lines
+}
+
+impl UiReport {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            out: io::stdout(),
+            timeout,
+            total: 0,
+            stats: Default::default(),
+            running: Vec::new(),
+            log: VecDeque::new(),
+            failed: Vec::new(),
+            started_at: Instant::now(),
+            drawn_at: None,
+            on_screen: false,
+        }
+    }
+
+    /// Gives the terminal back in the state it was before the
+    /// dashboard took it over.
+    fn restore(&mut self) -> io::Result<()> {
+        if !self.on_screen {
+            return Ok(());
+        }
+        self.on_screen = false;
+        let raw_mode = terminal::disable_raw_mode();
+        queue!(self.out, cursor::Show, LeaveAlternateScreen)?;
+        self.out.flush()?;
+        raw_mode
+    }
+
+    /// Replaces the content of the screen with `lines`, cut to fit.
+    fn render(&mut self, lines: &[Line]) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        for (row, (color, text)) in lines.iter().take(height as usize).enumerate() {
+            let text: String = text.chars().take(width as usize).collect();
+            queue!(
+                self.out,
+                cursor::MoveTo(0, row as u16),
+                SetForegroundColor(*color),
+                Print(text),
+                ResetColor,
+                Clear(ClearType::UntilNewLine)
+            )?;
+        }
+        let rows = lines.len().min(height as usize) as u16;
+        if rows < height {
+            queue!(
+                self.out,
+                cursor::MoveTo(0, rows),
+                Clear(ClearType::FromCursorDown)
+            )?;
+        }
+        self.out.flush()
+    }
+
+    /// Redraws the dashboard, at most every [REDRAW_INTERVAL] unless
+    /// `force` is set.
+    fn draw(&mut self, force: bool) -> io::Result<()> {
+        if !force && self.drawn_at.is_some_and(|t| t.elapsed() < REDRAW_INTERVAL) {
+            return Ok(());
+        }
+        self.drawn_at = Some(Instant::now());
+        let (_, height) = terminal::size()?;
+        let height = height as usize;
+
+        let mut lines = vec![
+            (
+                Color::Reset,
+                format!(
+                    "{} of {} tests completed: {} passed, {} failed, {} ignored ({:.1}s)",
+                    self.stats.total,
+                    self.total,
+                    self.stats.ok,
+                    self.stats.failed,
+                    self.stats.ignored,
+                    self.started_at.elapsed().as_secs_f64()
+                ),
+            ),
+            (Color::Reset, String::new()),
+            (Color::Cyan, format!("running ({}):", self.running.len())),
+        ];
+        // The running tests get up to half of the screen, the log
+        // the rest.
+        let shown = self.running.len().min(height.saturating_sub(6) / 2);
+        for (name, started_at, stage) in self.running.iter().take(shown) {
+            let elapsed = started_at.elapsed();
+            let (color, bar) = elapsed_bar(elapsed, self.timeout);
+            let mut line = format!("{} {:>7.1}s {}", bar, elapsed.as_secs_f64(), name);
+            if let Some((stage, started_at)) = stage {
+                line.push_str(&format!(", {}", current_stage(stage, started_at.elapsed())));
+            }
+            lines.push((color, line));
+        }
+        if shown < self.running.len() {
+            let more = format!("... and {} more", self.running.len() - shown);
+            lines.push((Color::Reset, more));
+        }
+        lines.push((Color::Reset, String::new()));
+        lines.push((Color::Cyan, "completed:".to_string()));
+        let room = height.saturating_sub(lines.len());
+        let skip = self.log.len().saturating_sub(room);
+        lines.extend(self.log.iter().skip(skip).cloned());
+        self.render(&lines)
+    }
+
+    /// Moves a task from the running tests to the log.
+    fn completed(&mut self, task: &CompletedTask, color: Color, outcome: String) {
+        let name = task.name();
+        if let Some(i) = self.running.iter().position(|(n, _, _)| *n == name) {
+            self.running.remove(i);
+        }
+        let line = format!(
+            "{:<7} {:>7.1}s {}",
+            outcome,
+            task.duration.as_secs_f64(),
+            name
+        );
+        if self.log.len() == LOG_SIZE {
+            self.log.pop_front();
+        }
+        self.log.push_back((color, line));
+    }
+
+    /// Lets the user look through the failed tests until they quit.
+    fn browse(&mut self) -> io::Result<()> {
+        terminal::enable_raw_mode()?;
+        let result = self.browse_failures();
+        terminal::disable_raw_mode()?;
+        result
+    }
+
+    fn browse_failures(&mut self) -> io::Result<()> {
+        let mut selected: usize = 0;
+        // The scroll offset if the details of the selected test are
+        // shown.
+        let mut scroll: Option<usize> = None;
+        loop {
+            let (_, height) = terminal::size()?;
+            let page = (height as usize).saturating_sub(1).max(1);
+            let lines = match scroll {
+                None => {
+                    let mut lines = vec![(
+                        Color::Cyan,
+                        format!(
+                            "{} failed test(s): up/down to select, enter to show, q to quit",
+                            self.failed.len()
+                        ),
+                    )];
+                    let first = selected.saturating_sub(page - 1);
+                    for (i, task) in self.failed.iter().enumerate().skip(first) {
+                        let marker = if i == selected { "> " } else { "  " };
+                        lines.push((Color::Red, format!("{}{}", marker, task.name())));
+                    }
+                    lines
+                }
+                Some(offset) => {
+                    let details = details(&self.failed[selected]);
+                    let offset = offset.min(details.len().saturating_sub(1));
+                    scroll = Some(offset);
+                    let mut lines = vec![(
+                        Color::Cyan,
+                        "up/down/page up/page down to scroll, esc to go back".to_string(),
+                    )];
+                    lines.extend(details.into_iter().skip(offset));
+                    lines
+                }
+            };
+            self.render(&lines)?;
+
+            let key = match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release => key,
+                _ => continue,
+            };
+            if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                return Ok(());
+            }
+            match (scroll, key.code) {
+                (None, KeyCode::Char('q')) | (None, KeyCode::Esc) => return Ok(()),
+                (None, KeyCode::Up) | (None, KeyCode::Char('k')) => {
+                    selected = selected.saturating_sub(1)
+                }
+                (None, KeyCode::Down) | (None, KeyCode::Char('j')) => {
+                    selected = (selected + 1).min(self.failed.len() - 1)
+                }
+                (None, KeyCode::Enter) => scroll = Some(0),
+                (Some(_), KeyCode::Char('q'))
+                | (Some(_), KeyCode::Esc)
+                | (Some(_), KeyCode::Backspace) => scroll = None,
+                (Some(s), KeyCode::Up) | (Some(s), KeyCode::Char('k')) => {
+                    scroll = Some(s.saturating_sub(1))
+                }
+                (Some(s), KeyCode::Down) | (Some(s), KeyCode::Char('j')) => scroll = Some(s + 1),
+                (Some(s), KeyCode::PageUp) => scroll = Some(s.saturating_sub(page)),
+                (Some(s), KeyCode::PageDown) => scroll = Some(s + page),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl Report for UiReport {
+    fn init(&mut self, test_count: usize) {
+        self.total = test_count;
+        self.started_at = Instant::now();
+        queue!(self.out, EnterAlternateScreen, cursor::Hide).unwrap();
+        self.on_screen = true;
+        self.draw(true).unwrap();
+    }
+
+    fn start(&mut self, full_name: &[String]) {
+        self.running
+            .push((full_name.join("::"), Instant::now(), None));
+        self.draw(false).unwrap();
+    }
+
+    fn stage_in_progress(&mut self, full_name: &[String], stage: &str, elapsed: Duration) {
+        let name = full_name.join("::");
+        if let Some((_, _, current)) = self.running.iter_mut().find(|(n, _, _)| *n == name) {
+            let started_at = Instant::now()
+                .checked_sub(elapsed)
+                .unwrap_or_else(Instant::now);
+            *current = Some((stage.to_string(), started_at));
+        }
+    }
+
+    fn report(&mut self, task: &CompletedTask) {
+        self.stats.update(task);
+        let (color, outcome) = outcome(&task.status);
+        self.completed(task, color, outcome);
+        if !task.status.is_ok() {
+            self.failed.push(task.clone());
+        }
+        self.draw(false).unwrap();
+    }
+
+    fn retry(&mut self, attempt: &CompletedTask) {
+        self.completed(attempt, Color::Yellow, "retry".to_string());
+        self.draw(false).unwrap();
+    }
+
+    fn tick(&mut self) {
+        self.draw(false).unwrap();
+    }
+
+    // The dashboard shows the running tests already.
+    fn heartbeat(&mut self, _running: &[(Vec<String>, Duration)]) {}
+
+    fn done(&mut self) {
+        self.draw(true).unwrap();
+        if !self.failed.is_empty() && io::stdin().is_tty() {
+            self.browse().unwrap();
+        }
+        self.restore().unwrap();
+
+        if !self.failed.is_empty() {
+            writeln!(self.out, "\nfailures:").unwrap();
+            for task in self.failed.iter() {
+                writeln!(self.out, "    {}", task.name()).unwrap();
+            }
+        }
+        let status = if self.stats.ok() { "ok" } else { "FAILED" };
+        writeln!(
+            self.out,
+            "\ntest result: {}. {} passed; {} failed; {} ignored; finished in {:.2}s\n",
+            status,
+            self.stats.ok,
+            self.stats.failed,
+            self.stats.ignored,
+            self.started_at.elapsed().as_secs_f64()
+        )
+        .unwrap();
+    }
+
+    fn compare(&mut self, comparison: &Comparison) {
+        for line in comparison_lines(comparison) {
+            writeln!(self.out, "{}", line).unwrap();
+        }
+        writeln!(self.out).unwrap();
+    }
+}
+
+// Restores the terminal if the run ends without `done`, e.g. when a
+// report panics, so that the shell isn't left in the alternate screen
+// or in raw mode.
+impl Drop for UiReport {
+    fn drop(&mut self) {
+        let _ = self.restore();
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn elapsed_bar_fills_up_to_the_timeout() {
+        let timeout = Duration::from_secs(10);
+        let bar = |secs| elapsed_bar(Duration::from_secs(secs), timeout);
+        assert_eq!(bar(0), (Color::Green, format!("[{}]", " ".repeat(20))));
+        assert_eq!(
+            bar(5),
+            (
+                Color::Yellow,
+                format!("[{}{}]", "#".repeat(10), " ".repeat(10))
+            )
+        );
+        assert_eq!(bar(60), (Color::Red, format!("[{}]", "#".repeat(20))));
+    }
+}
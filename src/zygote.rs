@@ -0,0 +1,191 @@
+//! The zygote execution mode.
+//!
+//! The zygote is a child of the driver that runs the child
+//! initialization hook once and then forks a process for each task
+//! the driver sends it.  To let the driver wait for test processes
+//! as if it forked them itself, the zygote forks them through a
+//! short-lived intermediate process: once the intermediate exits,
+//! the test process is adopted by the driver, which is marked as a
+//! child subreaper.  The driver's ends of the pipes of a test process
+//! are passed to the driver over a unix socket.
+
+use crate::config::Config;
+use crate::execution::{enlarge_report_pipe, run_in_child, set_child_pgid, StreamDecoder, Task};
+use crate::prefork::{self, WorkerCommand};
+use mio::unix::pipe;
+use nix::libc;
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use nix::sys::uio::IoVec;
+use nix::sys::wait::waitpid;
+use nix::unistd::{self, fork, ForkResult, Pid};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// Returns whether the zygote mode is available on this system.
+pub fn is_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// Makes the current process adopt its orphaned descendants.
+#[cfg(target_os = "linux")]
+pub fn become_subreaper() {
+    // SAFETY: prctl with PR_SET_CHILD_SUBREAPER only takes integers.
+    let rc = unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) };
+    if rc != 0 {
+        panic!(
+            "failed to become a child subreaper: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn become_subreaper() {
+    unreachable!("the zygote mode is only supported on Linux")
+}
+
+/// Sends the PID of a test process, the read ends of its stdout,
+/// stderr and report pipes and the driver's end of its control
+/// socket to the driver.
+fn send_task(control: &UnixStream, pid: Pid, pipes: &[RawFd; 4]) -> nix::Result<()> {
+    let pid_bytes = pid.as_raw().to_ne_bytes();
+    let iov = [IoVec::from_slice(&pid_bytes)];
+    let fds = [ControlMessage::ScmRights(pipes)];
+    sendmsg(control.as_raw_fd(), &iov, &fds, MsgFlags::empty(), None)?;
+    Ok(())
+}
+
+/// Receives the PID of a test process and the driver's ends of its
+/// pipes and control socket from the zygote.
+pub fn receive_task(control: &UnixStream) -> nix::Result<(Pid, [pipe::Receiver; 3], UnixStream)> {
+    let mut pid_bytes = [0u8; 4];
+    let mut cmsg_buf = nix::cmsg_space!([RawFd; 4]);
+    let iov = [IoVec::from_mut_slice(&mut pid_bytes)];
+    let msg = recvmsg(
+        control.as_raw_fd(),
+        &iov,
+        Some(&mut cmsg_buf),
+        MsgFlags::empty(),
+    )?;
+    let fds: Vec<RawFd> = msg
+        .cmsgs()
+        .filter_map(|cmsg| match cmsg {
+            ControlMessageOwned::ScmRights(fds) => Some(fds),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    if msg.bytes != pid_bytes.len() || fds.len() != 4 {
+        return Err(nix::Error::Sys(nix::errno::Errno::EPROTO));
+    }
+    // SAFETY: the descriptors have just been received and are not
+    // owned by anything else.
+    let pipes = unsafe {
+        [
+            pipe::Receiver::from_raw_fd(fds[0]),
+            pipe::Receiver::from_raw_fd(fds[1]),
+            pipe::Receiver::from_raw_fd(fds[2]),
+        ]
+    };
+    // SAFETY: same as above.
+    let task_control = unsafe { UnixStream::from_raw_fd(fds[3]) };
+    Ok((
+        Pid::from_raw(i32::from_ne_bytes(pid_bytes)),
+        pipes,
+        task_control,
+    ))
+}
+
+/// Forks a test process executing the task, returning its PID and
+/// the driver's ends of its pipes and control socket.  The process is
+/// forked through an intermediate process, so it's reparented to the
+/// driver by the time this function returns.
+fn fork_task(config: &Config, task: &mut Option<Task>) -> (Pid, [pipe::Receiver; 3], UnixStream) {
+    let (stdout_sender, stdout_receiver) = pipe::new().unwrap();
+    let (stderr_sender, stderr_receiver) = pipe::new().unwrap();
+    let (report_sender, report_receiver) = pipe::new().unwrap();
+    let (task_control, child_control) =
+        UnixStream::pair().expect("zygote: failed to create a socket pair");
+    let (pid_receiver, pid_sender) = unistd::pipe().expect("zygote: failed to create a pipe");
+    enlarge_report_pipe(&report_receiver);
+
+    io::stdout().lock().flush().unwrap();
+    io::stderr().lock().flush().unwrap();
+
+    match fork().expect("zygote: failed to fork") {
+        ForkResult::Child => match fork().expect("zygote: failed to fork") {
+            ForkResult::Child => {
+                std::mem::drop(stdout_receiver);
+                std::mem::drop(stderr_receiver);
+                std::mem::drop(report_receiver);
+                std::mem::drop(task_control);
+                let _ = unistd::close(pid_receiver);
+                let _ = unistd::close(pid_sender);
+                run_in_child(
+                    config,
+                    task.take().unwrap(),
+                    &stdout_sender,
+                    &stderr_sender,
+                    &report_sender,
+                    &child_control,
+                    None,
+                )
+            }
+            ForkResult::Parent { child } => {
+                set_child_pgid(child);
+                // SAFETY: the descriptor is owned by this process.
+                let mut w = unsafe { File::from_raw_fd(pid_sender) };
+                let _ = w.write_all(&child.as_raw().to_ne_bytes());
+                // Skip the exit handlers, they belong to the zygote.
+                // SAFETY: _exit is always safe to call.
+                unsafe { libc::_exit(0) }
+            }
+        },
+        ForkResult::Parent { child } => {
+            let _ = unistd::close(pid_sender);
+            // SAFETY: the descriptor is owned by this process.
+            let mut r = unsafe { File::from_raw_fd(pid_receiver) };
+            let mut pid_bytes = Vec::new();
+            r.read_to_end(&mut pid_bytes)
+                .expect("zygote: failed to read the PID of a test process");
+            waitpid(child, None).expect("zygote: failed to wait for a child");
+            let pid_bytes: [u8; 4] = pid_bytes[..]
+                .try_into()
+                .expect("zygote: failed to fork a test process");
+            (
+                Pid::from_raw(i32::from_ne_bytes(pid_bytes)),
+                [stdout_receiver, stderr_receiver, report_receiver],
+                task_control,
+            )
+        }
+    }
+}
+
+/// The main loop of the zygote: forks test processes as instructed by
+/// the driver until the control socket is closed.
+pub fn zygote_main(config: &Config, tasks: &mut [Option<Task>], mut control: UnixStream) -> ! {
+    if let Some(init) = config.child_init {
+        init();
+    }
+
+    let mut decoder = StreamDecoder::<WorkerCommand>::new();
+    while let Some(index) = prefork::next_task(&mut control, &mut decoder) {
+        // The task stays in the plan of the zygote in case it's
+        // retried.
+        let (pid, pipes, task_control) = fork_task(config, &mut tasks[index]);
+        let fds = [
+            pipes[0].as_raw_fd(),
+            pipes[1].as_raw_fd(),
+            pipes[2].as_raw_fd(),
+            task_control.as_raw_fd(),
+        ];
+        send_task(&control, pid, &fds).expect("zygote: failed to send a task to the driver");
+        // The driver has its own copies of the pipes now.
+        std::mem::drop(pipes);
+        std::mem::drop(task_control);
+    }
+    std::process::exit(0)
+}